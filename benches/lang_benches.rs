@@ -1,7 +1,11 @@
 use criterion::{criterion_group, criterion_main, Criterion};
+use morpho_c::ast::CallExpr;
 use morpho_c::parser::ProgParser;
-use morpho_c::program::evaluating_functions::eval_program;
+use morpho_c::program::environment::LocalEnvironment;
+use morpho_c::program::evaluating_functions::{call_func, eval_program, load_program};
+use morpho_c::program::shared::Shared;
 
+#[allow(dead_code)]
 fn bench_for_block_with_anon_func(c: &mut Criterion) {
     let ast = ProgParser::new()
         .parse(r#"func main = () { for(i in 0..10, $|i: i| { print(i); } ); }"#)
@@ -13,6 +17,7 @@ fn bench_for_block_with_anon_func(c: &mut Criterion) {
     });
 }
 
+#[allow(dead_code)]
 fn bench_print_func(c: &mut Criterion) {
     let ast = ProgParser::new()
         .parse(r#"func main = () { print("Hello"); }"#)
@@ -24,6 +29,7 @@ fn bench_print_func(c: &mut Criterion) {
     });
 }
 
+#[allow(dead_code)]
 fn bench_condition_block_recursion(c: &mut Criterion) {
     let ast = ProgParser::new()
         .parse(r#"func main = () { foo(10); } func foo = (a: int) { if(a == 0, $print|"end"|, $foo|a - 1|); } func foo1 = () {}"#).unwrap();
@@ -74,6 +80,7 @@ fn bench_for_block_with_func_and_ref_20(c: &mut Criterion) {
     });
 }
 
+#[allow(dead_code)]
 fn bench_evaluating_fibonacci_5(c: &mut Criterion) {
     let ast = ProgParser::new()
         .parse(r#"func main = () { fibonacci(5); } func fibonacci = (n: int) -> int {return if(n <= 1, $|n: n| -> int { return n; }, $|n: n| -> int { return fibonacci(n-1) + fibonacci(n-2); });}"#).unwrap();
@@ -84,6 +91,7 @@ fn bench_evaluating_fibonacci_5(c: &mut Criterion) {
     });
 }
 
+#[allow(dead_code)]
 fn bench_evaluating_fibonacci_10(c: &mut Criterion) {
     let ast = ProgParser::new()
         .parse(r#"func main = () { fibonacci(10); } func fibonacci = (n: int) -> int {return if(n <= 1, $|n: n| -> int { return n; }, $|n: n| -> int { return fibonacci(n-1) + fibonacci(n-2); });}"#).unwrap();
@@ -94,6 +102,7 @@ fn bench_evaluating_fibonacci_10(c: &mut Criterion) {
     });
 }
 
+#[allow(dead_code)]
 fn bench_evaluating_fibonacci_20(c: &mut Criterion) {
     let ast = ProgParser::new()
         .parse(r#"func main = () { fibonacci(20);} func fibonacci = (n: int) -> int {return if(n <= 1, $|n: n| -> int { return n; }, $|n: n| -> int { return fibonacci(n-1) + fibonacci(n-2); });}"#).unwrap();
@@ -104,6 +113,7 @@ fn bench_evaluating_fibonacci_20(c: &mut Criterion) {
     });
 }
 
+#[allow(dead_code)]
 fn bench_evaluating_fibonacci_iter_20(c: &mut Criterion) {
     let ast = ProgParser::new()
         .parse(r#"func main = () { let a = 0; let b = 1; for(0..20, $|a: &a, b: &b | { let temp = b; b = a + b; a = temp; }); }"#).unwrap();
@@ -113,6 +123,48 @@ fn bench_evaluating_fibonacci_iter_20(c: &mut Criterion) {
         });
     });
 }
+
+/// Runs `main` of `src` through `call_func` on each iteration. The program is set
+/// up once, so the iterations measure the resolved calls and not the setup
+fn bench_main(c: &mut Criterion, name: &str, src: &str) {
+    load_program(ProgParser::new().parse(src).unwrap()).unwrap();
+    let main = CallExpr::new("main".into(), vec![]);
+    let env = Shared::new(LocalEnvironment::new());
+    c.bench_function(name, |b| {
+        b.iter(|| call_func(&main, env.clone()));
+    });
+}
+
+fn bench_locals_and_globals_loop(c: &mut Criterion) {
+    bench_main(
+        c,
+        "bench_locals_and_globals_loop",
+        r#"const STEP = 3; func main = () { let total = 0; for(i in 0..200, $|total: &total, i: i| { let a = i * STEP; let b = a + i; let c = b - a; total = total + step(c); }); } func step = (n: int) -> int { let x = n + STEP; let y = x * 2; return y - x; }"#,
+    );
+}
+
+fn bench_module_function_calls(c: &mut Criterion) {
+    bench_main(
+        c,
+        "bench_module_function_calls",
+        r#"mod geometry { pub const SIDES = 4; pub func perimeter = (side: int) -> int { return side * SIDES; } } func main = () { let total = 0; for(i in 0..200, $|total: &total, i: i| { total = total + geometry::perimeter(i); }); }"#,
+    );
+}
+
+fn bench_large_function_calls(c: &mut Criterion) {
+    // Only the last statements of `large` run, calling it costs the same however long its body is
+    let unused = (0..200).map(|i| format!("let v{i} = n * {i} + {i};")).collect::<Vec<_>>().join(" ");
+    let src = format!(
+        r#"func main = () {{ let total = 0; for(i in 0..200, $|total: &total, i: i| {{ total = total + large(i); }}); }} func large = (n: int) -> int {{ if(n < 0, $|n: n| {{ {unused} }}); return n + 1; }}"#
+    );
+    let ast = ProgParser::new().parse(&src).unwrap();
+    c.bench_function("bench_large_function_calls", |b| {
+        b.iter(|| {
+            eval_program(ast.clone()).unwrap();
+        });
+    });
+}
+
 criterion_group!(
     benches,
     // bench_for_block_with_anon_func,
@@ -121,11 +173,14 @@ criterion_group!(
     bench_for_block_with_anon_func_and_ref_10,
     bench_for_block_with_func_and_ref_10,
     bench_for_block_with_anon_func_and_ref_20,
-    bench_for_block_with_func_and_ref_20
+    bench_for_block_with_func_and_ref_20,
     // bench_evaluating_fibonacci_5,
     // bench_evaluating_fibonacci_10,
     // bench_evaluating_fibonacci_20,
     // bench_evaluating_fibonacci_iter_20
+    bench_locals_and_globals_loop,
+    bench_module_function_calls,
+    bench_large_function_calls
 );
 
 criterion_main!(benches);
@@ -0,0 +1,72 @@
+use crate::ast::Prog;
+use serde_json::Value as Json;
+use std::fmt::Write;
+
+/// The parsed tree as JSON. Enum variants are objects keyed by the variant
+/// name, statement offsets are only present for blocks parsed from source
+pub fn to_json(prog: &Prog) -> Json {
+    serde_json::to_value(prog).expect("the AST always serializes")
+}
+
+/// The parsed tree with one node or field per line, children indented under
+/// their parent
+pub fn render(prog: &Prog) -> String {
+    let mut out = String::from("Prog\n");
+    for stmt in to_json(prog).as_array().into_iter().flatten() {
+        node(&mut out, stmt, 1);
+    }
+    out
+}
+
+fn node(out: &mut String, json: &Json, depth: usize) {
+    let indent = "  ".repeat(depth);
+    match json {
+        // A variant, `{"Add": [lhs, rhs]}` or `{"Ident": "a"}`
+        Json::Object(map) if is_variant(json) => {
+            let (variant, inner) = map.iter().next().unwrap();
+            match inner {
+                Json::Array(items) if items.iter().any(is_nested) => {
+                    writeln!(out, "{indent}{variant}").unwrap();
+                    items.iter().for_each(|item| node(out, item, depth + 1));
+                }
+                Json::Object(_) => {
+                    writeln!(out, "{indent}{variant}").unwrap();
+                    match is_variant(inner) {
+                        true => node(out, inner, depth + 1),
+                        false => fields(out, inner, depth + 1),
+                    }
+                }
+                inner => writeln!(out, "{indent}{variant} {inner}").unwrap(),
+            }
+        }
+        Json::Object(_) => fields(out, json, depth),
+        Json::Array(items) => items.iter().for_each(|item| node(out, item, depth)),
+        scalar => writeln!(out, "{indent}{scalar}").unwrap(),
+    }
+}
+
+fn fields(out: &mut String, json: &Json, depth: usize) {
+    let indent = "  ".repeat(depth);
+    for (field, value) in json.as_object().into_iter().flatten() {
+        if is_nested(value) {
+            writeln!(out, "{indent}{field}:").unwrap();
+            node(out, value, depth + 1);
+        } else {
+            writeln!(out, "{indent}{field}: {value}").unwrap();
+        }
+    }
+}
+
+fn is_variant(json: &Json) -> bool {
+    json.as_object()
+        .is_some_and(|map| map.len() == 1 && map.keys().all(|key| key.starts_with(char::is_uppercase)))
+}
+
+/// Whether `json` needs lines of its own, plain values and lists of them fit inline
+fn is_nested(json: &Json) -> bool {
+    match json {
+        Json::Object(_) => true,
+        Json::Array(items) => items.iter().any(|item| item.is_object() || item.as_array().is_some_and(|a| a.iter().any(is_nested))),
+        _ => false,
+    }
+}
@@ -1,12 +1,22 @@
+pub mod dump;
+pub mod string_lit;
+
+use serde::Serialize;
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Debug, PartialEq, Clone)]
 pub struct Prog(pub Vec<Stmt>);
 
-#[derive(Debug, Clone, PartialOrd)]
+#[derive(Serialize, Debug, Clone, PartialOrd)]
 pub enum Expr {
     Ident(String),
+    /// A local variable resolved to its slot in the function's frame, produced by the resolver
+    Local(String, u16),
+    /// A global or module item resolved to its id in the global environment, produced by the resolver
+    Global(String, u32),
     Integer(i64),
+    BigInteger(String),
     Float(f64),
     Bool(bool),
     StringLit(String),
@@ -34,20 +44,29 @@ pub enum Expr {
     Neg(Box<Expr>),
     Xor(Box<Expr>, Box<Expr>),
     Mod(Box<Expr>, Box<Expr>),
-    InlineAccess(InlineAccess)
+    InlineAccess(InlineAccess),
+    Concat(Vec<Expr>),
+    BitAnd(Box<Expr>, Box<Expr>),
+    BitOr(Box<Expr>, Box<Expr>),
+    Shl(Box<Expr>, Box<Expr>),
+    Shr(Box<Expr>, Box<Expr>),
 }
 
 impl PartialEq for Expr {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Expr::Ident(a), Expr::Ident(b)) => a == b,
+            (Expr::Local(a, i), Expr::Local(b, j)) => a == b && i == j,
+            (Expr::Global(a, i), Expr::Global(b, j)) => a == b && i == j,
             (Expr::Integer(a), Expr::Integer(b)) => a == b,
+            (Expr::BigInteger(a), Expr::BigInteger(b)) => a == b,
             (Expr::Float(a), Expr::Float(b)) => {
                 const EPSILON: f64 = 1e-10;
                 (a - b).abs() < EPSILON
             }
             (Expr::Bool(a), Expr::Bool(b)) => a == b,
             (Expr::StringLit(a), Expr::StringLit(b)) => a == b,
+            (Expr::Ref(a), Expr::Ref(b)) => a == b,
             (Expr::Array(a), Expr::Array(b)) => a == b,
             (Expr::Dictionary(a), Expr::Dictionary(b)) => a == b,
             (Expr::Call(a), Expr::Call(b)) => a == b,
@@ -76,6 +95,11 @@ impl PartialEq for Expr {
             (Expr::Xor(a1,b1 ), Expr::Xor(a2, b2)) => a1 == a2 && b1 == b2,
             (Expr::Mod(a, b), Expr::Mod(c, d)) => a == c && b == d,
             (Expr::InlineAccess(a), Expr::InlineAccess(b)) => a == b,
+            (Expr::Concat(a), Expr::Concat(b)) => a == b,
+            (Expr::BitAnd(a, b), Expr::BitAnd(c, d)) => a == c && b == d,
+            (Expr::BitOr(a, b), Expr::BitOr(c, d)) => a == c && b == d,
+            (Expr::Shl(a, b), Expr::Shl(c, d)) => a == c && b == d,
+            (Expr::Shr(a, b), Expr::Shr(c, d)) => a == c && b == d,
             _ => false,
         }
     }
@@ -223,14 +247,55 @@ impl Hash for Expr {
                 state.write_u8(29);
                 a.hash(state)
             }
+            Expr::Concat(parts) => {
+                state.write_u8(30);
+                parts.hash(state)
+            }
+            Expr::BigInteger(digits) => {
+                state.write_u8(31);
+                digits.hash(state)
+            }
+            Expr::BitAnd(ref lhs, ref rhs) => {
+                state.write_u8(32);
+                lhs.hash(state);
+                rhs.hash(state);
+            }
+            Expr::BitOr(ref lhs, ref rhs) => {
+                state.write_u8(33);
+                lhs.hash(state);
+                rhs.hash(state);
+            }
+            Expr::Shl(ref lhs, ref rhs) => {
+                state.write_u8(34);
+                lhs.hash(state);
+                rhs.hash(state);
+            }
+            Expr::Shr(ref lhs, ref rhs) => {
+                state.write_u8(35);
+                lhs.hash(state);
+                rhs.hash(state);
+            }
+            Expr::Local(ref s, slot) => {
+                state.write_u8(36);
+                s.hash(state);
+                slot.hash(state);
+            }
+            Expr::Global(ref s, id) => {
+                state.write_u8(37);
+                s.hash(state);
+                id.hash(state);
+            }
         }
     }
 }
 
-#[derive(Debug, Clone, PartialOrd, PartialEq, Hash)]
+#[derive(Serialize, Debug, Clone, PartialOrd, PartialEq, Hash)]
 pub struct FuncPtr {
     pub ident: String,
     pub args: Option<Vec<Expr>>,
+    /// Global id of the function, set by the resolver
+    #[serde(skip)]
+    pub target: Option<u32>,
 }
 
 impl FuncPtr {
@@ -238,18 +303,35 @@ impl FuncPtr {
         Self {
             ident: ident.into(),
             args,
+            target: None,
         }
     }
 }
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash)]
 pub struct CallExpr {
     func_name: String,
     args: Vec<Expr>,
+    /// Global id of the function, set by the resolver
+    #[serde(skip)]
+    target: Option<u32>,
 }
 
 impl CallExpr {
     pub fn new(func_name: String, args: Vec<Expr>) -> Self {
-        CallExpr { func_name, args }
+        CallExpr { func_name, args, target: None }
+    }
+    pub fn with_target(mut self, target: Option<u32>) -> Self {
+        self.target = target;
+        self
+    }
+    pub fn get_target(&self) -> Option<u32> {
+        self.target
+    }
+    pub(crate) fn set_target(&mut self, target: Option<u32>) {
+        self.target = target;
+    }
+    pub(crate) fn args_mut(&mut self) -> &mut Vec<Expr> {
+        &mut self.args
     }
     pub fn get_name(&self) -> String {
         self.func_name.clone()
@@ -257,9 +339,12 @@ impl CallExpr {
     pub fn get_args(&self) -> Vec<Expr> {
         self.args.clone()
     }
+    pub fn args(&self) -> &[Expr] {
+        &self.args
+    }
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub enum Stmt {
     FuncIdent(FuncIdent),
     FuncBody(Body),
@@ -268,22 +353,54 @@ pub enum Stmt {
     ReturnValue(Box<Expr>),
     Expr(Box<Expr>),
     Module(Module),
+    /// `mod name;` declaration, replaced by the module loader with the contents of `name.mo`
+    ModuleFile(PrivacyType, String),
     Import(Import),
+    /// `const NAME = expr;` or `pub let name = expr;` outside of functions
+    Global(GlobalIdent),
     Comment(String),
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub struct Import {
-    pub inline_access: Box<Expr>,
+    pub tree: UseTree,
 }
 
 impl Import {
-    pub fn new(inline_access: Box<Expr>) -> Self {
-        Self { inline_access }
+    pub fn new(tree: UseTree) -> Self {
+        Self { tree }
+    }
+}
+
+/// Path of a `use` declaration, e.g. `a::b` followed by what it imports
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+pub struct UseTree {
+    pub path: Vec<String>,
+    pub kind: UseKind,
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+pub enum UseKind {
+    /// `a::b` or `a::b as c`, binds the last segment of the path
+    Simple(Option<String>),
+    /// `a::*`
+    Glob,
+    /// `a::{b, c::d}`
+    Group(Vec<UseTree>),
+}
+
+impl UseTree {
+    pub fn new(path: Vec<String>, kind: UseKind) -> Self {
+        Self { path, kind }
+    }
+
+    pub fn prefixed(mut self, ident: String) -> Self {
+        self.path.insert(0, ident);
+        self
     }
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub struct InlineAccess {
     pub ident: String,
     pub next: Option<Box<Expr>>
@@ -295,19 +412,34 @@ impl InlineAccess {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub struct Module {
+    pub(crate) privacy_type: PrivacyType,
     pub(crate) ident: String,
     pub(crate) body: Body
 }
 
 impl Module {
-    pub fn new(ident: String, body: Body) -> Self {
-        Self { ident, body }
+    pub fn new(privacy_type: PrivacyType, ident: String, body: Body) -> Self {
+        Self { privacy_type, ident, body }
     }
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+pub struct GlobalIdent {
+    pub privacy_type: PrivacyType,
+    pub constant: bool,
+    pub ident: String,
+    pub expr: Expr,
+}
+
+impl GlobalIdent {
+    pub fn new(privacy_type: PrivacyType, constant: bool, ident: String, expr: Expr) -> Self {
+        Self { privacy_type, constant, ident, expr }
+    }
+}
+
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub struct VarAssign {
     pub ident: String,
     pub expr: Expr,
@@ -319,7 +451,7 @@ impl VarAssign {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub struct AnonymousFunc {
     pub args: Vec<(String, Expr)>,
     pub rty: String,
@@ -340,13 +472,13 @@ impl AnonymousFunc {
     }
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq, PartialOrd)]
+#[derive(Serialize, Clone, Debug, Eq, Hash, PartialEq, PartialOrd)]
 pub enum PrivacyType {
     Public,
     Private,
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub struct FuncIdent {
     pub privacy_type: PrivacyType,
     pub ident: String,
@@ -383,18 +515,68 @@ impl FuncIdent {
     }
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+/// Statements of a block. `offset` and `offsets` hold the byte offset of the
+/// block and of each statement in its source when the block was parsed and
+/// `scope` the slots the resolver gave the block's locals, all of them are
+/// ignored by comparisons
+#[derive(Serialize, Debug, Clone)]
 pub struct Body {
     pub stmt: Vec<Stmt>,
+    #[serde(skip)]
+    pub offset: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub offsets: Vec<usize>,
+    #[serde(skip)]
+    pub scope: Option<Arc<Scope>>,
 }
 
 impl Body {
     pub fn new(stmt: Vec<Stmt>) -> Self {
-        Self { stmt }
+        Self { stmt, offset: None, offsets: Vec::new(), scope: None }
+    }
+
+    pub fn located(offset: usize, stmts: Vec<(usize, Stmt)>) -> Self {
+        let (offsets, stmt) = stmts.into_iter().unzip();
+        Self { stmt, offset: Some(offset), offsets, scope: None }
+    }
+}
+
+/// Locals of a function body as laid out by the resolver. The parameters take
+/// the first slots, `targets` holds the slot each statement of the body defines
+/// or assigns when it is a local
+#[derive(Debug, Clone, Default)]
+pub struct Scope {
+    pub names: Vec<String>,
+    pub targets: Vec<Option<u16>>,
+}
+
+impl Scope {
+    pub fn slot(&self, ident: &str) -> Option<u16> {
+        self.names.iter().position(|name| name == ident).map(|slot| slot as u16)
+    }
+}
+
+impl PartialEq for Body {
+    fn eq(&self, other: &Self) -> bool {
+        self.stmt == other.stmt
+    }
+}
+
+impl Eq for Body {}
+
+impl PartialOrd for Body {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        self.stmt.partial_cmp(&other.stmt)
+    }
+}
+
+impl Hash for Body {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.stmt.hash(state)
     }
 }
 
-#[derive(PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
+#[derive(Serialize, PartialEq, Debug, Clone, PartialOrd, Hash, Eq)]
 pub struct VarIdent {
     pub ident: String,
     pub expr: Expr,
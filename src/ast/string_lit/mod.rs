@@ -0,0 +1,112 @@
+use crate::ast::Expr;
+use crate::parser::ExprParser;
+
+/// Splits the raw contents of a string literal (without the surrounding quotes)
+/// into text and `{expr}` segments. A literal without interpolation stays a plain
+/// `Expr::StringLit`, otherwise the segments are joined by `Expr::Concat`.
+pub fn parse_string_lit(raw: &str) -> Result<Expr, &'static str> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.push(unescape_char(&mut chars)?),
+            '{' => {
+                let mut source = String::new();
+                let mut depth = 1;
+                loop {
+                    match chars.next() {
+                        Some('{') => {
+                            depth += 1;
+                            source.push('{');
+                        }
+                        Some('}') => {
+                            depth -= 1;
+                            if depth == 0 {
+                                break;
+                            }
+                            source.push('}');
+                        }
+                        Some('\\') => match chars.next() {
+                            Some('"') => source.push('"'),
+                            Some(c) => {
+                                source.push('\\');
+                                source.push(c);
+                            }
+                            None => return Err("Unterminated escape sequence in string literal"),
+                        },
+                        Some(c) => source.push(c),
+                        None => return Err("Unterminated '{' in string literal"),
+                    }
+                }
+                if source.trim().is_empty() {
+                    return Err("Empty interpolation in string literal");
+                }
+                let expr = ExprParser::new()
+                    .parse(&source)
+                    .map_err(|_| "Invalid expression in string interpolation")?;
+                if !text.is_empty() {
+                    parts.push(Expr::StringLit(std::mem::take(&mut text)));
+                }
+                parts.push(expr);
+            }
+            '}' => return Err("Unmatched '}' in string literal, use '\\}' for a literal brace"),
+            c => text.push(c),
+        }
+    }
+
+    if parts.is_empty() {
+        return Ok(Expr::StringLit(text));
+    }
+    if !text.is_empty() {
+        parts.push(Expr::StringLit(text));
+    }
+    Ok(Expr::Concat(parts))
+}
+
+/// Resolves escape sequences of a string literal where interpolation is not
+/// allowed (e.g. dictionary keys), so braces are kept as they are.
+pub fn unescape(raw: &str) -> Result<String, &'static str> {
+    let mut text = String::new();
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => text.push(unescape_char(&mut chars)?),
+            c => text.push(c),
+        }
+    }
+    Ok(text)
+}
+
+fn unescape_char(chars: &mut std::str::Chars) -> Result<char, &'static str> {
+    match chars.next() {
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('r') => Ok('\r'),
+        Some('0') => Ok('\0'),
+        Some('"') => Ok('"'),
+        Some('\'') => Ok('\''),
+        Some('\\') => Ok('\\'),
+        Some('{') => Ok('{'),
+        Some('}') => Ok('}'),
+        Some('u') => {
+            if chars.next() != Some('{') {
+                return Err("Expected '{' after \\u in string literal");
+            }
+            let mut code = String::new();
+            loop {
+                match chars.next() {
+                    Some('}') => break,
+                    Some(c) if c.is_ascii_hexdigit() && code.len() < 6 => code.push(c),
+                    _ => return Err("Invalid unicode escape in string literal"),
+                }
+            }
+            u32::from_str_radix(&code, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or("Invalid unicode escape in string literal")
+        }
+        Some(_) => Err("Unknown escape sequence in string literal"),
+        None => Err("Unterminated escape sequence in string literal"),
+    }
+}
@@ -1,29 +1,257 @@
-use anyhow::Result;
-use clap::Parser;
-use morpho_c::parser::ProgParser;
-use morpho_c::program::evaluating_functions::eval_program;
-use lazy_static::lazy_static;
-use std::fs::File;
-use std::io::Read;
-use std::path::PathBuf;
-
-lazy_static! {
-    static ref CODE: String = {
-        let path = CLI::parse().path_to_file;
-        let mut buf = String::new();
-        File::open(path).unwrap().read_to_string(&mut buf).unwrap();
-        buf
-    };
-}
+use anyhow::{anyhow, Result};
+use clap::{Args, Parser, Subcommand};
+use morpho_c::ast::{dump, Prog};
+use morpho_c::package::registry::Registry;
+use morpho_c::package::Project;
+use morpho_c::program::evaluating_functions::{eval_bytecode_file, eval_file, eval_project};
+use morpho_c::program::loader::{find_entry, ModuleLoader, SourceMap};
+use morpho_c::program::vm::{compile, disasm, llvm, mbc};
+use morpho_c::program::optimizer::{optimize, OptLevel};
+#[cfg(feature = "jit")]
+use morpho_c::program::set_jit;
+#[cfg(feature = "jit")]
+use morpho_c::program::vm::jit_stats;
+use morpho_c::program::{set_backend, set_max_call_depth, set_opt_level, Backend, DEFAULT_MAX_CALL_DEPTH};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
 #[derive(Parser, Clone)]
-#[command()]
-struct CLI {
-    path_to_file: PathBuf,
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Runs a source file, a project or a compiled `.mbc` file
+    Run(RunArgs),
+    /// Compiles a source file or a project to a `.mbc` bytecode file or LLVM IR
+    Build(BuildArgs),
+    /// Prints the parsed syntax tree of a source file or a project
+    Ast(DumpArgs),
+    /// Prints the compiled bytecode of a program or a `.mbc` file with source lines
+    Disasm(DumpArgs),
+}
+
+#[derive(Args, Clone)]
+struct SourceArgs {
+    /// Source file or project directory with `morpho.toml` or `main.mo`
+    #[arg(required = true)]
+    path: Option<PathBuf>,
+    /// Entry file to run instead of the project's `main.mo`
+    #[arg(long)]
+    entry: Option<PathBuf>,
+    /// Package registry directory, `$MORPHO_REGISTRY` or `~/.morpho/registry` by default
+    #[arg(long)]
+    registry: Option<PathBuf>,
+    /// Optimization level: 1 folds constants and removes dead `if` branches,
+    /// 2 also inlines small functions and removes unused ones
+    #[arg(long, default_value_t = OptLevel::O0)]
+    opt_level: OptLevel,
+}
+
+#[derive(Args, Clone)]
+struct RunArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Execution backend: `tree` evaluates the AST, `vm` runs compiled bytecode.
+    /// `.mbc` files always run on the VM
+    #[arg(long, default_value_t = Backend::Tree)]
+    backend: Backend,
+    /// How deep calls may nest before the program fails with `StackOverflow`,
+    /// tail calls don't count
+    #[arg(long, default_value_t = DEFAULT_MAX_CALL_DEPTH)]
+    max_call_depth: usize,
+    /// Run every function on the VM interpreter instead of compiling hot ones to native code
+    #[cfg(feature = "jit")]
+    #[arg(long)]
+    no_jit: bool,
+    /// Print how many functions the JIT compiled and ran to stderr once the program ends
+    #[cfg(feature = "jit")]
+    #[arg(long)]
+    jit_stats: bool,
+}
+
+#[derive(Args, Clone)]
+struct BuildArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Output file, the entry file or the package name with the extension of
+    /// the output kind by default
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+    /// Output kind: `mbc` writes bytecode, `llvm-ir` writes a `.ll` module and
+    /// the C runtime it links with next to it
+    #[arg(long, default_value_t = Emit::Mbc)]
+    emit: Emit,
+}
+
+/// What `build` writes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Emit {
+    Mbc,
+    LlvmIr,
+}
+
+impl FromStr for Emit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mbc" => Ok(Emit::Mbc),
+            "llvm-ir" => Ok(Emit::LlvmIr),
+            _ => Err(anyhow!("Unknown output kind {s}, expected mbc or llvm-ir")),
+        }
+    }
+}
+
+impl Display for Emit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Emit::Mbc => write!(f, "mbc"),
+            Emit::LlvmIr => write!(f, "llvm-ir"),
+        }
+    }
+}
+
+#[derive(Args, Clone)]
+struct DumpArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Print JSON instead of the human-readable form
+    #[arg(long)]
+    json: bool,
+}
+
+impl SourceArgs {
+    fn path(&self) -> &Path {
+        self.path.as_deref().expect("path is required")
+    }
+
+    fn project(&self) -> Option<Result<Project>> {
+        if self.entry.is_some() || !Project::is_project(self.path()) {
+            return None;
+        }
+        let registry = self.registry.clone().map(Registry::new).unwrap_or_else(Registry::from_env);
+        Some(Project::open(self.path(), &registry))
+    }
+
+    fn entry(&self) -> Result<PathBuf> {
+        match &self.entry {
+            Some(entry) => Ok(self.path().join(entry)),
+            None => find_entry(self.path()),
+        }
+    }
 }
 
 fn main() -> Result<()> {
-    let ast = ProgParser::new().parse(&CODE)?;
-    //println!("{ast:?}");
-    eval_program(ast)
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Build(args)) => build(args),
+        Some(Command::Run(args)) => run(args),
+        Some(Command::Ast(args)) => ast(args),
+        Some(Command::Disasm(args)) => disasm(args),
+        None => run(cli.run),
+    }
+}
+
+fn run(args: RunArgs) -> Result<()> {
+    set_backend(args.backend);
+    set_opt_level(args.source.opt_level);
+    set_max_call_depth(args.max_call_depth);
+    #[cfg(feature = "jit")]
+    set_jit(!args.no_jit);
+    let source = args.source;
+    let result = if source.path().extension().is_some_and(|ext| ext == mbc::EXTENSION) {
+        eval_bytecode_file(source.path())
+    } else {
+        match source.project() {
+            Some(project) => eval_project(&project?),
+            None => eval_file(&source.entry()?),
+        }
+    };
+    #[cfg(feature = "jit")]
+    if args.jit_stats {
+        eprintln!("JIT {}", jit_stats());
+    }
+    result
+}
+
+fn build(args: BuildArgs) -> Result<()> {
+    set_opt_level(args.source.opt_level);
+    let (prog, sources, name) = load(&args.source)?;
+    let extension = match args.emit {
+        Emit::Mbc => mbc::EXTENSION,
+        Emit::LlvmIr => "ll",
+    };
+    let output = match args.output {
+        Some(output) => output,
+        None => name.with_extension(extension),
+    };
+    let bytecode = compile(prog, &sources)?;
+    match args.emit {
+        Emit::Mbc => {
+            mbc::write(&output, &bytecode)?;
+            println!("Wrote {}", output.display());
+        }
+        Emit::LlvmIr => {
+            let module = name.file_stem().unwrap_or_default().to_string_lossy();
+            fs::write(&output, llvm::emit(&bytecode, &module)?)?;
+            let runtime = output.with_file_name(llvm::RUNTIME_FILE);
+            fs::write(&runtime, llvm::RUNTIME)?;
+            println!("Wrote {} and {}", output.display(), runtime.display());
+        }
+    }
+    Ok(())
+}
+
+fn ast(args: DumpArgs) -> Result<()> {
+    let (prog, _, _) = load(&args.source)?;
+    let prog = optimize(prog, args.source.opt_level);
+    match args.json {
+        true => println!("{:#}", dump::to_json(&prog)),
+        false => print!("{}", dump::render(&prog)),
+    }
+    Ok(())
+}
+
+fn disasm(args: DumpArgs) -> Result<()> {
+    set_opt_level(args.source.opt_level);
+    let path = args.source.path();
+    let bytecode = if path.extension().is_some_and(|ext| ext == mbc::EXTENSION) {
+        mbc::read(path)?
+    } else {
+        let (prog, sources, _) = load(&args.source)?;
+        compile(prog, &sources)?
+    };
+    match args.json {
+        true => println!("{:#}", disasm::to_json(&bytecode)),
+        false => print!("{}", disasm::disassemble(&bytecode)),
+    }
+    Ok(())
+}
+
+/// Loads the program at `source` with the files it was read from and the path
+/// its build output is named after
+fn load(source: &SourceArgs) -> Result<(Prog, SourceMap, PathBuf)> {
+    let mut loader = ModuleLoader::new();
+    let (prog, name) = match source.project() {
+        Some(project) => {
+            let project = project?;
+            let prog = loader.load_project(&project)?;
+            (prog, source.path().join(&project.manifest.package.name))
+        }
+        None => {
+            let entry = source.entry()?;
+            let prog = loader.load(&entry)?;
+            let name = entry.file_stem().ok_or_else(|| anyhow!("{} is not a file", entry.display()))?;
+            (prog, entry.with_file_name(name))
+        }
+    };
+    Ok((prog, loader.sources().clone(), name))
 }
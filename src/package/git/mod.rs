@@ -0,0 +1,42 @@
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Checks out `rev` (the default branch when `None`) of the repository at `url`
+/// into `cache`, returning the checkout and the commit it points to. Checkouts
+/// are kept per commit, so a locked commit is only cloned once.
+pub(crate) fn checkout(url: &str, rev: Option<&str>, name: &str, cache: &Path) -> Result<(PathBuf, String)> {
+    let commit = match rev {
+        Some(rev) => rev_parse(url, &format!("{rev}^{{commit}}"))?,
+        None => rev_parse(url, "HEAD")?,
+    };
+    let dir = cache.join(format!("{name}-{}", &commit[..commit.len().min(12)]));
+    if dir.is_dir() {
+        return Ok((dir, commit));
+    }
+    std::fs::create_dir_all(cache).with_context(|| format!("Failed to create {}", cache.display()))?;
+    git(None, &["clone", "--quiet", url, &dir.to_string_lossy()])?;
+    git(Some(&dir), &["checkout", "--quiet", "--detach", &commit])?;
+    Ok((dir, commit))
+}
+
+fn rev_parse(url: &str, rev: &str) -> Result<String> {
+    git(Some(Path::new(url)), &["rev-parse", "--verify", "--quiet", rev])
+        .with_context(|| format!("Revision {rev} not found in {url}"))
+}
+
+fn git(dir: Option<&Path>, args: &[&str]) -> Result<String> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+    let output = command.args(args).output().context("Failed to run git")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
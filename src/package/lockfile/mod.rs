@@ -0,0 +1,52 @@
+use anyhow::{Context, Result};
+use semver::Version;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+pub const LOCK_FILE: &str = "morpho.lock";
+
+const HEADER: &str = "# This file is generated by morpho_c, do not edit it by hand.\n";
+
+/// Contents of `morpho.lock`: the exact packages a project was resolved to
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Lockfile {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: Version,
+    /// `registry`, `path+<dir>` or `git+<url>#<commit>`
+    pub source: String,
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+}
+
+impl Lockfile {
+    /// Reads the lockfile of the project in `dir`, empty when there is none
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let source = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        toml::from_str(&source).with_context(|| format!("Invalid lockfile {}", path.display()))
+    }
+
+    /// Writes the lockfile unless the one on disk is already up to date
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        if Self::load(dir).ok().as_ref() == Some(self) {
+            return Ok(());
+        }
+        let path = dir.join(LOCK_FILE);
+        let source = format!("{HEADER}{}", toml::to_string(self)?);
+        fs::write(&path, source).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LockedPackage> {
+        self.packages.iter().find(|package| package.name == name)
+    }
+}
@@ -0,0 +1,102 @@
+use anyhow::{anyhow, Context, Result};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_FILE: &str = "morpho.toml";
+
+/// Contents of `morpho.toml`
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Manifest {
+    pub package: PackageInfo,
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, Dependency>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct PackageInfo {
+    pub name: String,
+    pub version: Version,
+    /// Program run by `morpho_c <project>`, `src/main.mo` by default
+    pub entry: Option<PathBuf>,
+    /// Root module other packages import, `src/lib.mo` by default
+    pub lib: Option<PathBuf>,
+}
+
+/// `name = "1.2"` or `name = { version = "1.2" }` for the registry,
+/// `{ path = "../name" }` and `{ git = "/repos/name.git", rev = "v1" }` otherwise
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(untagged)]
+pub enum Dependency {
+    Version(VersionReq),
+    Detailed(DetailedDependency),
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct DetailedDependency {
+    pub version: Option<VersionReq>,
+    pub path: Option<PathBuf>,
+    pub git: Option<String>,
+    pub rev: Option<String>,
+}
+
+/// Where a dependency comes from
+#[derive(Clone, Debug, PartialEq)]
+pub enum DependencySource {
+    Registry,
+    Path(PathBuf),
+    Git { url: String, rev: Option<String> },
+}
+
+impl Dependency {
+    pub fn source(&self) -> Result<DependencySource> {
+        match self {
+            Dependency::Version(_) => Ok(DependencySource::Registry),
+            Dependency::Detailed(dep) => match (&dep.path, &dep.git) {
+                (Some(_), Some(_)) => Err(anyhow!("a dependency can't have both path and git")),
+                (Some(path), None) => Ok(DependencySource::Path(path.clone())),
+                (None, Some(url)) => Ok(DependencySource::Git {
+                    url: url.clone(),
+                    rev: dep.rev.clone(),
+                }),
+                (None, None) if dep.rev.is_some() => Err(anyhow!("rev is only allowed for git dependencies")),
+                (None, None) => Ok(DependencySource::Registry),
+            },
+        }
+    }
+
+    /// Version requirement, any version when none is given
+    pub fn version_req(&self) -> VersionReq {
+        match self {
+            Dependency::Version(req) => req.clone(),
+            Dependency::Detailed(dep) => dep.version.clone().unwrap_or(VersionReq::STAR),
+        }
+    }
+}
+
+impl Manifest {
+    pub fn parse(source: &str) -> Result<Self> {
+        let manifest: Manifest = toml::from_str(source)?;
+        for (name, dep) in &manifest.dependencies {
+            dep.source().with_context(|| format!("Invalid dependency {name}"))?;
+        }
+        Ok(manifest)
+    }
+
+    /// Reads `morpho.toml` from the package directory `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let source = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&source).with_context(|| format!("Invalid manifest {}", path.display()))
+    }
+
+    pub fn entry(&self) -> PathBuf {
+        self.package.entry.clone().unwrap_or_else(|| PathBuf::from("src/main.mo"))
+    }
+
+    pub fn lib(&self) -> PathBuf {
+        self.package.lib.clone().unwrap_or_else(|| PathBuf::from("src/lib.mo"))
+    }
+}
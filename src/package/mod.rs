@@ -0,0 +1,58 @@
+pub mod lockfile;
+pub mod manifest;
+pub mod registry;
+pub mod resolver;
+mod git;
+
+use crate::package::lockfile::Lockfile;
+use crate::package::manifest::{Manifest, MANIFEST_FILE};
+use crate::package::registry::Registry;
+use crate::package::resolver::{Resolution, Resolver};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// A package directory with `morpho.toml` and its resolved dependencies
+#[derive(Clone, Debug)]
+pub struct Project {
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+    pub resolution: Resolution,
+}
+
+impl Project {
+    pub fn is_project(dir: &Path) -> bool {
+        dir.join(MANIFEST_FILE).is_file()
+    }
+
+    /// Reads the manifest of the project in `dir`, resolves its dependencies
+    /// and updates `morpho.lock`. Git sources are checked out to `.morpho/git`
+    pub fn open(dir: &Path, registry: &Registry) -> Result<Self> {
+        let dir = dir
+            .canonicalize()
+            .with_context(|| format!("Project {} not found", dir.display()))?;
+        let manifest = Manifest::load(&dir)?;
+        let lock = Lockfile::load(&dir)?;
+        let resolution = Resolver::new(registry, &lock, dir.join(".morpho").join("git"))
+            .resolve(&dir, &manifest)
+            .with_context(|| format!("Failed to resolve dependencies of {}", manifest.package.name))?;
+        resolution.to_lockfile(&dir).save(&dir)?;
+        Ok(Self {
+            dir,
+            manifest,
+            resolution,
+        })
+    }
+
+    pub fn entry(&self) -> PathBuf {
+        self.dir.join(self.manifest.entry())
+    }
+
+    /// Root module file of every resolved package, keyed by package name
+    pub fn package_roots(&self) -> Vec<(String, PathBuf)> {
+        self.resolution
+            .packages
+            .values()
+            .map(|package| (package.name.clone(), package.dir.join(package.manifest.lib())))
+            .collect()
+    }
+}
@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Context, Result};
+use semver::{Version, VersionReq};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Environment variable overriding the default registry location
+pub const REGISTRY_ENV: &str = "MORPHO_REGISTRY";
+
+/// Package registry stored in a local directory, one directory per published
+/// version: `<root>/<name>/<version>/morpho.toml`
+#[derive(Clone, Debug)]
+pub struct Registry {
+    root: PathBuf,
+}
+
+impl Registry {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Registry from `MORPHO_REGISTRY`, `~/.morpho/registry` otherwise
+    pub fn from_env() -> Self {
+        match std::env::var_os(REGISTRY_ENV) {
+            Some(root) => Self::new(root),
+            None => {
+                let home = std::env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+                Self::new(home.join(".morpho").join("registry"))
+            }
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Published versions of `name`, oldest first
+    pub fn versions(&self, name: &str) -> Result<Vec<Version>> {
+        let dir = self.root.join(name);
+        if !dir.is_dir() {
+            return Err(anyhow!("Package {name} not found in registry {}", self.root.display()));
+        }
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+            let entry = entry?;
+            if let Some(version) = entry.file_name().to_str().and_then(|name| Version::parse(name).ok()) {
+                versions.push(version);
+            }
+        }
+        versions.sort();
+        Ok(versions)
+    }
+
+    /// Newest version of `name` matching every requirement in `reqs`
+    pub fn select(&self, name: &str, reqs: &[VersionReq]) -> Result<Version> {
+        self.versions(name)?
+            .into_iter()
+            .rev()
+            .find(|version| reqs.iter().all(|req| req.matches(version)))
+            .ok_or_else(|| {
+                let reqs: Vec<_> = reqs.iter().map(ToString::to_string).collect();
+                anyhow!("No version of {name} matches {}", reqs.join(", "))
+            })
+    }
+
+    pub fn package_dir(&self, name: &str, version: &Version) -> PathBuf {
+        self.root.join(name).join(version.to_string())
+    }
+}
@@ -0,0 +1,241 @@
+use crate::package::git;
+use crate::package::lockfile::{LockedPackage, Lockfile};
+use crate::package::manifest::{Dependency, DependencySource, Manifest};
+use crate::package::registry::Registry;
+use anyhow::{anyhow, Context, Result};
+use semver::{Version, VersionReq};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// Where a resolved package was taken from
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackageSource {
+    Registry,
+    Path(PathBuf),
+    Git { url: String, rev: Option<String>, commit: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct ResolvedPackage {
+    pub name: String,
+    pub version: Version,
+    pub source: PackageSource,
+    pub dir: PathBuf,
+    pub manifest: Manifest,
+}
+
+/// Every package a project depends on, directly or not, one version per name
+#[derive(Clone, Debug, Default)]
+pub struct Resolution {
+    pub packages: BTreeMap<String, ResolvedPackage>,
+}
+
+/// Resolves dependencies against a local registry, path and git sources.
+///
+/// Registry packages get the newest version matching every requirement seen
+/// so far, preferring the locked one. When a later requirement rules the chosen
+/// version out, resolution starts over with that requirement known upfront.
+pub struct Resolver<'a> {
+    registry: &'a Registry,
+    lock: &'a Lockfile,
+    git_cache: PathBuf,
+    constraints: HashMap<String, Vec<VersionReq>>,
+}
+
+enum Attempt {
+    Done(Resolution),
+    Restart,
+}
+
+impl<'a> Resolver<'a> {
+    pub fn new(registry: &'a Registry, lock: &'a Lockfile, git_cache: PathBuf) -> Self {
+        Self {
+            registry,
+            lock,
+            git_cache,
+            constraints: HashMap::new(),
+        }
+    }
+
+    pub fn resolve(&mut self, root_dir: &Path, manifest: &Manifest) -> Result<Resolution> {
+        loop {
+            if let Attempt::Done(resolution) = self.attempt(root_dir, manifest)? {
+                return Ok(resolution);
+            }
+        }
+    }
+
+    fn attempt(&mut self, root_dir: &Path, manifest: &Manifest) -> Result<Attempt> {
+        let mut packages: BTreeMap<String, ResolvedPackage> = BTreeMap::new();
+        let mut queue = VecDeque::new();
+        queue.extend(dependencies_of(&manifest.package.name, root_dir, manifest));
+
+        while let Some((requester, requester_dir, name, dep)) = queue.pop_front() {
+            let source = dep.source().with_context(|| format!("Invalid dependency {name} of {requester}"))?;
+            let req = dep.version_req();
+            if let Some(package) = packages.get(&name) {
+                if !same_source(&package.source, &source, &requester_dir) {
+                    return Err(anyhow!(
+                        "{requester} takes {name} from a different source than the other packages depending on it"
+                    ));
+                }
+                if req.matches(&package.version) {
+                    continue;
+                }
+                if package.source == PackageSource::Registry {
+                    let constraints = self.constraints.entry(name.clone()).or_default();
+                    if !constraints.contains(&req) {
+                        constraints.push(req);
+                        return Ok(Attempt::Restart);
+                    }
+                }
+                return Err(anyhow!(
+                    "{requester} requires {name} {req}, which conflicts with version {} used by the other packages",
+                    package.version
+                ));
+            }
+
+            let package = self.fetch(&name, source, req, &requester, &requester_dir)?;
+            queue.extend(dependencies_of(&name, &package.dir, &package.manifest));
+            packages.insert(name, package);
+        }
+        Ok(Attempt::Done(Resolution { packages }))
+    }
+
+    fn fetch(
+        &mut self,
+        name: &str,
+        source: DependencySource,
+        req: VersionReq,
+        requester: &str,
+        requester_dir: &Path,
+    ) -> Result<ResolvedPackage> {
+        let (source, dir) = match source {
+            DependencySource::Registry => {
+                let constraints = self.constraints.entry(name.to_string()).or_default();
+                if !constraints.contains(&req) {
+                    constraints.push(req.clone());
+                }
+                let locked = self
+                    .lock
+                    .get(name)
+                    .filter(|locked| locked.source == "registry")
+                    .map(|locked| locked.version.clone())
+                    .filter(|version| constraints.iter().all(|req| req.matches(version)))
+                    .filter(|version| self.registry.package_dir(name, version).is_dir());
+                let version = match locked {
+                    Some(version) => version,
+                    None => self.registry.select(name, constraints)?,
+                };
+                (PackageSource::Registry, self.registry.package_dir(name, &version))
+            }
+            DependencySource::Path(path) => {
+                let dir = requester_dir
+                    .join(&path)
+                    .canonicalize()
+                    .with_context(|| format!("Path dependency {name} of {requester} not found at {}", path.display()))?;
+                (PackageSource::Path(dir.clone()), dir)
+            }
+            DependencySource::Git { url, rev } => {
+                let url = git_url(&url, requester_dir);
+                let locked_commit = self.lock.get(name).and_then(|locked| {
+                    let (locked_url, locked_rev, commit) = parse_git_source(&locked.source)?;
+                    (locked_url == url && locked_rev == rev).then_some(commit)
+                });
+                let checkout_rev = locked_commit.or(rev.clone());
+                let (dir, commit) = git::checkout(&url, checkout_rev.as_deref(), name, &self.git_cache)
+                    .with_context(|| format!("Failed to fetch git dependency {name} of {requester}"))?;
+                (PackageSource::Git { url, rev, commit }, dir)
+            }
+        };
+
+        let manifest = Manifest::load(&dir)?;
+        if manifest.package.name != name {
+            return Err(anyhow!(
+                "{requester} depends on {name}, but {} contains package {}",
+                dir.display(),
+                manifest.package.name
+            ));
+        }
+        if !req.matches(&manifest.package.version) {
+            return Err(anyhow!(
+                "{requester} requires {name} {req}, found version {}",
+                manifest.package.version
+            ));
+        }
+        Ok(ResolvedPackage {
+            name: name.to_string(),
+            version: manifest.package.version.clone(),
+            source,
+            dir,
+            manifest,
+        })
+    }
+}
+
+type Request = (String, PathBuf, String, Dependency);
+
+fn dependencies_of(requester: &str, dir: &Path, manifest: &Manifest) -> Vec<Request> {
+    manifest
+        .dependencies
+        .iter()
+        .map(|(name, dep)| (requester.to_string(), dir.to_path_buf(), name.clone(), dep.clone()))
+        .collect()
+}
+
+fn same_source(resolved: &PackageSource, requested: &DependencySource, requester_dir: &Path) -> bool {
+    match (resolved, requested) {
+        (PackageSource::Registry, DependencySource::Registry) => true,
+        (PackageSource::Path(dir), DependencySource::Path(path)) => {
+            requester_dir.join(path).canonicalize().is_ok_and(|path| path == *dir)
+        }
+        (PackageSource::Git { url, .. }, DependencySource::Git { url: requested, .. }) => {
+            *url == git_url(requested, requester_dir)
+        }
+        _ => false,
+    }
+}
+
+/// Relative repository paths are relative to the package declaring the dependency
+fn git_url(url: &str, requester_dir: &Path) -> String {
+    let path = requester_dir.join(url);
+    if Path::new(url).is_relative() && path.exists() {
+        path.canonicalize().unwrap_or(path).to_string_lossy().into_owned()
+    } else {
+        url.to_string()
+    }
+}
+
+fn parse_git_source(source: &str) -> Option<(String, Option<String>, String)> {
+    let (location, commit) = source.strip_prefix("git+")?.rsplit_once('#')?;
+    let (url, rev) = match location.rsplit_once("?rev=") {
+        Some((url, rev)) => (url, Some(rev.to_string())),
+        None => (location, None),
+    };
+    Some((url.to_string(), rev, commit.to_string()))
+}
+
+impl Resolution {
+    /// Lockfile recording this resolution, paths are kept relative to `root_dir` when possible
+    pub fn to_lockfile(&self, root_dir: &Path) -> Lockfile {
+        let packages = self
+            .packages
+            .values()
+            .map(|package| LockedPackage {
+                name: package.name.clone(),
+                version: package.version.clone(),
+                source: match &package.source {
+                    PackageSource::Registry => "registry".to_string(),
+                    PackageSource::Path(dir) => {
+                        let dir = dir.strip_prefix(root_dir).unwrap_or(dir);
+                        format!("path+{}", dir.display())
+                    }
+                    PackageSource::Git { url, rev: Some(rev), commit } => format!("git+{url}?rev={rev}#{commit}"),
+                    PackageSource::Git { url, rev: None, commit } => format!("git+{url}#{commit}"),
+                },
+                dependencies: package.manifest.dependencies.keys().cloned().collect(),
+            })
+            .collect();
+        Lockfile { packages }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::program::error::{raise, MorphoError};
+use crate::program::max_call_depth;
+use std::cell::{Cell, RefCell};
+use std::panic;
+use std::thread;
+
+/// Native stack a call of the tree backend takes at most, the interpreter
+/// thread gets this much for every allowed call
+const FRAME_SIZE: usize = 64 * 1024;
+const MIN_STACK_SIZE: usize = 8 * 1024 * 1024;
+const MAX_STACK_SIZE: usize = 1024 * 1024 * 1024;
+/// Native stack left free under the deepest call for builtins and raising the error
+const STACK_MARGIN: usize = 256 * 1024;
+
+thread_local! {
+    /// Functions running on this thread, the innermost last
+    static CALL_STACK: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    /// Address under which the native stack of this thread is about to run
+    /// out, 0 outside of `with_stack`
+    static STACK_LIMIT: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Runs `f` on a thread with a native stack deep enough for `max_call_depth()`
+/// nested calls. Panics of `f`, raised errors included, continue on the caller's thread
+pub(crate) fn with_stack<T: Send>(f: impl FnOnce() -> T + Send) -> T {
+    let size = max_call_depth().saturating_mul(FRAME_SIZE).clamp(MIN_STACK_SIZE, MAX_STACK_SIZE);
+    thread::scope(|scope| {
+        let thread = thread::Builder::new()
+            .name("morpho".to_string())
+            .stack_size(size)
+            .spawn_scoped(scope, || {
+                STACK_LIMIT.set(stack_address().saturating_sub(size) + STACK_MARGIN);
+                f()
+            })
+            .expect("failed to spawn the interpreter thread");
+        thread.join().unwrap_or_else(|payload| panic::resume_unwind(payload))
+    })
+}
+
+/// Function on the call stack, removed when dropped
+pub(crate) struct Frame(());
+
+impl Frame {
+    /// Puts `name` in place of the function, which tail called it
+    pub(crate) fn replace(&self, name: String) {
+        CALL_STACK.with_borrow_mut(|stack| *stack.last_mut().unwrap() = name);
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        CALL_STACK.with_borrow_mut(|stack| stack.pop());
+    }
+}
+
+/// Pushes `name` on the call stack, raising `StackOverflow` when the call is
+/// deeper than allowed or the native stack runs out
+pub(crate) fn enter(name: String) -> Frame {
+    let depth = CALL_STACK.with_borrow(|stack| stack.len()) + 1;
+    if depth > max_call_depth() || stack_address() < STACK_LIMIT.get() {
+        let mut trace = vec![name];
+        CALL_STACK.with_borrow(|stack| trace.extend(stack.iter().rev().cloned()));
+        raise(MorphoError::StackOverflow { depth, trace });
+    }
+    CALL_STACK.with_borrow_mut(|stack| stack.push(name));
+    Frame(())
+}
+
+/// Name of the function `ident` of the module at `module_path` in a trace
+pub(crate) fn frame_name(module_path: &[String], ident: &str) -> String {
+    match module_path {
+        [] => ident.to_string(),
+        path => format!("{}::{ident}", path.join("::")),
+    }
+}
+
+fn stack_address() -> usize {
+    let marker = 0u8;
+    std::hint::black_box(&marker) as *const u8 as usize
+}
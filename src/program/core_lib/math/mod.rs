@@ -0,0 +1,229 @@
+use crate::program::core_lib::{arg, arg_float};
+use crate::program::environment::LocalEnvironment;
+use crate::program::error::{raise, MorphoError};
+use crate::program::module::Module;
+use crate::program::sandbox;
+use crate::program::value::Value;
+use num_bigint::BigInt;
+use num_traits::{Signed, ToPrimitive, Zero};
+use std::f64::consts;
+use crate::program::shared::Shared;
+
+pub fn module() -> Module {
+    let mut module = Module::new("math");
+    module.insert_const("pi", Value::Float(consts::PI));
+    module.insert_const("e", Value::Float(consts::E));
+    module.insert_const("tau", Value::Float(consts::TAU));
+    module.insert("sqrt", Value::FuncPtr(sqrt_func));
+    module.insert("pow", Value::FuncPtr(pow_func));
+    module.insert("abs", Value::FuncPtr(abs_func));
+    module.insert("min", Value::FuncPtr(min_func));
+    module.insert("max", Value::FuncPtr(max_func));
+    module.insert("floor", Value::FuncPtr(floor_func));
+    module.insert("ceil", Value::FuncPtr(ceil_func));
+    module.insert("round", Value::FuncPtr(round_func));
+    module.insert("sin", Value::FuncPtr(sin_func));
+    module.insert("cos", Value::FuncPtr(cos_func));
+    module.insert("tan", Value::FuncPtr(tan_func));
+    module.insert("asin", Value::FuncPtr(asin_func));
+    module.insert("acos", Value::FuncPtr(acos_func));
+    module.insert("atan", Value::FuncPtr(atan_func));
+    module.insert("atan2", Value::FuncPtr(atan2_func));
+    module.insert("log", Value::FuncPtr(log_func));
+    module.insert("log2", Value::FuncPtr(log2_func));
+    module.insert("log10", Value::FuncPtr(log10_func));
+    module.insert("exp", Value::FuncPtr(exp_func));
+    module.insert("gcd", Value::FuncPtr(gcd_func));
+    module.insert("lcm", Value::FuncPtr(lcm_func));
+    module
+}
+
+fn domain_error(func: &str, x: f64) -> ! {
+    raise(MorphoError::ValueError(format!("math::{func}: {x} is out of the function domain")))
+}
+
+pub fn sqrt_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let x = arg_float(&args, 0, "math::sqrt");
+    if x < 0.0 {
+        domain_error("sqrt", x)
+    }
+    Value::Float(x.sqrt())
+}
+
+/// Int and bigint bases with a non-negative int exponent stay exact, everything else is float
+pub fn pow_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    match (arg(&args, 0, "math::pow"), arg(&args, 1, "math::pow")) {
+        (Value::Int(base), Value::Int(exp)) if exp >= 0 => u32::try_from(exp)
+            .ok()
+            .and_then(|exp| base.checked_pow(exp))
+            .map(Value::Int)
+            .unwrap_or_else(|| raise(MorphoError::Overflow(format!("math::pow({base}, {exp})")))),
+        (Value::BigInt(base), Value::Int(exp)) if exp >= 0 => match u32::try_from(exp) {
+            Ok(exp) => {
+                // The power has at least this many bits, it is checked again once computed
+                sandbox::reserve(sandbox::bigint_size(base.bits().saturating_sub(1).saturating_mul(exp as u64)));
+                sandbox::limit_size(Value::BigInt(base.pow(exp)))
+            }
+            Err(_) => raise(MorphoError::Overflow(format!("math::pow({base}, {exp})"))),
+        },
+        _ => Value::Float(arg_float(&args, 0, "math::pow").powf(arg_float(&args, 1, "math::pow"))),
+    }
+}
+
+pub fn abs_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    match arg(&args, 0, "math::abs") {
+        Value::Int(i) => Value::Int(
+            i.checked_abs()
+                .unwrap_or_else(|| raise(MorphoError::Overflow(format!("math::abs({i})")))),
+        ),
+        Value::BigInt(b) => Value::BigInt(b.abs()),
+        _ => Value::Float(arg_float(&args, 0, "math::abs").abs()),
+    }
+}
+
+fn extremum(args: Vec<Value>, func: &str, pick: fn(&Value, &Value) -> bool) -> Value {
+    if args.is_empty() {
+        raise(MorphoError::TypeError(format!("{func}: expected at least one argument")))
+    }
+    let mut best = arg(&args, 0, func);
+    for i in 1..args.len() {
+        let value = arg(&args, i, func);
+        if value.partial_cmp(&best).is_none() {
+            raise(MorphoError::TypeError(format!(
+                "{func}: cannot compare {} and {}",
+                value.into_type(),
+                best.into_type()
+            )))
+        }
+        if pick(&value, &best) {
+            best = value;
+        }
+    }
+    best
+}
+
+pub fn min_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    extremum(args, "math::min", |value, best| value < best)
+}
+
+pub fn max_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    extremum(args, "math::max", |value, best| value > best)
+}
+
+macro_rules! float_func {
+    ($func: ident, $name: expr, $op: expr) => {
+        pub fn $func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+            let f: fn(f64) -> f64 = $op;
+            Value::Float(f(arg_float(&args, 0, $name)))
+        }
+    };
+}
+
+float_func!(floor_func, "math::floor", f64::floor);
+float_func!(ceil_func, "math::ceil", f64::ceil);
+float_func!(round_func, "math::round", f64::round);
+float_func!(sin_func, "math::sin", f64::sin);
+float_func!(cos_func, "math::cos", f64::cos);
+float_func!(tan_func, "math::tan", f64::tan);
+float_func!(atan_func, "math::atan", f64::atan);
+float_func!(exp_func, "math::exp", f64::exp);
+
+pub fn asin_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let x = arg_float(&args, 0, "math::asin");
+    if !(-1.0..=1.0).contains(&x) {
+        domain_error("asin", x)
+    }
+    Value::Float(x.asin())
+}
+
+pub fn acos_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let x = arg_float(&args, 0, "math::acos");
+    if !(-1.0..=1.0).contains(&x) {
+        domain_error("acos", x)
+    }
+    Value::Float(x.acos())
+}
+
+pub fn atan2_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let y = arg_float(&args, 0, "math::atan2");
+    let x = arg_float(&args, 1, "math::atan2");
+    Value::Float(y.atan2(x))
+}
+
+/// `log(x)` is the natural logarithm, `log(x, base)` uses the given base
+pub fn log_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let x = arg_float(&args, 0, "math::log");
+    if x <= 0.0 {
+        domain_error("log", x)
+    }
+    if args.len() > 1 {
+        let base = arg_float(&args, 1, "math::log");
+        if base <= 0.0 || base == 1.0 {
+            domain_error("log", base)
+        }
+        return Value::Float(x.log(base));
+    }
+    Value::Float(x.ln())
+}
+
+pub fn log2_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let x = arg_float(&args, 0, "math::log2");
+    if x <= 0.0 {
+        domain_error("log2", x)
+    }
+    Value::Float(x.log2())
+}
+
+pub fn log10_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let x = arg_float(&args, 0, "math::log10");
+    if x <= 0.0 {
+        domain_error("log10", x)
+    }
+    Value::Float(x.log10())
+}
+
+fn arg_integer(args: &[Value], i: usize, func: &str) -> BigInt {
+    match arg(args, i, func) {
+        Value::Int(i) => BigInt::from(i),
+        Value::BigInt(b) => b,
+        value => raise(MorphoError::TypeError(format!(
+            "{func}: expected int argument, found {}",
+            value.into_type()
+        ))),
+    }
+}
+
+fn gcd(mut a: BigInt, mut b: BigInt) -> BigInt {
+    while !b.is_zero() {
+        let r = &a % &b;
+        a = b;
+        b = r;
+    }
+    a.abs()
+}
+
+/// Results of int arguments are narrowed back to int when they fit
+fn integer_result(result: BigInt, args: &[Value]) -> Value {
+    let all_ints = args.iter().all(|value| matches!(value.clone().deref_value(), Value::Int(_)));
+    match result.to_i64() {
+        Some(i) if all_ints => Value::Int(i),
+        _ if all_ints => raise(MorphoError::Overflow(format!("{result} as int"))),
+        _ => Value::BigInt(result),
+    }
+}
+
+pub fn gcd_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let a = arg_integer(&args, 0, "math::gcd");
+    let b = arg_integer(&args, 1, "math::gcd");
+    integer_result(gcd(a, b), &args)
+}
+
+pub fn lcm_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let a = arg_integer(&args, 0, "math::lcm");
+    let b = arg_integer(&args, 1, "math::lcm");
+    if a.is_zero() || b.is_zero() {
+        return integer_result(BigInt::zero(), &args);
+    }
+    let lcm = (&a / gcd(a.clone(), b.clone()) * &b).abs();
+    integer_result(lcm, &args)
+}
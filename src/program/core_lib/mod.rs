@@ -0,0 +1,54 @@
+pub mod math;
+pub mod string;
+
+use crate::program::error::{raise, MorphoError};
+use crate::program::value::{bigint_to_f64, Value};
+
+pub(crate) fn arg(args: &[Value], i: usize, func: &str) -> Value {
+    match args.get(i) {
+        Some(value) => value.clone().deref_value(),
+        None => raise(MorphoError::TypeError(format!("{func}: missing argument #{}", i + 1))),
+    }
+}
+
+pub(crate) fn arg_string(args: &[Value], i: usize, func: &str) -> String {
+    match arg(args, i, func) {
+        Value::String(s) => s,
+        value => raise(MorphoError::TypeError(format!(
+            "{func}: expected string argument, found {}",
+            value.into_type()
+        ))),
+    }
+}
+
+pub(crate) fn arg_int(args: &[Value], i: usize, func: &str) -> i64 {
+    match arg(args, i, func) {
+        Value::Int(i) => i,
+        value => raise(MorphoError::TypeError(format!(
+            "{func}: expected int argument, found {}",
+            value.into_type()
+        ))),
+    }
+}
+
+pub(crate) fn arg_float(args: &[Value], i: usize, func: &str) -> f64 {
+    match arg(args, i, func) {
+        Value::Int(i) => i as f64,
+        Value::BigInt(b) => bigint_to_f64(&b),
+        Value::Float(f) => f,
+        value => raise(MorphoError::TypeError(format!(
+            "{func}: expected number argument, found {}",
+            value.into_type()
+        ))),
+    }
+}
+
+pub(crate) fn arg_array(args: &[Value], i: usize, func: &str) -> Vec<Value> {
+    match arg(args, i, func) {
+        Value::Array(items) => items,
+        value => raise(MorphoError::TypeError(format!(
+            "{func}: expected array argument, found {}",
+            value.into_type()
+        ))),
+    }
+}
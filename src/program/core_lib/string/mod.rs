@@ -0,0 +1,137 @@
+use crate::program::core_lib::{arg, arg_array, arg_int, arg_string};
+use crate::program::environment::LocalEnvironment;
+use crate::program::error::{raise, MorphoError};
+use crate::program::module::Module;
+use crate::program::sandbox;
+use crate::program::value::Value;
+use crate::program::shared::Shared;
+
+pub fn module() -> Module {
+    let mut module = Module::new("string");
+    module.insert("len", Value::FuncPtr(len_func));
+    module.insert("substr", Value::FuncPtr(substr_func));
+    module.insert("split", Value::FuncPtr(split_func));
+    module.insert("join", Value::FuncPtr(join_func));
+    module.insert("trim", Value::FuncPtr(trim_func));
+    module.insert("replace", Value::FuncPtr(replace_func));
+    module.insert("find", Value::FuncPtr(find_func));
+    module.insert("starts_with", Value::FuncPtr(starts_with_func));
+    module.insert("ends_with", Value::FuncPtr(ends_with_func));
+    module.insert("to_upper", Value::FuncPtr(to_upper_func));
+    module.insert("to_lower", Value::FuncPtr(to_lower_func));
+    module.insert("chars", Value::FuncPtr(chars_func));
+    module.insert("parse_int", Value::FuncPtr(parse_int_func));
+    module.insert("parse_float", Value::FuncPtr(parse_float_func));
+    module.insert("from", Value::FuncPtr(from_func));
+    module.insert("format", Value::FuncPtr(format_func));
+    module
+}
+
+pub fn len_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    Value::Int(arg_string(&args, 0, "string::len").chars().count() as i64)
+}
+
+/// `substr(s, start, len)`, indices are counted in chars and clamped to the string bounds
+pub fn substr_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::substr");
+    let start = arg_int(&args, 1, "string::substr").max(0) as usize;
+    let len = arg_int(&args, 2, "string::substr").max(0) as usize;
+    Value::String(s.chars().skip(start).take(len).collect())
+}
+
+/// `split(s, sep)`, an empty `sep` splits at runs of whitespace and drops the
+/// whitespace at both ends
+pub fn split_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::split");
+    let sep = arg_string(&args, 1, "string::split");
+    if sep.is_empty() {
+        sandbox::limit_array(s.split_whitespace().map(|part| Value::String(part.into())))
+    } else {
+        sandbox::limit_array(s.split(sep.as_str()).map(|part| Value::String(part.into())))
+    }
+}
+
+pub fn join_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let parts = arg_array(&args, 0, "string::join");
+    let sep = arg_string(&args, 1, "string::join");
+    sandbox::limit_size(Value::String(parts.iter().map(|part| part.to_string()).collect::<Vec<_>>().join(&sep)))
+}
+
+pub fn trim_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    Value::String(arg_string(&args, 0, "string::trim").trim().into())
+}
+
+pub fn replace_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::replace");
+    let from = arg_string(&args, 1, "string::replace");
+    let to = arg_string(&args, 2, "string::replace");
+    sandbox::limit_size(Value::String(s.replace(&from, &to)))
+}
+
+/// Returns the char index of the first occurrence or -1
+pub fn find_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::find");
+    let needle = arg_string(&args, 1, "string::find");
+    match s.find(&needle) {
+        Some(byte_idx) => Value::Int(s[..byte_idx].chars().count() as i64),
+        None => Value::Int(-1),
+    }
+}
+
+pub fn starts_with_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::starts_with");
+    Value::Bool(s.starts_with(&arg_string(&args, 1, "string::starts_with")))
+}
+
+pub fn ends_with_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::ends_with");
+    Value::Bool(s.ends_with(&arg_string(&args, 1, "string::ends_with")))
+}
+
+pub fn to_upper_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    sandbox::limit_size(Value::String(arg_string(&args, 0, "string::to_upper").to_uppercase()))
+}
+
+pub fn to_lower_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    sandbox::limit_size(Value::String(arg_string(&args, 0, "string::to_lower").to_lowercase()))
+}
+
+pub fn chars_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::chars");
+    sandbox::limit_array(s.chars().map(|c| Value::String(c.into())))
+}
+
+pub fn parse_int_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::parse_int");
+    match s.trim().parse() {
+        Ok(i) => Value::Int(i),
+        Err(_) => raise(MorphoError::ValueError(format!("string::parse_int: invalid int literal {s:?}"))),
+    }
+}
+
+pub fn parse_float_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let s = arg_string(&args, 0, "string::parse_float");
+    match s.trim().parse() {
+        Ok(f) => Value::Float(f),
+        Err(_) => raise(MorphoError::ValueError(format!("string::parse_float: invalid float literal {s:?}"))),
+    }
+}
+
+pub fn from_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    sandbox::limit_size(Value::String(arg(&args, 0, "string::from").to_string()))
+}
+
+/// `format(x, precision)` prints a number with a fixed amount of digits after the point
+pub fn format_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    let precision = arg_int(&args, 1, "string::format").max(0) as usize;
+    // The digits after the point are reserved before they are written
+    sandbox::reserve(precision);
+    match arg(&args, 0, "string::format") {
+        Value::Int(i) => sandbox::limit_size(Value::String(format!("{:.*}", precision, i as f64))),
+        Value::Float(f) => sandbox::limit_size(Value::String(format!("{:.*}", precision, f))),
+        value => raise(MorphoError::TypeError(format!(
+            "string::format: expected number argument, found {}",
+            value.into_type()
+        ))),
+    }
+}
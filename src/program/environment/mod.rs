@@ -1,37 +1,133 @@
+use crate::ast::Scope;
+use crate::program::function::Function;
+use crate::program::gc::{Trace, Tracer};
 use crate::program::value::Value;
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use crate::program::shared::Shared;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Environment {
-    pub(crate) global_stmts: HashMap<String, Arc<RwLock<Value>>>,
+    pub(crate) global_stmts: HashMap<String, Shared<Value>>,
+    /// Names of `global_stmts` declared with `const`
+    pub(crate) consts: HashSet<String>,
+    /// Globals and module items the resolver gave an id, indexed by it
+    cells: Vec<Shared<Value>>,
+    cell_ids: HashMap<usize, u32>,
+    /// Anonymous functions built so far, keyed by the module and the offset of their body
+    pub(crate) closures: HashMap<(Vec<String>, usize), Function>,
 }
 
 impl Environment {
     pub fn new() -> Self {
-        Self {
-            global_stmts: HashMap::new(),
-        }
+        Self::default()
     }
     pub fn insert_stmt(&mut self, ident: &str, stmt: Value) {
         self.global_stmts
-            .insert(ident.into(), Arc::new(RwLock::new(stmt)));
+            .insert(ident.into(), Shared::new(stmt));
     }
 
-    pub fn insert(&mut self, ident: &str, stmt: Arc<RwLock<Value>>) {
+    pub fn insert(&mut self, ident: &str, stmt: Shared<Value>) {
         self.global_stmts.insert(ident.into(), stmt);
     }
+
+    /// Id of `cell`, the same for every name the cell is bound to
+    pub(crate) fn global_id(&mut self, cell: &Shared<Value>) -> u32 {
+        let key = cell.as_ptr() as usize;
+        if let Some(&id) = self.cell_ids.get(&key) {
+            return id;
+        }
+        let id = self.cells.len() as u32;
+        self.cells.push(cell.clone());
+        self.cell_ids.insert(key, id);
+        id
+    }
+
+    pub(crate) fn global(&self, id: u32) -> &Shared<Value> {
+        &self.cells[id as usize]
+    }
 }
 
-#[derive(Clone, Debug)]
+impl Trace for Environment {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.global_stmts.values().chain(&self.cells).for_each(|cell| tracer.shared(cell));
+        self.closures.values().for_each(|func| func.trace(tracer));
+    }
+
+    fn clear(&mut self) {
+        *self = Environment::new();
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct LocalEnvironment {
-    pub(crate) variables: HashMap<String, Arc<RwLock<Value>>>,
+    /// Locals the resolver didn't give a slot
+    pub(crate) variables: HashMap<String, Shared<Value>>,
+    /// Path of the module whose code runs in this environment, empty at the root
+    pub(crate) module_path: Vec<String>,
+    scope: Arc<Scope>,
+    slots: Vec<Option<Shared<Value>>>,
 }
 
 impl LocalEnvironment {
     pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn in_module(module_path: Vec<String>) -> Self {
+        Self {
+            module_path,
+            ..Self::default()
+        }
+    }
+    /// Frame of a function whose locals were laid out by the resolver
+    pub fn with_scope(module_path: Vec<String>, scope: Arc<Scope>) -> Self {
         Self {
             variables: Default::default(),
+            module_path,
+            slots: vec![None; scope.names.len()],
+            scope,
+        }
+    }
+    /// Looks a local up by name
+    pub fn get(&self, ident: &str) -> Option<Shared<Value>> {
+        match self.scope.slot(ident) {
+            Some(slot) => self.slot(slot).cloned(),
+            None => self.variables.get(ident).cloned(),
+        }
+    }
+    /// Binds the local `ident` to `cell`, in its slot when it has one
+    pub fn define(&mut self, ident: &str, cell: Shared<Value>) {
+        match self.scope.slot(ident) {
+            Some(slot) => self.define_slot(slot, cell),
+            None => {
+                self.variables.insert(ident.into(), cell);
+            }
+        }
+    }
+    pub fn slot(&self, slot: u16) -> Option<&Shared<Value>> {
+        self.slots.get(slot as usize)?.as_ref()
+    }
+    pub fn define_slot(&mut self, slot: u16, cell: Shared<Value>) {
+        self.slots[slot as usize] = Some(cell);
+    }
+    /// Binds the `i`th parameter, parameters take the first slots of a resolved function
+    pub fn define_param(&mut self, i: usize, ident: &str, cell: Shared<Value>) {
+        match self.slots.get_mut(i) {
+            Some(slot) => *slot = Some(cell),
+            None => {
+                self.variables.insert(ident.into(), cell);
+            }
         }
     }
 }
+
+impl Trace for LocalEnvironment {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.variables.values().chain(self.slots.iter().flatten()).for_each(|cell| tracer.shared(cell));
+    }
+
+    fn clear(&mut self) {
+        self.variables.clear();
+        self.slots.fill(None);
+    }
+}
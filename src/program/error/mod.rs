@@ -0,0 +1,102 @@
+use crate::program::sandbox::Capability;
+use std::fmt::{Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+use std::time::Duration;
+
+/// Runtime error of a Morpho program.
+///
+/// The evaluator works with plain `Value`s, so errors are raised by unwinding
+/// with `raise` and turned back into `anyhow::Error` by `catch`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MorphoError {
+    DivisionByZero,
+    Overflow(String),
+    TypeError(String),
+    ValueError(String),
+    /// Variable, function or module that isn't defined where it is used
+    NameError(String),
+    /// Unresolved or conflicting `use` declaration
+    ImportError(String),
+    /// `item` is private to `module` and used outside of it
+    PrivacyError { item: String, module: String },
+    /// Calls nested `depth` deep, over the configured maximum or the native
+    /// stack. `trace` names the running functions, the innermost first
+    StackOverflow { depth: usize, trace: Vec<String> },
+    /// The program took all the steps of its sandbox
+    StepLimit(u64),
+    /// The program ran longer than its sandbox allows
+    Timeout(Duration),
+    /// A string or array of `size` bytes is over the sandbox's limit
+    MemoryLimit { size: usize, limit: usize },
+    /// The sandbox doesn't give the program this access to the host
+    CapabilityError(Capability),
+}
+
+impl Display for MorphoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MorphoError::DivisionByZero => write!(f, "DivisionByZero: division by zero"),
+            MorphoError::Overflow(op) => write!(f, "Overflow: {op} overflows"),
+            MorphoError::TypeError(msg) => write!(f, "TypeError: {msg}"),
+            MorphoError::ValueError(msg) => write!(f, "ValueError: {msg}"),
+            MorphoError::NameError(msg) => write!(f, "NameError: {msg}"),
+            MorphoError::ImportError(msg) => write!(f, "ImportError: {msg}"),
+            MorphoError::PrivacyError { item, module } => {
+                write!(f, "PrivacyError: {item} is private to module {module}")
+            }
+            MorphoError::StackOverflow { depth, trace } => {
+                write!(f, "StackOverflow: {depth} nested calls")?;
+                // Recursion fills the trace with the same function, runs of it are shown once
+                let mut frames = trace.iter().peekable();
+                while let Some(name) = frames.next() {
+                    let mut times = 1;
+                    while frames.next_if(|next| *next == name).is_some() {
+                        times += 1;
+                    }
+                    match times {
+                        1 => write!(f, "\n    at {name}")?,
+                        _ => write!(f, "\n    at {name} ({times} times)")?,
+                    }
+                }
+                Ok(())
+            }
+            MorphoError::StepLimit(steps) => write!(f, "StepLimit: program ran out of its {steps} steps"),
+            MorphoError::Timeout(timeout) => write!(f, "Timeout: program ran longer than {timeout:?}"),
+            MorphoError::MemoryLimit { size, limit } => {
+                write!(f, "MemoryLimit: value of {size} bytes exceeds the limit of {limit} bytes")
+            }
+            MorphoError::CapabilityError(capability) => {
+                write!(f, "CapabilityError: {capability} access is not allowed")
+            }
+        }
+    }
+}
+
+impl std::error::Error for MorphoError {}
+
+static SILENT_HOOK: Once = Once::new();
+
+/// Aborts evaluation of the current program with `error`
+pub fn raise(error: MorphoError) -> ! {
+    SILENT_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if info.payload().downcast_ref::<MorphoError>().is_none() {
+                default_hook(info)
+            }
+        }));
+    });
+    panic::panic_any(error)
+}
+
+/// Runs `f`, converting a raised `MorphoError` into an `Err`. Other panics are propagated
+pub fn catch<T>(f: impl FnOnce() -> T) -> anyhow::Result<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(value) => Ok(value),
+        Err(payload) => match payload.downcast::<MorphoError>() {
+            Ok(error) => Err((*error).into()),
+            Err(payload) => panic::resume_unwind(payload),
+        },
+    }
+}
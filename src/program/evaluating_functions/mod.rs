@@ -1,291 +1,305 @@
-use crate::ast::{CallExpr, Expr, Body, Prog, Stmt, PrivacyType, Import, InlineAccess};
+use crate::ast::{AnonymousFunc, CallExpr, Expr, Body, Prog, Stmt, PrivacyType, InlineAccess};
 use crate::program::environment::LocalEnvironment;
-use crate::program::function::Function;
-use crate::program::value::{CondType, Value};
+use crate::program::error::{catch, raise, MorphoError};
+use crate::program::{call_stack, sandbox};
+use crate::program::function::{Closure, Function};
+use crate::package::Project;
+use crate::program::loader::ModuleLoader;
+use crate::program::primitive_functions::{if_func, tail_if, while_func};
+use crate::program::value::{CondType, NativeFunc, Value};
+use crate::program::vm::{mbc, Vm};
 use crate::program::Program;
-use crate::{ANON_FUNC_CACHE, GLOBAL_ENV};
+use crate::program::{global_env, globals};
 use std::collections::HashMap;
 use std::ops::{Neg, Not};
-use std::sync::{Arc, RwLock};
-use uuid::Uuid;
+use std::path::Path;
+use crate::program::shared::{Ptr, Shared};
 use crate::program::module::Module;
 
 #[inline]
 pub fn eval_program(prog: Prog) -> anyhow::Result<()> {
-    let prog = Program::new(prog)?;
-    prog.run()?;
-    Ok(())
+    call_stack::with_stack(|| {
+        let prog = catch(|| Program::new(prog))??;
+        catch(|| prog.run())??;
+        Ok(())
+    })
+}
+
+/// Sets `prog` up for the tree backend and initializes its globals without
+/// running `main`, its functions are then called through `call_func`
+pub fn load_program(prog: Prog) -> anyhow::Result<()> {
+    let prog = catch(|| Program::new(prog))??;
+    catch(|| {
+        sandbox::start();
+        globals::init_globals(&prog.globals);
+    })
+}
+
+/// Loads the program from `entry` together with its file modules and runs it
+pub fn eval_file(entry: &Path) -> anyhow::Result<()> {
+    let prog = ModuleLoader::new().load(entry)?;
+    eval_program(prog)
+}
+
+/// Loads a compiled `.mbc` file and runs it on the VM, whatever the backend
+pub fn eval_bytecode_file(path: &Path) -> anyhow::Result<()> {
+    let bytecode = mbc::read(path)?;
+    catch(|| {
+        sandbox::start();
+        Vm::new(&bytecode).run();
+    })
+}
+
+/// Resolves the dependencies of the project in `dir` and runs its entry file
+pub fn eval_project(project: &Project) -> anyhow::Result<()> {
+    let prog = ModuleLoader::new().load_project(project)?;
+    eval_program(prog)
 }
 
 #[inline]
 pub fn extract_func(func_stmt: &Stmt) -> Option<(String, Function)> {
-    match func_stmt {
-        Stmt::FuncIdent(f_ident) => {
-            let f_ident = f_ident.clone();
-            if let Some(Body { stmt }) = f_ident.stmt {
-                return Some((
-                    f_ident.ident.clone(),
-                    Function::new(
-                        f_ident.privacy_type,
-                        HashMap::new(),
-                        Arc::new(RwLock::new(LocalEnvironment::new())),
-                        f_ident.ident,
-                        f_ident.args,
-                        f_ident.rty,
-                        stmt,
-                    ),
-                ));
-            }
+    if let Stmt::FuncIdent(f_ident) = func_stmt {
+        let f_ident = f_ident.clone();
+        if let Some(Body { stmt, offsets, .. }) = f_ident.stmt {
+            let mut func = Function::new(
+                f_ident.privacy_type,
+                HashMap::new(),
+                Shared::new(LocalEnvironment::new()),
+                f_ident.ident.clone(),
+                f_ident.args,
+                f_ident.rty,
+                stmt,
+            );
+            func.set_offsets(offsets);
+            return Some((f_ident.ident, func));
         }
-        _ => {}
     }
     None
 }
 
-pub fn extract_module(mod_stmt: &Stmt) -> Option<(String, Module)> {
+/// Builds the module declared by `mod_stmt` inside the module at `parent_path`
+pub fn extract_module(mod_stmt: &Stmt, parent_path: &[String]) -> Option<(String, PrivacyType, Module)> {
     match mod_stmt {
         Stmt::Module(module_ident) => {
-            let mut module = Module::new(&module_ident.ident);
+            let mut path = parent_path.to_vec();
+            path.push(module_ident.ident.clone());
+            let mut module = Module::with_path(path.clone());
             let mut extracted_functions = HashMap::new();
             let mut extracted_modules = HashMap::new();
             for stmt in &module_ident.body.stmt {
-                if let Some((ident, module)) = extract_module(stmt) {
-                    extracted_modules.insert(ident, module);
+                if let Some((ident, privacy, module)) = extract_module(stmt, &path) {
+                    extracted_modules.insert(ident, (privacy, module));
                 }
-                if let Some((ident, func)) = extract_func(stmt) {
-                   extracted_functions.insert(ident, func);
+                if let Some((ident, mut func)) = extract_func(stmt) {
+                    func.set_module_path(path.clone());
+                    extracted_functions.insert(ident, func);
+                }
+                // Globals are initialized before main, see `globals::init_globals`
+                match stmt {
+                    Stmt::Global(global) => module.declare_global(&global.ident, &global.privacy_type, global.constant),
+                    Stmt::VarIdent(var) => module.declare_global(&var.ident, &PrivacyType::Private, false),
+                    _ => {}
                 }
             }
-            for (ident, m) in extracted_modules {
-                module.insert(&ident, Value::Module(m));
+            for (ident, (privacy, m)) in extracted_modules {
+                module.insert_with_privacy(&ident, Value::Module(m), &privacy);
             }
             for (ident, func) in extracted_functions {
-                module.insert(&ident, Value::Func(func));
+                let privacy = func.get_privacy().clone();
+                module.insert_with_privacy(&ident, Value::Func(func), &privacy);
             }
-            Some((module_ident.ident.clone(), module))
+            Some((module_ident.ident.clone(), module_ident.privacy_type.clone(), module))
         }
         _ => None
     }
 }
 
-pub fn extract_import(import_stmt: &Stmt, ) -> Option<(String, Arc<RwLock<Value>>)> {
-    match import_stmt {
-        Stmt::Import(Import{ inline_access }) => {
-            let mut idents = vec![];
-            let mut curr_expr = Some(inline_access.clone());
-            while let Some(expr) = curr_expr {
-                match *expr {
-                    Expr::InlineAccess(InlineAccess { ident, next }) => {
-                        idents.push(ident);
-                        curr_expr = next;
-                    }
-                    Expr::Ident(ident) => {
-                        idents.push(ident);
-                        curr_expr = None;
-                    }
-                    _ => {
-                        curr_expr = None;
-                    }
-                }
-            }
-            let mut curr_stmt = GLOBAL_ENV.read().unwrap().global_stmts.get(&idents[1]).unwrap().clone();
-            for i in 2..idents.len() {
-                let module = if let Value::Module(module) = curr_stmt.read().unwrap().clone() {
-                    module
-                } else { panic!("Module not found!") };
-                curr_stmt = module.get(&idents[i]).unwrap().clone();
-            }
-            return Some((idents[idents.len()-1].clone(), curr_stmt))
-        }
-        _ => {}
+/// Looks `ident` up in `module` on behalf of code in module `from`, raising on private items
+pub(crate) fn module_item(module: &Module, ident: &str, from: &[String]) -> Option<Shared<Value>> {
+    match module.lookup(ident, from) {
+        Ok(value) => value.cloned(),
+        Err(error) => raise(error),
     }
-    None
+}
+
+/// Finds the module at `path`, starting from the global environment
+pub(crate) fn find_module(path: &[String]) -> Option<Module> {
+    let module_value = module_cell(path)?;
+    let value = module_value.borrow();
+    match &*value {
+        Value::Module(module) => Some(module.clone()),
+        _ => None,
+    }
+}
+
+/// Finds the item `ident` of the module at `path` without copying the module
+fn find_module_item(path: &[String], ident: &str) -> Option<Shared<Value>> {
+    let module_value = module_cell(path)?;
+    let value = module_value.borrow();
+    match &*value {
+        Value::Module(module) => module.get(ident).cloned(),
+        _ => None,
+    }
+}
+
+fn module_cell(path: &[String]) -> Option<Shared<Value>> {
+    let (first, rest) = path.split_first()?;
+    let mut module_value = global_env().borrow().global_stmts.get(first)?.clone();
+    for ident in rest {
+        let next = match &*module_value.borrow() {
+            Value::Module(module) => module.get(ident)?.clone(),
+            _ => return None,
+        };
+        module_value = next;
+    }
+    Some(module_value)
 }
 
 macro_rules! eval_primitive_expr {
-        ($rhs: expr, $lhs: expr, $v: expr) => {
-            if let Value::Void = $rhs {
-                $rhs = $v;
-            } else {
-                $lhs = $v;
-            }
+        ($values: expr, $v: expr) => {
+            $values.push($v)
         };
     }
 
 macro_rules! eval_binary_expr {
-        ($rhs: expr, $lhs: expr, $op: tt) => {
+        ($values: expr, $env: expr, $op: tt) => {
             {
-                $rhs = $lhs $op $rhs;
-                $lhs = Value::Void
+                let rhs = resolve_cond($values.pop().unwrap_or(Value::Void), $env.clone());
+                let lhs = resolve_cond($values.pop().unwrap_or(Value::Void), $env.clone());
+                $values.push(lhs $op rhs)
             }
         };
     }
 
 macro_rules! eval_cond_expr {
-        ($rhs: expr, $lhs: expr, $l: expr, $r: expr, $cond_type: expr) => {
-            if let Value::Void = $rhs {
-                $rhs = Value::Cond($cond_type, $l, $r);
-            } else {
-                $lhs = Value::Cond($cond_type, $l, $r);
-            }
+        ($values: expr, $l: expr, $r: expr, $cond_type: expr) => {
+            $values.push(Value::Cond($cond_type, $l, $r))
         };
     }
 
 
 #[inline]
-pub fn eval_expr(expr: Expr, env: Arc<RwLock<LocalEnvironment>>) -> Value {
-    let mut expr_stack = vec![expr.clone()];
+pub fn eval_expr(expr: &Expr, env: Shared<LocalEnvironment>) -> Value {
+    // Resolved names are the most common operands, they don't need the stacks
+    match expr {
+        Expr::Local(ident, slot) => return load_local(ident, *slot, &env),
+        Expr::Global(_, id) => return load_global(*id),
+        _ => {}
+    }
+    // Operands are collected in pre-order (node, right, left), so popping
+    // `expr_stack` yields them in post-order and they can be evaluated on a value stack
+    let mut expr_stack = vec![];
     let mut curr_exprs = vec![expr];
     while let Some(expr) = curr_exprs.pop() {
-        //println!("CURR_EXPR: {:?}", curr_exprs);
         match expr {
             Expr::Add(l, r)
             | Expr::Sub(l, r)
             | Expr::Mul(l, r)
             | Expr::Div(l, r)
             | Expr::Xor(l, r)
-            | Expr::Mod(l, r) => {
-                expr_stack.push(*l.clone());
-                expr_stack.push(*r.clone());
-                curr_exprs.push(*l.clone());
-                curr_exprs.push(*r.clone());
+            | Expr::Mod(l, r)
+            | Expr::BitAnd(l, r)
+            | Expr::BitOr(l, r)
+            | Expr::Shl(l, r)
+            | Expr::Shr(l, r) => {
+                curr_exprs.push(l);
+                curr_exprs.push(r);
             }
             Expr::Neg(r) | Expr::Not(r) => {
-                expr_stack.push(*r.clone());
-                curr_exprs.push(*r.clone());
+                curr_exprs.push(r);
             }
             _ => {}
         }
+        expr_stack.push(expr);
     }
 
-    //println!("EXPR_STACK: {expr_stack:#?}");
-
-
-    let mut value = Value::Void;
-    let mut rhs = Value::Void;
-    let mut lhs = Value::Void;
+    let mut values = vec![];
     while let Some(expr) = expr_stack.pop() {
-        //println!("LHS: {lhs} RHS: {rhs}");
         match expr {
-            Expr::Integer(v) => eval_primitive_expr!(rhs, lhs, Value::Int(v)),
-            Expr::Float(v) => eval_primitive_expr!(rhs, lhs, Value::Float(v)),
-            Expr::Bool(v) => eval_primitive_expr!(rhs, lhs, Value::Bool(v)),
-            Expr::StringLit(v) => eval_primitive_expr!(rhs, lhs, Value::String(v)),
-            Expr::Add(_, _) => eval_binary_expr!(rhs, lhs, +),
-            Expr::Sub(_, _) => eval_binary_expr!(rhs, lhs, -),
-            Expr::Mul(_, _) => eval_binary_expr!(rhs, lhs, *),
-            Expr::Div(_, _) => eval_binary_expr!(rhs, lhs, /),
-            Expr::Eq(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::Eq),
-            Expr::NotEq(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::Ne),
-            Expr::Gt(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::Gt),
-            Expr::Lt(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::Lt),
-            Expr::Ge(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::Ge),
-            Expr::Le(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::Le),
-            Expr::Or(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::Or),
-            Expr::And(l, r) => eval_cond_expr!(rhs, lhs, l, r, CondType::And),
+            Expr::Integer(v) => eval_primitive_expr!(values, Value::Int(*v)),
+            Expr::BigInteger(v) => eval_primitive_expr!(values, Value::BigInt(v.parse().unwrap())),
+            Expr::Float(v) => eval_primitive_expr!(values, Value::Float(*v)),
+            Expr::Bool(v) => eval_primitive_expr!(values, Value::Bool(*v)),
+            Expr::StringLit(v) => eval_primitive_expr!(values, Value::String(v.clone())),
+            Expr::Add(_, _) => eval_binary_expr!(values, env, +),
+            Expr::Sub(_, _) => eval_binary_expr!(values, env, -),
+            Expr::Mul(_, _) => eval_binary_expr!(values, env, *),
+            Expr::Div(_, _) => eval_binary_expr!(values, env, /),
+            Expr::Eq(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::Eq),
+            Expr::NotEq(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::Ne),
+            Expr::Gt(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::Gt),
+            Expr::Lt(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::Lt),
+            Expr::Ge(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::Ge),
+            Expr::Le(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::Le),
+            Expr::Or(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::Or),
+            Expr::And(l, r) => eval_cond_expr!(values, l.clone(), r.clone(), CondType::And),
             Expr::Not(_) => {
-                if let Value::Void = rhs {
-                    lhs = lhs.not()
-                } else {
-                    rhs = rhs.not()
-                }
+                let value = resolve_cond(values.pop().unwrap_or(Value::Void), env.clone());
+                values.push(value.not())
             }
             Expr::Neg(_) => {
-                if let Value::Void = rhs {
-                    lhs = lhs.neg()
-                } else {
-                    rhs = rhs.neg()
-                }
+                let value = resolve_cond(values.pop().unwrap_or(Value::Void), env.clone());
+                values.push(value.neg())
             }
-            Expr::Xor(_, _) => eval_binary_expr!(rhs, lhs, ^),
-            Expr::Mod(_, _) => eval_binary_expr!(rhs, lhs, %),
-            Expr::Call(call_expr) => eval_primitive_expr!(rhs, lhs, call_func(call_expr, env.clone())),
+            Expr::Xor(_, _) => eval_binary_expr!(values, env, ^),
+            Expr::Mod(_, _) => eval_binary_expr!(values, env, %),
+            Expr::BitAnd(_, _) => eval_binary_expr!(values, env, &),
+            Expr::BitOr(_, _) => eval_binary_expr!(values, env, |),
+            Expr::Shl(_, _) => eval_binary_expr!(values, env, <<),
+            Expr::Shr(_, _) => eval_binary_expr!(values, env, >>),
+            Expr::Call(call_expr) => eval_primitive_expr!(values, call_func(call_expr, env.clone())),
             Expr::Ident(ident) => {
-                let var_value = {
-                    env.try_read().unwrap().variables.get(&ident).unwrap().clone()
-                };
-                let resolved_value = var_value.try_read().unwrap().clone();
-
-                if let Value::Void = rhs {
-                    rhs = resolved_value;
-                } else {
-                    lhs = resolved_value;
-                }
+                let (var_value, _) = lookup_name(ident, &env).unwrap_or_else(|| not_found(ident));
+                let resolved_value = var_value.get();
+                values.push(resolved_value)
             }
-            Expr::Func(f_ptr) => eval_primitive_expr!(rhs, lhs, Value::CallFunc(CallExpr::new(
+            Expr::Local(ident, slot) => eval_primitive_expr!(values, load_local(ident, *slot, &env)),
+            Expr::Global(_, id) => eval_primitive_expr!(values, load_global(*id)),
+            Expr::Func(f_ptr) => eval_primitive_expr!(values, Value::CallFunc(CallExpr::new(
                 f_ptr.ident.clone(),
-                f_ptr.args.unwrap()
-            ))),
-            Expr::AnonFunc(a_func) => {
-                let mut cache = ANON_FUNC_CACHE.write().unwrap();
-                let cached_func = match cache.get(&a_func) {
-                    None => {
-                        //println!("ANON: {a_func:?}");
-                        let ident = Uuid::new_v4().to_string();
-                        let a_func_clone = a_func.clone();
-
-                        let args: Vec<_> = a_func.args.iter().map(|(arg_ident, expr)| {
-                            let value = eval_expr(expr.clone(), Arc::clone(&env));
-                            (arg_ident.clone(), value.into_type().to_string())
-                        }).collect();
-
-
-                        let call_args: Vec<_> = a_func.args.into_iter().map(|(_, expr)| expr).collect();
-
-
-                        let func = Function::new(
-                            PrivacyType::Private,
-                            HashMap::new(),
-                            Arc::clone(&env),
-                            ident.clone(),
-                            args,
-                            a_func.rty,
-                            a_func.stmt.unwrap().stmt,
-                        );
-
-                        env.write().unwrap().variables.insert(
-                            ident.clone(),
-                            Arc::new(RwLock::new(Value::Func(func.clone()))),
-                        );
-
-                        eval_primitive_expr!(rhs, lhs, Value::CallFunc(CallExpr::new(ident, call_args)));
-                        Some((a_func_clone, func))
-                    }
-                    Some(func) => {
-                        let call_args: Vec<_> = a_func.args.into_iter().map(|(_, expr)| expr).collect();
-
-                        env.write().unwrap().variables.insert(
-                            func.get_ident().to_string(),
-                            Arc::new(RwLock::new(Value::Func(func.clone()))),
-                        );
-
-                        // Подготавливаем значение для вызова
-                        eval_primitive_expr!(rhs, lhs, Value::CallFunc(CallExpr::new(func.get_ident().to_string(), call_args)));
-                        None
-                    }
-                };
-                if let Some((a_func, func)) = cached_func {
-                    cache.insert(a_func, func);
+                f_ptr.args.clone().unwrap()
+            ).with_target(f_ptr.target))),
+            Expr::AnonFunc(a_func) => eval_primitive_expr!(values, Value::Closure(Ptr::new(closure(a_func, &env)))),
+            Expr::Concat(parts) => {
+                let mut string = String::new();
+                for part in parts {
+                    string.push_str(&stringify_value(eval_expr(part, env.clone()), env.clone()));
                 }
-                drop(cache)
-
+                eval_primitive_expr!(values, sandbox::limit_size(Value::String(string)))
+            }
+            Expr::Array(items) => {
+                let items = items.iter()
+                    .map(|item| resolve_cond(eval_expr(item, env.clone()), env.clone()))
+                    .collect();
+                eval_primitive_expr!(values, sandbox::limit_size(Value::Array(items)))
             }
-            Expr::Range((start, end)) => eval_primitive_expr!(rhs, lhs, Value::Range(start, end)),
+            Expr::InlineAccess(inline_access) => eval_primitive_expr!(values, eval_inline_access(inline_access.clone(), env.clone())),
+            Expr::Range((start, end)) => eval_primitive_expr!(values, Value::Range(*start, *end)),
             Expr::Counter((ident, (start, end))) => {
-                env.try_write()
-                    .unwrap()
-                    .variables
-                    .insert(ident.clone(), Arc::new(RwLock::new(Value::Int(start))));
-                eval_primitive_expr!(rhs, lhs, Value::Counter(ident, start, end))
+                env.borrow_mut().define(ident, Shared::new(Value::Int(*start)));
+                eval_primitive_expr!(values, Value::Counter(ident.clone(), *start, *end))
             }
-            Expr::Ref(expr) => match *expr {
+            Expr::Ref(expr) => match expr.as_ref() {
+                Expr::Local(ident, slot) => {
+                    let local = env.borrow().slot(*slot).cloned();
+                    let var_value = match local {
+                        Some(cell) => cell,
+                        None => lookup_name(ident, &env).unwrap_or_else(|| not_found(ident)).0,
+                    };
+                    eval_primitive_expr!(values, Value::RefValue(var_value));
+                }
                 Expr::Ident(ident) => {
-                    let var_value = env.try_read().unwrap().variables.get(&ident).unwrap().clone();
-                    eval_primitive_expr!(rhs, lhs, Value::RefValue(var_value));
+                    let var_value = match lookup_name(ident, &env) {
+                        // Constants are referenced through a copy so they can't be changed
+                        Some((value, true)) => Shared::new(value.get()),
+                        Some((value, false)) => value,
+                        None => not_found(ident),
+                    };
+                    eval_primitive_expr!(values, Value::RefValue(var_value));
                 }
-                _ => {
-                    let evaluated = eval_expr(*expr, env.clone());
-                    eval_primitive_expr!(rhs, lhs, Value::RefValue(Arc::new(RwLock::new(evaluated))));
+                expr => {
+                    let evaluated = eval_expr(expr, env.clone());
+                    eval_primitive_expr!(values, Value::RefValue(Shared::new(evaluated)));
                 }
             },
 
@@ -294,84 +308,359 @@ pub fn eval_expr(expr: Expr, env: Arc<RwLock<LocalEnvironment>>) -> Value {
         }
     }
 
-    value = rhs;
-    //println!("FINAL: {value:?}");
-    value
+    values.pop().unwrap_or(Value::Void)
 }
 
+/// Raises the `NameError` of a variable or function that isn't defined
+pub(crate) fn not_found(ident: &str) -> ! {
+    raise(MorphoError::NameError(format!("{ident} not found")))
+}
 
-macro_rules! macro_extract_func {
-    ($expr: expr, $call_expr: expr, $env: expr) => {{
-        let call_expr: CallExpr = $call_expr;
-        let env: Arc<RwLock<LocalEnvironment>> = $env;
-        let args = call_expr.get_args();
-        let mut parsed_args: Vec<Value> = vec![];
-        for arg in args {
-            parsed_args.push(eval_expr(arg, env.clone()));
+/// Value of the local in `slot`, looked up by name when its slot is still empty
+#[inline]
+fn load_local(ident: &str, slot: u16, env: &Shared<LocalEnvironment>) -> Value {
+    let local = env.borrow().slot(slot).map(Shared::get);
+    match local {
+        Some(value) => value,
+        None => {
+            let (cell, _) = lookup_name(ident, env).unwrap_or_else(|| not_found(ident));
+            cell.get()
         }
+    }
+}
 
-        return match $expr.try_read().unwrap().clone() {
-            Value::FuncPtr(func) => func(parsed_args, env.clone()),
-            Value::Func(mut func) => {
-                let l_env = Arc::new(RwLock::new(LocalEnvironment::new()));
-                let args = func.get_args();
-                let parsed_args_len = parsed_args.len();
-                let l_env_clone = l_env.clone();
-                let mut env_lock = l_env_clone.write().expect("Failed to acquire write lock");
-
-                for (i, (ident, ty)) in args.into_iter().enumerate() {
-                    // Early exit if index is out of bounds
-                    if i >= parsed_args_len {
-                        panic!("Argument index out of bounds");
-                    }
-
-                    let parsed_value = &parsed_args[i];
-
-                    // Check if the type matches
-                    if Value::Type(ty.to_string()) == parsed_value.clone().into_type() {
-                        match parsed_value {
-                            Value::RefValue(r) => {
-                                env_lock.variables.insert(ident.clone(), r.clone());
-                            },
-                            _ => {
-                                env_lock.variables.insert(ident.clone(), Arc::new(RwLock::new(parsed_value.clone())));
-                            },
-                        }
-                    } else {
-                        panic!("Expected other type");
-                    }
+#[inline]
+fn load_global(id: u32) -> Value {
+    global_env().borrow().global(id).get()
+}
+
+#[inline]
+fn resolve_cond(value: Value, env: Shared<LocalEnvironment>) -> Value {
+    match value {
+        Value::Cond(ty, l, r) => Value::Bool(ty.eval_cond(&l, &r, env)),
+        value => value,
+    }
+}
+
+#[inline]
+fn stringify_value(value: Value, env: Shared<LocalEnvironment>) -> String {
+    match value {
+        Value::Cond(ty, l, r) => ty.eval_cond(&l, &r, env).to_string(),
+        Value::RefValue(r) => {
+            let value = r.get();
+            stringify_value(value, env)
+        }
+        value => value.to_string(),
+    }
+}
+
+/// Call whose arguments have been evaluated
+pub(crate) enum Call {
+    /// Result of a built-in function or of a call that has already run
+    Done(Value),
+    /// Morpho function with its parameters bound, left to the caller to run
+    Enter(Function),
+}
+
+impl Call {
+    pub(crate) fn run(self) -> Value {
+        match self {
+            Call::Done(value) => value,
+            Call::Enter(func) => func.run(),
+        }
+    }
+}
+
+/// Evaluates the arguments of `call_expr` in `env` and binds them to the
+/// parameters of the function stored in `callee`, which is called by `name`.
+/// Built-in functions run right away
+fn enter(callee: &Shared<Value>, call_expr: &CallExpr, name: &str, env: Shared<LocalEnvironment>) -> Call {
+    let args = call_expr.args().iter().map(|arg| eval_expr(arg, env.clone())).collect();
+    let callee = callee.get();
+    match callee {
+        Value::FuncPtr(func) => Call::Done(func(native_args(func, args, &env), env.clone())),
+        Value::Func(func) => Call::Enter(bind(func, args, &env, Some(name))),
+        // A closure binds its own arguments, it is called without any
+        Value::Closure(closure) if args.is_empty() => Call::Enter(enter_closure(&closure, &env)),
+        Value::Closure(_) => raise(MorphoError::TypeError(format!("{name} expects 0 arguments, found {}", args.len()))),
+        _ => raise(MorphoError::TypeError(format!("{name} is not a function"))),
+    }
+}
+
+/// Arguments of the built-in `func`, evaluated in `env`. Conditions are only
+/// left to `while`, which evaluates its own on every iteration, the other
+/// built-in functions get their value as on the VM
+pub(crate) fn native_args(func: NativeFunc, args: Vec<Value>, env: &Shared<LocalEnvironment>) -> Vec<Value> {
+    if std::ptr::fn_addr_eq(func, while_func as NativeFunc) {
+        return args;
+    }
+    args.into_iter().map(|arg| resolve_cond(arg, env.clone())).collect()
+}
+
+/// Binds `args`, evaluated in the caller's `env`, to the parameters of `func`
+/// in a new frame. `name` is the name the function is called by, its arguments
+/// are converted to the declared types. Closures are bound without a name and
+/// take arguments of any type
+fn bind(mut func: Function, args: Vec<Value>, env: &Shared<LocalEnvironment>, name: Option<&str>) -> Function {
+    let l_env = Shared::new(LocalEnvironment::with_scope(
+        func.get_module_path().to_vec(),
+        func.get_scope().clone(),
+    ));
+    let params = func.get_args().len();
+    if args.len() != params {
+        raise(MorphoError::TypeError(format!(
+            "{} expects {params} arguments, found {}",
+            name.unwrap_or(func.get_ident()),
+            args.len()
+        )));
+    }
+    let mut env_lock = l_env.borrow_mut();
+    for (i, ((ident, ty), arg)) in func.get_args().iter().zip(&args).enumerate() {
+
+        // Conditions refer to the caller's locals, they can't be evaluated in the callee
+        let parsed_value = resolve_cond(arg.clone(), env.clone());
+
+        let Some(name) = name else {
+            match parsed_value {
+                Value::RefValue(r) => env_lock.define_param(i, ident, r),
+                value => env_lock.define_param(i, ident, Shared::new(value)),
+            }
+            continue;
+        };
+        // Check if the type matches
+        match &parsed_value {
+            Value::RefValue(r) if Value::Type(ty.to_string()) == parsed_value.clone().into_type() => {
+                env_lock.define_param(i, ident, r.clone());
+            }
+            _ => match parsed_value.clone().coerce_to(ty) {
+                Some(value) => {
+                    env_lock.define_param(i, ident, Shared::new(value));
+                }
+                None => {
+                    drop(env_lock);
+                    raise(MorphoError::TypeError(format!(
+                        "argument {ident} of {name} expects {ty}, found {}",
+                        parsed_value.into_type()
+                    )))
                 }
-                drop(env_lock);
-                func.set_env(l_env);
-                func.run()
+            },
+        }
+    }
+    drop(env_lock);
+    func.set_env(l_env);
+    func
+}
+
+/// Closure of the anonymous function `a_func` written in `env`. The function is
+/// built once for every place in the source it is written at, its arguments
+/// are evaluated when it is called
+fn closure(a_func: &AnonymousFunc, env: &Shared<LocalEnvironment>) -> Closure {
+    let body = a_func.stmt.as_ref().expect("Anonymous function without a body");
+    let module_path = env.borrow().module_path.clone();
+    // Bodies of trees that were not parsed have no offset and are built every time
+    let key = body.offset.map(|offset| (module_path.clone(), offset));
+    let cached = key.as_ref().and_then(|key| global_env().borrow().closures.get(key).cloned());
+    let func = cached.unwrap_or_else(|| {
+        let params = a_func.args.iter().map(|(ident, _)| (ident.clone(), "any".to_string())).collect();
+        let mut func = Function::new(
+            PrivacyType::Private,
+            HashMap::new(),
+            env.clone(),
+            "<anonymous>".to_string(),
+            params,
+            a_func.rty.clone(),
+            body.stmt.clone(),
+        );
+        if let Some(scope) = &body.scope {
+            func.set_scope(scope.clone());
+        }
+        func.set_offsets(body.offsets.clone());
+        func.set_module_path(module_path);
+        if let Some(key) = key {
+            global_env().borrow_mut().closures.insert(key, func.clone());
+        }
+        func
+    });
+    Closure {
+        func,
+        args: a_func.args.iter().map(|(_, arg)| arg.clone()).collect(),
+    }
+}
+
+/// Evaluates the arguments of `closure` in `env` and binds them to its function
+pub(crate) fn enter_closure(closure: &Closure, env: &Shared<LocalEnvironment>) -> Function {
+    let args = closure.args.iter().map(|arg| eval_expr(arg, env.clone())).collect();
+    bind(closure.func.clone(), args, env, None)
+}
+
+pub(crate) fn call_closure(closure: &Closure, env: &Shared<LocalEnvironment>) -> Value {
+    enter_closure(closure, env).run()
+}
+
+/// Cell of the function `call_expr` calls from `env`: its resolved target, an
+/// item of the current module, a global or a local, in that order
+fn callee(call_expr: &CallExpr, env: &Shared<LocalEnvironment>) -> Shared<Value> {
+    if let Some(id) = call_expr.get_target() {
+        return global_env().borrow().global(id).clone();
+    }
+    let ident = call_expr.get_name();
+    let module_path = env.borrow().module_path.clone();
+    if let Some(func) = find_module_item(&module_path, &ident) {
+        return func;
+    }
+    // The guard is released before the call, callees may need to write to GLOBAL_ENV
+    let global = global_env().borrow().global_stmts.get(&ident).cloned();
+    match global {
+        Some(func) => func,
+        None => env
+            .borrow()
+            .get(&ident)
+            .unwrap_or_else(|| raise(MorphoError::NameError(format!("Function {ident} not found")))),
+    }
+}
+
+#[inline]
+pub fn call_func(call_expr: &CallExpr, env: Shared<LocalEnvironment>) -> Value {
+    let callee = callee(call_expr, &env);
+    enter(&callee, call_expr, &call_expr.get_name(), env).run()
+}
+
+/// Prepares `call_expr` in tail position of a function returning `rty`.
+///
+/// A Morpho function returning `rty` is entered by the loop of `Function::run`
+/// instead of recursing, so tail calls run in constant stack space. The branch
+/// taken by `if` is a tail call too. Any other call runs right away, its result
+/// still has to be converted to `rty` by the caller
+pub(crate) fn tail_call(call_expr: &CallExpr, rty: &str, env: Shared<LocalEnvironment>) -> Call {
+    let callee = callee(call_expr, &env);
+    let (same_rty, is_if) = match &*callee.borrow() {
+        Value::Func(func) => (func.get_rty() == rty, false),
+        Value::Closure(closure) => (closure.func.get_rty() == rty, false),
+        Value::FuncPtr(func) => (false, std::ptr::fn_addr_eq(*func, if_func as NativeFunc)),
+        _ => (false, false),
+    };
+    if same_rty {
+        return enter(&callee, call_expr, &call_expr.get_name(), env);
+    }
+    if is_if {
+        let args = call_expr.args().iter().map(|arg| eval_expr(arg, env.clone())).collect();
+        return tail_if(args, rty, env);
+    }
+    Call::Done(enter(&callee, call_expr, &call_expr.get_name(), env).run())
+}
+
+/// Evaluates a qualified path like `a::b::f(x)` or `a::NAME` used by code in `env`.
+///
+/// Paths starting with `crate`, `self` or `super` are resolved from the root, the
+/// current module or its parent. Other paths are looked up in the current module
+/// first and then in the global environment
+pub fn eval_inline_access(inline_access: InlineAccess, env: Shared<LocalEnvironment>) -> Value {
+    let from = env.borrow().module_path.clone();
+    let (idents, terminal) = flatten_path(inline_access);
+    let container = resolve_path(&idents, &from).unwrap_or_else(|msg| raise(MorphoError::NameError(msg)));
+    let module_name = container.as_ref().map_or("crate".to_string(), |module| module.get_path().join("::"));
+    match terminal {
+        Some(Expr::Call(call_expr)) => {
+            let ident = call_expr.get_name();
+            let func = path_item(&container, &ident, &from)
+                .unwrap_or_else(|| raise(MorphoError::NameError(format!("Function {ident} not found in {module_name}"))));
+            let name = format!("{}::{ident}", idents.join("::"));
+            enter(&func, &call_expr, &name, env).run()
+        }
+        Some(Expr::Ident(ident)) => match path_item(&container, &ident, &from) {
+            Some(value) => value.get(),
+            None => raise(MorphoError::NameError(format!("{ident} not found in {module_name}"))),
+        },
+        _ => raise(MorphoError::TypeError(format!("expected a name or a call after {}::", idents.join("::")))),
+    }
+}
+
+/// Splits `a::b::f(x)` into its module segments `[a, b]` and the final expression
+pub(crate) fn flatten_path(inline_access: InlineAccess) -> (Vec<String>, Option<Expr>) {
+    let InlineAccess { ident, mut next } = inline_access;
+    let mut idents = vec![ident];
+    let mut terminal = None;
+    while let Some(expr) = next {
+        match *expr {
+            Expr::InlineAccess(InlineAccess { ident, next: n }) => {
+                idents.push(ident);
+                next = n;
             }
+            expr => {
+                terminal = Some(expr);
+                next = None;
+            }
+        }
+    }
+    (idents, terminal)
+}
 
-            _ => panic!("Expected func"),
+/// Resolves the module the segments `idents` lead to when used by code in module
+/// `from`, `None` standing for the crate root
+pub(crate) fn resolve_path(idents: &[String], from: &[String]) -> Result<Option<Module>, String> {
+    let (base, idents) = match idents[0].as_str() {
+        "crate" => (vec![], &idents[1..]),
+        "self" => (from.to_vec(), &idents[1..]),
+        "super" => {
+            let supers = idents.iter().take_while(|ident| *ident == "super").count();
+            if supers > from.len() {
+                return Err(format!("super goes beyond the crate root in {}", from.join("::")));
+            }
+            (from[..from.len() - supers].to_vec(), &idents[supers..])
+        }
+        first => {
+            let relative = find_module(from).is_some_and(|module| module.get(first).is_some());
+            (if relative { from.to_vec() } else { vec![] }, idents)
+        }
+    };
+
+    let mut container = if base.is_empty() { None } else { find_module(&base) };
+    for ident in idents {
+        let value = path_item(&container, ident, from)
+            .ok_or_else(|| format!("Module {ident} not found"))?
+            .borrow()
+            .clone();
+        container = match value {
+            Value::Module(module) => Some(module),
+            _ => return Err(format!("{ident} is not a module")),
         };
-    }};
+    }
+    Ok(container)
 }
 
-#[inline]
-pub fn call_func(call_expr: CallExpr, env: Arc<RwLock<LocalEnvironment>>) -> Value {
-    //println!("{call_expr:?} {env:?}");
-    let ident = call_expr.get_name();
-    match GLOBAL_ENV
-        .try_read()
-        .unwrap()
-        .global_stmts
-        .clone()
-        .get(&ident)
-    {
-        None => {
-            let func = env
-                .try_read()
-                .unwrap()
-                .variables
-                .get(&ident)
-                .unwrap()
-                .clone();
-            macro_extract_func!(func, call_expr, env)
+/// Looks `ident` up in `container`, the crate root when `None`, on behalf of code in module `from`
+pub(crate) fn path_item(container: &Option<Module>, ident: &str, from: &[String]) -> Option<Shared<Value>> {
+    match container {
+        None => global_env().borrow().global_stmts.get(ident).cloned(),
+        Some(module) => module_item(module, ident, from),
+    }
+}
+
+pub(crate) fn path_item_is_const(container: &Option<Module>, ident: &str) -> bool {
+    match container {
+        None => global_env().borrow().consts.contains(ident),
+        Some(module) => module.is_const(ident),
+    }
+}
+
+/// Finds the cell a plain name refers to: a local variable, an item of the current
+/// module or a global. The flag tells whether the name is a constant
+pub(crate) fn lookup_name(ident: &str, env: &Shared<LocalEnvironment>) -> Option<(Shared<Value>, bool)> {
+    let (local, from) = {
+        let env = env.borrow();
+        (env.get(ident), env.module_path.clone())
+    };
+    if let Some(value) = local {
+        return Some((value, false));
+    }
+    if let Some(module_value) = module_cell(&from) {
+        if let Value::Module(module) = &*module_value.borrow() {
+            if let Some(value) = module.get(ident) {
+                return Some((value.clone(), module.is_const(ident)));
+            }
         }
-        Some(func) => macro_extract_func!(func.clone(), call_expr, env),
     }
+    let global_env = global_env();
+    let global = global_env.borrow();
+    global.global_stmts.get(ident).map(|value| (value.clone(), global.consts.contains(ident)))
 }
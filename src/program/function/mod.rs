@@ -1,155 +1,255 @@
-use crate::ast::{Expr, InlineAccess, PrivacyType, Stmt, VarAssign, VarIdent};
+use crate::ast::{Expr, GlobalIdent, PrivacyType, Scope, Stmt, VarAssign, VarIdent};
+use crate::program::{call_stack, sandbox};
 use crate::program::environment::LocalEnvironment;
-use crate::program::evaluating_functions::{call_func, eval_expr};
+use crate::program::error::{raise, MorphoError};
+use crate::program::gc::{Node, Trace, Tracer};
+use crate::program::evaluating_functions::{call_func, eval_expr, eval_inline_access, lookup_name, not_found, tail_call, Call};
 use crate::program::value::Value;
 use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-use crate::GLOBAL_ENV;
+use crate::program::shared::{Ptr, Shared};
+use std::sync::Arc;
 
+/// Anonymous function with the expressions of its arguments, which are
+/// evaluated each time it is called in the caller's environment. Its
+/// parameters take any type
+#[derive(Clone, Debug)]
+pub struct Closure {
+    pub(crate) func: Function,
+    pub(crate) args: Vec<Expr>,
+}
+
+impl Trace for Closure {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.func.trace(tracer);
+    }
+}
+
+/// Closures are shared by the values they were copied to
+impl Node for Closure {
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        Trace::trace(self, tracer);
+        true
+    }
+
+    fn clear(&self) {}
+}
+
+/// Function value. Clones share the definition, which doesn't change once the
+/// program is set up, and only differ by the environment of their call
 #[derive(Clone, Debug)]
 pub struct Function {
+    definition: Ptr<Definition>,
+    environment: Shared<LocalEnvironment>,
+}
+
+/// Only the environment is traced, the `function_fields` of definitions are always empty
+impl Trace for Function {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.shared(&self.environment);
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Definition {
     privacy: PrivacyType,
+    #[allow(dead_code)]
     function_fields: HashMap<String, Value>,
-    environment: Arc<RwLock<LocalEnvironment>>,
     ident: String,
     args: Vec<(String, String)>,
-    pub rty: String,
+    rty: String,
     body: Vec<Stmt>,
+    /// Byte offsets of the body statements in their source file, when known
+    offsets: Vec<usize>,
+    /// Slots of the locals, empty until the resolver has seen the function
+    scope: Arc<Scope>,
+    module_path: Vec<String>,
 }
+
 impl Function {
     pub fn new(
         privacy: PrivacyType,
         function_fields: HashMap<String, Value>,
-        environment: Arc<RwLock<LocalEnvironment>>,
+        environment: Shared<LocalEnvironment>,
         ident: String,
         args: Vec<(String, String)>,
         rty: String,
         body: Vec<Stmt>,
     ) -> Self {
-        Self {
+        let definition = Definition {
             privacy,
             function_fields,
-            environment,
             ident,
             args,
             rty,
             body,
+            offsets: Vec::new(),
+            scope: Arc::default(),
+            module_path: Vec::new(),
+        };
+        Self {
+            definition: Ptr::new(definition),
+            environment,
         }
     }
-    pub(crate) fn run(mut self) -> Value {
-        for stmt in self.body {
+    /// Runs the body, then the bodies of the functions it calls in tail position
+    pub(crate) fn run(self) -> Value {
+        let frame = call_stack::enter(self.frame_name());
+        let mut func = self;
+        loop {
+            match func.run_body() {
+                Call::Done(value) => return value,
+                Call::Enter(next) => {
+                    frame.replace(next.frame_name());
+                    func = next;
+                }
+            }
+        }
+    }
+
+    fn frame_name(&self) -> String {
+        call_stack::frame_name(&self.definition.module_path, &self.definition.ident)
+    }
+
+    /// Runs the statements of the body until a `return` or a tail call, which is
+    /// returned with its arguments bound instead of being run
+    fn run_body(&self) -> Call {
+        let Definition { body, scope, rty, ident, .. } = &*self.definition;
+        let env = &self.environment;
+        let tail = body.iter().rposition(|stmt| !matches!(stmt, Stmt::Comment(_)));
+        for (i, stmt) in body.iter().enumerate() {
+            sandbox::step();
+            let target = scope.targets.get(i).copied().flatten();
             match stmt {
-                Stmt::Expr(expr) => match *expr {
+                Stmt::Expr(expr) => match expr.as_ref() {
+                    // The value of the last statement is dropped, only a call returning nothing can take its place
+                    Expr::Call(call_expr) if Some(i) == tail => {
+                        if let Call::Enter(next) = tail_call(call_expr, "void", env.clone()) {
+                            return Call::Enter(next);
+                        }
+                    }
                     Expr::Call(call_expr) => {
-                        call_func(call_expr, self.environment.clone());
+                        call_func(call_expr, env.clone());
                     }
-                    Expr::InlineAccess(InlineAccess{ ident, next }) => {
-                        let mut idents = vec![ident];
-                        let mut curr_expr = next;
-                        while let Some(expr) = curr_expr.clone() {
-                            match *expr {
-                                Expr::InlineAccess(InlineAccess{ident, next}) => {
-                                    idents.push(ident);
-                                    curr_expr = next;
-                                }
-                                Expr::Call(call_expr) => {
-                                    let mut module_value = GLOBAL_ENV.read().unwrap().global_stmts.get(&idents[0]).unwrap().clone();
-                                    let mut deleted_func = None;
-                                    let mut inserted_ident = call_expr.get_name();
-                                    //println!("MODULE:{module_value:?}");
-                                    for i in 1..idents.len() {
-                                        let module = if let Value::Module(val) = module_value.read().unwrap().clone() {
-                                            val
-                                        } else { panic!("Module not found") };
-                                        module_value = module.get(&idents[i]).unwrap().clone();
-                                    }
-                                    if let Value::Module(module) = module_value.read().unwrap().clone() {
-                                        if let Some(func_value) = module.get(&inserted_ident) {
-                                            if let Value::Func(func) = func_value.clone().read().unwrap().clone() {
-                                                deleted_func = GLOBAL_ENV.write().unwrap().global_stmts.insert(inserted_ident.clone(), Arc::new(RwLock::new(Value::Func(func))));
-                                            }
-                                        }
-                                    }
-
-                                    //println!("SELF_ENV: {:?}", self.environment);
-                                    call_func(call_expr, self.environment.clone());
-                                    if let Some(value) = deleted_func {
-                                        GLOBAL_ENV.write().unwrap().global_stmts.insert(inserted_ident, value);
-                                    } else {
-                                        GLOBAL_ENV.write().unwrap().global_stmts.remove(&inserted_ident);
-                                    }
-                                    curr_expr = None;
-                                }
-                                _ => panic!("Unhandled expression"),
-                            }
+                    Expr::InlineAccess(inline_access) => {
+                        eval_inline_access(inline_access.clone(), env.clone());
+                    }
+                    // Other expressions are evaluated for their errors and dropped
+                    expr => {
+                        if let Value::Cond(ty, l, r) = eval_expr(expr, env.clone()) {
+                            ty.eval_cond(&l, &r, env.clone());
                         }
                     }
-                    _ => panic!("Unhandled expression"),
                 },
-                Stmt::VarIdent(VarIdent { ident, expr }) => {
-                    let value = eval_expr(expr.clone(), self.environment.clone());
+                Stmt::VarIdent(VarIdent { ident, expr })
+                | Stmt::Global(GlobalIdent { ident, expr, .. }) => {
+                    let value = eval_expr(expr, env.clone());
                     let value = if let Value::Cond(ty, l, r) = value {
-                        Value::Bool(ty.eval_cond(l, r, self.environment.clone()))
+                        Value::Bool(ty.eval_cond(&l, &r, env.clone()))
                     } else {
                         value
                     };
-                    (*self.environment.try_write().unwrap())
-                        .variables
-                        .insert(ident, Arc::new(RwLock::new(value)));
+                    let cell = Shared::new(value);
+                    match target {
+                        Some(slot) => env.borrow_mut().define_slot(slot, cell),
+                        None => env.borrow_mut().define(ident, cell),
+                    }
                 }
                 Stmt::VarAssign(VarAssign { ident, expr }) => {
-                    let value = eval_expr(expr, self.environment.clone());
+                    let value = eval_expr(expr, env.clone());
                     let value = if let Value::Cond(ty, l, r) = value {
-                        Value::Bool(ty.eval_cond(l, r, self.environment.clone()))
+                        Value::Bool(ty.eval_cond(&l, &r, env.clone()))
                     } else {
                         value
                     };
-                    if let Value::RefValue(r) = self
-                        .environment
-                        .try_read()
-                        .unwrap()
-                        .variables
-                        .get(&ident)
-                        .unwrap()
-                        .try_read()
-                        .unwrap()
-                        .clone()
-                    {
-                        *r.try_write().unwrap() = value;
+                    let local = target.and_then(|slot| env.borrow().slot(slot).cloned());
+                    let cell = match local.map(|cell| (cell, false)).or_else(|| lookup_name(ident, env)) {
+                        Some((_, true)) => raise(MorphoError::TypeError(format!("cannot assign to constant {ident}"))),
+                        Some((cell, false)) => cell,
+                        None => not_found(ident),
+                    };
+                    if let Value::RefValue(r) = cell.get() {
+                        r.set(value);
                         continue;
                     }
-                    *self
-                        .environment
-                        .try_read()
-                        .unwrap()
-                        .variables
-                        .get(&ident)
-                        .unwrap()
-                        .try_write()
-                        .unwrap() = value;
+                    cell.set(value);
                 }
                 Stmt::ReturnValue(expr) => {
-                    let value = eval_expr(*expr, self.environment.clone());
-                    if value.clone().into_type() == Value::Type(self.rty.clone()) {
-                        return value;
+                    let value = match expr.as_ref() {
+                        Expr::Call(call_expr) => match tail_call(call_expr, rty, env.clone()) {
+                            Call::Enter(next) => return Call::Enter(next),
+                            Call::Done(value) => value,
+                        },
+                        expr => eval_expr(expr, env.clone()),
+                    };
+                    let value = match value {
+                        Value::Cond(ty, l, r) => Value::Bool(ty.eval_cond(&l, &r, env.clone())),
+                        value => value,
+                    };
+                    if let Some(value) = value.clone().coerce_to(rty) {
+                        return Call::Done(value);
                     }
-                    panic!("Excepted other returning type");
+                    raise(MorphoError::TypeError(format!(
+                        "{ident} should return {rty}, found {}",
+                        value.into_type()
+                    )));
                 }
                 _ => panic!("Unhandled statement"),
             };
         }
-        Value::Void
+        Call::Done(Value::Void)
     }
 
     pub(crate) fn get_ident(&self) -> &str {
-        &self.ident
+        &self.definition.ident
+    }
+
+    pub(crate) fn get_privacy(&self) -> &PrivacyType {
+        &self.definition.privacy
     }
 
     pub(crate) fn get_args(&self) -> &Vec<(String, String)> {
-        &self.args
+        &self.definition.args
+    }
+
+    pub(crate) fn get_rty(&self) -> &str {
+        &self.definition.rty
+    }
+
+    pub(crate) fn get_body(&self) -> &[Stmt] {
+        &self.definition.body
+    }
+
+    pub(crate) fn get_offsets(&self) -> &[usize] {
+        &self.definition.offsets
+    }
+
+    /// The setters below change the definition and are meant for setting the
+    /// program up, a definition still shared with other clones is copied first
+    pub(crate) fn set_offsets(&mut self, offsets: Vec<usize>) {
+        Ptr::make_mut(&mut self.definition).offsets = offsets
+    }
+
+    pub(crate) fn set_body(&mut self, body: Vec<Stmt>) {
+        Ptr::make_mut(&mut self.definition).body = body
+    }
+
+    pub(crate) fn get_scope(&self) -> &Arc<Scope> {
+        &self.definition.scope
+    }
+
+    pub(crate) fn set_scope(&mut self, scope: Arc<Scope>) {
+        Ptr::make_mut(&mut self.definition).scope = scope
+    }
+
+    pub(crate) fn get_module_path(&self) -> &[String] {
+        &self.definition.module_path
+    }
+
+    pub(crate) fn set_module_path(&mut self, module_path: Vec<String>) {
+        Ptr::make_mut(&mut self.definition).module_path = module_path
     }
 
-    pub(crate) fn set_env(&mut self, env: Arc<RwLock<LocalEnvironment>>) {
+    pub(crate) fn set_env(&mut self, env: Shared<LocalEnvironment>) {
         self.environment = env
     }
 }
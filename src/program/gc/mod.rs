@@ -0,0 +1,173 @@
+use crate::program::shared::{try_read, try_write, Lock, Ptr, Shared, Weak};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Data holding `Shared` storage, which may refer back to it through a cycle
+/// that reference counting alone never frees
+pub trait Trace {
+    /// Reports the storage `self` holds a reference to, once for every reference
+    fn trace(&self, tracer: &mut Tracer);
+
+    /// Drops what `self` holds, called on unreachable storage to break its cycles
+    fn clear(&mut self) {}
+}
+
+/// Reference counted allocation the collector walks: a `Shared` storage, or
+/// immutable data referenced from several values like a closure
+pub(crate) trait Node {
+    /// Traces the data, `false` when it is in use and can't be looked at
+    fn trace(&self, tracer: &mut Tracer) -> bool;
+
+    fn clear(&self);
+}
+
+impl<T: Trace> Node for Lock<T> {
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        match try_read(self) {
+            Some(value) => {
+                value.trace(tracer);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn clear(&self) {
+        if let Some(mut value) = try_write(self) {
+            value.clear();
+        }
+    }
+}
+
+/// Allocations the collector has seen so far and the references between them
+pub struct Tracer {
+    nodes: Vec<Ptr<dyn Node>>,
+    index: HashMap<*const (), usize>,
+    edges: Vec<(usize, usize)>,
+    /// Node being traced
+    from: usize,
+}
+
+impl Tracer {
+    pub fn shared<T: Trace + 'static>(&mut self, shared: &Shared<T>) {
+        self.edge(shared.as_ptr(), || shared.node());
+    }
+
+    pub(crate) fn ptr<T: Node + 'static>(&mut self, ptr: &Ptr<T>) {
+        self.edge(Ptr::as_ptr(ptr) as *const (), || ptr.clone());
+    }
+
+    fn edge(&mut self, address: *const (), node: impl FnOnce() -> Ptr<dyn Node>) {
+        let to = self.node(address, node);
+        self.edges.push((self.from, to));
+    }
+
+    fn node(&mut self, address: *const (), node: impl FnOnce() -> Ptr<dyn Node>) -> usize {
+        *self.index.entry(address).or_insert_with(|| {
+            self.nodes.push(node());
+            self.nodes.len() - 1
+        })
+    }
+}
+
+/// Collections run automatically once this many storages were allocated, or
+/// as many as survived the last collection if there were more
+const THRESHOLD: usize = 10_000;
+
+thread_local! {
+    /// Every storage allocated on this thread since the last collection or still alive then
+    static HEAP: RefCell<Vec<Weak<dyn Node>>> = const { RefCell::new(Vec::new()) };
+    static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    static NEXT_COLLECTION: Cell<usize> = const { Cell::new(THRESHOLD) };
+}
+
+static COLLECTIONS: AtomicU64 = AtomicU64::new(0);
+static COLLECTED: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the collector, `collections` and `collected` add up over all
+/// threads, `live` counts the storages of the calling thread
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcStats {
+    pub collections: u64,
+    pub collected: u64,
+    pub live: usize,
+}
+
+impl Display for GcStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "collections: {}, collected: {}, live: {}", self.collections, self.collected, self.live)
+    }
+}
+
+pub fn stats() -> GcStats {
+    GcStats {
+        collections: COLLECTIONS.load(Ordering::Relaxed),
+        collected: COLLECTED.load(Ordering::Relaxed),
+        live: HEAP.with_borrow(|heap| heap.iter().filter(|node| node.strong_count() > 0).count()),
+    }
+}
+
+/// Registers a new storage, collecting once enough were allocated. With the
+/// `sync` feature other threads may be using the values, so only `collect` does
+pub(crate) fn track(node: Weak<dyn Node>) {
+    HEAP.with_borrow_mut(|heap| heap.push(node));
+    let allocated = ALLOCATED.get() + 1;
+    ALLOCATED.set(allocated);
+    if allocated >= NEXT_COLLECTION.get() && !cfg!(feature = "sync") {
+        collect();
+    }
+}
+
+/// Frees the cycles of storage no longer reachable from the program and
+/// returns how many allocations were freed.
+///
+/// Storage referenced more times than the heap accounts for is used from
+/// outside of it, by the interpreter, and is kept with everything it reaches.
+/// The rest is only referenced by itself: it's cleared, which breaks the cycles
+pub fn collect() -> usize {
+    let mut tracer = Tracer { nodes: Vec::new(), index: HashMap::new(), edges: Vec::new(), from: 0 };
+    HEAP.with_borrow_mut(|heap| {
+        heap.retain(|node| node.strong_count() > 0);
+        for node in heap.iter().filter_map(Weak::upgrade) {
+            tracer.node(Ptr::as_ptr(&node) as *const (), || node);
+        }
+    });
+    let mut traced = Vec::new();
+    while traced.len() < tracer.nodes.len() {
+        tracer.from = traced.len();
+        let node = tracer.nodes[tracer.from].clone();
+        traced.push(node.trace(&mut tracer));
+    }
+    let Tracer { nodes, edges, .. } = tracer;
+
+    // The collector holds one reference to every node
+    let mut outside = nodes.iter().map(|node| Ptr::strong_count(node) - 1).collect::<Vec<_>>();
+    let mut children = vec![Vec::new(); nodes.len()];
+    for &(from, to) in &edges {
+        outside[to] -= 1;
+        children[from].push(to);
+    }
+    let mut reachable = vec![false; nodes.len()];
+    let mut stack = (0..nodes.len()).filter(|&i| outside[i] > 0 || !traced[i]).collect::<Vec<_>>();
+    while let Some(i) = stack.pop() {
+        if !reachable[i] {
+            reachable[i] = true;
+            stack.extend(children[i].iter().filter(|&&child| !reachable[child]));
+        }
+    }
+    let mut collected = 0;
+    for (node, _) in nodes.iter().zip(&reachable).filter(|(_, reachable)| !**reachable) {
+        node.clear();
+        collected += 1;
+    }
+    let live = nodes.len() - collected;
+    drop(nodes);
+
+    ALLOCATED.set(0);
+    NEXT_COLLECTION.set(live.max(THRESHOLD));
+    COLLECTIONS.fetch_add(1, Ordering::Relaxed);
+    COLLECTED.fetch_add(collected as u64, Ordering::Relaxed);
+    collected
+}
@@ -0,0 +1,226 @@
+use crate::ast::{Expr, GlobalIdent, Stmt, VarIdent};
+use crate::program::environment::LocalEnvironment;
+use crate::program::evaluating_functions::{
+    eval_expr, find_module, flatten_path, path_item, path_item_is_const, resolve_path,
+};
+use crate::program::value::Value;
+use crate::program::global_env;
+use anyhow::{anyhow, Result};
+use crate::program::shared::Shared;
+
+/// Name read by an initializer, its cell and whether it is a constant
+type Reference = (String, Shared<Value>, bool);
+
+/// Module-level `const` or `let` together with the cell declared for it
+#[derive(Clone, Debug)]
+pub(crate) struct Global {
+    pub(crate) scope: Vec<String>,
+    pub(crate) ident: String,
+    pub(crate) constant: bool,
+    pub(crate) expr: Expr,
+    pub(crate) cell: Shared<Value>,
+}
+
+impl Global {
+    pub(crate) fn name(&self) -> String {
+        self.scope.iter().chain(std::iter::once(&self.ident)).cloned().collect::<Vec<_>>().join("::")
+    }
+}
+
+/// Collects every module-level `const` and `let` in the order they have to be
+/// initialized before `main`.
+///
+/// Globals are initialized after the ones their initializers mention, a cycle
+/// between them is an error. Constants may only use literals, operators and
+/// other constants. Dependencies hidden behind function calls aren't tracked.
+pub(crate) fn order_globals(stmts: &[Stmt]) -> Result<Vec<Global>> {
+    let mut globals = Vec::new();
+    collect(stmts, &[], &mut globals);
+
+    let mut deps = Vec::with_capacity(globals.len());
+    for global in &globals {
+        let mut refs = Vec::new();
+        references(&global.expr, &global.scope, global.constant, &mut refs)
+            .map_err(|msg| anyhow!("{} {}: {msg}", kind(global), global.name()))?;
+        let mut global_deps = Vec::new();
+        for (name, cell, constant) in refs {
+            if global.constant && !constant {
+                return Err(anyhow!("const {} refers to {name}, which is not a constant", global.name()));
+            }
+            if let Some(dep) = globals.iter().position(|global| Shared::ptr_eq(&global.cell, &cell)) {
+                global_deps.push(dep);
+            }
+        }
+        deps.push(global_deps);
+    }
+
+    let mut order = Vec::with_capacity(globals.len());
+    let mut state = vec![State::New; globals.len()];
+    for i in 0..globals.len() {
+        visit(i, &deps, &mut state, &mut order, &mut vec![]).map_err(|cycle| {
+            let names: Vec<_> = cycle.iter().map(|&i| globals[i].name()).collect();
+            anyhow!("Globals depend on each other: {}", names.join(" -> "))
+        })?;
+    }
+
+    let mut globals: Vec<_> = globals.into_iter().map(Some).collect();
+    Ok(order.into_iter().filter_map(|i| globals[i].take()).collect())
+}
+
+/// Evaluates the initializers of globals sorted by `order_globals`
+pub(crate) fn init_globals(globals: &[Global]) {
+    for global in globals {
+        let env = Shared::new(LocalEnvironment::in_module(global.scope.clone()));
+        let value = match eval_expr(&global.expr, env.clone()) {
+            Value::Cond(ty, l, r) => Value::Bool(ty.eval_cond(&l, &r, env)),
+            value => value.deref_value(),
+        };
+        global.cell.set(value);
+    }
+}
+
+fn kind(global: &Global) -> &'static str {
+    if global.constant {
+        "const"
+    } else {
+        "let"
+    }
+}
+
+fn collect(stmts: &[Stmt], scope: &[String], globals: &mut Vec<Global>) {
+    for stmt in stmts {
+        let (ident, constant, expr) = match stmt {
+            Stmt::Global(GlobalIdent { ident, constant, expr, .. }) => (ident, *constant, expr),
+            Stmt::VarIdent(VarIdent { ident, expr }) => (ident, false, expr),
+            Stmt::Module(module) => {
+                let mut path = scope.to_vec();
+                path.push(module.ident.clone());
+                collect(&module.body.stmt, &path, globals);
+                continue;
+            }
+            _ => continue,
+        };
+        let cell = match find_module(scope) {
+            Some(module) => module.get(ident).cloned(),
+            None => global_env().borrow().global_stmts.get(ident).cloned(),
+        };
+        globals.push(Global {
+            scope: scope.to_vec(),
+            ident: ident.clone(),
+            constant,
+            expr: expr.clone(),
+            cell: cell.expect("globals are declared before initialization"),
+        });
+    }
+}
+
+/// Collects the cells `expr` reads by name, with their names and constness
+fn references(
+    expr: &Expr,
+    scope: &[String],
+    constant: bool,
+    refs: &mut Vec<Reference>,
+) -> Result<(), String> {
+    let mut exprs = vec![expr];
+    while let Some(expr) = exprs.pop() {
+        match expr {
+            Expr::Ident(ident) => {
+                let container = find_module(scope).filter(|module| module.get(ident).is_some());
+                match path_item(&container, ident, scope) {
+                    Some(cell) => refs.push((ident.clone(), cell, path_item_is_const(&container, ident))),
+                    None => return Err(format!("{ident} not found")),
+                }
+            }
+            Expr::InlineAccess(inline_access) => {
+                let (idents, terminal) = flatten_path(inline_access.clone());
+                let container = resolve_path(&idents, scope)?;
+                match terminal {
+                    Some(Expr::Ident(ident)) => {
+                        let name = format!("{}::{ident}", idents.join("::"));
+                        match path_item(&container, &ident, scope) {
+                            Some(cell) => refs.push((name, cell, path_item_is_const(&container, &ident))),
+                            None => return Err(format!("{name} not found")),
+                        }
+                    }
+                    Some(Expr::Call(_)) if constant => return Err("constants can't call functions".into()),
+                    Some(Expr::Call(call_expr)) => {
+                        refs.extend(call_args_refs(call_expr.get_args(), scope)?);
+                    }
+                    _ => {}
+                }
+            }
+            Expr::Call(_) | Expr::Func(_) | Expr::AnonFunc(_) | Expr::Ref(_) if constant => {
+                return Err("constants can't call functions or take references".into())
+            }
+            Expr::Call(call_expr) => refs.extend(call_args_refs(call_expr.get_args(), scope)?),
+            Expr::Func(func_ptr) => exprs.extend(func_ptr.args.iter().flatten()),
+            Expr::Ref(expr) | Expr::Not(expr) | Expr::Neg(expr) => exprs.push(expr),
+            Expr::Array(items) | Expr::Concat(items) => exprs.extend(items),
+            Expr::Dictionary(entries) => exprs.extend(entries.iter().flat_map(|(k, v)| [k, v])),
+            Expr::Add(l, r)
+            | Expr::Sub(l, r)
+            | Expr::Mul(l, r)
+            | Expr::Div(l, r)
+            | Expr::Eq(l, r)
+            | Expr::NotEq(l, r)
+            | Expr::Gt(l, r)
+            | Expr::Lt(l, r)
+            | Expr::Ge(l, r)
+            | Expr::Le(l, r)
+            | Expr::Or(l, r)
+            | Expr::And(l, r)
+            | Expr::Xor(l, r)
+            | Expr::Mod(l, r)
+            | Expr::BitAnd(l, r)
+            | Expr::BitOr(l, r)
+            | Expr::Shl(l, r)
+            | Expr::Shr(l, r) => exprs.extend([l.as_ref(), r.as_ref()]),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn call_args_refs(args: Vec<Expr>, scope: &[String]) -> Result<Vec<Reference>, String> {
+    let mut refs = Vec::new();
+    for arg in &args {
+        references(arg, scope, false, &mut refs)?;
+    }
+    Ok(refs)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    New,
+    Visiting,
+    Done,
+}
+
+/// Depth-first topological sort, returns the cycle when one is found
+fn visit(
+    i: usize,
+    deps: &[Vec<usize>],
+    state: &mut [State],
+    order: &mut Vec<usize>,
+    stack: &mut Vec<usize>,
+) -> Result<(), Vec<usize>> {
+    match state[i] {
+        State::Done => return Ok(()),
+        State::Visiting => {
+            let start = stack.iter().position(|&j| j == i).unwrap_or(0);
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(i);
+            return Err(cycle);
+        }
+        State::New => {}
+    }
+    state[i] = State::Visiting;
+    stack.push(i);
+    for &dep in &deps[i] {
+        visit(dep, deps, state, order, stack)?;
+    }
+    stack.pop();
+    state[i] = State::Done;
+    order.push(i);
+    Ok(())
+}
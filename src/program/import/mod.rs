@@ -0,0 +1,246 @@
+use crate::ast::{Import, Stmt, UseKind};
+use crate::program::error::MorphoError;
+use crate::program::value::Value;
+use crate::program::global_env;
+use std::collections::{HashMap, HashSet};
+use crate::program::shared::Shared;
+
+type Item = Shared<Value>;
+
+/// Imported item and whether it is a constant
+type Binding = (Item, bool);
+
+/// Module a path is walked through, `None` being the crate root
+type Container = Option<(String, Item)>;
+
+/// Resolves the `use` declarations of the program root and of every module.
+///
+/// Names are bound in the scope that declares them: the root imports go to
+/// `GLOBAL_ENV`, module imports become private items of their module. Paths
+/// are absolute unless they start with `self` or `super`, `crate` names the
+/// root explicitly.
+pub(crate) fn resolve_imports(stmts: &[Stmt]) -> Result<(), MorphoError> {
+    resolve_scope(stmts, &[])
+}
+
+fn resolve_scope(stmts: &[Stmt], scope: &[String]) -> Result<(), MorphoError> {
+    let defined: HashSet<&str> = stmts
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::FuncIdent(func) if func.stmt.is_some() => Some(func.ident.as_str()),
+            Stmt::Module(module) => Some(module.ident.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    let mut bindings = Bindings::new(scope);
+    for stmt in stmts {
+        if let Stmt::Import(Import { tree }) = stmt {
+            let (container, path) = start(&tree.path, scope)?;
+            bindings.expand(path, &tree.kind, container)?;
+        }
+    }
+    if let Some(ident) = bindings.explicit.keys().find(|ident| defined.contains(ident.as_str())) {
+        return Err(MorphoError::ImportError(format!(
+            "{ident} is imported into {} where it is already defined",
+            scope_name(scope)
+        )));
+    }
+
+    // Explicit imports and local definitions shadow glob imports
+    let Bindings { explicit, globbed, .. } = bindings;
+    let globbed: Vec<_> = globbed
+        .into_iter()
+        .filter(|(ident, _)| !defined.contains(ident.as_str()) && !explicit.contains_key(ident))
+        .collect();
+    for (ident, (item, constant)) in globbed.into_iter().chain(explicit) {
+        bind(scope, &ident, item, constant);
+    }
+
+    for stmt in stmts {
+        if let Stmt::Module(module) = stmt {
+            let mut path = scope.to_vec();
+            path.push(module.ident.clone());
+            resolve_scope(&module.body.stmt, &path)?;
+        }
+    }
+    Ok(())
+}
+
+struct Bindings<'a> {
+    scope: &'a [String],
+    explicit: HashMap<String, Binding>,
+    globbed: HashMap<String, Binding>,
+}
+
+impl<'a> Bindings<'a> {
+    fn new(scope: &'a [String]) -> Self {
+        Self {
+            scope,
+            explicit: HashMap::new(),
+            globbed: HashMap::new(),
+        }
+    }
+
+    fn expand(&mut self, path: &[String], kind: &UseKind, container: Container) -> Result<(), MorphoError> {
+        match kind {
+            UseKind::Simple(alias) => {
+                let Some((last, path)) = path.split_last() else {
+                    return Err(self.error("use declaration imports nothing".to_string()));
+                };
+                let container = self.walk(path, container)?;
+                let item = self.child(&container, last)?;
+                self.bind_explicit(alias.clone().unwrap_or_else(|| last.clone()), item)
+            }
+            UseKind::Glob => {
+                let container = self.walk(path, container)?;
+                for (ident, item, constant) in self.items(&container)? {
+                    match self.globbed.get(&ident) {
+                        Some((bound, _)) if !Shared::ptr_eq(bound, &item) => {
+                            return Err(self.error(format!("{ident} is imported by several globs")));
+                        }
+                        _ => {
+                            self.globbed.insert(ident, (item, constant));
+                        }
+                    }
+                }
+                Ok(())
+            }
+            UseKind::Group(trees) => {
+                let container = self.walk(path, container)?;
+                for tree in trees {
+                    match (tree.path.as_slice(), &tree.kind) {
+                        ([ident], UseKind::Simple(alias)) if ident == "self" => {
+                            let Some((name, item)) = container.clone() else {
+                                return Err(self.error("self can't be imported from the crate root".to_string()));
+                            };
+                            self.bind_explicit(alias.clone().unwrap_or(name), (item, false))?;
+                        }
+                        _ => self.expand(&tree.path, &tree.kind, container.clone())?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn bind_explicit(&mut self, ident: String, item: Binding) -> Result<(), MorphoError> {
+        if self.explicit.contains_key(&ident) {
+            return Err(self.error(format!("{ident} is imported more than once")));
+        }
+        self.explicit.insert(ident, item);
+        Ok(())
+    }
+
+    fn walk(&self, path: &[String], mut container: Container) -> Result<Container, MorphoError> {
+        for ident in path {
+            container = Some((ident.clone(), self.child(&container, ident)?.0));
+        }
+        Ok(container)
+    }
+
+    fn child(&self, container: &Container, ident: &str) -> Result<Binding, MorphoError> {
+        match container {
+            None => {
+                let global_env = global_env();
+                let global = global_env.borrow();
+                global
+                    .global_stmts
+                    .get(ident)
+                    .map(|item| (item.clone(), global.consts.contains(ident)))
+                    .ok_or_else(|| MorphoError::ImportError(format!("{ident} not found in the crate root")))
+            }
+            Some((name, item)) => match &*item.borrow() {
+                Value::Module(module) => module
+                    .lookup(ident, self.scope)?
+                    .map(|item| (item.clone(), module.is_const(ident)))
+                    .ok_or_else(|| {
+                        MorphoError::ImportError(format!("{ident} not found in module {}", module.get_path().join("::")))
+                    }),
+                _ => Err(MorphoError::ImportError(format!("{name} is not a module"))),
+            },
+        }
+    }
+
+    fn items(&self, container: &Container) -> Result<Vec<(String, Item, bool)>, MorphoError> {
+        match container {
+            None => {
+                let global_env = global_env();
+                let global = global_env.borrow();
+                Ok(global
+                    .global_stmts
+                    .iter()
+                    .map(|(ident, item)| (ident.clone(), item.clone(), global.consts.contains(ident)))
+                    .collect())
+            }
+            Some((name, item)) => match &*item.borrow() {
+                Value::Module(module) => Ok(module.visible_items(self.scope)),
+                _ => Err(MorphoError::ImportError(format!("{name} is not a module"))),
+            },
+        }
+    }
+
+    fn error(&self, msg: String) -> MorphoError {
+        MorphoError::ImportError(format!("{msg} in {}", scope_name(self.scope)))
+    }
+}
+
+/// Splits the leading `crate`, `self` and `super` segments off `path`
+fn start<'p>(path: &'p [String], scope: &[String]) -> Result<(Container, &'p [String]), MorphoError> {
+    let mut base = Vec::new();
+    let mut rest = path;
+    match path.first().map(String::as_str) {
+        Some("crate") => rest = &path[1..],
+        Some("self") => {
+            base = scope.to_vec();
+            rest = &path[1..];
+        }
+        Some("super") => {
+            base = scope.to_vec();
+            while let Some(("super", tail)) = rest.split_first().map(|(first, tail)| (first.as_str(), tail)) {
+                if base.pop().is_none() {
+                    return Err(MorphoError::ImportError(format!(
+                        "super goes beyond the crate root in {}",
+                        scope_name(scope)
+                    )));
+                }
+                rest = tail;
+            }
+        }
+        _ => {}
+    }
+    let container = Bindings::new(scope).walk(&base, None)?;
+    Ok((container, rest))
+}
+
+fn bind(scope: &[String], ident: &str, item: Item, constant: bool) {
+    let Some((first, rest)) = scope.split_first() else {
+        let global_env = global_env();
+        let mut global = global_env.borrow_mut();
+        if constant {
+            global.consts.insert(ident.to_string());
+        }
+        global.insert(ident, item);
+        return;
+    };
+    let mut module_value = global_env().borrow().global_stmts.get(first).unwrap().clone();
+    for segment in rest {
+        let next = match &*module_value.borrow() {
+            Value::Module(module) => module.get(segment).unwrap().clone(),
+            _ => unreachable!("scopes are module paths"),
+        };
+        module_value = next;
+    }
+    let mut value = module_value.borrow_mut();
+    if let Value::Module(module) = &mut *value {
+        module.import(ident, item, constant);
+    }
+}
+
+fn scope_name(scope: &[String]) -> String {
+    if scope.is_empty() {
+        "the crate root".to_string()
+    } else {
+        format!("module {}", scope.join("::"))
+    }
+}
@@ -0,0 +1,214 @@
+use crate::ast::{Body, Module, PrivacyType, Prog, Stmt};
+use crate::package::Project;
+use crate::parser::ProgParser;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const SOURCE_EXTENSION: &str = "mo";
+
+/// Loads multi-file programs.
+///
+/// `mod name;` declared in a module whose directory is `dir` is read from
+/// `dir/name.mo` or `dir/name/mod.mo`, and its own submodules live in
+/// `dir/name/`. The declaration is replaced with an inline `mod name { ... }`,
+/// so the evaluator works with a single `Prog`.
+#[derive(Debug, Default)]
+pub struct ModuleLoader {
+    cache: HashMap<(PathBuf, PathBuf), Vec<Stmt>>,
+    loading: Vec<PathBuf>,
+    sources: SourceMap,
+}
+
+/// Source file a module was read from, with the offsets its lines start at
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    pub module_path: Vec<String>,
+    pub path: PathBuf,
+    line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+    fn new(module_path: Vec<String>, path: PathBuf, source: &str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(source.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        Self { module_path, path, line_starts }
+    }
+
+    /// 1-based line of the byte at `offset`
+    pub fn line(&self, offset: usize) -> u32 {
+        self.line_starts.partition_point(|&start| start <= offset) as u32
+    }
+}
+
+/// Files read by a `ModuleLoader`, used to map statement offsets back to lines
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    /// File the module at `module_path` is written in. Inline modules live in
+    /// the file of their closest file module
+    pub fn file(&self, module_path: &[String]) -> Option<&SourceFile> {
+        self.files
+            .iter()
+            .filter(|file| module_path.starts_with(&file.module_path))
+            .max_by_key(|file| file.module_path.len())
+    }
+}
+
+impl ModuleLoader {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses `entry` and every file module reachable from it
+    pub fn load(&mut self, entry: &Path) -> Result<Prog> {
+        self.load_module(entry, Vec::new())
+    }
+
+    /// Files read so far, keyed by the module they define
+    pub fn sources(&self) -> &SourceMap {
+        &self.sources
+    }
+
+    fn load_module(&mut self, entry: &Path, module_path: Vec<String>) -> Result<Prog> {
+        let entry = canonicalize(entry)?;
+        let dir = entry.parent().map(Path::to_path_buf).unwrap_or_default();
+        Ok(Prog(self.load_file(&entry, &dir, module_path)?))
+    }
+
+    /// Loads the entry file of `project` and adds every resolved package as a
+    /// public root module named after it, so `use dep::thing;` finds it
+    pub fn load_project(&mut self, project: &Project) -> Result<Prog> {
+        let Prog(stmts) = self.load(&project.entry())?;
+        let mut packages = Vec::new();
+        for (name, root) in project.package_roots() {
+            let defined = stmts.iter().any(|stmt| match stmt {
+                Stmt::Module(module) => module.ident == name,
+                Stmt::ModuleFile(_, ident) => *ident == name,
+                _ => false,
+            });
+            if defined {
+                return Err(anyhow!("Module {name} of {} conflicts with the package {name}", project.manifest.package.name));
+            }
+            let Prog(package) = self.load_module(&root, vec![name.clone()]).with_context(|| format!("Failed to load package {name}"))?;
+            packages.push(Stmt::Module(Module::new(PrivacyType::Public, name, Body::new(package))));
+        }
+        packages.extend(stmts);
+        Ok(Prog(packages))
+    }
+
+    fn load_file(&mut self, path: &Path, module_dir: &Path, module_path: Vec<String>) -> Result<Vec<Stmt>> {
+        let path = canonicalize(path)?;
+        if let Some(pos) = self.loading.iter().position(|loading| *loading == path) {
+            let chain = self.loading[pos..]
+                .iter()
+                .chain(std::iter::once(&path))
+                .map(|path| path.display().to_string())
+                .collect::<Vec<_>>()
+                .join(" -> ");
+            return Err(anyhow!("Cyclic module dependency: {chain}"));
+        }
+        let key = (path.clone(), module_dir.to_path_buf());
+        if let Some(stmts) = self.cache.get(&key) {
+            if let Some(file) = self.sources.files.iter().find(|file| file.path == path).cloned() {
+                self.sources.files.push(SourceFile { module_path, ..file });
+            }
+            return Ok(stmts.clone());
+        }
+
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read module file {}", path.display()))?;
+        let prog = ProgParser::new()
+            .parse(&source)
+            .map_err(|e| anyhow!("{}: {e}", path.display()))?;
+
+        self.sources.files.push(SourceFile::new(module_path.clone(), path.clone(), &source));
+        self.loading.push(path);
+        let stmts = self.resolve_stmts(prog.0, module_dir, &module_path);
+        self.loading.pop();
+
+        let stmts = stmts?;
+        self.cache.insert(key, stmts.clone());
+        Ok(stmts)
+    }
+
+    fn resolve_stmts(&mut self, stmts: Vec<Stmt>, dir: &Path, module_path: &[String]) -> Result<Vec<Stmt>> {
+        stmts
+            .into_iter()
+            .map(|stmt| match stmt {
+                Stmt::ModuleFile(privacy_type, ident) => {
+                    let file = find_module_file(dir, &ident)?;
+                    let path = [module_path, std::slice::from_ref(&ident)].concat();
+                    let stmts = self.load_file(&file, &dir.join(&ident), path)?;
+                    Ok(Stmt::Module(Module::new(privacy_type, ident, Body::new(stmts))))
+                }
+                Stmt::Module(Module { privacy_type, ident, body }) => {
+                    let path = [module_path, std::slice::from_ref(&ident)].concat();
+                    let stmts = self.resolve_stmts(body.stmt, &dir.join(&ident), &path)?;
+                    let body = Body { stmt: stmts, ..body };
+                    Ok(Stmt::Module(Module::new(privacy_type, ident, body)))
+                }
+                stmt => Ok(stmt),
+            })
+            .collect()
+    }
+}
+
+/// Finds a `mod name;` declaration the loader hasn't replaced yet
+pub(crate) fn unresolved_module(stmts: &[Stmt]) -> Option<&str> {
+    stmts.iter().find_map(|stmt| match stmt {
+        Stmt::ModuleFile(_, ident) => Some(ident.as_str()),
+        Stmt::Module(module) => unresolved_module(&module.body.stmt),
+        _ => None,
+    })
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize()
+        .with_context(|| format!("Module file {} not found", path.display()))
+}
+
+fn find_module_file(dir: &Path, ident: &str) -> Result<PathBuf> {
+    let file = dir.join(format!("{ident}.{SOURCE_EXTENSION}"));
+    let mod_file = dir.join(ident).join(format!("mod.{SOURCE_EXTENSION}"));
+    match (file.is_file(), mod_file.is_file()) {
+        (true, false) => Ok(file),
+        (false, true) => Ok(mod_file),
+        (true, true) => Err(anyhow!(
+            "Module {ident} is ambiguous: both {} and {} exist",
+            file.display(),
+            mod_file.display()
+        )),
+        (false, false) => Err(anyhow!(
+            "Module {ident} not found, expected {} or {}",
+            file.display(),
+            mod_file.display()
+        )),
+    }
+}
+
+/// Resolves what to run for `path`: a source file is used as is, a project
+/// directory runs `main.mo` or `src/main.mo`
+pub fn find_entry(path: &Path) -> Result<PathBuf> {
+    if path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+    if path.is_dir() {
+        let main_file = format!("main.{SOURCE_EXTENSION}");
+        for candidate in [path.join(&main_file), path.join("src").join(&main_file)] {
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+        }
+        return Err(anyhow!(
+            "Entry file {main_file} not found in project {}",
+            path.display()
+        ));
+    }
+    Err(anyhow!("{} not found", path.display()))
+}
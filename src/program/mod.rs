@@ -1,74 +1,184 @@
+mod call_stack;
+pub mod core_lib;
 pub mod environment;
+pub mod error;
 pub mod evaluating_functions;
 pub mod function;
+pub mod gc;
+mod globals;
+mod import;
+pub mod loader;
+pub mod optimizer;
 pub mod primitive_functions;
+mod resolver;
+pub mod sandbox;
+pub mod shared;
 pub mod value;
+pub mod vm;
 mod module;
 
-use crate::ast::{Prog};
-use crate::program::evaluating_functions::{extract_func, extract_import, extract_module};
+use crate::ast::{Prog, Stmt};
+use crate::program::environment::Environment;
+use crate::program::evaluating_functions::{extract_func, extract_module};
 use crate::program::function::Function;
-use crate::program::primitive_functions::{for_func, if_func, input_func, print_func, while_func};
+use crate::program::loader::SourceMap;
+use crate::program::optimizer::OptLevel;
+use crate::program::primitive_functions::{bigint_func, float_func, for_func, gc_func, gc_stats_func, if_func, input_func, int_func, print_func, while_func};
 use crate::program::value::Value;
-use crate::GLOBAL_ENV;
+use crate::program::vm::compiler::Compiler;
+use crate::program::vm::Vm;
+use crate::program::shared::Shared;
 use anyhow::{Error, Result};
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::RwLock;
 use crate::program::module::Module;
 
+/// Engine running `main` once the program is set up
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Backend {
+    /// Evaluates the AST directly
+    #[default]
+    Tree,
+    /// Compiles the program to bytecode and runs it on the stack VM
+    Vm,
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tree" => Ok(Backend::Tree),
+            "vm" => Ok(Backend::Vm),
+            _ => Err(Error::msg(format!("Unknown backend {s}, expected tree or vm"))),
+        }
+    }
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Backend::Tree => write!(f, "tree"),
+            Backend::Vm => write!(f, "vm"),
+        }
+    }
+}
+
+static BACKEND: RwLock<Backend> = RwLock::new(Backend::Tree);
+
+/// Selects the backend used by `eval_program` and the functions built on it
+pub fn set_backend(backend: Backend) {
+    *BACKEND.write().unwrap() = backend;
+}
+
+pub fn backend() -> Backend {
+    *BACKEND.read().unwrap()
+}
+
+static OPT_LEVEL: RwLock<OptLevel> = RwLock::new(OptLevel::O0);
+
+/// Selects how much programs are optimized before they are run or compiled
+pub fn set_opt_level(level: OptLevel) {
+    *OPT_LEVEL.write().unwrap() = level;
+}
+
+pub fn opt_level() -> OptLevel {
+    *OPT_LEVEL.read().unwrap()
+}
+
+/// Call depth allowed unless set with `set_max_call_depth`
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+static MAX_CALL_DEPTH: RwLock<usize> = RwLock::new(DEFAULT_MAX_CALL_DEPTH);
+
+/// Sets how deep calls may nest before the program fails with `StackOverflow`.
+/// Tail calls don't count
+pub fn set_max_call_depth(depth: usize) {
+    *MAX_CALL_DEPTH.write().unwrap() = depth;
+}
+
+pub fn max_call_depth() -> usize {
+    *MAX_CALL_DEPTH.read().unwrap()
+}
+
+#[cfg(feature = "jit")]
+static JIT: RwLock<bool> = RwLock::new(true);
+
+/// Enables compiling hot functions of the VM to native code, on by default
+#[cfg(feature = "jit")]
+pub fn set_jit(enabled: bool) {
+    *JIT.write().unwrap() = enabled;
+}
+
+#[cfg(feature = "jit")]
+pub fn jit() -> bool {
+    *JIT.read().unwrap()
+}
+
+thread_local! {
+    /// Items of the program set up last on this thread, by `Program::new`
+    static GLOBAL_ENV: Shared<Environment> = Shared::new(Environment::new());
+}
+
+/// Global environment of this thread
+pub fn global_env() -> Shared<Environment> {
+    GLOBAL_ENV.with(Shared::clone)
+}
+
+/// Functions and core modules every program starts with
+pub(crate) fn builtins() -> Vec<(&'static str, Value)> {
+    vec![
+        ("print", Value::FuncPtr(print_func)),
+        ("if", Value::FuncPtr(if_func)),
+        ("for", Value::FuncPtr(for_func)),
+        ("while", Value::FuncPtr(while_func)),
+        ("input", Value::FuncPtr(input_func)),
+        ("int", Value::FuncPtr(int_func)),
+        ("float", Value::FuncPtr(float_func)),
+        ("bigint", Value::FuncPtr(bigint_func)),
+        ("gc", Value::FuncPtr(gc_func)),
+        ("gc_stats", Value::FuncPtr(gc_stats_func)),
+        ("string", Value::Module(core_lib::string::module())),
+        ("math", Value::Module(core_lib::math::module())),
+    ]
+}
+
 struct Program {
     main_function: Function,
+    /// Module-level globals in initialization order, evaluated by the backend before `main`
+    globals: Vec<globals::Global>,
 }
 
 impl Program {
     pub fn new(prog: Prog) -> Result<Self> {
-        GLOBAL_ENV
-            .try_write()
-            .unwrap()
-            .insert_stmt("print", Value::FuncPtr(print_func));
-        GLOBAL_ENV
-            .try_write()
-            .unwrap()
-            .insert_stmt("if".into(), Value::FuncPtr(if_func));
-        GLOBAL_ENV
-            .try_write()
-            .unwrap()
-            .insert_stmt("for".into(), Value::FuncPtr(for_func));
-        GLOBAL_ENV
-            .try_write()
-            .unwrap()
-            .insert_stmt("while".into(), Value::FuncPtr(while_func));
-        GLOBAL_ENV
-            .try_write()
-            .unwrap()
-            .insert_stmt("input".into(), Value::FuncPtr(input_func));
+        if let Some(ident) = loader::unresolved_module(&prog.0) {
+            return Err(Error::msg(format!(
+                "Module {ident} is declared in another file, load the program with eval_file"
+            )));
+        }
+        let prog = optimizer::optimize(prog, opt_level());
+
+        let global_env = global_env();
+        global_env.set(Environment::new());
+        for (ident, value) in builtins() {
+            global_env.borrow_mut().insert_stmt(ident, value);
+        }
 
         let mut extracted_modules:  HashMap<String, Module> = HashMap::new();
 
         for stmt in &prog.0 {
-            if let Some((ident, module)) = extract_module(stmt) {
+            if let Some((ident, _, module)) = extract_module(stmt, &[]) {
                 extracted_modules.insert(ident, module);
             }
         }
 
         for (ident, module) in extracted_modules {
-            GLOBAL_ENV
-                .try_write()
-                .unwrap()
-                .insert_stmt(&ident, Value::Module(module));
+            global_env.borrow_mut().insert_stmt(&ident, Value::Module(module));
         }
 
 
-        for stmt in &prog.0 {
-            if let Some((ident, value)) = extract_import(stmt) {
-                GLOBAL_ENV
-                    .try_write()
-                    .unwrap()
-                    .insert(&ident, value);
-            }
-        }
-
-
-
         let mut extracted_functions: HashMap<String, Function> = HashMap::new();
 
         for stmt in &prog.0 {
@@ -78,23 +188,45 @@ impl Program {
         }
 
         for (ident, func) in extracted_functions {
-            GLOBAL_ENV
-                .try_write()
-                .unwrap()
-                .insert_stmt(&ident, Value::Func(func));
+            global_env.borrow_mut().insert_stmt(&ident, Value::Func(func));
         }
 
-        if let Some(main_func) = GLOBAL_ENV.try_read().unwrap().global_stmts.get("main") {
-            if let Value::Func(main_func) = main_func.clone().try_read().unwrap().clone() {
-                return Ok(Self {
-                    main_function: main_func.clone(),
-                });
+        for stmt in &prog.0 {
+            let (ident, constant) = match stmt {
+                Stmt::Global(global) => (&global.ident, global.constant),
+                Stmt::VarIdent(var) => (&var.ident, false),
+                _ => continue,
+            };
+            let mut global = global_env.borrow_mut();
+            if constant {
+                global.consts.insert(ident.clone());
             }
+            global.insert_stmt(ident, Value::Void);
+        }
+
+        import::resolve_imports(&prog.0)?;
+        let globals = globals::order_globals(&prog.0)?;
+        resolver::resolve_program();
+
+        let main_func = global_env.borrow().global_stmts.get("main").map(Shared::get);
+        if let Some(Value::Func(main_function)) = main_func {
+            return Ok(Self { main_function, globals });
         }
         Err(Error::msg("Main function not found"))
     }
-    pub fn run(mut self) -> Result<()> {
-        self.main_function.run();
+    pub fn run(self) -> Result<()> {
+        match backend() {
+            Backend::Tree => {
+                sandbox::start();
+                globals::init_globals(&self.globals);
+                self.main_function.run();
+            }
+            Backend::Vm => {
+                let bytecode = Compiler::compile(&self.main_function, &self.globals, SourceMap::default())?;
+                sandbox::start();
+                Vm::new(&bytecode).run();
+            }
+        }
         Ok(())
     }
 }
@@ -1,21 +1,98 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use crate::program::shared::Shared;
+use crate::ast::PrivacyType;
+use crate::program::error::MorphoError;
+use crate::program::gc::{Trace, Tracer};
 use crate::program::value::Value;
 
 #[derive(Clone, Debug)]
 pub struct Module {
     ident: String,
-    stmts: HashMap<String,Arc<RwLock<Value>>>
+    path: Vec<String>,
+    stmts: HashMap<String,Shared<Value>>,
+    private: HashSet<String>,
+    consts: HashSet<String>,
 }
 
 impl Module {
     pub fn new(ident: &str) -> Self {
-        Self { ident: ident.to_string(), stmts: Default::default() }
+        Self::with_path(vec![ident.to_string()])
+    }
+    /// Creates a module nested at `path`, the last segment being its ident
+    pub fn with_path(path: Vec<String>) -> Self {
+        Self {
+            ident: path.last().cloned().unwrap_or_default(),
+            path,
+            stmts: Default::default(),
+            private: Default::default(),
+            consts: Default::default(),
+        }
     }
     pub fn insert(&mut self, ident: &str, stmt: Value) {
-        self.stmts.insert(ident.into(), Arc::new(RwLock::new(stmt)));
+        self.stmts.insert(ident.into(), Shared::new(stmt));
+    }
+    pub fn insert_with_privacy(&mut self, ident: &str, stmt: Value, privacy: &PrivacyType) {
+        if *privacy == PrivacyType::Private {
+            self.private.insert(ident.into());
+        }
+        self.insert(ident, stmt);
+    }
+    pub fn insert_const(&mut self, ident: &str, stmt: Value) {
+        self.consts.insert(ident.into());
+        self.insert(ident, stmt);
+    }
+    /// Declares a module-level `const` or `let`, its value is set when globals are initialized
+    pub fn declare_global(&mut self, ident: &str, privacy: &PrivacyType, constant: bool) {
+        if constant {
+            self.consts.insert(ident.into());
+        }
+        self.insert_with_privacy(ident, Value::Void, privacy);
+    }
+    /// Binds an item imported with `use`, imports are private to the module
+    pub fn import(&mut self, ident: &str, stmt: Shared<Value>, constant: bool) {
+        self.private.insert(ident.into());
+        if constant {
+            self.consts.insert(ident.into());
+        }
+        self.stmts.insert(ident.into(), stmt);
+    }
+    pub fn is_const(&self, ident: &str) -> bool {
+        self.consts.contains(ident)
+    }
+    pub fn get_ident(&self) -> &str {
+        &self.ident
     }
-    pub fn get(&self, ident: &str) -> Option<&Arc<RwLock<Value>>> {
+    pub fn get_path(&self) -> &[String] {
+        &self.path
+    }
+    /// Looks an item up ignoring its visibility
+    pub fn get(&self, ident: &str) -> Option<&Shared<Value>> {
         self.stmts.get(ident)
     }
-}
\ No newline at end of file
+    /// Items that code in module `from` may use
+    pub fn visible_items(&self, from: &[String]) -> Vec<(String, Shared<Value>, bool)> {
+        let inside = from.starts_with(&self.path);
+        self.stmts
+            .iter()
+            .filter(|(ident, _)| inside || !self.private.contains(*ident))
+            .map(|(ident, stmt)| (ident.clone(), stmt.clone(), self.is_const(ident)))
+            .collect()
+    }
+    /// Looks an item up on behalf of code in module `from`. Private items are
+    /// visible only inside this module and its children
+    pub fn lookup(&self, ident: &str, from: &[String]) -> Result<Option<&Shared<Value>>, MorphoError> {
+        if self.private.contains(ident) && !from.starts_with(&self.path) {
+            return Err(MorphoError::PrivacyError {
+                item: ident.to_string(),
+                module: self.path.join("::"),
+            });
+        }
+        Ok(self.stmts.get(ident))
+    }
+}
+
+impl Trace for Module {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.stmts.values().for_each(|stmt| tracer.shared(stmt));
+    }
+}
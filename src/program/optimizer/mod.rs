@@ -0,0 +1,810 @@
+use crate::ast::{AnonymousFunc, Body, CallExpr, Expr, FuncIdent, FuncPtr, InlineAccess, Prog, Stmt, UseKind, UseTree};
+use crate::program::error::catch;
+use crate::program::value::Value;
+use anyhow::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Rem, Shl, Shr, Sub};
+use std::str::FromStr;
+
+/// Bodies of inlined functions are limited to this many expression nodes
+const INLINE_LIMIT: usize = 12;
+
+/// How much `optimize` rewrites the program before it is run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OptLevel {
+    /// Runs the program as written
+    #[default]
+    O0,
+    /// Folds constant expressions and removes dead `if` branches
+    O1,
+    /// Also inlines small functions and removes functions that are never used
+    O2,
+}
+
+impl FromStr for OptLevel {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "0" => Ok(OptLevel::O0),
+            "1" => Ok(OptLevel::O1),
+            "2" => Ok(OptLevel::O2),
+            _ => Err(Error::msg(format!("Unknown optimization level {s}, expected 0, 1 or 2"))),
+        }
+    }
+}
+
+impl Display for OptLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptLevel::O0 => write!(f, "0"),
+            OptLevel::O1 => write!(f, "1"),
+            OptLevel::O2 => write!(f, "2"),
+        }
+    }
+}
+
+/// Rewrites `prog` into a program printing the same output and raising the
+/// same errors.
+///
+/// Expressions are only folded when they evaluate without an error, anything
+/// that can fail or has an effect is left for the backend. Comparisons are lazy
+/// conditions at run time and are only folded in the condition of `if`, where
+/// a condition and a bool behave the same
+pub fn optimize(mut prog: Prog, level: OptLevel) -> Prog {
+    if level == OptLevel::O0 {
+        return prog;
+    }
+    let optimizer = Optimizer::new(&prog);
+    optimizer.fold_stmts(&mut prog.0);
+    optimizer.prune_stmts(&mut prog.0);
+    if level >= OptLevel::O2 {
+        Inliner::new(&prog).stmts(&mut prog.0, &[]);
+        optimizer.fold_stmts(&mut prog.0);
+        optimizer.prune_stmts(&mut prog.0);
+        remove_unused(&mut prog);
+    }
+    prog
+}
+
+struct Optimizer {
+    /// `if` is the built-in one, no item of the program shadows it
+    builtin_if: bool,
+}
+
+impl Optimizer {
+    fn new(prog: &Prog) -> Self {
+        let mut items = HashSet::new();
+        prog.0.iter().for_each(|stmt| collect_items(stmt, &mut items));
+        Self {
+            builtin_if: !items.contains("if"),
+        }
+    }
+
+    fn fold_stmts(&self, stmts: &mut [Stmt]) {
+        for stmt in stmts {
+            stmt_exprs(stmt).into_iter().for_each(|expr| self.fold(expr));
+            stmt_bodies(stmt).into_iter().for_each(|body| self.fold_stmts(&mut body.stmt));
+        }
+    }
+
+    fn fold(&self, expr: &mut Expr) {
+        children(expr).into_iter().for_each(|child| self.fold(child));
+        if let Expr::AnonFunc(AnonymousFunc { stmt: Some(body), .. }) = expr {
+            self.fold_stmts(&mut body.stmt);
+        }
+        if let Expr::Call(call_expr) = expr {
+            if self.is_if(call_expr) {
+                fold_cond(&mut call_expr.args_mut()[0]);
+                if let Some(branch) = select_branch(call_expr) {
+                    *expr = branch;
+                }
+            }
+            return;
+        }
+        if let Some(folded) = fold_literals(expr) {
+            *expr = folded;
+        }
+    }
+
+    fn is_if(&self, call_expr: &CallExpr) -> bool {
+        self.builtin_if && call_expr.get_name() == "if" && !call_expr.get_args().is_empty()
+    }
+
+    /// Removes `if(false, ...)` statements without an else branch and moves the
+    /// statements of a taken `if(true, $|| { ... })` into the enclosing function
+    fn prune_stmts(&self, stmts: &mut [Stmt]) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::FuncIdent(FuncIdent { args, stmt: Some(body), .. }) => {
+                    let params = args.iter().map(|(ident, _)| ident.clone()).collect::<Vec<_>>();
+                    self.prune_body(body, &params);
+                }
+                Stmt::Module(module) => self.prune_stmts(&mut module.body.stmt),
+                _ => {}
+            }
+        }
+    }
+
+    fn prune_body(&self, body: &mut Body, params: &[String]) {
+        for stmt in &mut body.stmt {
+            for expr in stmt_exprs(stmt) {
+                self.prune_anon_funcs(expr);
+            }
+        }
+        if !self.builtin_if {
+            return;
+        }
+        let located = body.offsets.len() == body.stmt.len();
+        let mut i = 0;
+        while i < body.stmt.len() {
+            let Stmt::Expr(expr) = &body.stmt[i] else {
+                i += 1;
+                continue;
+            };
+            let Expr::Call(call_expr) = expr.as_ref() else {
+                i += 1;
+                continue;
+            };
+            if !self.is_if(call_expr) {
+                i += 1;
+                continue;
+            }
+            match call_expr.get_args().as_slice() {
+                [Expr::Bool(false), branch] if is_inert(branch) => {
+                    body.stmt.remove(i);
+                    if located {
+                        body.offsets.remove(i);
+                    }
+                }
+                [Expr::Bool(true), Expr::AnonFunc(anon_func)] if can_splice(anon_func, &body.stmt, i, params) => {
+                    let inner = anon_func.stmt.clone().unwrap();
+                    let count = inner.stmt.len();
+                    if located {
+                        let offsets = match inner.offsets.len() == count {
+                            true => inner.offsets,
+                            false => vec![body.offsets[i]; count],
+                        };
+                        body.offsets.splice(i..=i, offsets);
+                    }
+                    body.stmt.splice(i..=i, inner.stmt);
+                    i += count;
+                }
+                _ => i += 1,
+            }
+        }
+    }
+
+    fn prune_anon_funcs(&self, expr: &mut Expr) {
+        children(expr).into_iter().for_each(|child| self.prune_anon_funcs(child));
+        if let Expr::AnonFunc(AnonymousFunc { args, stmt: Some(body), .. }) = expr {
+            let params = args.iter().map(|(ident, _)| ident.clone()).collect::<Vec<_>>();
+            self.prune_body(body, &params);
+        }
+    }
+}
+
+/// Replaces an `if` with a literal condition by the call of the taken branch.
+/// The branches have to be callable and free of effects, `if` evaluates both
+/// of them before it picks one
+fn select_branch(call_expr: &CallExpr) -> Option<Expr> {
+    let args = call_expr.get_args();
+    if !(2..=3).contains(&args.len()) || !args[1..].iter().all(is_inert) {
+        return None;
+    }
+    let Expr::Bool(cond) = args[0] else {
+        return None;
+    };
+    let taken = match cond {
+        true => args.get(1),
+        false => args.get(2),
+    };
+    match taken {
+        Some(Expr::Func(FuncPtr { ident, args: Some(args), .. })) => {
+            Some(Expr::Call(CallExpr::new(ident.clone(), args.clone())))
+        }
+        Some(branch @ Expr::AnonFunc(_)) if args.len() == 3 => Some(Expr::Call(CallExpr::new(
+            "if".into(),
+            vec![Expr::Bool(true), branch.clone()],
+        ))),
+        _ => None,
+    }
+}
+
+/// Branch of `if` whose evaluation can't fail or have an effect
+fn is_inert(branch: &Expr) -> bool {
+    match branch {
+        Expr::Func(FuncPtr { args, .. }) => args.is_some(),
+        Expr::AnonFunc(anon_func) => anon_func.stmt.is_some() && anon_func.args.iter().all(|(_, arg)| literal(arg).is_some()),
+        _ => false,
+    }
+}
+
+/// The statements of an anonymous function without parameters can run in the
+/// enclosing function when none of its names is a local there and none of the
+/// locals it declares is used anywhere else in the enclosing function
+fn can_splice(anon_func: &AnonymousFunc, stmts: &[Stmt], index: usize, params: &[String]) -> bool {
+    let Some(body) = &anon_func.stmt else {
+        return false;
+    };
+    if !anon_func.args.is_empty() || body.stmt.iter().any(|stmt| matches!(stmt, Stmt::ReturnValue(_))) {
+        return false;
+    }
+    let mut names = HashSet::new();
+    let mut declared = HashSet::new();
+    body.stmt.iter().for_each(|stmt| collect_names(stmt, &mut names, &mut declared));
+
+    let mut outside_names = params.iter().cloned().collect::<HashSet<_>>();
+    let mut outside_locals = outside_names.clone();
+    for (i, stmt) in stmts.iter().enumerate() {
+        if i != index {
+            collect_names(stmt, &mut outside_names, &mut outside_locals);
+        }
+    }
+    names.is_disjoint(&outside_locals) && declared.is_disjoint(&outside_names)
+}
+
+/// Folds a comparison or a logical operator of literals in the condition of `if`
+fn fold_cond(cond: &mut Expr) {
+    let folded = match cond {
+        Expr::Eq(l, r) => compare(l, r, |a, b| a == b),
+        Expr::NotEq(l, r) => compare(l, r, |a, b| a != b),
+        Expr::Gt(l, r) => compare(l, r, |a, b| a > b),
+        Expr::Lt(l, r) => compare(l, r, |a, b| a < b),
+        Expr::Ge(l, r) => compare(l, r, |a, b| a >= b),
+        Expr::Le(l, r) => compare(l, r, |a, b| a <= b),
+        Expr::And(l, r) => match (l.as_ref(), r.as_ref()) {
+            (Expr::Bool(a), Expr::Bool(b)) => Some(*a && *b),
+            _ => None,
+        },
+        Expr::Or(l, r) => match (l.as_ref(), r.as_ref()) {
+            (Expr::Bool(a), Expr::Bool(b)) => Some(*a || *b),
+            _ => None,
+        },
+        _ => None,
+    };
+    if let Some(cond_value) = folded {
+        *cond = Expr::Bool(cond_value);
+    }
+}
+
+fn compare(l: &Expr, r: &Expr, cmp: fn(&Value, &Value) -> bool) -> Option<bool> {
+    Some(cmp(&literal(l)?, &literal(r)?))
+}
+
+/// Value of an arithmetic, bitwise or string expression of literals
+fn fold_literals(expr: &Expr) -> Option<Expr> {
+    if let Expr::Concat(parts) = expr {
+        let parts = parts.iter().map(literal).collect::<Option<Vec<_>>>()?;
+        return Some(Expr::StringLit(parts.iter().map(|part| part.to_string()).collect()));
+    }
+    let value = if let Some((l, r, op)) = binary_op(expr) {
+        let (l, r) = (literal(l)?, literal(r)?);
+        catch(|| op(l, r)).ok()?
+    } else if let Some((operand, op)) = unary_op(expr) {
+        let operand = literal(operand)?;
+        catch(|| op(operand)).ok()?
+    } else {
+        return None;
+    };
+    to_literal(value)
+}
+
+type BinaryOp = fn(Value, Value) -> Value;
+type UnaryOp = fn(Value) -> Value;
+
+fn binary_op(expr: &Expr) -> Option<(&Expr, &Expr, BinaryOp)> {
+    let (l, r, op): (_, _, BinaryOp) = match expr {
+        Expr::Add(l, r) => (l, r, Add::add),
+        Expr::Sub(l, r) => (l, r, Sub::sub),
+        Expr::Mul(l, r) => (l, r, Mul::mul),
+        Expr::Div(l, r) => (l, r, Div::div),
+        Expr::Mod(l, r) => (l, r, Rem::rem),
+        Expr::Xor(l, r) => (l, r, BitXor::bitxor),
+        Expr::BitAnd(l, r) => (l, r, BitAnd::bitand),
+        Expr::BitOr(l, r) => (l, r, BitOr::bitor),
+        Expr::Shl(l, r) => (l, r, Shl::shl),
+        Expr::Shr(l, r) => (l, r, Shr::shr),
+        _ => return None,
+    };
+    Some((l, r, op))
+}
+
+fn unary_op(expr: &Expr) -> Option<(&Expr, UnaryOp)> {
+    match expr {
+        Expr::Neg(operand) => Some((operand, Neg::neg)),
+        Expr::Not(operand) => Some((operand, Not::not)),
+        _ => None,
+    }
+}
+
+fn literal(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Integer(i) => Some(Value::Int(*i)),
+        Expr::BigInteger(digits) => digits.parse().ok().map(Value::BigInt),
+        Expr::Float(f) => Some(Value::Float(*f)),
+        Expr::Bool(b) => Some(Value::Bool(*b)),
+        Expr::StringLit(s) => Some(Value::String(s.clone())),
+        _ => None,
+    }
+}
+
+fn to_literal(value: Value) -> Option<Expr> {
+    match value {
+        Value::Int(i) => Some(Expr::Integer(i)),
+        Value::BigInt(b) => Some(Expr::BigInteger(b.to_string())),
+        Value::Float(f) => Some(Expr::Float(f)),
+        Value::Bool(b) => Some(Expr::Bool(b)),
+        Value::String(s) => Some(Expr::StringLit(s)),
+        _ => None,
+    }
+}
+
+/// Type of the value of `expr` when its names have the types in `types`,
+/// found by evaluating its operators on values of those types
+fn static_type(expr: &Expr, types: &HashMap<String, String>) -> Option<String> {
+    let value = match expr {
+        Expr::Ident(ident) => sample(types.get(ident)?)?,
+        Expr::Concat(parts) => {
+            for part in parts {
+                static_type(part, types)?;
+            }
+            Value::String(String::new())
+        }
+        expr => match (binary_op(expr), unary_op(expr)) {
+            (Some((l, r, op)), _) => {
+                let (l, r) = (sample(&static_type(l, types)?)?, sample(&static_type(r, types)?)?);
+                catch(|| op(l, r)).ok()?
+            }
+            (_, Some((operand, op))) => {
+                let operand = sample(&static_type(operand, types)?)?;
+                catch(|| op(operand)).ok()?
+            }
+            _ => literal(expr)?,
+        },
+    };
+    match value.into_type() {
+        Value::Type(ty) => Some(ty),
+        _ => None,
+    }
+}
+
+fn sample(ty: &str) -> Option<Value> {
+    match ty {
+        "int" => Some(Value::Int(1)),
+        "bigint" => Some(Value::BigInt(1.into())),
+        "float" => Some(Value::Float(1.0)),
+        "bool" => Some(Value::Bool(true)),
+        "string" => Some(Value::String("a".into())),
+        _ => None,
+    }
+}
+
+/// Function whose body is a single `return` of an expression of its parameters
+#[derive(Clone)]
+struct Inlinable {
+    params: Vec<(String, String)>,
+    expr: Expr,
+}
+
+impl Inlinable {
+    fn new(func: &FuncIdent) -> Option<Self> {
+        let mut stmts = func.stmt.as_ref()?.stmt.iter().filter(|stmt| !matches!(stmt, Stmt::Comment(_)));
+        let (Some(Stmt::ReturnValue(expr)), None) = (stmts.next(), stmts.next()) else {
+            return None;
+        };
+        let types = func.args.iter().cloned().collect::<HashMap<_, _>>();
+        if size(expr) > INLINE_LIMIT || static_type(expr, &types)? != func.rty {
+            return None;
+        }
+        Some(Self {
+            params: func.args.clone(),
+            expr: *expr.clone(),
+        })
+    }
+
+    /// The body with the parameters replaced by `args`
+    fn expand(&self, args: &[Expr]) -> Expr {
+        let mut expr = self.expr.clone();
+        self.substitute(&mut expr, args);
+        expr
+    }
+
+    fn substitute(&self, expr: &mut Expr, args: &[Expr]) {
+        if let Expr::Ident(ident) = expr {
+            if let Some(i) = self.params.iter().position(|(param, _)| param == ident) {
+                *expr = args[i].clone();
+            }
+            return;
+        }
+        children(expr).into_iter().for_each(|child| self.substitute(child, args));
+    }
+}
+
+fn size(expr: &Expr) -> usize {
+    let mut size = 0;
+    visit(expr, &mut |_| size += 1);
+    size
+}
+
+/// Items declared directly in a module or at the root of the program
+#[derive(Default)]
+struct Items {
+    names: HashSet<String>,
+    imports: bool,
+    inlinable: HashMap<String, Option<Inlinable>>,
+}
+
+/// Replaces calls of functions returning an expression of their parameters
+/// with that expression. The arguments have to be literals or parameters of
+/// the caller that are never reassigned, with exactly the types of the
+/// parameters, so no conversion or type check is skipped
+struct Inliner {
+    modules: HashMap<Vec<String>, Items>,
+}
+
+impl Inliner {
+    fn new(prog: &Prog) -> Self {
+        let mut inliner = Self { modules: HashMap::new() };
+        inliner.collect(&prog.0, Vec::new());
+        inliner
+    }
+
+    fn collect(&mut self, stmts: &[Stmt], path: Vec<String>) {
+        let mut items = Items::default();
+        for stmt in stmts {
+            match stmt {
+                Stmt::FuncIdent(func) => {
+                    let inlinable = match items.names.insert(func.ident.clone()) {
+                        true => Inlinable::new(func),
+                        false => None,
+                    };
+                    items.inlinable.insert(func.ident.clone(), inlinable);
+                }
+                Stmt::Module(module) => {
+                    items.names.insert(module.ident.clone());
+                    let mut path = path.clone();
+                    path.push(module.ident.clone());
+                    self.collect(&module.body.stmt, path);
+                }
+                Stmt::Global(global) => {
+                    items.names.insert(global.ident.clone());
+                }
+                Stmt::VarIdent(var) => {
+                    items.names.insert(var.ident.clone());
+                }
+                Stmt::Import(_) => items.imports = true,
+                _ => {}
+            }
+        }
+        self.modules.insert(path, items);
+    }
+
+    /// Function called as `ident` in the module at `path`: an item of the module
+    /// or, unless a `use` of the module may bind the name, a root function
+    fn callee(&self, path: &[String], ident: &str) -> Option<&Inlinable> {
+        let items = self.modules.get(path)?;
+        if items.names.contains(ident) {
+            return items.inlinable.get(ident)?.as_ref();
+        }
+        if path.is_empty() || items.imports {
+            return None;
+        }
+        self.callee(&[], ident)
+    }
+
+    fn stmts(&self, stmts: &mut [Stmt], path: &[String]) {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Module(module) => {
+                    let mut path = path.to_vec();
+                    path.push(module.ident.clone());
+                    self.stmts(&mut module.body.stmt, &path);
+                }
+                Stmt::FuncIdent(FuncIdent { args, stmt: Some(body), .. }) => {
+                    let mut reassigned = HashSet::new();
+                    body.stmt.iter().for_each(|stmt| collect_names(stmt, &mut HashSet::new(), &mut reassigned));
+                    let params = args
+                        .iter()
+                        .filter(|(ident, _)| !reassigned.contains(ident))
+                        .cloned()
+                        .collect::<HashMap<_, _>>();
+                    self.body(body, path, &params);
+                }
+                stmt => stmt_exprs(stmt).into_iter().for_each(|expr| self.expr(expr, path, &HashMap::new())),
+            }
+        }
+    }
+
+    fn body(&self, body: &mut Body, path: &[String], params: &HashMap<String, String>) {
+        for stmt in &mut body.stmt {
+            stmt_exprs(stmt).into_iter().for_each(|expr| self.expr(expr, path, params));
+        }
+    }
+
+    fn expr(&self, expr: &mut Expr, path: &[String], params: &HashMap<String, String>) {
+        children(expr).into_iter().for_each(|child| self.expr(child, path, params));
+        match expr {
+            // Parameters of anonymous functions take the types of their arguments
+            Expr::AnonFunc(AnonymousFunc { stmt: Some(body), .. }) => self.body(body, path, &HashMap::new()),
+            Expr::Call(call_expr) => {
+                let Some(callee) = self.callee(path, &call_expr.get_name()) else {
+                    return;
+                };
+                let args = call_expr.get_args();
+                let matches = args.len() == callee.params.len()
+                    && args.iter().zip(&callee.params).all(|(arg, (_, ty))| {
+                        let arg_type = match arg {
+                            Expr::Ident(ident) => params.get(ident).cloned(),
+                            arg => literal(arg).and_then(|_| static_type(arg, params)),
+                        };
+                        arg_type.as_ref() == Some(ty)
+                    });
+                if matches {
+                    *expr = callee.expand(&args);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Removes the functions that can't be reached from `main` or from the
+/// initializers of globals. Names are matched regardless of the module the
+/// functions are in
+fn remove_unused(prog: &mut Prog) {
+    let mut functions: HashMap<String, Vec<&FuncIdent>> = HashMap::new();
+    let mut used = HashSet::new();
+    let mut roots = Vec::new();
+    collect_functions(&prog.0, &mut functions, &mut roots);
+    if !prog.0.iter().any(|stmt| matches!(stmt, Stmt::FuncIdent(func) if func.ident == "main")) {
+        return;
+    }
+    roots.iter().for_each(|stmt| collect_names(stmt, &mut used, &mut HashSet::new()));
+    used.insert("main".to_string());
+
+    let mut pending = used.iter().cloned().collect::<Vec<_>>();
+    while let Some(ident) = pending.pop() {
+        for func in functions.get(&ident).into_iter().flatten() {
+            let mut names = HashSet::new();
+            for stmt in func.stmt.iter().flat_map(|body| &body.stmt) {
+                collect_names(stmt, &mut names, &mut HashSet::new());
+            }
+            pending.extend(names.into_iter().filter(|name| used.insert(name.clone())));
+        }
+    }
+    retain_used(&mut prog.0, None, &used);
+}
+
+fn collect_functions<'a>(
+    stmts: &'a [Stmt],
+    functions: &mut HashMap<String, Vec<&'a FuncIdent>>,
+    roots: &mut Vec<&'a Stmt>,
+) {
+    for stmt in stmts {
+        match stmt {
+            Stmt::FuncIdent(func) => functions.entry(func.ident.clone()).or_default().push(func),
+            Stmt::Module(module) => collect_functions(&module.body.stmt, functions, roots),
+            stmt => roots.push(stmt),
+        }
+    }
+}
+
+fn retain_used(stmts: &mut Vec<Stmt>, mut offsets: Option<&mut Vec<usize>>, used: &HashSet<String>) {
+    let keep = stmts
+        .iter()
+        .map(|stmt| !matches!(stmt, Stmt::FuncIdent(func) if !used.contains(&func.ident)))
+        .collect::<Vec<_>>();
+    if let Some(offsets) = offsets.as_mut().filter(|offsets| offsets.len() == keep.len()) {
+        let mut keep = keep.iter();
+        offsets.retain(|_| *keep.next().unwrap());
+    }
+    let mut keep = keep.iter();
+    stmts.retain(|_| *keep.next().unwrap());
+    for stmt in stmts {
+        if let Stmt::Module(module) = stmt {
+            retain_used(&mut module.body.stmt, Some(&mut module.body.offsets), used);
+        }
+    }
+}
+
+/// Names of the functions, globals, modules and imports declared anywhere in the program
+fn collect_items(stmt: &Stmt, items: &mut HashSet<String>) {
+    match stmt {
+        Stmt::FuncIdent(func) => {
+            items.insert(func.ident.clone());
+        }
+        Stmt::Global(global) => {
+            items.insert(global.ident.clone());
+        }
+        Stmt::VarIdent(var) => {
+            items.insert(var.ident.clone());
+        }
+        Stmt::Module(module) => {
+            items.insert(module.ident.clone());
+            module.body.stmt.iter().for_each(|stmt| collect_items(stmt, items));
+        }
+        Stmt::Import(import) => collect_use_names(&import.tree, items),
+        _ => {}
+    }
+}
+
+/// Every name `stmt` mentions, and separately the ones it declares or assigns
+fn collect_names(stmt: &Stmt, names: &mut HashSet<String>, declared: &mut HashSet<String>) {
+    match stmt {
+        Stmt::VarIdent(var) => {
+            declared.insert(var.ident.clone());
+        }
+        Stmt::VarAssign(assign) => {
+            declared.insert(assign.ident.clone());
+        }
+        Stmt::Global(global) => {
+            declared.insert(global.ident.clone());
+        }
+        Stmt::FuncIdent(func) => {
+            names.insert(func.ident.clone());
+            names.extend(func.args.iter().map(|(ident, _)| ident.clone()));
+        }
+        Stmt::Module(module) => {
+            names.insert(module.ident.clone());
+        }
+        Stmt::Import(import) => collect_use_names(&import.tree, names),
+        _ => {}
+    }
+    names.extend(declared.iter().cloned());
+    for body in stmt_bodies_ref(stmt) {
+        body.stmt.iter().for_each(|stmt| collect_names(stmt, names, declared));
+    }
+    for expr in stmt_exprs_ref(stmt) {
+        visit(expr, &mut |expr| match expr {
+            Expr::Ident(ident) | Expr::Local(ident, _) | Expr::Global(ident, _) => {
+                names.insert(ident.clone());
+            }
+            Expr::Call(call_expr) => {
+                names.insert(call_expr.get_name());
+            }
+            Expr::Func(func_ptr) => {
+                names.insert(func_ptr.ident.clone());
+            }
+            Expr::InlineAccess(access) => {
+                names.insert(access.ident.clone());
+            }
+            Expr::Counter((ident, _)) => {
+                names.insert(ident.clone());
+                declared.insert(ident.clone());
+            }
+            Expr::AnonFunc(anon_func) => {
+                names.extend(anon_func.args.iter().map(|(ident, _)| ident.clone()));
+                for stmt in anon_func.stmt.iter().flat_map(|body| &body.stmt) {
+                    collect_names(stmt, names, declared);
+                }
+            }
+            _ => {}
+        });
+    }
+}
+
+fn collect_use_names(tree: &UseTree, names: &mut HashSet<String>) {
+    names.extend(tree.path.iter().cloned());
+    match &tree.kind {
+        UseKind::Simple(Some(alias)) => {
+            names.insert(alias.clone());
+        }
+        UseKind::Group(trees) => trees.iter().for_each(|tree| collect_use_names(tree, names)),
+        _ => {}
+    }
+}
+
+/// Calls `f` on `expr` and every expression nested in it, including the
+/// statements of anonymous functions and the path of qualified names
+pub(crate) fn visit(expr: &Expr, f: &mut impl FnMut(&Expr)) {
+    f(expr);
+    match expr {
+        Expr::Ref(inner) | Expr::Not(inner) | Expr::Neg(inner) => visit(inner, f),
+        Expr::Array(items) | Expr::Concat(items) => items.iter().for_each(|item| visit(item, f)),
+        Expr::Dictionary(entries) => entries.iter().for_each(|(key, value)| {
+            visit(key, f);
+            visit(value, f);
+        }),
+        Expr::Call(call_expr) => call_expr.get_args().iter().for_each(|arg| visit(arg, f)),
+        Expr::Func(func_ptr) => func_ptr.args.iter().flatten().for_each(|arg| visit(arg, f)),
+        Expr::AnonFunc(anon_func) => {
+            anon_func.args.iter().for_each(|(_, arg)| visit(arg, f));
+            for stmt in anon_func.stmt.iter().flat_map(|body| &body.stmt) {
+                stmt_exprs_ref(stmt).into_iter().for_each(|expr| visit(expr, f));
+            }
+        }
+        Expr::InlineAccess(InlineAccess { next: Some(next), .. }) => visit(next, f),
+        Expr::Eq(l, r)
+        | Expr::NotEq(l, r)
+        | Expr::Gt(l, r)
+        | Expr::Lt(l, r)
+        | Expr::Ge(l, r)
+        | Expr::Le(l, r)
+        | Expr::Or(l, r)
+        | Expr::And(l, r) => {
+            visit(l, f);
+            visit(r, f);
+        }
+        expr => {
+            if let Some((l, r, _)) = binary_op(expr) {
+                visit(l, f);
+                visit(r, f);
+            }
+        }
+    }
+}
+
+/// Subexpressions of `expr` evaluated in the same scope. The path of a
+/// qualified name is not an expression, only the arguments of its call are
+fn children(expr: &mut Expr) -> Vec<&mut Expr> {
+    match expr {
+        Expr::Ref(inner) | Expr::Not(inner) | Expr::Neg(inner) => vec![inner],
+        Expr::Array(items) | Expr::Concat(items) => items.iter_mut().collect(),
+        Expr::Dictionary(entries) => entries.iter_mut().flat_map(|(key, value)| [key, value]).collect(),
+        Expr::Call(call_expr) => call_expr.args_mut().iter_mut().collect(),
+        Expr::Func(func_ptr) => func_ptr.args.iter_mut().flatten().collect(),
+        Expr::AnonFunc(anon_func) => anon_func.args.iter_mut().map(|(_, arg)| arg).collect(),
+        Expr::InlineAccess(InlineAccess { next: Some(next), .. }) => match next.as_mut() {
+            Expr::Call(call_expr) => call_expr.args_mut().iter_mut().collect(),
+            inner @ Expr::InlineAccess(_) => vec![inner],
+            _ => vec![],
+        },
+        Expr::Add(l, r)
+        | Expr::Sub(l, r)
+        | Expr::Mul(l, r)
+        | Expr::Div(l, r)
+        | Expr::Eq(l, r)
+        | Expr::NotEq(l, r)
+        | Expr::Gt(l, r)
+        | Expr::Lt(l, r)
+        | Expr::Ge(l, r)
+        | Expr::Le(l, r)
+        | Expr::Or(l, r)
+        | Expr::And(l, r)
+        | Expr::Xor(l, r)
+        | Expr::Mod(l, r)
+        | Expr::BitAnd(l, r)
+        | Expr::BitOr(l, r)
+        | Expr::Shl(l, r)
+        | Expr::Shr(l, r) => vec![l, r],
+        _ => vec![],
+    }
+}
+
+fn stmt_exprs(stmt: &mut Stmt) -> Vec<&mut Expr> {
+    match stmt {
+        Stmt::VarIdent(var) => vec![&mut var.expr],
+        Stmt::VarAssign(assign) => vec![&mut assign.expr],
+        Stmt::Global(global) => vec![&mut global.expr],
+        Stmt::ReturnValue(expr) | Stmt::Expr(expr) => vec![expr],
+        _ => vec![],
+    }
+}
+
+fn stmt_exprs_ref(stmt: &Stmt) -> Vec<&Expr> {
+    match stmt {
+        Stmt::VarIdent(var) => vec![&var.expr],
+        Stmt::VarAssign(assign) => vec![&assign.expr],
+        Stmt::Global(global) => vec![&global.expr],
+        Stmt::ReturnValue(expr) | Stmt::Expr(expr) => vec![expr],
+        _ => vec![],
+    }
+}
+
+fn stmt_bodies(stmt: &mut Stmt) -> Vec<&mut Body> {
+    match stmt {
+        Stmt::FuncIdent(FuncIdent { stmt: Some(body), .. }) | Stmt::FuncBody(body) => vec![body],
+        Stmt::Module(module) => vec![&mut module.body],
+        _ => vec![],
+    }
+}
+
+fn stmt_bodies_ref(stmt: &Stmt) -> Vec<&Body> {
+    match stmt {
+        Stmt::FuncIdent(FuncIdent { stmt: Some(body), .. }) | Stmt::FuncBody(body) => vec![body],
+        Stmt::Module(module) => vec![&module.body],
+        _ => vec![],
+    }
+}
@@ -1,154 +1,230 @@
+use crate::program::core_lib::arg;
 use crate::program::environment::LocalEnvironment;
-use crate::program::evaluating_functions::{call_func, eval_expr};
-use crate::program::value::Value;
-use crate::GLOBAL_ENV;
-use std::sync::{Arc, RwLock};
+use crate::program::error::{raise, MorphoError};
+use crate::ast::CallExpr;
+use crate::program::evaluating_functions::{call_closure, call_func, enter_closure, eval_expr, native_args, tail_call, Call};
+use crate::program::function::Closure;
+use crate::program::gc;
+use crate::program::sandbox::{self, Capability};
+use crate::program::value::{bigint_to_f64, NativeFunc, Value};
+use num_bigint::BigInt;
+use num_traits::{FromPrimitive, ToPrimitive};
+use crate::program::global_env;
+use crate::program::shared::{Ptr, Shared};
 
 #[inline]
-pub fn print_func(args: Vec<Value>, env: Arc<RwLock<LocalEnvironment>>) -> Value {
-    for i in 0..args.len() - 1 {
-        args[i].print(env.clone())
+pub fn print_func(args: Vec<Value>, env: Shared<LocalEnvironment>) -> Value {
+    for arg in &args[..args.len() - 1] {
+        arg.print(env.clone())
     }
     args[args.len() - 1].println(env);
     Value::Void
 }
 
 #[inline]
-fn extract_value(value: Value, env: Arc<RwLock<LocalEnvironment>>) -> Value {
+fn extract_value(value: Value, env: Shared<LocalEnvironment>) -> Value {
     match value {
-        Value::CallFunc(call_expr) => {
-            let mut parsed_args = vec![];
-
-            for arg in call_expr.get_args() {
-                parsed_args.push(eval_expr(arg, env.clone()))
-            }
+        Value::CallFunc(call_expr) => match native(&call_expr) {
+            Some(func) => func(eval_args(func, &call_expr, &env), env),
+            None => call_func(&call_expr, env),
+        },
+        Value::Closure(closure) => call_closure(&closure, &env),
+        _ => Value::Void,
+    }
+}
 
-            if let Some(func) = GLOBAL_ENV
-                .try_read()
-                .unwrap()
-                .global_stmts
-                .get(&call_expr.get_name())
-            {
-                if let Value::FuncPtr(func) = func.try_read().unwrap().clone() {
-                    return func(parsed_args, env.clone());
-                }
-            }
-            return call_func(call_expr, env.clone());
-        }
-        _ => {}
+/// Built-in function a branch given as `$name|...|` calls
+fn native(call_expr: &CallExpr) -> Option<NativeFunc> {
+    match &*global_env().borrow().global_stmts.get(&call_expr.get_name())?.borrow() {
+        Value::FuncPtr(func) => Some(*func),
+        _ => None,
     }
-    Value::Void
 }
 
+fn eval_args(func: NativeFunc, call_expr: &CallExpr, env: &Shared<LocalEnvironment>) -> Vec<Value> {
+    let args = call_expr.args().iter().map(|arg| eval_expr(arg, env.clone())).collect();
+    native_args(func, args, env)
+}
+
+/// Branch of `if(cond, then, else)` its condition selects, `None` without an else branch
+fn if_branch(args: Vec<Value>, env: &Shared<LocalEnvironment>) -> Value {
+    let taken = match &args[0] {
+        Value::Cond(ty, a, b) => ty.eval_cond(a, b, env.clone()),
+        Value::Bool(b) => *b,
+        _ => return Value::Void,
+    };
+    let branch = if taken { 1 } else { 2 };
+    args.into_iter().nth(branch).unwrap_or(Value::Void)
+}
+
+/// `if(cond, then, else)`, the missing else branch gives `None`
 #[inline]
-pub fn if_func(args: Vec<Value>, env: Arc<RwLock<LocalEnvironment>>) -> Value {
-    match args[0].clone() {
-        Value::Cond(ty, a, b) => {
-            if ty.eval_cond(a, b, env.clone()) {
-                extract_value(args[1].clone(), env.clone())
-            } else {
-                extract_value(args[2].clone(), env.clone())
-            }
-        }
-        Value::Bool(b) => {
-            if b {
-                extract_value(args[1].clone(), env.clone())
-            } else {
-                extract_value(args[2].clone(), env.clone())
-            }
-        }
-        _ => Value::Void,
+pub fn if_func(args: Vec<Value>, env: Shared<LocalEnvironment>) -> Value {
+    let branch = if_branch(args, &env);
+    extract_value(branch, env)
+}
+
+/// `if` in tail position of a function returning `rty`, the taken branch is called as a tail call
+pub(crate) fn tail_if(args: Vec<Value>, rty: &str, env: Shared<LocalEnvironment>) -> Call {
+    match if_branch(args, &env) {
+        Value::CallFunc(call_expr) => match native(&call_expr) {
+            Some(func) => Call::Done(func(eval_args(func, &call_expr, &env), env)),
+            None => tail_call(&call_expr, rty, env),
+        },
+        Value::Closure(closure) if closure.func.get_rty() == rty => Call::Enter(enter_closure(&closure, &env)),
+        Value::Closure(closure) => Call::Done(call_closure(&closure, &env)),
+        _ => Call::Done(Value::Void),
     }
 }
 
 #[inline(always)]
-pub fn for_func(args: Vec<Value>, env: Arc<RwLock<LocalEnvironment>>) -> Value {
+pub fn for_func(args: Vec<Value>, env: Shared<LocalEnvironment>) -> Value {
     let (start, end, ident) = match args[0].clone() {
         Value::Range(start, end) => (start, end, None),
         Value::Counter(ident, start, end) => (start, end, Some(ident)),
         _ => return Value::Void,
     };
 
-    let call_expr = match args[1].clone() {
-        Value::CallFunc(call_expr) => call_expr,
-        _ => return Value::Void,
+    let Some(body) = LoopBody::new(args[1].clone(), &env) else {
+        return Value::Void;
     };
 
-    let parsed_args: Vec<Value> = call_expr.get_args()
-        .iter()
-        .map(|arg| eval_expr(arg.clone(), env.clone()))
-        .collect();
-
-    let func_option = GLOBAL_ENV
-        .try_read()
-        .unwrap()
-        .global_stmts
-        .get(&call_expr.get_name())
-        .and_then(|func| {
-            if let Value::FuncPtr(func_ptr) = func.try_read().unwrap().clone() {
-                Some(func_ptr)
-            } else {
-                None
-            }
-        });
-
     for i in start..end {
+        sandbox::step();
         if let Some(ref ident) = ident {
-            env.try_write().unwrap().variables.insert(ident.clone(), Arc::new(RwLock::new(Value::Int(i))));
-        }
-        if let Some(func) = func_option {
-            func(parsed_args.clone(), env.clone());
-        } else {
-            call_func(call_expr.clone(), env.clone());
+            env.borrow_mut().define(ident, Shared::new(Value::Int(i)));
         }
+        body.call(&env);
     }
 
     Value::Void
 }
 
-pub fn while_func(args: Vec<Value>, env: Arc<RwLock<LocalEnvironment>>) -> Value {
+pub fn while_func(args: Vec<Value>, env: Shared<LocalEnvironment>) -> Value {
     let (ty, lhs, rhs) = match args[0].clone() {
         Value::Cond(ty, lhs, rhs) => (ty, lhs, rhs),
         _ => return Value::Void,
     };
 
-    let call_expr = match args[1].clone() {
-        Value::CallFunc(call_expr) => call_expr,
-        _ => return Value::Void,
+    let Some(body) = LoopBody::new(args[1].clone(), &env) else {
+        return Value::Void;
     };
 
-    let parsed_args: Vec<Value> = call_expr.get_args()
-        .iter()
-        .map(|arg| eval_expr(arg.clone(), env.clone()))
-        .collect();
-
-    let func_option = GLOBAL_ENV
-        .try_read()
-        .unwrap()
-        .global_stmts
-        .get(&call_expr.get_name())
-        .and_then(|func| {
-            if let Value::FuncPtr(func_ptr) = func.try_read().unwrap().clone() {
-                Some(func_ptr)
-            } else {
-                None
-            }
-        });
+    while ty.eval_cond(&lhs, &rhs, env.clone()) {
+        sandbox::step();
+        body.call(&env);
+    }
+    Value::Void
+}
+
+/// Body of `for` and `while`, called on every iteration
+enum LoopBody {
+    /// Built-in function with the arguments it was given, evaluated once
+    Native(NativeFunc, Vec<Value>),
+    Call(CallExpr),
+    Closure(Ptr<Closure>),
+}
 
-    while ty.eval_cond(lhs.clone(), rhs.clone(), env.clone()) {
-        if let Some(func) = func_option {
-            func(parsed_args.clone(), env.clone());
-        } else {
-            call_func(call_expr.clone(), env.clone());
+impl LoopBody {
+    fn new(value: Value, env: &Shared<LocalEnvironment>) -> Option<Self> {
+        match value {
+            Value::CallFunc(call_expr) => Some(match native(&call_expr) {
+                Some(func) => LoopBody::Native(func, eval_args(func, &call_expr, env)),
+                None => LoopBody::Call(call_expr),
+            }),
+            Value::Closure(closure) => Some(LoopBody::Closure(closure)),
+            _ => None,
+        }
+    }
+
+    fn call(&self, env: &Shared<LocalEnvironment>) {
+        match self {
+            LoopBody::Native(func, args) => {
+                func(args.clone(), env.clone());
+            }
+            LoopBody::Call(call_expr) => {
+                call_func(call_expr, env.clone());
+            }
+            LoopBody::Closure(closure) => {
+                call_closure(closure, env);
+            }
         }
     }
-    Value::Void
 }
 
-pub fn input_func(_args: Vec<Value>, _env: Arc<RwLock<LocalEnvironment>>) -> Value {
+pub fn input_func(_args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    sandbox::require(Capability::Stdin);
     let mut input = String::new();
     if let Err(e) = std::io::stdin().read_line(&mut input) {
         panic!("{}",e);
     }
     Value::String(input.trim().to_string())
-}
\ No newline at end of file
+}
+
+/// `int(x)` truncates floats towards zero, parses strings and converts bools,
+/// conditions included, to 0 and 1
+pub fn int_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    match arg(&args, 0, "int") {
+        Value::Int(i) => Value::Int(i),
+        Value::Bool(b) => Value::Int(b as i64),
+        Value::BigInt(b) => match b.to_i64() {
+            Some(i) => Value::Int(i),
+            None => raise(MorphoError::Overflow(format!("int({b})"))),
+        },
+        Value::Float(f) => {
+            if f.is_nan() {
+                raise(MorphoError::ValueError("cannot convert NaN to int".into()))
+            }
+            let truncated = f.trunc();
+            if truncated < i64::MIN as f64 || truncated >= i64::MAX as f64 {
+                raise(MorphoError::Overflow(format!("int({f})")))
+            }
+            Value::Int(truncated as i64)
+        }
+        Value::String(s) => match s.trim().parse() {
+            Ok(i) => Value::Int(i),
+            Err(_) => raise(MorphoError::ValueError(format!("invalid int literal {s:?}"))),
+        },
+        value => raise(MorphoError::TypeError(format!("cannot convert {} to int", value.into_type()))),
+    }
+}
+
+pub fn float_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    match arg(&args, 0, "float") {
+        Value::Int(i) => Value::Float(i as f64),
+        Value::BigInt(b) => Value::Float(bigint_to_f64(&b)),
+        Value::Float(f) => Value::Float(f),
+        Value::String(s) => match s.trim().parse() {
+            Ok(f) => Value::Float(f),
+            Err(_) => raise(MorphoError::ValueError(format!("invalid float literal {s:?}"))),
+        },
+        value => raise(MorphoError::TypeError(format!("cannot convert {} to float", value.into_type()))),
+    }
+}
+
+/// `bigint(x)` converts ints, integral parts of floats and decimal strings
+pub fn bigint_func(args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    match arg(&args, 0, "bigint") {
+        Value::Int(i) => Value::BigInt(BigInt::from(i)),
+        Value::BigInt(b) => Value::BigInt(b),
+        Value::Float(f) => match BigInt::from_f64(f.trunc()) {
+            Some(b) => Value::BigInt(b),
+            None => raise(MorphoError::ValueError(format!("cannot convert {f} to bigint"))),
+        },
+        Value::String(s) => match s.trim().parse() {
+            Ok(b) => Value::BigInt(b),
+            Err(_) => raise(MorphoError::ValueError(format!("invalid bigint literal {s:?}"))),
+        },
+        value => raise(MorphoError::TypeError(format!("cannot convert {} to bigint", value.into_type()))),
+    }
+}
+
+/// `gc()` frees the unreachable cycles of values and returns how many allocations they took
+pub fn gc_func(_args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    Value::Int(gc::collect() as i64)
+}
+
+/// `gc_stats()` describes the work of the collector so far
+pub fn gc_stats_func(_args: Vec<Value>, _env: Shared<LocalEnvironment>) -> Value {
+    Value::String(gc::stats().to_string())
+}
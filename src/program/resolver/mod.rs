@@ -0,0 +1,219 @@
+use crate::ast::{CallExpr, Expr, GlobalIdent, InlineAccess, Scope, Stmt, VarAssign, VarIdent};
+use crate::program::environment::LocalEnvironment;
+use crate::program::evaluating_functions::find_module;
+use crate::program::function::Function;
+use crate::program::module::Module;
+use crate::program::value::Value;
+use crate::program::global_env;
+use std::collections::HashSet;
+use crate::program::shared::Shared;
+use std::sync::Arc;
+
+/// Rewrites the body of every function of the program once it is set up.
+///
+/// Locals get a slot in their function's frame in the order they are declared,
+/// parameters first, and are read as `Expr::Local`. Names of items of the
+/// current module or of globals become `Expr::Global` with the id of their cell,
+/// calls get the id of their callee. Names are resolved in statement order the
+/// way the evaluator finds them at run time, a name used before its `let` still
+/// refers to the global. Anything else, like paths through other modules, is
+/// left to be looked up by name
+pub(crate) fn resolve_program() {
+    let mut cells = global_env().borrow().global_stmts.values().cloned().collect::<Vec<_>>();
+    let mut seen = HashSet::new();
+    while let Some(cell) = cells.pop() {
+        if !seen.insert(cell.as_ptr() as usize) {
+            continue;
+        }
+        let value = cell.get();
+        match value {
+            Value::Module(module) => cells.extend(module.visible_items(module.get_path()).into_iter().map(|(_, cell, _)| cell)),
+            Value::Func(func) => {
+                let func = resolve_function(func);
+                cell.set(Value::Func(func));
+            }
+            _ => {}
+        }
+    }
+}
+
+fn resolve_function(mut func: Function) -> Function {
+    let params = func.get_args().iter().map(|(ident, _)| ident.clone()).collect();
+    let mut resolver = Resolver::new(func.get_module_path(), params);
+    let body = func.get_body().to_vec();
+    let (body, scope) = resolver.body(body);
+    func.set_body(body);
+    func.set_env(Shared::new(LocalEnvironment::with_scope(
+        func.get_module_path().to_vec(),
+        scope.clone(),
+    )));
+    func.set_scope(scope);
+    func
+}
+
+struct Resolver {
+    /// Module the function is defined in, `None` at the root
+    module: Option<Module>,
+    locals: Vec<String>,
+}
+
+impl Resolver {
+    fn new(module_path: &[String], locals: Vec<String>) -> Self {
+        Self {
+            module: find_module(module_path),
+            locals,
+        }
+    }
+
+    fn body(&mut self, stmts: Vec<Stmt>) -> (Vec<Stmt>, Arc<Scope>) {
+        let mut targets = Vec::with_capacity(stmts.len());
+        let stmts = stmts
+            .into_iter()
+            .map(|stmt| {
+                let (stmt, target) = self.stmt(stmt);
+                targets.push(target);
+                stmt
+            })
+            .collect();
+        let scope = Scope {
+            names: self.locals.clone(),
+            targets,
+        };
+        (stmts, Arc::new(scope))
+    }
+
+    fn stmt(&mut self, stmt: Stmt) -> (Stmt, Option<u16>) {
+        match stmt {
+            Stmt::VarIdent(VarIdent { ident, mut expr }) => {
+                self.expr(&mut expr);
+                let slot = self.declare(&ident);
+                (Stmt::VarIdent(VarIdent { ident, expr }), Some(slot))
+            }
+            Stmt::Global(GlobalIdent { privacy_type, constant, ident, mut expr }) => {
+                self.expr(&mut expr);
+                let slot = self.declare(&ident);
+                (Stmt::Global(GlobalIdent { privacy_type, constant, ident, expr }), Some(slot))
+            }
+            Stmt::VarAssign(VarAssign { ident, mut expr }) => {
+                self.expr(&mut expr);
+                let slot = self.local(&ident);
+                (Stmt::VarAssign(VarAssign { ident, expr }), slot)
+            }
+            Stmt::Expr(mut expr) => {
+                self.expr(&mut expr);
+                (Stmt::Expr(expr), None)
+            }
+            Stmt::ReturnValue(mut expr) => {
+                self.expr(&mut expr);
+                (Stmt::ReturnValue(expr), None)
+            }
+            stmt => (stmt, None),
+        }
+    }
+
+    fn expr(&mut self, expr: &mut Expr) {
+        match expr {
+            Expr::Ident(ident) => {
+                if let Some(slot) = self.local(ident) {
+                    *expr = Expr::Local(std::mem::take(ident), slot);
+                } else if let Some(id) = self.global(ident) {
+                    *expr = Expr::Global(std::mem::take(ident), id);
+                }
+            }
+            // Globals are referenced by name, constants have to be copied
+            Expr::Ref(inner) => match inner.as_mut() {
+                Expr::Ident(ident) => {
+                    if let Some(slot) = self.local(ident) {
+                        **inner = Expr::Local(std::mem::take(ident), slot);
+                    }
+                }
+                inner => self.expr(inner),
+            },
+            Expr::Call(call_expr) => self.call(call_expr),
+            Expr::Func(func_ptr) => {
+                func_ptr.args.iter_mut().flatten().for_each(|arg| self.expr(arg));
+                func_ptr.target = self.global(&func_ptr.ident);
+            }
+            Expr::AnonFunc(anon_func) => {
+                anon_func.args.iter_mut().for_each(|(_, arg)| self.expr(arg));
+                let params = anon_func.args.iter().map(|(ident, _)| ident.clone()).collect();
+                if let Some(body) = &mut anon_func.stmt {
+                    let mut resolver = Resolver {
+                        module: self.module.clone(),
+                        locals: params,
+                    };
+                    let (stmts, scope) = resolver.body(std::mem::take(&mut body.stmt));
+                    body.stmt = stmts;
+                    body.scope = Some(scope);
+                }
+            }
+            Expr::Counter((ident, _)) => {
+                self.declare(ident);
+            }
+            // The path is looked up when the code runs, only the arguments are resolved
+            Expr::InlineAccess(InlineAccess { next: Some(next), .. }) => match next.as_mut() {
+                Expr::InlineAccess(_) => self.expr(next),
+                Expr::Call(call_expr) => call_expr.args_mut().iter_mut().for_each(|arg| self.expr(arg)),
+                _ => {}
+            },
+            Expr::Array(items) | Expr::Concat(items) => items.iter_mut().for_each(|item| self.expr(item)),
+            Expr::Dictionary(entries) => entries.iter_mut().for_each(|(key, value)| {
+                self.expr(key);
+                self.expr(value);
+            }),
+            Expr::Not(inner) | Expr::Neg(inner) => self.expr(inner),
+            Expr::Add(l, r)
+            | Expr::Sub(l, r)
+            | Expr::Mul(l, r)
+            | Expr::Div(l, r)
+            | Expr::Eq(l, r)
+            | Expr::NotEq(l, r)
+            | Expr::Gt(l, r)
+            | Expr::Lt(l, r)
+            | Expr::Ge(l, r)
+            | Expr::Le(l, r)
+            | Expr::Or(l, r)
+            | Expr::And(l, r)
+            | Expr::Xor(l, r)
+            | Expr::Mod(l, r)
+            | Expr::BitAnd(l, r)
+            | Expr::BitOr(l, r)
+            | Expr::Shl(l, r)
+            | Expr::Shr(l, r) => {
+                self.expr(l);
+                self.expr(r);
+            }
+            _ => {}
+        }
+    }
+
+    /// Calls look the callee up in the current module and the globals before the locals
+    fn call(&mut self, call_expr: &mut CallExpr) {
+        call_expr.args_mut().iter_mut().for_each(|arg| self.expr(arg));
+        let target = self.global(&call_expr.get_name());
+        call_expr.set_target(target);
+    }
+
+    fn local(&self, ident: &str) -> Option<u16> {
+        self.locals.iter().position(|local| local == ident).map(|slot| slot as u16)
+    }
+
+    fn declare(&mut self, ident: &str) -> u16 {
+        match self.local(ident) {
+            Some(slot) => slot,
+            None => {
+                self.locals.push(ident.to_string());
+                (self.locals.len() - 1) as u16
+            }
+        }
+    }
+
+    /// Id of the item of the current module or the global `ident`
+    fn global(&self, ident: &str) -> Option<u32> {
+        let cell = match self.module.as_ref().and_then(|module| module.get(ident)) {
+            Some(cell) => cell.clone(),
+            None => global_env().borrow().global_stmts.get(ident)?.clone(),
+        };
+        Some(global_env().borrow_mut().global_id(&cell))
+    }
+}
@@ -0,0 +1,186 @@
+use crate::program::error::{raise, MorphoError};
+use crate::program::value::Value;
+use std::cell::{Cell, RefCell};
+use std::fmt::{Display, Formatter};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+
+/// Access to the host a script may be given
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Reading the standard input with `input`
+    Stdin,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 1] = [Capability::Stdin];
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Stdin => write!(f, "stdin"),
+        }
+    }
+}
+
+/// Limits a program runs under, set with `set_sandbox`.
+///
+/// The default has no limits and allows every capability, `Sandbox::strict`
+/// allows none for running untrusted scripts
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sandbox {
+    max_steps: Option<u64>,
+    timeout: Option<Duration>,
+    max_memory: Option<usize>,
+    capabilities: Vec<Capability>,
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            max_steps: None,
+            timeout: None,
+            max_memory: None,
+            capabilities: Capability::ALL.to_vec(),
+        }
+    }
+}
+
+impl Sandbox {
+    /// No limits and no access to the host
+    pub fn strict() -> Self {
+        Self {
+            capabilities: Vec::new(),
+            ..Self::default()
+        }
+    }
+
+    /// Steps the program may take: statements and loop iterations on the tree
+    /// backend, instructions on the VM
+    pub fn with_max_steps(mut self, steps: u64) -> Self {
+        self.max_steps = Some(steps);
+        self
+    }
+
+    /// Wall-clock time the program may run for
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Bytes a single string, array or bigint the program builds may take
+    pub fn with_max_memory(mut self, bytes: usize) -> Self {
+        self.max_memory = Some(bytes);
+        self
+    }
+
+    pub fn allow(mut self, capability: Capability) -> Self {
+        if !self.capabilities.contains(&capability) {
+            self.capabilities.push(capability);
+        }
+        self
+    }
+}
+
+static SANDBOX: RwLock<Option<Sandbox>> = RwLock::new(None);
+
+/// Sets the limits the next programs run under
+pub fn set_sandbox(sandbox: Sandbox) {
+    *SANDBOX.write().unwrap() = Some(sandbox);
+}
+
+pub fn sandbox() -> Sandbox {
+    SANDBOX.read().unwrap().clone().unwrap_or_default()
+}
+
+/// The clock is read once in this many steps
+const CLOCK_INTERVAL: u64 = 1024;
+
+thread_local! {
+    /// Sandbox of the program running on this thread
+    static LIMITS: RefCell<Sandbox> = RefCell::new(Sandbox::default());
+    static STEPS_LEFT: Cell<u64> = const { Cell::new(u64::MAX) };
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Starts the budget of a program with the current sandbox
+pub(crate) fn start() {
+    let sandbox = sandbox();
+    STEPS_LEFT.set(sandbox.max_steps.unwrap_or(u64::MAX));
+    DEADLINE.set(sandbox.timeout.map(|timeout| Instant::now() + timeout));
+    LIMITS.set(sandbox);
+}
+
+/// Takes a step of the budget, raising `StepLimit` when there are none left
+/// and `Timeout` when the program ran out of time
+#[inline]
+pub(crate) fn step() {
+    let left = STEPS_LEFT.get();
+    if left == 0 {
+        raise(MorphoError::StepLimit(LIMITS.with_borrow(|limits| limits.max_steps.unwrap_or_default())));
+    }
+    STEPS_LEFT.set(left - 1);
+    if left.is_multiple_of(CLOCK_INTERVAL) {
+        if let Some(deadline) = DEADLINE.get() {
+            if Instant::now() >= deadline {
+                raise(MorphoError::Timeout(LIMITS.with_borrow(|limits| limits.timeout.unwrap_or_default())));
+            }
+        }
+    }
+}
+
+/// Whether the program has a step budget or a deadline, which only the
+/// interpreter keeps track of
+#[cfg(feature = "jit")]
+pub(crate) fn is_metered() -> bool {
+    LIMITS.with_borrow(|limits| limits.max_steps.is_some() || limits.timeout.is_some())
+}
+
+/// Raises `CapabilityError` unless the program may use `capability`
+pub(crate) fn require(capability: Capability) {
+    if !LIMITS.with_borrow(|limits| limits.capabilities.contains(&capability)) {
+        raise(MorphoError::CapabilityError(capability));
+    }
+}
+
+/// Raises `MemoryLimit` if a string, array or bigint of `size` bytes is over the limit
+pub(crate) fn reserve(size: usize) {
+    if let Some(limit) = LIMITS.with_borrow(|limits| limits.max_memory) {
+        if size > limit {
+            raise(MorphoError::MemoryLimit { size, limit });
+        }
+    }
+}
+
+/// `value` once it is checked against the memory limit
+pub(crate) fn limit_size(value: Value) -> Value {
+    reserve(size(&value));
+    value
+}
+
+/// Array of `items`, checked against the memory limit as it grows
+pub(crate) fn limit_array(items: impl Iterator<Item = Value>) -> Value {
+    let mut total = 0;
+    let items = items
+        .inspect(|item| {
+            total += size_of::<Value>() + size(item);
+            reserve(total);
+        })
+        .collect();
+    Value::Array(items)
+}
+
+/// Bytes of the digits of a bigint with `bits` bits
+pub(crate) fn bigint_size(bits: u64) -> usize {
+    usize::try_from(bits.div_ceil(8)).unwrap_or(usize::MAX)
+}
+
+fn size(value: &Value) -> usize {
+    match value {
+        Value::String(s) => s.len(),
+        Value::BigInt(b) => bigint_size(b.bits()),
+        Value::Array(items) => items.iter().map(|item| size_of::<Value>() + size(item)).sum(),
+        _ => 0,
+    }
+}
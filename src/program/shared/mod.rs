@@ -0,0 +1,137 @@
+use crate::program::gc::{self, Node, Trace};
+use std::fmt::{Debug, Formatter};
+
+#[cfg(not(feature = "sync"))]
+mod imp {
+    pub use std::cell::{Ref as ReadGuard, RefCell as Lock, RefMut as WriteGuard};
+    pub use std::rc::{Rc as Ptr, Weak};
+
+    pub(super) fn read<T>(lock: &Lock<T>) -> ReadGuard<'_, T> {
+        lock.borrow()
+    }
+
+    pub(super) fn write<T>(lock: &Lock<T>) -> WriteGuard<'_, T> {
+        lock.borrow_mut()
+    }
+
+    pub(crate) fn try_read<T>(lock: &Lock<T>) -> Option<ReadGuard<'_, T>> {
+        lock.try_borrow().ok()
+    }
+
+    pub(crate) fn try_write<T>(lock: &Lock<T>) -> Option<WriteGuard<'_, T>> {
+        lock.try_borrow_mut().ok()
+    }
+}
+
+#[cfg(feature = "sync")]
+mod imp {
+    pub use std::sync::{Arc as Ptr, RwLock as Lock, RwLockReadGuard as ReadGuard, RwLockWriteGuard as WriteGuard, Weak};
+    use std::sync::{PoisonError, TryLockError};
+
+    // Errors of Morpho programs unwind through the interpreter and poison the
+    // locks they pass, the values are still whole so the poison is ignored
+
+    pub(super) fn read<T>(lock: &Lock<T>) -> ReadGuard<'_, T> {
+        lock.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(super) fn write<T>(lock: &Lock<T>) -> WriteGuard<'_, T> {
+        lock.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn try_read<T>(lock: &Lock<T>) -> Option<ReadGuard<'_, T>> {
+        match lock.try_read() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poison)) => Some(poison.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+
+    pub(crate) fn try_write<T>(lock: &Lock<T>) -> Option<WriteGuard<'_, T>> {
+        match lock.try_write() {
+            Ok(guard) => Some(guard),
+            Err(TryLockError::Poisoned(poison)) => Some(poison.into_inner()),
+            Err(TryLockError::WouldBlock) => None,
+        }
+    }
+}
+
+pub use imp::{ReadGuard, WriteGuard};
+/// Reference counted pointer to data that isn't mutated once shared, `Rc` or `Arc` with `sync`
+pub use imp::Ptr;
+pub(crate) use imp::{try_read, try_write, Lock, Weak};
+
+/// Mutable storage shared by the interpreter: variables, references, frames,
+/// module items and the global environment.
+///
+/// It is a single-threaded `Rc<RefCell>` unless the `sync` feature is enabled,
+/// which makes it an `Arc<RwLock>` so values can be sent to other threads.
+/// `get` and `set` only borrow the value for the copy, prefer them to holding
+/// a guard while evaluating code that may use the same storage.
+///
+/// Every storage is known to the cycle collector, see `gc`
+pub struct Shared<T>(imp::Ptr<imp::Lock<T>>);
+
+impl<T: Trace + 'static> Shared<T> {
+    pub fn new(value: T) -> Self {
+        let ptr = imp::Ptr::new(imp::Lock::new(value));
+        gc::track(imp::Ptr::downgrade(&ptr) as Weak<dyn Node>);
+        Self(ptr)
+    }
+
+    /// The storage as a node of the heap the collector walks
+    pub(crate) fn node(&self) -> Ptr<dyn Node> {
+        self.0.clone()
+    }
+}
+
+impl<T> Shared<T> {
+
+    pub fn borrow(&self) -> ReadGuard<'_, T> {
+        imp::read(&self.0)
+    }
+
+    pub fn borrow_mut(&self) -> WriteGuard<'_, T> {
+        imp::write(&self.0)
+    }
+
+    /// Replaces the value, the old one is dropped once the storage is released
+    pub fn set(&self, value: T) {
+        let old = std::mem::replace(&mut *self.borrow_mut(), value);
+        drop(old);
+    }
+
+    /// Address of the storage, the same for every clone
+    pub fn as_ptr(&self) -> *const () {
+        imp::Ptr::as_ptr(&self.0) as *const ()
+    }
+
+    pub fn ptr_eq(a: &Self, b: &Self) -> bool {
+        imp::Ptr::ptr_eq(&a.0, &b.0)
+    }
+}
+
+impl<T: Clone> Shared<T> {
+    /// Copy of the value
+    pub fn get(&self) -> T {
+        self.borrow().clone()
+    }
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Default + Trace + 'static> Default for Shared<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Debug> Debug for Shared<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
@@ -1,13 +1,19 @@
 use crate::ast::{CallExpr, Expr};
 use crate::program::environment::LocalEnvironment;
 use crate::program::evaluating_functions::eval_expr;
-use crate::program::function::Function;
+use crate::program::function::{Closure, Function};
+use num_bigint::BigInt;
+use num_traits::{ToPrimitive, Zero};
 use std::cmp::Ordering;
 use std::fmt::{Display, Formatter};
-use std::ops::{Add, BitOr, BitXor, Div, Mul, Neg, Not, Rem, Sub};
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Neg, Not, Rem, Shl, Shr, Sub};
 use std::ptr::fn_addr_eq;
-use std::sync::{Arc, RwLock};
+use crate::program::shared::{Ptr, Shared};
+use crate::program::error::{raise, MorphoError};
+use crate::program::gc::{Trace, Tracer};
 use crate::program::module::Module;
+use crate::program::sandbox;
+use crate::program::vm::bytecode::Callable;
 
 #[derive(Clone, Debug)]
 pub enum CondType {
@@ -24,20 +30,20 @@ pub enum CondType {
 impl CondType {
     pub(crate) fn eval_cond(
         &self,
-        lhs: Box<Expr>,
-        rhs: Box<Expr>,
-        env: Arc<RwLock<LocalEnvironment>>,
+        lhs: &Expr,
+        rhs: &Expr,
+        env: Shared<LocalEnvironment>,
     ) -> bool {
         match self {
-            CondType::Eq => eval_expr(*lhs, env.clone()) == eval_expr(*rhs, env.clone()),
-            CondType::Ne => eval_expr(*lhs, env.clone()) != eval_expr(*rhs, env.clone()),
-            CondType::Gt => eval_expr(*lhs, env.clone()) > eval_expr(*rhs, env.clone()),
-            CondType::Lt => eval_expr(*lhs, env.clone()) < eval_expr(*rhs, env.clone()),
-            CondType::Ge => eval_expr(*lhs, env.clone()) >= eval_expr(*rhs, env.clone()),
-            CondType::Le => eval_expr(*lhs, env.clone()) <= eval_expr(*rhs, env.clone()),
+            CondType::Eq => eval_expr(lhs, env.clone()) == eval_expr(rhs, env.clone()),
+            CondType::Ne => eval_expr(lhs, env.clone()) != eval_expr(rhs, env.clone()),
+            CondType::Gt => eval_expr(lhs, env.clone()) > eval_expr(rhs, env.clone()),
+            CondType::Lt => eval_expr(lhs, env.clone()) < eval_expr(rhs, env.clone()),
+            CondType::Ge => eval_expr(lhs, env.clone()) >= eval_expr(rhs, env.clone()),
+            CondType::Le => eval_expr(lhs, env.clone()) <= eval_expr(rhs, env.clone()),
             CondType::Or => {
                 if let Value::Bool(b) =
-                    eval_expr(*lhs, env.clone()).logical_or(eval_expr(*rhs, env.clone()), env)
+                    eval_expr(lhs, env.clone()).logical_or(eval_expr(rhs, env.clone()), env)
                 {
                     b
                 } else {
@@ -46,7 +52,7 @@ impl CondType {
             }
             CondType::And => {
                 if let Value::Bool(b) =
-                    eval_expr(*lhs, env.clone()).logical_and(eval_expr(*rhs, env.clone()))
+                    eval_expr(lhs, env.clone()).logical_and(eval_expr(rhs, env.clone()))
                 {
                     b
                 } else {
@@ -57,22 +63,49 @@ impl CondType {
     }
 }
 
+/// Built-in function, called with its evaluated arguments
+pub type NativeFunc = fn(Vec<Value>, Shared<LocalEnvironment>) -> Value;
+
 #[derive(Clone, Debug)]
 pub enum Value {
     String(String),
     Int(i64),
+    BigInt(BigInt),
     Bool(bool),
     Float(f64),
-    FuncPtr(fn(Vec<Value>, Arc<RwLock<LocalEnvironment>>) -> Value),
-    RefValue(Arc<RwLock<Value>>),
+    FuncPtr(NativeFunc),
+    RefValue(Shared<Value>),
     Func(Function),
     CallFunc(CallExpr),
+    /// Anonymous function, called by `if`, `for` and `while`
+    Closure(Ptr<Closure>),
+    /// Function value of the VM backend
+    Callable(Ptr<Callable>),
     Type(String),
     Range(i64, i64),
     Counter(String, i64, i64),
     Cond(CondType, Box<Expr>, Box<Expr>),
     Void,
     Module(Module),
+    Array(Vec<Value>),
+}
+
+impl Trace for Value {
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Value::RefValue(cell) => tracer.shared(cell),
+            Value::Func(func) => func.trace(tracer),
+            Value::Closure(closure) => tracer.ptr(closure),
+            Value::Callable(callable) => tracer.ptr(callable),
+            Value::Module(module) => module.trace(tracer),
+            Value::Array(items) => items.iter().for_each(|item| item.trace(tracer)),
+            _ => {}
+        }
+    }
+
+    fn clear(&mut self) {
+        *self = Value::Void;
+    }
 }
 
 impl Neg for Value {
@@ -80,13 +113,20 @@ impl Neg for Value {
 
     fn neg(self) -> Self::Output {
         match self {
-            Value::Int(i) => Value::Int(i.neg()),
+            Value::Int(i) => Value::Int(
+                i.checked_neg()
+                    .unwrap_or_else(|| raise(MorphoError::Overflow(format!("-({i})")))),
+            ),
+            Value::Float(f) => Value::Float(-f),
+            Value::BigInt(b) => Value::BigInt(-b),
             Value::RefValue(r) => {
-                let mut value = r.try_write().unwrap();
-                *value = -value.clone();
+                r.set(-r.get());
                 Value::Void
             }
-            _ => Value::Void,
+            value => raise(MorphoError::TypeError(format!(
+                "bad operand type for unary -: {}",
+                value.into_type()
+            ))),
         }
     }
 }
@@ -97,13 +137,16 @@ impl Not for Value {
     fn not(self) -> Self::Output {
         match self {
             Value::Int(i) => Value::Int(!i),
+            Value::BigInt(b) => Value::BigInt(!b),
             Value::Bool(b) => Value::Bool(!b),
             Value::RefValue(r) => {
-                let mut value = r.try_write().unwrap();
-                *value = !value.clone();
+                r.set(!r.get());
                 Value::Void
             }
-            _ => Value::Void,
+            value => raise(MorphoError::TypeError(format!(
+                "bad operand type for unary !: {}",
+                value.into_type()
+            ))),
         }
     }
 }
@@ -124,7 +167,18 @@ impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         match (self, other) {
             (Value::Int(a), Value::Int(b)) => impl_partial_ord!(a, b),
+            (Value::Float(a), Value::Float(b)) => a.partial_cmp(b),
+            (Value::Int(a), Value::Float(b)) => (*a as f64).partial_cmp(b),
+            (Value::Float(a), Value::Int(b)) => a.partial_cmp(&(*b as f64)),
+            (Value::BigInt(a), Value::BigInt(b)) => a.partial_cmp(b),
+            (Value::BigInt(a), Value::Int(b)) => a.partial_cmp(&BigInt::from(*b)),
+            (Value::Int(a), Value::BigInt(b)) => BigInt::from(*a).partial_cmp(b),
+            (Value::BigInt(a), Value::Float(b)) => bigint_to_f64(a).partial_cmp(b),
+            (Value::Float(a), Value::BigInt(b)) => a.partial_cmp(&bigint_to_f64(b)),
             (Value::Bool(a), Value::Bool(b)) => impl_partial_ord!(a, b),
+            (Value::String(a), Value::String(b)) => impl_partial_ord!(a, b),
+            (Value::RefValue(a), b) => a.borrow().partial_cmp(b),
+            (a, Value::RefValue(b)) => a.partial_cmp(&*b.borrow()),
             (_, _) => None,
         }
     }
@@ -135,249 +189,228 @@ impl PartialEq for Value {
         match (self, other) {
             (Value::String(a), Value::String(b)) => a == b,
             (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Float(a), Value::Float(b)) => a == b,
+            (Value::Int(a), Value::Float(b)) => *a as f64 == *b,
+            (Value::Float(a), Value::Int(b)) => *a == *b as f64,
+            (Value::BigInt(a), Value::BigInt(b)) => a == b,
+            (Value::BigInt(a), Value::Int(b)) => *a == BigInt::from(*b),
+            (Value::Int(a), Value::BigInt(b)) => BigInt::from(*a) == *b,
+            (Value::BigInt(a), Value::Float(b)) => bigint_to_f64(a) == *b,
+            (Value::Float(a), Value::BigInt(b)) => *a == bigint_to_f64(b),
             (Value::Bool(a), Value::Bool(b)) => a == b,
             (Value::FuncPtr(a), Value::FuncPtr(b)) => fn_addr_eq(*a, *b),
             (Value::Type(a), Value::Type(b)) => a == b,
-            (Value::Func(a), Value::Func(b)) => a.rty == b.rty,
+            (Value::Func(a), Value::Func(b)) => a.get_rty() == b.get_rty(),
+            (Value::Array(a), Value::Array(b)) => a == b,
+            (Value::RefValue(a), b) => *a.borrow() == *b,
+            (a, Value::RefValue(b)) => *a == *b.borrow(),
             (_, _) => false,
         }
     }
 }
 
-impl Add for Value {
-    type Output = Value;
+#[inline]
+pub(crate) fn bigint_to_f64(b: &BigInt) -> f64 {
+    b.to_f64().unwrap_or(f64::NAN)
+}
+
+/// Implements a binary arithmetic operator with checked int arithmetic and
+/// implicit int -> bigint -> float promotion for mixed operands. `$extra`
+/// arms are matched first, on already dereferenced operands.
+macro_rules! impl_arith_op {
+    ($trait: ident, $method: ident, $checked: ident, $op: tt, $($extra: pat $(if $guard: expr)? => $res: expr),* $(,)?) => {
+        impl $trait for Value {
+            type Output = Value;
 
-    fn add(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
-                    (Value::Int(a), Value::Int(b)) => Value::Int(a + b),
-                    _ => Value::Void,
+            fn $method(self, rhs: Self) -> Self::Output {
+                match (self.deref_value(), rhs.deref_value()) {
+                    $($extra $(if $guard)? => $res,)*
+                    (Value::Int(a), Value::Int(b)) => Value::Int(a.$checked(b).unwrap_or_else(|| {
+                        raise(MorphoError::Overflow(format!("{a} {} {b}", stringify!($op))))
+                    })),
+                    (Value::Int(a), Value::Float(b)) => Value::Float(a as f64 $op b),
+                    (Value::Float(a), Value::Int(b)) => Value::Float(a $op b as f64),
+                    (Value::Float(a), Value::Float(b)) => Value::Float(a $op b),
+                    (Value::BigInt(a), Value::BigInt(b)) => sandbox::limit_size(Value::BigInt(a $op b)),
+                    (Value::BigInt(a), Value::Int(b)) => sandbox::limit_size(Value::BigInt(a $op BigInt::from(b))),
+                    (Value::Int(a), Value::BigInt(b)) => sandbox::limit_size(Value::BigInt(BigInt::from(a) $op b)),
+                    (Value::BigInt(a), Value::Float(b)) => Value::Float(bigint_to_f64(&a) $op b),
+                    (Value::Float(a), Value::BigInt(b)) => Value::Float(a $op bigint_to_f64(&b)),
+                    (a, b) => raise(MorphoError::TypeError(format!(
+                        "unsupported operand types for {}: {} and {}",
+                        stringify!($op),
+                        a.into_type(),
+                        b.into_type()
+                    ))),
                 }
             }
-            (Value::Int(a), Value::Int(b)) => Value::Int(a + b),
-            (Value::RefValue(a), Value::Int(b)) => match a.try_read().unwrap().clone() {
-                Value::Int(a) => Value::Int(a + b),
-                _ => Value::Void,
-            },
-            (Value::Int(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Int(b) => Value::Int(a + b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::Float(b)) => Value::Float(a + b),
-            (Value::RefValue(a), Value::Float(b)) => match a.try_read().unwrap().clone() {
-                Value::Float(a) => Value::Float(a + b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Float(b) => Value::Float(a + b),
-                _ => Value::Void,
-            },
-            _ => Value::Void,
         }
-    }
+    };
 }
 
-impl Sub for Value {
-    type Output = Value;
+impl_arith_op!(Add, add, checked_add, +,
+    (Value::String(a), Value::String(b)) => {
+        sandbox::reserve(a.len() + b.len());
+        Value::String(a + &b)
+    },
+);
+impl_arith_op!(Sub, sub, checked_sub, -,);
+impl_arith_op!(Mul, mul, checked_mul, *,);
+impl_arith_op!(Div, div, checked_div, /,
+    (_, Value::Int(0)) => raise(MorphoError::DivisionByZero),
+    (_, Value::Float(b)) if b == 0.0 => raise(MorphoError::DivisionByZero),
+    (_, Value::BigInt(ref b)) if b.is_zero() => raise(MorphoError::DivisionByZero),
+);
+impl_arith_op!(Rem, rem, checked_rem, %,
+    (_, Value::Int(0)) => raise(MorphoError::DivisionByZero),
+    (_, Value::Float(b)) if b == 0.0 => raise(MorphoError::DivisionByZero),
+    (_, Value::BigInt(ref b)) if b.is_zero() => raise(MorphoError::DivisionByZero),
+);
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
-                    (Value::Int(a), Value::Int(b)) => Value::Int(a - b),
-                    _ => Value::Void,
-                }
-            }
-            (Value::Int(a), Value::Int(b)) => Value::Int(a - b),
-            (Value::RefValue(a), Value::Int(b)) => match a.try_read().unwrap().clone() {
-                Value::Int(a) => Value::Int(a - b),
-                _ => Value::Void,
-            },
-            (Value::Int(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Int(b) => Value::Int(a - b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::Float(b)) => Value::Float(a - b),
-            (Value::RefValue(a), Value::Float(b)) => match a.try_read().unwrap().clone() {
-                Value::Float(a) => Value::Float(a - b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Float(b) => Value::Float(a - b),
-                _ => Value::Void,
-            },
-            _ => Value::Void,
+impl BitXor for Value {
+    type Output = Self;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        match (self.deref_value(), rhs.deref_value()) {
+            (Value::Int(a), Value::Int(b)) => Value::Int(a ^ b),
+            (Value::BigInt(a), Value::BigInt(b)) => Value::BigInt(a ^ b),
+            (Value::BigInt(a), Value::Int(b)) => Value::BigInt(a ^ BigInt::from(b)),
+            (Value::Int(a), Value::BigInt(b)) => Value::BigInt(BigInt::from(a) ^ b),
+            (Value::Bool(a), Value::Bool(b)) => Value::Bool(a ^ b),
+            (a, b) => raise(MorphoError::TypeError(format!(
+                "unsupported operand types for ^: {} and {}",
+                a.into_type(),
+                b.into_type()
+            ))),
         }
     }
 }
 
-impl Mul for Value {
-    type Output = Value;
+macro_rules! impl_bit_op {
+    ($trait: ident, $method: ident, $op: tt) => {
+        impl $trait for Value {
+            type Output = Self;
 
-    fn mul(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Int(a), Value::Int(b)) => Value::Int(a * b),
-            (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
-                    (Value::Int(a), Value::Int(b)) => Value::Int(a * b),
-                    _ => Value::Void,
+            fn $method(self, rhs: Self) -> Self::Output {
+                match (self.deref_value(), rhs.deref_value()) {
+                    (Value::Int(a), Value::Int(b)) => Value::Int(a $op b),
+                    (Value::BigInt(a), Value::BigInt(b)) => Value::BigInt(a $op b),
+                    (Value::BigInt(a), Value::Int(b)) => Value::BigInt(a $op BigInt::from(b)),
+                    (Value::Int(a), Value::BigInt(b)) => Value::BigInt(BigInt::from(a) $op b),
+                    (Value::Bool(a), Value::Bool(b)) => Value::Bool(a $op b),
+                    (a, b) => raise(MorphoError::TypeError(format!(
+                        "unsupported operand types for {}: {} and {}",
+                        stringify!($op),
+                        a.into_type(),
+                        b.into_type()
+                    ))),
                 }
             }
-            (Value::RefValue(a), Value::Int(b)) => match a.try_read().unwrap().clone() {
-                Value::Int(a) => Value::Int(a * b),
-                _ => Value::Void,
-            },
-            (Value::Int(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Int(b) => Value::Int(a * b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::Float(b)) => Value::Float(a * b),
-            (Value::RefValue(a), Value::Float(b)) => match a.try_read().unwrap().clone() {
-                Value::Float(a) => Value::Float(a * b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Float(b) => Value::Float(a * b),
-                _ => Value::Void,
-            },
-            _ => Value::Void,
         }
-    }
+    };
 }
 
-impl Div for Value {
-    type Output = Value;
+impl_bit_op!(BitAnd, bitand, &);
+impl_bit_op!(BitOr, bitor, |);
 
-    fn div(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Int(a), Value::Int(b)) => Value::Int(a / b),
-            (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
-                    (Value::Int(a), Value::Int(b)) => Value::Int(a / b),
-                    _ => Value::Void,
+/// Shifts of ints are checked against the bit width, bigints can be shifted by any
+/// amount. `$bits` gives the bits of a shifted bigint, which are reserved first
+macro_rules! impl_shift_op {
+    ($trait: ident, $method: ident, $checked: ident, $op: tt, $bits: path) => {
+        impl $trait for Value {
+            type Output = Self;
+
+            fn $method(self, rhs: Self) -> Self::Output {
+                match (self.deref_value(), rhs.deref_value()) {
+                    (Value::Int(a), Value::Int(b)) => u32::try_from(b)
+                        .ok()
+                        .and_then(|b| a.$checked(b))
+                        .map(Value::Int)
+                        .unwrap_or_else(|| {
+                            raise(MorphoError::Overflow(format!("{a} {} {b}", stringify!($op))))
+                        }),
+                    (Value::BigInt(a), Value::Int(b)) => match usize::try_from(b) {
+                        Ok(b) => {
+                            sandbox::reserve(sandbox::bigint_size($bits(a.bits(), b as u64)));
+                            Value::BigInt(a $op b)
+                        }
+                        Err(_) => raise(MorphoError::ValueError(format!("negative shift amount {b}"))),
+                    },
+                    (a, b) => raise(MorphoError::TypeError(format!(
+                        "unsupported operand types for {}: {} and {}",
+                        stringify!($op),
+                        a.into_type(),
+                        b.into_type()
+                    ))),
                 }
             }
-            (Value::RefValue(a), Value::Int(b)) => match a.try_read().unwrap().clone() {
-                Value::Int(a) => Value::Int(a / b),
-                _ => Value::Void,
-            },
-            (Value::Int(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Int(b) => Value::Int(a / b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::Float(b)) => Value::Float(a / b),
-            (Value::RefValue(a), Value::Float(b)) => match a.try_read().unwrap().clone() {
-                Value::Float(a) => Value::Float(a / b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Float(b) => Value::Float(a / b),
-                _ => Value::Void,
-            },
-            _ => Value::Void,
         }
-    }
+    };
 }
 
-impl Rem for Value {
-    type Output = Self;
+impl_shift_op!(Shl, shl, checked_shl, <<, u64::saturating_add);
+impl_shift_op!(Shr, shr, checked_shr, >>, u64::saturating_sub);
 
-    fn rem(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Int(a), Value::Int(b)) => Value::Int(a % b),
-            (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
-                    (Value::Int(a), Value::Int(b)) => Value::Int(a % b),
-                    _ => Value::Void,
-                }
-            }
-            (Value::RefValue(a), Value::Int(b)) => match a.try_read().unwrap().clone() {
-                Value::Int(a) => Value::Int(a % b),
-                _ => Value::Void,
-            },
-            (Value::Int(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Int(b) => Value::Int(a % b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::Float(b)) => Value::Float(a % b),
-            (Value::RefValue(a), Value::Float(b)) => match a.try_read().unwrap().clone() {
-                Value::Float(a) => Value::Float(a % b),
-                _ => Value::Void,
-            },
-            (Value::Float(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Float(b) => Value::Float(a % b),
-                _ => Value::Void,
-            },
-            _ => Value::Void,
+impl Value {
+    /// Clones the value behind a `RefValue`, other values are returned as is
+    pub(crate) fn deref_value(self) -> Value {
+        match self {
+            Value::RefValue(r) => r.get().deref_value(),
+            value => value,
         }
     }
-}
-
-impl BitXor for Value {
-    type Output = Self;
 
-    fn bitxor(self, rhs: Self) -> Self::Output {
-        match (self, rhs) {
-            (Value::Int(a), Value::Int(b)) => Value::Int(a ^ b),
-            (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
-                    (Value::Int(a), Value::Int(b)) => Value::Int(a ^ b),
-                    _ => Value::Void,
-                }
-            }
-            (Value::RefValue(a), Value::Int(b)) => match a.try_read().unwrap().clone() {
-                Value::Int(a) => Value::Int(a ^ b),
-                _ => Value::Void,
-            },
-            (Value::Int(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
-                Value::Int(b) => Value::Int(a ^ b),
-                _ => Value::Void,
-            },
-            _ => Value::Void,
+    /// Checks the value against a declared `ty`, widening ints where a float is expected
+    pub(crate) fn coerce_to(self, ty: &str) -> Option<Value> {
+        match (self, ty) {
+            (Value::Int(i), "float") => Some(Value::Float(i as f64)),
+            (Value::Int(i), "bigint") => Some(Value::BigInt(BigInt::from(i))),
+            (value, ty) if value.clone().into_type() == Value::Type(ty.to_string()) => Some(value),
+            _ => None,
         }
     }
-}
 
-impl Value {
     pub(crate) fn into_type(self) -> Value {
         match self {
             Value::String(_) => Value::Type("string".into()),
             Value::Int(_) => Value::Type("int".into()),
+            Value::BigInt(_) => Value::Type("bigint".into()),
             Value::FuncPtr(func) => Value::Type(format!("{:?}", func)),
-            Value::Func(Function { rty, .. }) => Value::Type(rty),
+            Value::Func(func) => Value::Type(func.get_rty().to_string()),
             Value::Type(ty) => Value::Type(ty),
             Value::Void => Value::Type("none".into()),
             Value::Bool(_) => Value::Type("bool".into()),
-            Value::CallFunc { .. } => Value::Type("func".into()),
+            Value::CallFunc { .. } | Value::Closure(_) => Value::Type("func".into()),
+            Value::Callable(callable) => Value::Type(callable.ty.clone()),
             Value::Range(s, e) => Value::Type(format!("range<{}, {}>", s, e)),
             Value::Counter(ident, s, e) => Value::Type(format!("counter<{}, {}, {}>", ident, s, e)),
             Value::Cond(_, _, _) => Value::Type("bool".into()),
-            Value::RefValue(r) => r.try_read().unwrap().clone().into_type(),
+            Value::RefValue(r) => r.get().into_type(),
             Value::Float(_) => Value::Type("float".into()),
             Value::Module(_) => Value::Type("module".into()),
+            Value::Array(_) => Value::Type("array".into()),
         }
     }
 
-    pub fn logical_or(self, other: Value, env: Arc<RwLock<LocalEnvironment>>) -> Value {
+    pub fn logical_or(self, other: Value, env: Shared<LocalEnvironment>) -> Value {
         match (self, other) {
             (Value::Bool(a), Value::Bool(b)) => Value::Bool(a || b),
-            (Value::RefValue(a), Value::Bool(b)) => match a.try_read().unwrap().clone() {
+            (Value::RefValue(a), Value::Bool(b)) => match a.get() {
                 Value::Bool(a) => Value::Bool(a || b),
                 _ => Value::Void,
             },
-            (Value::Bool(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
+            (Value::Bool(a), Value::RefValue(b)) => match b.get() {
                 Value::Bool(b) => Value::Bool(a || b),
                 _ => Value::Void,
             },
             (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
+                match (a.get(), b.get()) {
                     (Value::Bool(a), Value::Bool(b)) => Value::Bool(a || b),
                     _ => Value::Void,
                 }
             }
             (Value::Cond(ty1, l1, r1), Value::Cond(ty2, l2, r2)) => Value::Bool(
-                ty1.eval_cond(l1, r1, env.clone()) || ty2.eval_cond(l2, r2, env.clone()),
+                ty1.eval_cond(&l1, &r1, env.clone()) || ty2.eval_cond(&l2, &r2, env.clone()),
             ),
             (_, _) => panic!("Expected bool type"),
         }
@@ -385,16 +418,16 @@ impl Value {
     pub fn logical_and(self, other: Value) -> Value {
         match (self, other) {
             (Value::Bool(a), Value::Bool(b)) => Value::Bool(a && b),
-            (Value::RefValue(a), Value::Bool(b)) => match a.try_read().unwrap().clone() {
+            (Value::RefValue(a), Value::Bool(b)) => match a.get() {
                 Value::Bool(a) => Value::Bool(a && b),
                 _ => Value::Void,
             },
-            (Value::Bool(a), Value::RefValue(b)) => match b.try_read().unwrap().clone() {
+            (Value::Bool(a), Value::RefValue(b)) => match b.get() {
                 Value::Bool(b) => Value::Bool(a && b),
                 _ => Value::Void,
             },
             (Value::RefValue(a), Value::RefValue(b)) => {
-                match (a.try_read().unwrap().clone(), b.try_read().unwrap().clone()) {
+                match (a.get(), b.get()) {
                     (Value::Bool(a), Value::Bool(b)) => Value::Bool(a && b),
                     _ => Value::Void,
                 }
@@ -402,81 +435,81 @@ impl Value {
             (_, _) => panic!("Expected bool type"),
         }
     }
-    pub fn print(&self, env: Arc<RwLock<LocalEnvironment>>) {
+    pub fn print(&self, env: Shared<LocalEnvironment>) {
         match self {
             Value::Cond(cond_ty, a, b) => match cond_ty {
                 CondType::Eq => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) == eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) == eval_expr(b, env)
                 ),
                 CondType::Ne => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) != eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) != eval_expr(b, env)
                 ),
                 CondType::Gt => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) > eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) > eval_expr(b, env)
                 ),
                 CondType::Lt => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) < eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) < eval_expr(b, env)
                 ),
                 CondType::Ge => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) >= eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) >= eval_expr(b, env)
                 ),
                 CondType::Le => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) <= eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) <= eval_expr(b, env)
                 ),
                 CondType::Or => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone())
-                        .logical_or(eval_expr(*b.clone(), env.clone()), env)
+                    eval_expr(a, env.clone())
+                        .logical_or(eval_expr(b, env.clone()), env)
                 ),
                 CondType::And => print!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()).logical_and(eval_expr(*b.clone(), env))
+                    eval_expr(a, env.clone()).logical_and(eval_expr(b, env))
                 ),
             },
             _ => print!("{self}"),
         }
     }
-    pub fn println(&self, env: Arc<RwLock<LocalEnvironment>>) {
+    pub fn println(&self, env: Shared<LocalEnvironment>) {
         match self {
             Value::Cond(cond_ty, a, b) => match cond_ty {
                 CondType::Eq => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) == eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) == eval_expr(b, env)
                 ),
                 CondType::Ne => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) != eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) != eval_expr(b, env)
                 ),
                 CondType::Gt => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) > eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) > eval_expr(b, env)
                 ),
                 CondType::Lt => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) < eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) < eval_expr(b, env)
                 ),
                 CondType::Ge => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) >= eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) >= eval_expr(b, env)
                 ),
                 CondType::Le => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()) <= eval_expr(*b.clone(), env)
+                    eval_expr(a, env.clone()) <= eval_expr(b, env)
                 ),
                 CondType::Or => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone())
-                        .logical_or(eval_expr(*b.clone(), env.clone()), env)
+                    eval_expr(a, env.clone())
+                        .logical_or(eval_expr(b, env.clone()), env)
                 ),
                 CondType::And => println!(
                     "{}",
-                    eval_expr(*a.clone(), env.clone()).logical_and(eval_expr(*b.clone(), env))
+                    eval_expr(a, env.clone()).logical_and(eval_expr(b, env))
                 ),
             },
             _ => println!("{self}"),
@@ -489,16 +522,31 @@ impl Display for Value {
         match self {
             Value::String(s) => write!(f, "{s}"),
             Value::Int(i) => write!(f, "{i}"),
+            Value::BigInt(b) => write!(f, "{b}"),
             Value::Func(func) => write!(f, "{}", func.get_ident()),
             Value::Void => write!(f, "None"),
             Value::FuncPtr(func_ptr) => write!(f, "{:?}", func_ptr),
             Value::Type(ty) => write!(f, "{}", ty),
             Value::Bool(b) => write!(f, "{}", b),
             Value::CallFunc(call) => write!(f, "{}", call.get_name()),
+            Value::Closure(closure) => write!(f, "{}", closure.func.get_ident()),
+            Value::Callable(callable) => write!(f, "{}", callable.name),
             Value::Range(s, e) => write!(f, "range<{}, {}>", s, e),
             Value::Counter(ident, s, e) => write!(f, "counter<{}, {}, {}>", ident, s, e),
             Value::RefValue(r) => write!(f, "{:?}", r),
-            Value::Float(f64) => write!(f, "{}", f64),
+            Value::Float(float) if float.is_finite() && float.fract() == 0.0 => write!(f, "{:.1}", float),
+            Value::Float(float) => write!(f, "{}", float),
+            Value::Module(module) => write!(f, "module<{}>", module.get_ident()),
+            Value::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
             _ => write!(f, "Not printable"),
         }
     }
@@ -0,0 +1,409 @@
+use crate::program::value::Value;
+use crate::program::vm::bytecode::{Bytecode, FunctionProto, Op};
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+/// Type of a value known before the code runs, strings only appear in whole
+/// programs. `Void` is returned, popped and printed, never stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Ty {
+    Int,
+    Float,
+    Bool,
+    Str,
+    Void,
+}
+
+impl Ty {
+    /// The type a function declares it returns or a parameter expects
+    pub(crate) fn parse(ty: &str) -> Option<Ty> {
+        match ty {
+            "int" => Some(Ty::Int),
+            "float" => Some(Ty::Float),
+            "bool" => Some(Ty::Bool),
+            "string" => Some(Ty::Str),
+            "void" => Some(Ty::Void),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn of(value: &Value) -> Option<Ty> {
+        match value {
+            Value::Int(_) => Some(Ty::Int),
+            Value::Float(_) => Some(Ty::Float),
+            Value::Bool(_) => Some(Ty::Bool),
+            Value::String(_) => Some(Ty::Str),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Ty {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Ty::Int => write!(f, "int"),
+            Ty::Float => write!(f, "float"),
+            Ty::Bool => write!(f, "bool"),
+            Ty::Str => write!(f, "string"),
+            Ty::Void => write!(f, "void"),
+        }
+    }
+}
+
+/// Value on the stack or argument of a function
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Param {
+    Value(Ty),
+    /// Reference to a variable of the type, passed as a pointer to it
+    Ref(Ty),
+}
+
+/// Function typed for the types of its arguments. Anonymous functions leave
+/// their parameters untyped, so each list of types they're called with is a
+/// specialization of its own
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Spec {
+    pub func: u32,
+    pub params: Vec<Param>,
+}
+
+impl Spec {
+    /// Specialization of `func` called with `args`, checked against its
+    /// parameters the way the VM does when it calls it
+    pub(crate) fn of(func: u32, proto: &FunctionProto, args: &[Param]) -> Result<Spec, String> {
+        if args.len() != proto.params.len() {
+            return Err(format!("{} expects {} arguments, found {}", proto.name, proto.params.len(), args.len()));
+        }
+        let params = proto.params.iter().zip(args).map(|((name, ty), &arg)| {
+            let Some(ty) = ty else {
+                return match arg {
+                    Param::Value(Ty::Void) => Err(format!("argument {name} of {} is void", proto.name)),
+                    arg => Ok(arg),
+                };
+            };
+            match (Ty::parse(ty).filter(|&ty| ty != Ty::Void), arg) {
+                (Some(ty), Param::Value(found) | Param::Ref(found)) if ty == found => Ok(arg),
+                (Some(Ty::Float), Param::Value(Ty::Int)) => Ok(Param::Value(Ty::Float)),
+                (_, Param::Value(found) | Param::Ref(found)) => {
+                    Err(format!("argument {name} of {} expects {ty}, found {found}", proto.name))
+                }
+            }
+        });
+        Ok(Spec { func, params: params.collect::<Result<_, _>>()? })
+    }
+}
+
+/// Code the types are inferred for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// A function compiled on its own, which only works with ints, floats and
+    /// bools: no strings, globals or built-ins
+    #[cfg_attr(not(feature = "jit"), allow(dead_code))]
+    Function,
+    /// A function of a whole program, which may also use strings, globals
+    /// and `print`
+    Program,
+}
+
+/// Types of everything a specialization works with
+pub(crate) struct Typed {
+    /// Types on the stack before each instruction, `None` where it can't be reached
+    pub stacks: Vec<Option<Vec<Param>>>,
+    /// Type of every local, references for parameters passed by reference
+    pub locals: Vec<Option<Param>>,
+    /// Specializations the calls of the function call, by instruction
+    pub callees: HashMap<usize, Spec>,
+    pub rty: Ty,
+}
+
+#[derive(Clone)]
+struct State {
+    stack: Vec<Param>,
+    /// Locals assigned on every path to the instruction
+    defined: Vec<bool>,
+}
+
+/// Infers the type of every value and local of `spec` by running its code on
+/// types, and the specializations it calls. `globals` holds the types of the
+/// globals of the program assigned so far, the code may assign more.
+///
+/// Fails on what `scope` doesn't allow, on values of other types, on locals
+/// and globals holding values of different types or read before they are
+/// assigned on some path, on calls returning something other than the
+/// function should, and on the type errors the interpreter would raise
+pub(crate) fn infer(bytecode: &Bytecode, spec: &Spec, scope: Scope, globals: &mut [Option<Ty>]) -> Result<Typed> {
+    let proto = &bytecode.functions[spec.func as usize];
+    let code = &proto.code;
+    let supported = |ty: Ty| ty != Ty::Str || scope == Scope::Program;
+    let rty = Ty::parse(&proto.rty)
+        .filter(|&ty| supported(ty))
+        .ok_or_else(|| anyhow!("{}: can't return {}", proto.name, proto.rty))?;
+    let mut locals: Vec<Option<Param>> = vec![None; proto.locals as usize];
+    for (local, &param) in locals.iter_mut().zip(&spec.params) {
+        let (Param::Value(ty) | Param::Ref(ty)) = param;
+        if !supported(ty) {
+            return Err(anyhow!("{}: arguments of type {ty} aren't supported", proto.name));
+        }
+        *local = Some(param);
+    }
+    let mut callees = HashMap::new();
+
+    let mut defined = vec![false; locals.len()];
+    defined[..spec.params.len()].fill(true);
+    let mut states: Vec<Option<State>> = vec![None; code.len()];
+    states[0] = Some(State { stack: Vec::new(), defined });
+    let mut pending = vec![0];
+    while let Some(pc) = pending.pop() {
+        let State { mut stack, mut defined } = states[pc].clone().unwrap();
+        let op = code[pc];
+        let error = |message: String| match proto.line(pc) {
+            Some(line) => anyhow!("{} (line {line}): {message}", proto.name),
+            None => anyhow!("{}: {message}", proto.name),
+        };
+        let program_only = |what: &str| match scope {
+            Scope::Program => Ok(()),
+            Scope::Function => Err(error(format!("{what} aren't supported"))),
+        };
+        let mut next = Some(pc + 1);
+        match op {
+            Op::Const(id) => {
+                let value = &bytecode.constants[id as usize];
+                let ty = Ty::of(value)
+                    .filter(|&ty| supported(ty))
+                    .ok_or_else(|| error(format!("constant {value} isn't supported")))?;
+                stack.push(Param::Value(ty));
+            }
+            Op::Void => stack.push(Param::Value(Ty::Void)),
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::LoadLocal(slot) | Op::RefLocal(slot) => {
+                let slot = slot as usize;
+                let (Some(Param::Value(ty) | Param::Ref(ty)), true) = (locals[slot], defined[slot]) else {
+                    return Err(error(format!("{} may be read before it is assigned", local_name(proto, slot))));
+                };
+                stack.push(match op {
+                    Op::RefLocal(_) => Param::Ref(ty),
+                    _ => Param::Value(ty),
+                });
+            }
+            Op::DefineLocal(slot) | Op::StoreLocal(slot) => {
+                let slot = slot as usize;
+                let ty = stored(&mut stack).map_err(error)?;
+                match locals[slot] {
+                    None => locals[slot] = Some(Param::Value(ty)),
+                    // Defining a parameter passed by reference would drop the reference
+                    Some(Param::Ref(_)) if matches!(op, Op::DefineLocal(_)) => {
+                        return Err(error(format!("{} is a reference and can't be redefined", local_name(proto, slot))))
+                    }
+                    Some(Param::Value(known) | Param::Ref(known)) if known == ty => {}
+                    Some(Param::Value(known) | Param::Ref(known)) => {
+                        return Err(error(format!("{} holds {known}, found {ty}", local_name(proto, slot))))
+                    }
+                }
+                defined[slot] = true;
+            }
+            Op::LoadGlobal(id) | Op::RefGlobal(id) => {
+                program_only("globals")?;
+                let global = &bytecode.globals[id as usize];
+                let ty = globals[id as usize].ok_or_else(|| error(format!("global {} isn't supported", global.name)))?;
+                stack.push(match op {
+                    Op::RefGlobal(_) => Param::Ref(ty),
+                    _ => Param::Value(ty),
+                });
+            }
+            Op::StoreGlobal(id) => {
+                program_only("globals")?;
+                let ty = stored(&mut stack).map_err(error)?;
+                let name = &bytecode.globals[id as usize].name;
+                match globals[id as usize] {
+                    None => globals[id as usize] = Some(ty),
+                    Some(known) if known == ty => {}
+                    Some(known) => return Err(error(format!("global {name} holds {known}, found {ty}"))),
+                }
+            }
+            Op::Box | Op::Array(_) | Op::Closure { .. } => {
+                return Err(error("values on the heap aren't supported".to_string()))
+            }
+            Op::CallValue { .. } => return Err(error("calls of function values aren't supported".to_string())),
+            Op::Concat(len) => {
+                program_only("strings")?;
+                for _ in 0..len {
+                    stack.pop();
+                }
+                stack.push(Param::Value(Ty::Str));
+            }
+            Op::Neg | Op::Not => {
+                let ty = value(&mut stack).map_err(error)?;
+                let ty = unary(op, ty).ok_or_else(|| error(format!("bad operand type for {op:?}: {ty}")))?;
+                stack.push(Param::Value(ty));
+            }
+            Op::Jump(target) => {
+                merge(&mut states, &mut pending, target as usize, &stack, &defined).map_err(error)?;
+                next = None;
+            }
+            Op::JumpIfFalse(target) => {
+                let ty = value(&mut stack).map_err(error)?;
+                if ty != Ty::Bool {
+                    return Err(error(format!("expected bool, found {ty}")));
+                }
+                merge(&mut states, &mut pending, target as usize, &stack, &defined).map_err(error)?;
+            }
+            Op::Call { func, argc } | Op::TailCall { func, argc } => {
+                let start = stack.len().checked_sub(argc as usize).ok_or_else(|| error("missing arguments".to_string()))?;
+                let args = stack.split_off(start);
+                let callee = &bytecode.functions[func as usize];
+                let callee_rty = Ty::parse(&callee.rty)
+                    .filter(|&ty| supported(ty))
+                    .ok_or_else(|| error(format!("{} can't return {}", callee.name, callee.rty)))?;
+                callees.insert(pc, Spec::of(func, callee, &args).map_err(error)?);
+                match op {
+                    // The callee returns in place of the function, its result isn't checked
+                    Op::TailCall { .. } if callee_rty != rty => {
+                        return Err(error(format!("should return {rty}, {} returns {callee_rty}", callee.name)))
+                    }
+                    Op::TailCall { .. } => next = None,
+                    _ => stack.push(Param::Value(callee_rty)),
+                }
+            }
+            Op::CallNative { global, argc } => {
+                program_only("built-in functions")?;
+                let name = &bytecode.globals[global as usize].name;
+                if name != "print" {
+                    return Err(error(format!("built-in function {name} isn't supported")));
+                }
+                if argc == 0 {
+                    return Err(error("print needs an argument".to_string()));
+                }
+                for _ in 0..argc {
+                    value(&mut stack).map_err(error)?;
+                }
+                stack.push(Param::Value(Ty::Void));
+            }
+            Op::Return => {
+                let ty = value(&mut stack).map_err(error)?;
+                if !(ty == rty && ty != Ty::Void || ty == Ty::Int && rty == Ty::Float) {
+                    return Err(error(format!("should return {rty}, found {ty}")));
+                }
+                next = None;
+            }
+            // Functions that should return a value but don't leave `void` to their caller
+            Op::ReturnVoid if rty != Ty::Void => return Err(error(format!("should return {rty} on every path"))),
+            Op::ReturnVoid => next = None,
+            Op::NameError(_) | Op::TypeError(_) => return Err(error("raising errors isn't supported".to_string())),
+            op => {
+                let rhs = value(&mut stack).map_err(error)?;
+                let lhs = value(&mut stack).map_err(error)?;
+                let ty = binary(op, lhs, rhs)
+                    .ok_or_else(|| error(format!("unsupported operand types for {op:?}: {lhs} and {rhs}")))?;
+                stack.push(Param::Value(ty));
+            }
+        }
+        if let Some(next) = next {
+            if next >= code.len() {
+                return Err(error("the code runs past its end".to_string()));
+            }
+            merge(&mut states, &mut pending, next, &stack, &defined).map_err(error)?;
+        }
+    }
+
+    Ok(Typed {
+        stacks: states.into_iter().map(|state| state.map(|state| state.stack)).collect(),
+        locals,
+        callees,
+        rty,
+    })
+}
+
+/// Instructions starting a block: the first one, jump targets and the
+/// instructions after conditional jumps
+pub(crate) fn leaders(code: &[Op]) -> Vec<usize> {
+    let mut leaders = vec![0];
+    for (pc, op) in code.iter().enumerate() {
+        match *op {
+            Op::Jump(target) => leaders.push(target as usize),
+            Op::JumpIfFalse(target) => leaders.extend([target as usize, pc + 1]),
+            _ => {}
+        }
+    }
+    leaders
+}
+
+/// How errors refer to the local in `slot`, only parameters have names
+fn local_name(proto: &FunctionProto, slot: usize) -> String {
+    match proto.params.get(slot) {
+        Some((name, _)) => name.clone(),
+        None => format!("local {slot}"),
+    }
+}
+
+/// Pops a value, references are only passed to functions
+fn value(stack: &mut Vec<Param>) -> Result<Ty, String> {
+    match stack.pop() {
+        Some(Param::Value(ty)) => Ok(ty),
+        _ => Err("a reference is used as a value".to_string()),
+    }
+}
+
+/// Pops a value assigned to a variable, which can't be `void`
+fn stored(stack: &mut Vec<Param>) -> Result<Ty, String> {
+    match value(stack)? {
+        Ty::Void => Err("void is assigned to a variable".to_string()),
+        ty => Ok(ty),
+    }
+}
+
+/// Joins the state flowing into `target`, which needs to be looked at again
+/// when it changed
+fn merge(states: &mut [Option<State>], pending: &mut Vec<usize>, target: usize, stack: &[Param], defined: &[bool]) -> Result<(), String> {
+    match &mut states[target] {
+        Some(state) if state.stack != stack => {
+            return Err(format!("values of different types meet at {target}: {:?} and {stack:?}", state.stack))
+        }
+        Some(state) => {
+            let mut changed = false;
+            for (known, &defined) in state.defined.iter_mut().zip(defined) {
+                if *known && !defined {
+                    *known = false;
+                    changed = true;
+                }
+            }
+            if changed {
+                pending.push(target);
+            }
+        }
+        None => {
+            states[target] = Some(State { stack: stack.to_vec(), defined: defined.to_vec() });
+            pending.push(target);
+        }
+    }
+    Ok(())
+}
+
+/// Type of the result of unary `op`, `None` when the interpreter raises a type error
+pub(crate) fn unary(op: Op, ty: Ty) -> Option<Ty> {
+    match (op, ty) {
+        (Op::Neg, Ty::Int | Ty::Float) | (Op::Not, Ty::Int | Ty::Bool) => Some(ty),
+        _ => None,
+    }
+}
+
+/// Type of the result of binary `op`, `None` when the interpreter raises a
+/// type error or the operands are compared as different kinds of values
+pub(crate) fn binary(op: Op, lhs: Ty, rhs: Ty) -> Option<Ty> {
+    let numbers = matches!(lhs, Ty::Int | Ty::Float) && matches!(rhs, Ty::Int | Ty::Float);
+    let bools = lhs == Ty::Bool && rhs == Ty::Bool;
+    let ints = lhs == Ty::Int && rhs == Ty::Int;
+    match op {
+        Op::Add if lhs == Ty::Str && rhs == Ty::Str => Some(Ty::Str),
+        Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod if ints => Some(Ty::Int),
+        Op::Add | Op::Sub | Op::Mul | Op::Div | Op::Mod if numbers => Some(Ty::Float),
+        Op::Xor | Op::BitAnd | Op::BitOr if ints || bools => Some(lhs),
+        Op::Shl | Op::Shr if ints => Some(Ty::Int),
+        Op::Eq | Op::Ne | Op::Gt | Op::Lt | Op::Ge | Op::Le if numbers || bools => Some(Ty::Bool),
+        Op::Eq | Op::Ne | Op::Gt | Op::Lt | Op::Ge | Op::Le if lhs == Ty::Str && rhs == Ty::Str => Some(Ty::Bool),
+        Op::And | Op::Or if bools => Some(Ty::Bool),
+        _ => None,
+    }
+}
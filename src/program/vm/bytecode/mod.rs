@@ -0,0 +1,142 @@
+use crate::program::gc::{Node, Trace, Tracer};
+use crate::program::value::Value;
+use serde::Serialize;
+use crate::program::shared::Shared;
+
+/// Instruction of the stack VM. Operands index the constant pool, the slots
+/// of the current frame, the global table or the function table
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Op {
+    Const(u32),
+    Void,
+    Pop,
+    /// Pushes a local, reading through it when the slot aliases a referenced cell
+    LoadLocal(u16),
+    /// Binds a fresh value to a slot, dropping any alias it had
+    DefineLocal(u16),
+    /// Assigns to a local, writing through to the referenced cell when it aliases one
+    StoreLocal(u16),
+    /// Pushes a reference to a local, moving the local into a shared cell first
+    RefLocal(u16),
+    LoadGlobal(u32),
+    StoreGlobal(u32),
+    RefGlobal(u32),
+    /// Moves the value on top of the stack into a new cell and pushes a reference to it
+    Box,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Xor,
+    BitAnd,
+    BitOr,
+    Shl,
+    Shr,
+    Neg,
+    Not,
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+    And,
+    Or,
+    Array(u32),
+    /// Joins the string forms of the top values, used by string interpolation
+    Concat(u32),
+    Jump(u32),
+    JumpIfFalse(u32),
+    Call { func: u32, argc: u32 },
+    /// Calls a function in place of the current one, which returns what the callee returns
+    TailCall { func: u32, argc: u32 },
+    /// Calls the built-in function stored in a global
+    CallNative { global: u32, argc: u32 },
+    /// Pops the first `captured` parameters of `func` and pushes a `Callable`
+    /// of it binding them
+    Closure { func: u32, captured: u32 },
+    /// Calls the function value below the arguments, which the code calls by
+    /// the name in the constant `name`
+    CallValue { name: u32, argc: u32 },
+    /// Returns the value on top of the stack, checked against the return type
+    Return,
+    ReturnVoid,
+    /// Raises a `NameError` with the message in the constant, compiled for a
+    /// name the compiler couldn't resolve
+    NameError(u32),
+    /// Raises a `TypeError` with the message in the constant, compiled for a
+    /// call or assignment that can't be made
+    TypeError(u32),
+}
+
+/// Function value of the VM, a compiled function with the values of its first
+/// parameters bound to it. Anonymous functions bind the cells of the locals
+/// their arguments read, named functions don't bind anything
+#[derive(Clone, Debug)]
+pub struct Callable {
+    pub func: u32,
+    /// Name the value is printed with
+    pub name: String,
+    /// Type of the value, the return type for named functions as on the tree backend
+    pub ty: String,
+    pub bound: Vec<Value>,
+}
+
+impl Trace for Callable {
+    fn trace(&self, tracer: &mut Tracer) {
+        self.bound.iter().for_each(|value| value.trace(tracer));
+    }
+}
+
+/// Callables are shared by the values they were copied to
+impl Node for Callable {
+    fn trace(&self, tracer: &mut Tracer) -> bool {
+        Trace::trace(self, tracer);
+        true
+    }
+
+    fn clear(&self) {}
+}
+
+/// Compiled function. Parameters take the first slots of the frame, anonymous
+/// functions leave their parameters untyped.
+///
+/// `lines` maps the first instruction of each source line to that line of
+/// `file`, both are empty when the program wasn't loaded from files
+#[derive(Clone, Debug, Default)]
+pub struct FunctionProto {
+    pub name: String,
+    pub params: Vec<(String, Option<String>)>,
+    pub rty: String,
+    pub locals: u16,
+    pub code: Vec<Op>,
+    pub file: Option<String>,
+    pub lines: Vec<(u32, u32)>,
+}
+
+impl FunctionProto {
+    /// Source line of the instruction at `pc`
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        let i = self.lines.partition_point(|&(start, _)| start as usize <= pc);
+        i.checked_sub(1).map(|i| self.lines[i].1)
+    }
+}
+
+/// Module item or global the code reads by index
+#[derive(Clone, Debug)]
+pub struct Global {
+    pub name: String,
+    pub cell: Shared<Value>,
+}
+
+/// Program compiled for the VM, `entry` being the index of `main`. The `init`
+/// functions set the module-level globals up and run before it, in order
+#[derive(Clone, Debug, Default)]
+pub struct Bytecode {
+    pub constants: Vec<Value>,
+    pub functions: Vec<FunctionProto>,
+    pub globals: Vec<Global>,
+    pub init: Vec<u32>,
+    pub entry: u32,
+}
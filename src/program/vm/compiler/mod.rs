@@ -0,0 +1,651 @@
+use crate::ast::{AnonymousFunc, CallExpr, Expr, GlobalIdent, InlineAccess, Stmt, VarAssign, VarIdent};
+use crate::program::environment::LocalEnvironment;
+use crate::program::evaluating_functions::{flatten_path, lookup_name, path_item, resolve_path};
+use crate::program::function::Function;
+use crate::program::globals;
+use crate::program::loader::SourceMap;
+use crate::program::optimizer::visit;
+use crate::program::value::Value;
+use crate::program::vm::bytecode::{Bytecode, FunctionProto, Global, Op};
+use crate::program::global_env;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use crate::program::shared::Shared;
+
+/// Compiles the functions reachable from `main` once the program is set up.
+///
+/// Names are resolved at compile time the way the evaluator resolves them when
+/// the code runs: locals first, then items of the current module, then globals.
+/// Names that don't resolve and calls that can't be made compile to code
+/// raising the error, which the program only gets once it runs that code.
+/// `if`, `for` and `while` called with anonymous functions or `$f|...|` branches
+/// become jumps, their branches being called in place with arguments evaluated
+/// in the caller's frame. Other anonymous functions and named functions used as
+/// values become `Callable`s. Module-level globals get an initializer function
+/// each, run in dependency order before `main`
+#[derive(Default)]
+pub struct Compiler {
+    constants: Vec<Value>,
+    constant_ids: HashMap<String, u32>,
+    functions: Vec<FunctionProto>,
+    function_ids: HashMap<(Vec<String>, String), u32>,
+    globals: Vec<Global>,
+    global_ids: HashMap<usize, u32>,
+    pending: Vec<(u32, Function)>,
+    /// Files the program was loaded from, used for the line tables
+    sources: SourceMap,
+}
+
+impl Compiler {
+    pub(crate) fn compile(main: &Function, globals: &[globals::Global], sources: SourceMap) -> Result<Bytecode> {
+        let mut compiler = Compiler { sources, ..Compiler::default() };
+        let mut init = Vec::with_capacity(globals.len());
+        for global in globals {
+            let name = global.name();
+            let id = compiler.global(name.clone(), global.cell.clone());
+            let mut initializer =
+                FunctionCompiler::new(&mut compiler, global.scope.clone(), format!("{name}::<init>"), vec![], "void".into());
+            initializer.expr(&global.expr)?;
+            initializer.emit(Op::StoreGlobal(id));
+            let proto = initializer.compile(&[], &[])?;
+            init.push(compiler.functions.len() as u32);
+            compiler.functions.push(proto);
+        }
+        let entry = compiler.function_id(main);
+        while let Some((id, func)) = compiler.pending.pop() {
+            let name = qualified_name(func.get_module_path(), func.get_ident());
+            let params = func.get_args().iter().map(|(ident, ty)| (ident.clone(), Some(ty.clone()))).collect();
+            let proto = FunctionCompiler::new(&mut compiler, func.get_module_path().to_vec(), name, params, func.get_rty().to_string())
+                .compile(func.get_body(), func.get_offsets())?;
+            compiler.functions[id as usize] = proto;
+        }
+        Ok(Bytecode {
+            constants: compiler.constants,
+            functions: compiler.functions,
+            globals: compiler.globals,
+            init,
+            entry,
+        })
+    }
+
+    /// Index of `func` in the function table, queueing it for compilation on first use
+    fn function_id(&mut self, func: &Function) -> u32 {
+        let key = (func.get_module_path().to_vec(), func.get_ident().to_string());
+        if let Some(&id) = self.function_ids.get(&key) {
+            return id;
+        }
+        let id = self.functions.len() as u32;
+        self.functions.push(FunctionProto::default());
+        self.function_ids.insert(key, id);
+        self.pending.push((id, func.clone()));
+        id
+    }
+
+    fn constant(&mut self, value: Value) -> u32 {
+        let key = format!("{value:?}");
+        if let Some(&id) = self.constant_ids.get(&key) {
+            return id;
+        }
+        let id = self.constants.len() as u32;
+        self.constants.push(value);
+        self.constant_ids.insert(key, id);
+        id
+    }
+
+    fn global(&mut self, name: String, cell: Shared<Value>) -> u32 {
+        let key = cell.as_ptr() as usize;
+        if let Some(&id) = self.global_ids.get(&key) {
+            return id;
+        }
+        let id = self.globals.len() as u32;
+        self.globals.push(Global { name, cell });
+        self.global_ids.insert(key, id);
+        id
+    }
+}
+
+fn qualified_name(module_path: &[String], ident: &str) -> String {
+    module_path.iter().map(String::as_str).chain([ident]).collect::<Vec<_>>().join("::")
+}
+
+struct FunctionCompiler<'c> {
+    compiler: &'c mut Compiler,
+    /// Environment of the function's module, used to resolve names that aren't locals
+    scope: Shared<LocalEnvironment>,
+    module_path: Vec<String>,
+    name: String,
+    params: Vec<(String, Option<String>)>,
+    rty: String,
+    locals: HashMap<String, u16>,
+    slots: u16,
+    code: Vec<Op>,
+}
+
+impl<'c> FunctionCompiler<'c> {
+    fn new(
+        compiler: &'c mut Compiler,
+        module_path: Vec<String>,
+        name: String,
+        params: Vec<(String, Option<String>)>,
+        rty: String,
+    ) -> Self {
+        let mut function = Self {
+            compiler,
+            scope: Shared::new(LocalEnvironment::in_module(module_path.clone())),
+            module_path,
+            name,
+            params: params.clone(),
+            rty,
+            locals: HashMap::new(),
+            slots: 0,
+            code: Vec::new(),
+        };
+        for (ident, _) in &params {
+            function.declare(ident);
+        }
+        function
+    }
+
+    /// Compiles `body`, `offsets` being the source offsets of its statements
+    fn compile(mut self, body: &[Stmt], offsets: &[usize]) -> Result<FunctionProto> {
+        let file = self.compiler.sources.file(&self.module_path).cloned();
+        let mut lines: Vec<(u32, u32)> = Vec::new();
+        let tail = body.iter().rposition(|stmt| !matches!(stmt, Stmt::Comment(_)));
+        for (i, stmt) in body.iter().enumerate() {
+            if let (Some(file), Some(&offset)) = (&file, offsets.get(i)) {
+                let line = file.line(offset);
+                if lines.last().is_none_or(|&(_, last)| last != line) {
+                    lines.push((self.code.len() as u32, line));
+                }
+            }
+            self.stmt(stmt, Some(i) == tail)?;
+        }
+        self.code.push(Op::ReturnVoid);
+        Ok(FunctionProto {
+            name: self.name,
+            params: self.params,
+            rty: self.rty,
+            locals: self.slots,
+            code: self.code,
+            file: file.filter(|_| !lines.is_empty()).map(|file| file.path.display().to_string()),
+            lines,
+        })
+    }
+
+    /// Binds `ident` to a new slot, later uses of the name refer to it
+    fn declare(&mut self, ident: &str) -> u16 {
+        let slot = self.new_slot();
+        self.locals.insert(ident.to_string(), slot);
+        slot
+    }
+
+    fn new_slot(&mut self) -> u16 {
+        let slot = self.slots;
+        self.slots += 1;
+        slot
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len() as u32;
+        match &mut self.code[at] {
+            Op::Jump(to) | Op::JumpIfFalse(to) => *to = target,
+            op => unreachable!("{op:?} is not a jump"),
+        }
+    }
+
+    fn constant(&mut self, value: Value) {
+        let id = self.compiler.constant(value);
+        self.emit(Op::Const(id));
+    }
+
+    /// Compiles `stmt`, `tail` telling whether it is the last statement of the body
+    fn stmt(&mut self, stmt: &Stmt, tail: bool) -> Result<()> {
+        match stmt {
+            // The value of the last statement is dropped, only a call returning nothing can take its place
+            Stmt::Expr(expr) => {
+                match expr.as_ref() {
+                    Expr::Call(call_expr) if tail => self.call(call_expr, Some("void"))?,
+                    expr => self.expr(expr)?,
+                }
+                self.emit(Op::Pop);
+            }
+            Stmt::VarIdent(VarIdent { ident, expr }) | Stmt::Global(GlobalIdent { ident, expr, .. }) => {
+                self.expr(expr)?;
+                let slot = self.declare(ident);
+                self.emit(Op::DefineLocal(slot));
+            }
+            Stmt::VarAssign(VarAssign { ident, expr }) => {
+                self.expr(expr)?;
+                if let Some(&slot) = self.locals.get(ident) {
+                    self.emit(Op::StoreLocal(slot));
+                    return Ok(());
+                }
+                match lookup_name(ident, &self.scope) {
+                    Some((_, true)) => self.raise(Op::TypeError, format!("cannot assign to constant {ident}")),
+                    Some((cell, false)) => {
+                        let global = self.compiler.global(ident.clone(), cell);
+                        self.emit(Op::StoreGlobal(global));
+                    }
+                    None => self.raise(Op::NameError, format!("{ident} not found")),
+                }
+            }
+            Stmt::ReturnValue(expr) => {
+                match expr.as_ref() {
+                    Expr::Call(call_expr) => self.call(call_expr, Some(&self.rty.clone()))?,
+                    expr => self.expr(expr)?,
+                }
+                self.emit(Op::Return);
+            }
+            Stmt::Comment(_) => {}
+            stmt => return Err(anyhow!("Unhandled statement in {}: {stmt:?}", self.name)),
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<()> {
+        match expr {
+            Expr::Integer(i) => self.constant(Value::Int(*i)),
+            Expr::BigInteger(digits) => self.constant(Value::BigInt(digits.parse()?)),
+            Expr::Float(f) => self.constant(Value::Float(*f)),
+            Expr::Bool(b) => self.constant(Value::Bool(*b)),
+            Expr::StringLit(s) => self.constant(Value::String(s.clone())),
+            Expr::Range((start, end)) => self.constant(Value::Range(*start, *end)),
+            Expr::Ident(ident) | Expr::Local(ident, _) => {
+                if let Some(&slot) = self.locals.get(ident) {
+                    self.emit(Op::LoadLocal(slot));
+                    return Ok(());
+                }
+                match lookup_name(ident, &self.scope) {
+                    Some((cell, _)) => self.load_global(ident.clone(), cell),
+                    None => self.raise(Op::NameError, format!("{ident} not found")),
+                }
+            }
+            Expr::Global(ident, id) => {
+                let cell = global_env().borrow().global(*id).clone();
+                self.load_global(ident.clone(), cell);
+            }
+            Expr::Ref(expr) => match expr.as_ref() {
+                Expr::Ident(ident) | Expr::Local(ident, _) => {
+                    if let Some(&slot) = self.locals.get(ident) {
+                        self.emit(Op::RefLocal(slot));
+                        return Ok(());
+                    }
+                    let Some((cell, constant)) = lookup_name(ident, &self.scope) else {
+                        self.raise(Op::NameError, format!("{ident} not found"));
+                        return Ok(());
+                    };
+                    let global = self.compiler.global(ident.clone(), cell);
+                    // Constants are referenced through a copy so they can't be changed
+                    if constant {
+                        self.emit(Op::LoadGlobal(global));
+                        self.emit(Op::Box);
+                    } else {
+                        self.emit(Op::RefGlobal(global));
+                    }
+                }
+                expr => {
+                    self.expr(expr)?;
+                    self.emit(Op::Box);
+                }
+            },
+            Expr::Array(items) => {
+                for item in items {
+                    self.expr(item)?;
+                }
+                self.emit(Op::Array(items.len() as u32));
+            }
+            Expr::Dictionary(_) => {
+                self.emit(Op::Void);
+            }
+            Expr::Concat(parts) => {
+                for part in parts {
+                    self.expr(part)?;
+                }
+                self.emit(Op::Concat(parts.len() as u32));
+            }
+            Expr::Call(call_expr) => self.call(call_expr, None)?,
+            Expr::InlineAccess(inline_access) => self.inline_access(inline_access)?,
+            Expr::Add(l, r) => self.binary(l, r, Op::Add)?,
+            Expr::Sub(l, r) => self.binary(l, r, Op::Sub)?,
+            Expr::Mul(l, r) => self.binary(l, r, Op::Mul)?,
+            Expr::Div(l, r) => self.binary(l, r, Op::Div)?,
+            Expr::Mod(l, r) => self.binary(l, r, Op::Mod)?,
+            Expr::Xor(l, r) => self.binary(l, r, Op::Xor)?,
+            Expr::BitAnd(l, r) => self.binary(l, r, Op::BitAnd)?,
+            Expr::BitOr(l, r) => self.binary(l, r, Op::BitOr)?,
+            Expr::Shl(l, r) => self.binary(l, r, Op::Shl)?,
+            Expr::Shr(l, r) => self.binary(l, r, Op::Shr)?,
+            Expr::Eq(l, r) => self.binary(l, r, Op::Eq)?,
+            Expr::NotEq(l, r) => self.binary(l, r, Op::Ne)?,
+            Expr::Gt(l, r) => self.binary(l, r, Op::Gt)?,
+            Expr::Lt(l, r) => self.binary(l, r, Op::Lt)?,
+            Expr::Ge(l, r) => self.binary(l, r, Op::Ge)?,
+            Expr::Le(l, r) => self.binary(l, r, Op::Le)?,
+            Expr::And(l, r) => self.binary(l, r, Op::And)?,
+            Expr::Or(l, r) => self.binary(l, r, Op::Or)?,
+            Expr::Neg(expr) => {
+                self.expr(expr)?;
+                self.emit(Op::Neg);
+            }
+            Expr::Not(expr) => {
+                self.expr(expr)?;
+                self.emit(Op::Not);
+            }
+            Expr::AnonFunc(anon_func) => self.closure(anon_func)?,
+            // The call is only made by the built-in functions it is passed to, as on the tree backend
+            Expr::Func(func_ptr) => {
+                let args = func_ptr.args.clone().unwrap_or_default();
+                self.constant(Value::CallFunc(CallExpr::new(func_ptr.ident.clone(), args).with_target(func_ptr.target)))
+            }
+            Expr::Counter((ident, _)) => return Err(anyhow!("{}: counter {ident} is only allowed in for", self.name)),
+        }
+        Ok(())
+    }
+
+    fn binary(&mut self, lhs: &Expr, rhs: &Expr, op: Op) -> Result<()> {
+        self.expr(lhs)?;
+        self.expr(rhs)?;
+        self.emit(op);
+        Ok(())
+    }
+
+    /// Compiles a call, as a tail call when `tail` is the return type of the
+    /// enclosing function and the callee returns the same type
+    fn call(&mut self, call_expr: &CallExpr, tail: Option<&str>) -> Result<()> {
+        let ident = call_expr.get_name();
+        let cell = match call_expr.get_target() {
+            Some(id) => global_env().borrow().global(id).clone(),
+            None => match lookup_name(&ident, &self.scope) {
+                Some((cell, _)) => cell,
+                // Locals are looked up last, they may hold function values
+                None if self.locals.contains_key(&ident) => {
+                    self.emit(Op::LoadLocal(self.locals[&ident]));
+                    self.args(call_expr.args())?;
+                    let name = self.compiler.constant(Value::String(ident));
+                    self.emit(Op::CallValue { name, argc: call_expr.args().len() as u32 });
+                    return Ok(());
+                }
+                None => {
+                    self.raise(Op::NameError, format!("Function {ident} not found"));
+                    return Ok(());
+                }
+            },
+        };
+        self.callee(ident, cell, &call_expr.get_args(), tail)
+    }
+
+    fn inline_access(&mut self, inline_access: &InlineAccess) -> Result<()> {
+        let (idents, terminal) = flatten_path(inline_access.clone());
+        let container = match resolve_path(&idents, &self.module_path) {
+            Ok(container) => container,
+            Err(message) => {
+                self.raise(Op::NameError, message);
+                return Ok(());
+            }
+        };
+        let module_name = container.as_ref().map_or("crate".to_string(), |module| module.get_path().join("::"));
+        match terminal {
+            Some(Expr::Call(call_expr)) => {
+                let ident = call_expr.get_name();
+                match path_item(&container, &ident, &self.module_path) {
+                    Some(cell) => self.callee(format!("{}::{ident}", idents.join("::")), cell, &call_expr.get_args(), None)?,
+                    None => self.raise(Op::NameError, format!("Function {ident} not found in {module_name}")),
+                }
+                Ok(())
+            }
+            Some(Expr::Ident(ident)) => {
+                match path_item(&container, &ident, &self.module_path) {
+                    Some(cell) => self.load_global(format!("{}::{ident}", idents.join("::")), cell),
+                    None => self.raise(Op::NameError, format!("{ident} not found in {module_name}")),
+                }
+                Ok(())
+            }
+            _ => {
+                self.raise(Op::TypeError, format!("expected a name or a call after {}::", idents.join("::")));
+                Ok(())
+            }
+        }
+    }
+
+    /// Calls the function stored in `cell` with `args`
+    fn callee(&mut self, name: String, cell: Shared<Value>, args: &[Expr], tail: Option<&str>) -> Result<()> {
+        let value = cell.get();
+        match value {
+            Value::Func(func) => {
+                self.args(args)?;
+                if args.len() != func.get_args().len() {
+                    let message = format!("{name} expects {} arguments, found {}", func.get_args().len(), args.len());
+                    self.raise(Op::TypeError, message);
+                    return Ok(());
+                }
+                let argc = args.len() as u32;
+                match tail == Some(func.get_rty()) {
+                    true => {
+                        let func = self.compiler.function_id(&func);
+                        self.emit(Op::TailCall { func, argc })
+                    }
+                    false => {
+                        let func = self.compiler.function_id(&func);
+                        self.emit(Op::Call { func, argc })
+                    }
+                };
+                Ok(())
+            }
+            Value::FuncPtr(_) => {
+                let inlined = match builtin(&cell) {
+                    Some("if") => self.if_branches(args, tail)?,
+                    Some("for") => self.for_loop(args)?,
+                    Some("while") => self.while_loop(args)?,
+                    _ => false,
+                };
+                if inlined {
+                    return Ok(());
+                }
+                self.args(args)?;
+                let global = self.compiler.global(name, cell);
+                self.emit(Op::CallNative { global, argc: args.len() as u32 });
+                Ok(())
+            }
+            _ => {
+                self.args(args)?;
+                self.raise(Op::TypeError, format!("{name} is not a function"));
+                Ok(())
+            }
+        }
+    }
+
+    /// Pushes the value of the global in `cell`, named functions becoming `Callable`s
+    fn load_global(&mut self, name: String, cell: Shared<Value>) {
+        let value = cell.get();
+        match value {
+            Value::Func(func) => {
+                let func = self.compiler.function_id(&func);
+                self.emit(Op::Closure { func, captured: 0 });
+            }
+            _ => {
+                let global = self.compiler.global(name, cell);
+                self.emit(Op::LoadGlobal(global));
+            }
+        }
+    }
+
+    fn args(&mut self, args: &[Expr]) -> Result<()> {
+        for arg in args {
+            self.expr(arg)?;
+        }
+        Ok(())
+    }
+
+    /// Compiles code raising the error `op` makes with `message` when it runs,
+    /// as the evaluator only raises it once it gets there. Nothing after it runs
+    fn raise(&mut self, op: fn(u32) -> Op, message: String) {
+        let id = self.compiler.constant(Value::String(message));
+        self.emit(op(id));
+    }
+
+    /// `if(cond, then, else)` with callable branches, the missing else branch gives
+    /// `None`. The branches are tail calls when the `if` is
+    fn if_branches(&mut self, args: &[Expr], tail: Option<&str>) -> Result<bool> {
+        if !(2..=3).contains(&args.len()) || !args[1..].iter().all(is_callable) {
+            return Ok(false);
+        }
+        self.expr(&args[0])?;
+        let to_else = self.emit(Op::JumpIfFalse(0));
+        self.invoke(&args[1], tail)?;
+        let to_end = self.emit(Op::Jump(0));
+        self.patch(to_else);
+        match args.get(2) {
+            Some(branch) => self.invoke(branch, tail)?,
+            None => {
+                self.emit(Op::Void);
+            }
+        }
+        self.patch(to_end);
+        Ok(true)
+    }
+
+    /// `for(i in a..b, f)` or `for(a..b, f)`, the counter stays bound after the loop
+    fn for_loop(&mut self, args: &[Expr]) -> Result<bool> {
+        let (counter, (start, end)) = match args {
+            [Expr::Counter((ident, range)), body] if is_callable(body) => (Some(ident), *range),
+            [Expr::Range(range), body] if is_callable(body) => (None, *range),
+            _ => return Ok(false),
+        };
+        let index = self.new_slot();
+        let counter = counter.map(|ident| self.declare(ident));
+        self.constant(Value::Int(start));
+        self.emit(Op::DefineLocal(index));
+        if let Some(counter) = counter {
+            self.emit(Op::LoadLocal(index));
+            self.emit(Op::DefineLocal(counter));
+        }
+
+        let start = self.code.len() as u32;
+        self.emit(Op::LoadLocal(index));
+        self.constant(Value::Int(end));
+        self.emit(Op::Lt);
+        let to_end = self.emit(Op::JumpIfFalse(0));
+        if let Some(counter) = counter {
+            self.emit(Op::LoadLocal(index));
+            self.emit(Op::DefineLocal(counter));
+        }
+        self.invoke(&args[1], None)?;
+        self.emit(Op::Pop);
+        self.emit(Op::LoadLocal(index));
+        self.constant(Value::Int(1));
+        self.emit(Op::Add);
+        self.emit(Op::DefineLocal(index));
+        self.emit(Op::Jump(start));
+        self.patch(to_end);
+        self.emit(Op::Void);
+        Ok(true)
+    }
+
+    fn while_loop(&mut self, args: &[Expr]) -> Result<bool> {
+        let [cond, body] = args else { return Ok(false) };
+        if !is_callable(body) {
+            return Ok(false);
+        }
+        let start = self.code.len() as u32;
+        self.expr(cond)?;
+        let to_end = self.emit(Op::JumpIfFalse(0));
+        self.invoke(body, None)?;
+        self.emit(Op::Pop);
+        self.emit(Op::Jump(start));
+        self.patch(to_end);
+        self.emit(Op::Void);
+        Ok(true)
+    }
+
+    /// Calls an anonymous function or `$f|...|` in place
+    fn invoke(&mut self, callable: &Expr, tail: Option<&str>) -> Result<()> {
+        match callable {
+            Expr::AnonFunc(anon_func) => {
+                let func = self.anonymous(anon_func)?;
+                for (_, arg) in &anon_func.args {
+                    self.expr(arg)?;
+                }
+                let argc = anon_func.args.len() as u32;
+                match tail == Some(anon_func.rty.as_str()) {
+                    true => self.emit(Op::TailCall { func, argc }),
+                    false => self.emit(Op::Call { func, argc }),
+                };
+                Ok(())
+            }
+            Expr::Func(func_ptr) => {
+                let args = func_ptr.args.clone().unwrap_or_default();
+                self.call(&CallExpr::new(func_ptr.ident.clone(), args).with_target(func_ptr.target), tail)
+            }
+            _ => unreachable!("branches are checked with is_callable"),
+        }
+    }
+
+    /// Makes a `Callable` of `anon_func`. Its arguments are evaluated each time
+    /// it is called as on the tree backend, so the function takes the cells of
+    /// the locals they read as parameters and evaluates them first. Its body
+    /// only sees the arguments
+    fn closure(&mut self, anon_func: &AnonymousFunc) -> Result<()> {
+        let mut captured: Vec<String> = Vec::new();
+        for (_, arg) in &anon_func.args {
+            visit(arg, &mut |expr| {
+                if let Expr::Ident(ident) | Expr::Local(ident, _) = expr {
+                    if self.locals.contains_key(ident) && !captured.contains(ident) {
+                        captured.push(ident.clone());
+                    }
+                }
+            });
+        }
+        for ident in &captured {
+            self.emit(Op::RefLocal(self.locals[ident]));
+        }
+
+        let params = captured.iter().map(|ident| (ident.clone(), None)).collect();
+        let name = format!("{}::<anonymous>", self.name);
+        let mut function =
+            FunctionCompiler::new(self.compiler, self.module_path.clone(), name, params, anon_func.rty.clone());
+        for (_, arg) in &anon_func.args {
+            function.expr(arg)?;
+        }
+        function.locals.clear();
+        let slots: Vec<_> = anon_func.args.iter().map(|(ident, _)| function.declare(ident)).collect();
+        for slot in slots.into_iter().rev() {
+            function.emit(Op::DefineLocal(slot));
+        }
+        let (body, offsets) = anon_func.stmt.as_ref().map_or((&[][..], &[][..]), |body| (&body.stmt, &body.offsets));
+        let proto = function.compile(body, offsets)?;
+        let func = self.compiler.functions.len() as u32;
+        self.compiler.functions.push(proto);
+        self.emit(Op::Closure { func, captured: captured.len() as u32 });
+        Ok(())
+    }
+
+    fn anonymous(&mut self, anon_func: &AnonymousFunc) -> Result<u32> {
+        let params = anon_func.args.iter().map(|(ident, _)| (ident.clone(), None)).collect();
+        let name = format!("{}::<anonymous>", self.name);
+        let (body, offsets) = anon_func.stmt.as_ref().map_or((&[][..], &[][..]), |body| (&body.stmt, &body.offsets));
+        let proto = FunctionCompiler::new(self.compiler, self.module_path.clone(), name, params, anon_func.rty.clone())
+            .compile(body, offsets)?;
+        let id = self.compiler.functions.len() as u32;
+        self.compiler.functions.push(proto);
+        Ok(id)
+    }
+}
+
+fn is_callable(expr: &Expr) -> bool {
+    matches!(expr, Expr::AnonFunc(_) | Expr::Func(_))
+}
+
+/// Name of the built-in control flow function stored in `cell`, if any
+fn builtin(cell: &Shared<Value>) -> Option<&'static str> {
+    let global_env = global_env();
+    let global = global_env.borrow();
+    ["if", "for", "while"]
+        .into_iter()
+        .find(|name| global.global_stmts.get(*name).is_some_and(|builtin| Shared::ptr_eq(builtin, cell)))
+}
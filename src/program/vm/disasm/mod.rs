@@ -0,0 +1,127 @@
+use crate::program::value::Value;
+use crate::program::vm::bytecode::{Bytecode, FunctionProto, Op};
+use serde_json::{json, Value as Json};
+use std::fmt::Write;
+
+/// Lists the tables and the code of every function. Each instruction is
+/// preceded by its source line where one starts and followed by what its
+/// operand refers to
+pub fn disassemble(bytecode: &Bytecode) -> String {
+    let mut out = String::new();
+    writeln!(out, "constants:").unwrap();
+    for (i, value) in bytecode.constants.iter().enumerate() {
+        writeln!(out, "  {i:>4}  {}", constant(value)).unwrap();
+    }
+    writeln!(out, "globals:").unwrap();
+    for (i, global) in bytecode.globals.iter().enumerate() {
+        writeln!(out, "  {i:>4}  {}", global.name).unwrap();
+    }
+    for (i, func) in bytecode.functions.iter().enumerate() {
+        let role = if i as u32 == bytecode.entry {
+            " (entry)"
+        } else if bytecode.init.contains(&(i as u32)) {
+            " (init)"
+        } else {
+            ""
+        };
+        writeln!(out).unwrap();
+        writeln!(out, "func #{i} {}{}{role}", func.name, signature(func)).unwrap();
+        if let Some(file) = &func.file {
+            writeln!(out, "  file {file}").unwrap();
+        }
+        for (pc, op) in func.code.iter().enumerate() {
+            let line = match func.lines.iter().find(|&&(start, _)| start as usize == pc) {
+                Some((_, line)) => line.to_string(),
+                None => String::new(),
+            };
+            let op_text = format!("{op:?}");
+            match comment(bytecode, func, op) {
+                Some(comment) => writeln!(out, "  {line:>5} {pc:>5}  {op_text:<32} ; {comment}").unwrap(),
+                None => writeln!(out, "  {line:>5} {pc:>5}  {op_text}").unwrap(),
+            }
+        }
+    }
+    out
+}
+
+/// The same listing as `disassemble` as JSON, instructions being serialized
+/// like `{"Const": 0}` or `{"Call": {"func": 1, "argc": 2}}`
+pub fn to_json(bytecode: &Bytecode) -> Json {
+    let constants: Vec<_> = bytecode
+        .constants
+        .iter()
+        .map(|value| json!({ "type": value.clone().into_type().to_string(), "value": constant(value) }))
+        .collect();
+    let globals: Vec<_> = bytecode.globals.iter().map(|global| &global.name).collect();
+    let functions: Vec<_> = bytecode
+        .functions
+        .iter()
+        .map(|func| {
+            let code: Vec<_> = func
+                .code
+                .iter()
+                .enumerate()
+                .map(|(pc, op)| {
+                    json!({
+                        "pc": pc,
+                        "line": func.line(pc),
+                        "op": op,
+                        "comment": comment(bytecode, func, op),
+                    })
+                })
+                .collect();
+            let params: Vec<_> = func.params.iter().map(|(ident, ty)| json!({ "name": ident, "type": ty })).collect();
+            json!({
+                "name": func.name,
+                "params": params,
+                "rty": func.rty,
+                "locals": func.locals,
+                "file": func.file,
+                "code": code,
+            })
+        })
+        .collect();
+    json!({
+        "constants": constants,
+        "globals": globals,
+        "functions": functions,
+        "init": bytecode.init,
+        "entry": bytecode.entry,
+    })
+}
+
+fn signature(func: &FunctionProto) -> String {
+    let params: Vec<_> = func
+        .params
+        .iter()
+        .map(|(ident, ty)| match ty {
+            Some(ty) => format!("{ident}: {ty}"),
+            None => ident.clone(),
+        })
+        .collect();
+    format!("({}) -> {}, {} locals", params.join(", "), func.rty, func.locals)
+}
+
+fn constant(value: &Value) -> String {
+    match value {
+        Value::String(s) => format!("{s:?}"),
+        value => value.to_string(),
+    }
+}
+
+/// What the operand of `op` refers to: a constant, a global, a callee or a parameter
+fn comment(bytecode: &Bytecode, func: &FunctionProto, op: &Op) -> Option<String> {
+    let global = |id: u32| bytecode.globals.get(id as usize).map(|global| global.name.clone());
+    match *op {
+        Op::Const(id) | Op::NameError(id) | Op::TypeError(id) => bytecode.constants.get(id as usize).map(constant),
+        Op::LoadLocal(slot) | Op::DefineLocal(slot) | Op::StoreLocal(slot) | Op::RefLocal(slot) => {
+            func.params.get(slot as usize).map(|(ident, _)| ident.clone())
+        }
+        Op::LoadGlobal(id) | Op::StoreGlobal(id) | Op::RefGlobal(id) | Op::CallNative { global: id, .. } => global(id),
+        Op::Call { func, .. } | Op::TailCall { func, .. } | Op::Closure { func, .. } => {
+            bytecode.functions.get(func as usize).map(|callee| callee.name.clone())
+        }
+        Op::CallValue { name, .. } => bytecode.constants.get(name as usize).map(Value::to_string),
+        _ => None,
+    }
+}
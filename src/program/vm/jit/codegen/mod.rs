@@ -0,0 +1,492 @@
+use crate::program::value::Value as MorphoValue;
+use crate::program::vm::analysis::{binary, leaders, Param, Spec, Ty, Typed};
+use crate::program::vm::bytecode::{Bytecode, Op};
+use crate::program::vm::jit::Context;
+use cranelift_codegen::ir::condcodes::{FloatCC, IntCC};
+use cranelift_codegen::ir::{
+    types, AbiParam, Block, FuncRef, Function, InstBuilder, MemFlags, Signature, StackSlot, StackSlotData, StackSlotKind, Type, Value,
+};
+use cranelift_codegen::isa::CallConv;
+use cranelift_frontend::{FunctionBuilder, FunctionBuilderContext, Variable};
+use cranelift_jit::JITModule;
+use cranelift_module::{FuncId, Linkage, Module};
+use std::collections::HashMap;
+use std::mem::offset_of;
+
+/// Symbol of the float remainder helper the compiled code calls
+pub(super) const FMOD: &str = "morpho_fmod";
+
+/// Signature of the compiled `spec`: the context, then the parameters, with
+/// references as pointers. Compiled functions use the tail calling convention,
+/// so tail calls don't grow the native stack
+pub(super) fn signature(module: &JITModule, spec: &Spec, rty: Ty) -> Signature {
+    let pointer = module.target_config().pointer_type();
+    let mut signature = Signature::new(CallConv::Tail);
+    signature.params.push(AbiParam::new(pointer));
+    signature.params.extend(spec.params.iter().map(|param| match param {
+        Param::Value(ty) => AbiParam::new(ty.native()),
+        Param::Ref(_) => AbiParam::new(pointer),
+    }));
+    if rty != Ty::Void {
+        signature.returns.push(AbiParam::new(rty.native()));
+    }
+    signature
+}
+
+/// Translates the entry point of `spec`, which calls the compiled function with
+/// the arguments of an array and stores its result, see `EntryFn`
+pub(super) fn entry(
+    module: &mut JITModule,
+    func: &mut Function,
+    builder_context: &mut FunctionBuilderContext,
+    spec: &Spec,
+    rty: Ty,
+    id: FuncId,
+) {
+    let pointer = module.target_config().pointer_type();
+    let callee = module.declare_func_in_func(id, func);
+    let mut builder = FunctionBuilder::new(func, builder_context);
+    let block = builder.create_block();
+    builder.append_block_params_for_function_params(block);
+    builder.switch_to_block(block);
+    let [context, args, result] = builder.block_params(block).try_into().expect("entry points take three pointers");
+
+    let mut call_args = vec![context];
+    for (i, param) in spec.params.iter().enumerate() {
+        let offset = 8 * i as i32;
+        call_args.push(match param {
+            Param::Value(ty) => load(&mut builder, *ty, args, offset),
+            Param::Ref(_) => builder.ins().load(pointer, MemFlags::trusted(), args, offset),
+        });
+    }
+    let call = builder.ins().call(callee, &call_args);
+    if rty != Ty::Void {
+        let value = builder.inst_results(call)[0];
+        store(&mut builder, rty, value, result, 0);
+    }
+    builder.ins().return_(&[]);
+    builder.seal_all_blocks();
+    builder.finalize();
+}
+
+/// Reads a value of `ty` from 8 bytes of memory, where bools are whole words
+fn load(builder: &mut FunctionBuilder, ty: Ty, address: Value, offset: i32) -> Value {
+    match ty {
+        Ty::Float => builder.ins().load(types::F64, MemFlags::trusted(), address, offset),
+        Ty::Bool => {
+            let word = builder.ins().load(types::I64, MemFlags::trusted(), address, offset);
+            builder.ins().ireduce(types::I8, word)
+        }
+        _ => builder.ins().load(types::I64, MemFlags::trusted(), address, offset),
+    }
+}
+
+fn store(builder: &mut FunctionBuilder, ty: Ty, value: Value, address: Value, offset: i32) {
+    let value = match ty {
+        Ty::Bool => builder.ins().uextend(types::I64, value),
+        _ => value,
+    };
+    builder.ins().store(MemFlags::trusted(), value, address, offset);
+}
+
+/// Where a local lives in the compiled function
+#[derive(Clone, Copy)]
+enum Local {
+    Var(Variable),
+    Stack(StackSlot),
+    Ptr(Value),
+}
+
+/// Translates the code of `spec` to Cranelift IR.
+///
+/// The values on the VM stack become SSA values, passed to the blocks
+/// starting at jump targets as block parameters. Every check the interpreter
+/// makes that could fail branches to a block that sets `failed` in the
+/// context and returns, the calls check it after the callee returned
+pub(super) fn translate(
+    module: &mut JITModule,
+    func: &mut Function,
+    builder_context: &mut FunctionBuilderContext,
+    bytecode: &Bytecode,
+    spec: &Spec,
+    typed: &Typed,
+    ids: &HashMap<Spec, FuncId>,
+) {
+    let code = &bytecode.functions[spec.func as usize].code;
+    let pointer = module.target_config().pointer_type();
+    let mut callees = HashMap::new();
+    for (&pc, callee) in &typed.callees {
+        callees.insert(pc, module.declare_func_in_func(ids[callee], func));
+    }
+    let fmod = {
+        let mut signature = module.make_signature();
+        signature.params.extend([AbiParam::new(types::F64); 2]);
+        signature.returns.push(AbiParam::new(types::F64));
+        let id = module.declare_function(FMOD, Linkage::Import, &signature).expect("the helper keeps its signature");
+        module.declare_func_in_func(id, func)
+    };
+
+    let mut builder = FunctionBuilder::new(func, builder_context);
+    let entry = builder.create_block();
+    builder.append_block_params_for_function_params(entry);
+    let fail = builder.create_block();
+    let mut blocks = HashMap::new();
+    for pc in leaders(code) {
+        if let (Some(stack), None) = (&typed.stacks[pc], blocks.get(&pc)) {
+            let block = builder.create_block();
+            for param in stack {
+                builder.append_block_param(block, native(pointer, *param));
+            }
+            blocks.insert(pc, block);
+        }
+    }
+
+    // Locals, and the depth check of the call
+    builder.switch_to_block(entry);
+    let params = builder.block_params(entry).to_vec();
+    let context = params[0];
+    let mut addressed = vec![false; typed.locals.len()];
+    for (pc, op) in code.iter().enumerate() {
+        if let (Op::RefLocal(slot), Some(_)) = (op, &typed.stacks[pc]) {
+            addressed[*slot as usize] = true;
+        }
+    }
+    let mut locals = Vec::new();
+    for (i, (local, addressed)) in typed.locals.iter().zip(addressed).enumerate() {
+        let param = params.get(i + 1).copied();
+        locals.push(match (*local, addressed) {
+            (None, _) => None,
+            // Locals no reference is taken to live in registers
+            (Some(Param::Value(ty)), false) => {
+                let var = Variable::from_u32(i as u32);
+                builder.declare_var(var, ty.native());
+                let value = param.unwrap_or_else(|| zero(&mut builder, ty));
+                builder.def_var(var, value);
+                Some((Local::Var(var), ty))
+            }
+            (Some(Param::Value(ty)), true) => {
+                let slot = builder.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 8, 3));
+                if let Some(param) = param {
+                    let address = builder.ins().stack_addr(pointer, slot, 0);
+                    store(&mut builder, ty, param, address, 0);
+                }
+                Some((Local::Stack(slot), ty))
+            }
+            (Some(Param::Ref(ty)), _) => Some((Local::Ptr(param.expect("references are parameters")), ty)),
+        });
+    }
+    let depth = builder.ins().load(types::I64, MemFlags::trusted(), context, offset_of!(Context, depth) as i32);
+    let max_depth = builder.ins().load(types::I64, MemFlags::trusted(), context, offset_of!(Context, max_depth) as i32);
+    let too_deep = builder.ins().icmp(IntCC::SignedGreaterThanOrEqual, depth, max_depth);
+    let depth = builder.ins().iadd_imm(depth, 1);
+    builder.ins().store(MemFlags::trusted(), depth, context, offset_of!(Context, depth) as i32);
+    let start = blocks[&0];
+    builder.ins().brif(too_deep, fail, &[], start, &[]);
+
+    let mut translator = Translator { builder, pointer, context, fail, locals };
+    let mut stack: Option<Vec<Value>> = None;
+    for (pc, op) in code.iter().enumerate() {
+        let Some(types) = &typed.stacks[pc] else {
+            stack = None;
+            continue;
+        };
+        if let Some(&block) = blocks.get(&pc) {
+            if let Some(values) = stack.take() {
+                translator.builder.ins().jump(block, &values);
+            }
+            translator.builder.switch_to_block(block);
+            stack = Some(translator.builder.block_params(block).to_vec());
+        }
+        let values = stack.as_mut().expect("reachable code follows a jump target or the previous instruction");
+        let top = |n: usize| match types[types.len() - n] {
+            Param::Value(ty) => ty,
+            Param::Ref(ty) => ty,
+        };
+        let b = &mut translator;
+        match *op {
+            Op::Const(id) => {
+                let value = b.constant(&bytecode.constants[id as usize]);
+                values.push(value);
+            }
+            Op::Void => values.push(zero(&mut b.builder, Ty::Void)),
+            Op::Pop => {
+                values.pop();
+            }
+            Op::LoadLocal(slot) => {
+                let value = b.load_local(slot as usize);
+                values.push(value);
+            }
+            Op::DefineLocal(slot) | Op::StoreLocal(slot) => {
+                let value = values.pop().unwrap();
+                b.store_local(slot as usize, value);
+            }
+            Op::RefLocal(slot) => {
+                let address = b.address(slot as usize);
+                values.push(address);
+            }
+            Op::Neg | Op::Not => {
+                let value = values.pop().unwrap();
+                let value = b.unary(*op, top(1), value);
+                values.push(value);
+            }
+            Op::Jump(target) => {
+                b.builder.ins().jump(blocks[&(target as usize)], &values[..]);
+                stack = None;
+            }
+            Op::JumpIfFalse(target) => {
+                let cond = values.pop().unwrap();
+                b.builder.ins().brif(cond, blocks[&(pc + 1)], &values[..], blocks[&(target as usize)], &values[..]);
+                stack = None;
+            }
+            Op::Call { argc, .. } | Op::TailCall { argc, .. } => {
+                let callee = &typed.callees[&pc];
+                let mut args = values.split_off(values.len() - argc as usize);
+                let arg_types = &types[types.len() - argc as usize..];
+                args.truncate(callee.params.len());
+                for ((arg, param), ty) in args.iter_mut().zip(&callee.params).zip(arg_types) {
+                    if *param == Param::Value(Ty::Float) && *ty == Param::Value(Ty::Int) {
+                        *arg = b.builder.ins().fcvt_from_sint(types::F64, *arg);
+                    }
+                }
+                args.insert(0, context);
+                if let Op::TailCall { .. } = op {
+                    b.leave();
+                    b.builder.ins().return_call(callees[&pc], &args);
+                    stack = None;
+                    continue;
+                }
+                let call = b.builder.ins().call(callees[&pc], &args);
+                let result = match b.builder.inst_results(call).first() {
+                    Some(&result) => result,
+                    None => zero(&mut b.builder, Ty::Void),
+                };
+                let failed = b.builder.ins().load(types::I64, MemFlags::trusted(), context, offset_of!(Context, failed) as i32);
+                b.check(failed);
+                values.push(result);
+            }
+            Op::Return => {
+                let mut value = values.pop().unwrap();
+                if top(1) == Ty::Int && typed.rty == Ty::Float {
+                    value = b.builder.ins().fcvt_from_sint(types::F64, value);
+                }
+                b.leave();
+                b.builder.ins().return_(&[value]);
+                stack = None;
+            }
+            Op::ReturnVoid => {
+                b.leave();
+                b.builder.ins().return_(&[]);
+                stack = None;
+            }
+            op => {
+                let rhs = values.pop().unwrap();
+                let lhs = values.pop().unwrap();
+                let value = b.binary(op, top(2), top(1), lhs, rhs, fmod);
+                values.push(value);
+            }
+        }
+    }
+
+    let mut builder = translator.builder;
+    builder.switch_to_block(fail);
+    let one = builder.ins().iconst(types::I64, 1);
+    builder.ins().store(MemFlags::trusted(), one, context, offset_of!(Context, failed) as i32);
+    let result = match typed.rty {
+        Ty::Void => vec![],
+        rty => vec![zero(&mut builder, rty)],
+    };
+    builder.ins().return_(&result);
+    builder.seal_all_blocks();
+    builder.finalize();
+}
+
+/// Type a stack value is passed as
+fn native(pointer: Type, param: Param) -> Type {
+    match param {
+        Param::Value(ty) => ty.native(),
+        Param::Ref(_) => pointer,
+    }
+}
+
+fn zero(builder: &mut FunctionBuilder, ty: Ty) -> Value {
+    match ty {
+        Ty::Float => builder.ins().f64const(0.0),
+        ty => builder.ins().iconst(ty.native(), 0),
+    }
+}
+
+struct Translator<'f> {
+    builder: FunctionBuilder<'f>,
+    pointer: Type,
+    context: Value,
+    fail: Block,
+    locals: Vec<Option<(Local, Ty)>>,
+}
+
+impl Translator<'_> {
+    fn local(&self, slot: usize) -> (Local, Ty) {
+        self.locals[slot].expect("the analysis typed every local the code uses")
+    }
+
+    fn load_local(&mut self, slot: usize) -> Value {
+        match self.local(slot) {
+            (Local::Var(var), _) => self.builder.use_var(var),
+            (Local::Stack(stack_slot), ty) => {
+                let address = self.builder.ins().stack_addr(self.pointer, stack_slot, 0);
+                load(&mut self.builder, ty, address, 0)
+            }
+            (Local::Ptr(address), ty) => load(&mut self.builder, ty, address, 0),
+        }
+    }
+
+    fn store_local(&mut self, slot: usize, value: Value) {
+        match self.local(slot) {
+            (Local::Var(var), _) => self.builder.def_var(var, value),
+            (Local::Stack(stack_slot), ty) => {
+                let address = self.builder.ins().stack_addr(self.pointer, stack_slot, 0);
+                store(&mut self.builder, ty, value, address, 0);
+            }
+            (Local::Ptr(address), ty) => store(&mut self.builder, ty, value, address, 0),
+        }
+    }
+
+    fn address(&mut self, slot: usize) -> Value {
+        match self.local(slot) {
+            (Local::Stack(stack_slot), _) => self.builder.ins().stack_addr(self.pointer, stack_slot, 0),
+            (Local::Ptr(address), _) => address,
+            (Local::Var(_), _) => unreachable!("referenced locals are kept on the stack"),
+        }
+    }
+
+    fn constant(&mut self, value: &MorphoValue) -> Value {
+        match value {
+            MorphoValue::Int(i) => self.builder.ins().iconst(types::I64, *i),
+            MorphoValue::Float(f) => self.builder.ins().f64const(*f),
+            MorphoValue::Bool(b) => self.builder.ins().iconst(types::I8, *b as i64),
+            _ => unreachable!("the analysis only lets int, float and bool constants through"),
+        }
+    }
+
+    /// Continues only if `failed` is zero
+    fn check(&mut self, failed: Value) {
+        let next = self.builder.create_block();
+        self.builder.ins().brif(failed, self.fail, &[], next, &[]);
+        self.builder.switch_to_block(next);
+    }
+
+    /// Takes the frame of the function off the depth before it returns
+    fn leave(&mut self) {
+        let offset = offset_of!(Context, depth) as i32;
+        let depth = self.builder.ins().load(types::I64, MemFlags::trusted(), self.context, offset);
+        let depth = self.builder.ins().iadd_imm(depth, -1);
+        self.builder.ins().store(MemFlags::trusted(), depth, self.context, offset);
+    }
+
+    fn float(&mut self, ty: Ty, value: Value) -> Value {
+        match ty {
+            Ty::Int => self.builder.ins().fcvt_from_sint(types::F64, value),
+            _ => value,
+        }
+    }
+
+    fn unary(&mut self, op: Op, ty: Ty, value: Value) -> Value {
+        match (op, ty) {
+            (Op::Neg, Ty::Int) => {
+                let min = self.builder.ins().icmp_imm(IntCC::Equal, value, i64::MIN);
+                self.check(min);
+                self.builder.ins().ineg(value)
+            }
+            (Op::Neg, _) => self.builder.ins().fneg(value),
+            (Op::Not, Ty::Bool) => self.builder.ins().icmp_imm(IntCC::Equal, value, 0),
+            _ => self.builder.ins().bnot(value),
+        }
+    }
+
+    /// Mirrors the operators of `Value`: int arithmetic is checked, ints
+    /// mixed with floats are converted and dividing by zero is an error
+    fn binary(&mut self, op: Op, lhs_ty: Ty, rhs_ty: Ty, lhs: Value, rhs: Value, fmod: FuncRef) -> Value {
+        let ty = binary(op, lhs_ty, rhs_ty).expect("the analysis typed every operation");
+        let ints = lhs_ty == Ty::Int && rhs_ty == Ty::Int;
+        let floats = !ints && lhs_ty != Ty::Bool;
+        let (lhs, rhs) = match floats {
+            true => (self.float(lhs_ty, lhs), self.float(rhs_ty, rhs)),
+            false => (lhs, rhs),
+        };
+        match op {
+            Op::Add | Op::Sub | Op::Mul if ty == Ty::Int => {
+                let (value, overflow) = match op {
+                    Op::Add => self.builder.ins().sadd_overflow(lhs, rhs),
+                    Op::Sub => self.builder.ins().ssub_overflow(lhs, rhs),
+                    _ => self.builder.ins().smul_overflow(lhs, rhs),
+                };
+                self.check(overflow);
+                value
+            }
+            Op::Div | Op::Mod if ty == Ty::Int => {
+                let zero = self.builder.ins().icmp_imm(IntCC::Equal, rhs, 0);
+                self.check(zero);
+                let min = self.builder.ins().icmp_imm(IntCC::Equal, lhs, i64::MIN);
+                let minus_one = self.builder.ins().icmp_imm(IntCC::Equal, rhs, -1);
+                let overflow = self.builder.ins().band(min, minus_one);
+                self.check(overflow);
+                match op {
+                    Op::Div => self.builder.ins().sdiv(lhs, rhs),
+                    _ => self.builder.ins().srem(lhs, rhs),
+                }
+            }
+            Op::Add => self.builder.ins().fadd(lhs, rhs),
+            Op::Sub => self.builder.ins().fsub(lhs, rhs),
+            Op::Mul => self.builder.ins().fmul(lhs, rhs),
+            Op::Div | Op::Mod => {
+                let zero = self.builder.ins().f64const(0.0);
+                let zero = self.builder.ins().fcmp(FloatCC::Equal, rhs, zero);
+                self.check(zero);
+                match op {
+                    Op::Div => self.builder.ins().fdiv(lhs, rhs),
+                    _ => {
+                        let call = self.builder.ins().call(fmod, &[lhs, rhs]);
+                        self.builder.inst_results(call)[0]
+                    }
+                }
+            }
+            Op::Xor => self.builder.ins().bxor(lhs, rhs),
+            Op::BitAnd | Op::And => self.builder.ins().band(lhs, rhs),
+            Op::BitOr | Op::Or => self.builder.ins().bor(lhs, rhs),
+            Op::Shl | Op::Shr => {
+                let out_of_range = self.builder.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, rhs, 64);
+                self.check(out_of_range);
+                match op {
+                    Op::Shl => self.builder.ins().ishl(lhs, rhs),
+                    _ => self.builder.ins().sshr(lhs, rhs),
+                }
+            }
+            Op::Eq | Op::Ne | Op::Gt | Op::Lt | Op::Ge | Op::Le if floats => {
+                let cc = match op {
+                    Op::Eq => FloatCC::Equal,
+                    Op::Ne => FloatCC::NotEqual,
+                    Op::Gt => FloatCC::GreaterThan,
+                    Op::Lt => FloatCC::LessThan,
+                    Op::Ge => FloatCC::GreaterThanOrEqual,
+                    _ => FloatCC::LessThanOrEqual,
+                };
+                self.builder.ins().fcmp(cc, lhs, rhs)
+            }
+            _ => {
+                // Ints compare signed, bools as `false < true`
+                let cc = match (op, ints) {
+                    (Op::Eq, _) => IntCC::Equal,
+                    (Op::Ne, _) => IntCC::NotEqual,
+                    (Op::Gt, true) => IntCC::SignedGreaterThan,
+                    (Op::Lt, true) => IntCC::SignedLessThan,
+                    (Op::Ge, true) => IntCC::SignedGreaterThanOrEqual,
+                    (Op::Le, true) => IntCC::SignedLessThanOrEqual,
+                    (Op::Gt, false) => IntCC::UnsignedGreaterThan,
+                    (Op::Lt, false) => IntCC::UnsignedLessThan,
+                    (Op::Ge, false) => IntCC::UnsignedGreaterThanOrEqual,
+                    _ => IntCC::UnsignedLessThanOrEqual,
+                };
+                self.builder.ins().icmp(cc, lhs, rhs)
+            }
+        }
+    }
+}
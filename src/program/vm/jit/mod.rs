@@ -0,0 +1,292 @@
+mod codegen;
+
+use crate::program::shared::Shared;
+use crate::program::value::Value;
+use crate::program::vm::analysis::{self, Param, Scope, Spec, Ty, Typed};
+use crate::program::vm::bytecode::Bytecode;
+use cranelift_codegen::ir::{types, AbiParam, Type, UserFuncName};
+use cranelift_codegen::settings::{self, Configurable};
+use cranelift_frontend::FunctionBuilderContext;
+use cranelift_jit::{JITBuilder, JITModule};
+use cranelift_module::{default_libcall_names, FuncId, Module};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Calls of a function from the interpreter before it is compiled
+const HOT_CALLS: u32 = 100;
+
+/// Calls compiled code may nest on the native stack, deeper recursion goes
+/// back to the interpreter, whose frames don't use the native stack
+const MAX_NATIVE_DEPTH: usize = 1_000;
+
+impl Ty {
+    /// Type compiled code keeps the value in, ints and floats are 64 bits and
+    /// bools are bytes
+    fn native(self) -> Type {
+        match self {
+            Ty::Int => types::I64,
+            Ty::Float => types::F64,
+            Ty::Bool | Ty::Void => types::I8,
+            Ty::Str => unreachable!("functions compiled on their own don't use strings"),
+        }
+    }
+
+    /// `value` the way compiled code passes it through memory
+    fn to_bits(value: &Value) -> u64 {
+        match value {
+            Value::Int(i) => *i as u64,
+            Value::Float(f) => f.to_bits(),
+            Value::Bool(b) => *b as u64,
+            _ => 0,
+        }
+    }
+
+    fn with_bits(self, bits: u64) -> Value {
+        match self {
+            Ty::Int => Value::Int(bits as i64),
+            Ty::Float => Value::Float(f64::from_bits(bits)),
+            Ty::Bool => Value::Bool(bits != 0),
+            Ty::Void => Value::Void,
+            Ty::Str => unreachable!("functions compiled on their own don't return strings"),
+        }
+    }
+}
+
+impl Param {
+    fn of(value: &Value) -> Option<Param> {
+        match value {
+            Value::RefValue(cell) => Ty::of(&cell.borrow()).map(Param::Ref),
+            value => Ty::of(value).map(Param::Value),
+        }
+    }
+}
+
+static COMPILED: AtomicU64 = AtomicU64::new(0);
+static NATIVE_CALLS: AtomicU64 = AtomicU64::new(0);
+
+/// Counters of the JIT over all threads: specializations compiled to native
+/// code and calls of the VM that ran them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JitStats {
+    pub compiled: u64,
+    pub native_calls: u64,
+}
+
+impl Display for JitStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "compiled: {}, native calls: {}", self.compiled, self.native_calls)
+    }
+}
+
+pub fn stats() -> JitStats {
+    JitStats {
+        compiled: COMPILED.load(Ordering::Relaxed),
+        native_calls: NATIVE_CALLS.load(Ordering::Relaxed),
+    }
+}
+
+/// State shared by compiled code, `failed` is set when the code ran into
+/// anything the interpreter would raise an error for
+#[repr(C)]
+struct Context {
+    depth: i64,
+    max_depth: i64,
+    failed: i64,
+}
+
+/// Compiled entry point of a specialization. It reads the arguments from an
+/// array, with references as pointers to slots of the value, and writes the
+/// result to the slot it is given
+type EntryFn = unsafe extern "C" fn(*mut Context, *const u64, *mut u64);
+
+#[derive(Clone, Copy)]
+struct Entry {
+    code: EntryFn,
+    rty: Ty,
+}
+
+/// Compiles the functions the VM calls most to native code with Cranelift.
+///
+/// A function is compiled for the types of the arguments it's called with once
+/// it is hot, along with everything it calls, as long as all of it only works
+/// with ints, floats and bools: no globals, built-ins, arrays or strings.
+/// Compiled code has no side effect other than assigning to the variables its
+/// references point to, which are written back only once it returned. When it
+/// runs into an error it gives up and the VM runs the call again, raising the
+/// error the same way it would have without the JIT
+pub(crate) struct Jit {
+    module: Option<JITModule>,
+    /// The host isn't one Cranelift supports
+    unsupported: bool,
+    calls: Vec<u32>,
+    /// Compiled specializations, `None` for those that can't be
+    functions: HashMap<Spec, Option<FuncId>>,
+    entries: HashMap<Spec, Option<Entry>>,
+}
+
+impl Jit {
+    pub(crate) fn new(bytecode: &Bytecode) -> Self {
+        Self {
+            module: None,
+            unsupported: false,
+            calls: vec![0; bytecode.functions.len()],
+            functions: HashMap::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Runs the call of `func` with `args` as native code if it is compiled or
+    /// just got hot. `None` when the interpreter has to make the call
+    pub(crate) fn call(&mut self, bytecode: &Bytecode, func: u32, args: &[Value], max_depth: usize) -> Option<Value> {
+        let calls = &mut self.calls[func as usize];
+        if *calls < HOT_CALLS {
+            *calls += 1;
+            return None;
+        }
+        let proto = &bytecode.functions[func as usize];
+        let params = args.iter().take(proto.params.len()).map(Param::of).collect::<Option<Vec<_>>>()?;
+        let spec = Spec::of(func, proto, &params).ok()?;
+        let entry = match self.entries.get(&spec) {
+            Some(entry) => (*entry)?,
+            None => {
+                let entry = self.compile(bytecode, &spec);
+                self.entries.insert(spec.clone(), entry);
+                entry?
+            }
+        };
+
+        // Variables passed by reference are copied to slots, one per variable
+        let mut cells: Vec<&Shared<Value>> = Vec::new();
+        let mut refs = Vec::new();
+        for arg in &args[..spec.params.len()] {
+            if let Value::RefValue(cell) = arg {
+                let index = cells.iter().position(|known| Shared::ptr_eq(known, cell)).unwrap_or_else(|| {
+                    cells.push(cell);
+                    cells.len() - 1
+                });
+                refs.push(index);
+            }
+        }
+        let mut slots = cells.iter().map(|cell| Ty::to_bits(&cell.borrow())).collect::<Vec<_>>();
+        let mut refs = refs.into_iter();
+        let bits = args.iter().zip(&spec.params).map(|(arg, param)| match param {
+            Param::Value(Ty::Float) => match arg {
+                Value::Int(i) => (*i as f64).to_bits(),
+                arg => Ty::to_bits(arg),
+            },
+            Param::Value(_) => Ty::to_bits(arg),
+            Param::Ref(_) => &mut slots[refs.next().unwrap()] as *mut u64 as u64,
+        });
+        let bits = bits.collect::<Vec<_>>();
+
+        let max_depth = max_depth.min(MAX_NATIVE_DEPTH) as i64;
+        let mut context = Context { depth: 0, max_depth, failed: 0 };
+        let mut result = 0;
+        // SAFETY: the entry was compiled for these argument types, and the
+        // slots outlive the call
+        unsafe { (entry.code)(&mut context, bits.as_ptr(), &mut result) };
+        if context.failed != 0 {
+            // The interpreter runs it from now on, the error is likely to happen again
+            self.entries.insert(spec, None);
+            return None;
+        }
+        for (cell, bits) in cells.iter().zip(slots) {
+            let ty = Ty::of(&cell.borrow()).unwrap_or(Ty::Void);
+            cell.set(ty.with_bits(bits));
+        }
+        NATIVE_CALLS.fetch_add(1, Ordering::Relaxed);
+        Some(entry.rty.with_bits(result))
+    }
+
+    fn module(&mut self) -> Option<&mut JITModule> {
+        if self.module.is_none() && !self.unsupported {
+            let mut flags = settings::builder();
+            flags.set("opt_level", "speed").expect("opt_level is a Cranelift setting");
+            // Cranelift needs them for tail calls
+            flags.set("preserve_frame_pointers", "true").expect("preserve_frame_pointers is a Cranelift setting");
+            match cranelift_native::builder() {
+                Ok(isa) => {
+                    let isa = isa.finish(settings::Flags::new(flags)).expect("the host ISA accepts the flags");
+                    let mut builder = JITBuilder::with_isa(isa, default_libcall_names());
+                    builder.symbol(codegen::FMOD, fmod as *const u8);
+                    self.module = Some(JITModule::new(builder));
+                }
+                Err(_) => self.unsupported = true,
+            }
+        }
+        self.module.as_mut()
+    }
+
+    /// Compiles `root` and the specializations it calls, unless one of them
+    /// can't be, and returns the entry point of `root`
+    fn compile(&mut self, bytecode: &Bytecode, root: &Spec) -> Option<Entry> {
+        let mut typed: Vec<(Spec, Typed)> = Vec::new();
+        let mut seen = HashSet::from([root.clone()]);
+        let mut pending = vec![root.clone()];
+        while let Some(spec) = pending.pop() {
+            match self.functions.get(&spec) {
+                Some(Some(_)) => continue,
+                Some(None) => return None,
+                None => {}
+            }
+            let Ok(types) = analysis::infer(bytecode, &spec, Scope::Function, &mut []) else {
+                self.functions.insert(spec, None);
+                return None;
+            };
+            pending.extend(types.callees.values().filter(|&callee| seen.insert(callee.clone())).cloned());
+            typed.push((spec, types));
+        }
+
+        let rty = Ty::parse(&bytecode.functions[root.func as usize].rty)?;
+        self.module()?;
+        let module = self.module.as_mut().unwrap();
+        for (spec, types) in &typed {
+            let signature = codegen::signature(module, spec, types.rty);
+            let id = module.declare_anonymous_function(&signature).expect("signatures of new functions can't clash");
+            self.functions.insert(spec.clone(), Some(id));
+        }
+        let ids = self.functions.iter().filter_map(|(spec, id)| Some((spec.clone(), (*id)?))).collect::<HashMap<_, _>>();
+
+        let mut context = module.make_context();
+        let mut builder_context = FunctionBuilderContext::new();
+        for (spec, types) in &typed {
+            let id = ids[spec];
+            context.func.signature = codegen::signature(module, spec, types.rty);
+            context.func.name = UserFuncName::user(0, id.as_u32());
+            codegen::translate(module, &mut context.func, &mut builder_context, bytecode, spec, types, &ids);
+            module.define_function(id, &mut context).expect("Cranelift rejected the compiled code");
+            module.clear_context(&mut context);
+        }
+
+        let mut signature = module.make_signature();
+        let pointer = module.target_config().pointer_type();
+        signature.params.extend([AbiParam::new(pointer); 3]);
+        let id = module.declare_anonymous_function(&signature).expect("signatures of new functions can't clash");
+        context.func.signature = signature;
+        context.func.name = UserFuncName::user(0, id.as_u32());
+        codegen::entry(module, &mut context.func, &mut builder_context, root, rty, ids[root]);
+        module.define_function(id, &mut context).expect("Cranelift rejected the compiled code");
+        module.clear_context(&mut context);
+        module.finalize_definitions().expect("compiled code can be linked");
+        COMPILED.fetch_add(typed.len() as u64, Ordering::Relaxed);
+        let code = module.get_finalized_function(id);
+        // SAFETY: the entry was compiled with the signature of `EntryFn`
+        let code = unsafe { std::mem::transmute::<*const u8, EntryFn>(code) };
+        Some(Entry { code, rty })
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        if let Some(module) = self.module.take() {
+            // SAFETY: the entries pointing into the module are dropped with it
+            unsafe { module.free_memory() };
+        }
+    }
+}
+
+/// Remainder of floats, which Cranelift has no instruction for
+extern "C" fn fmod(a: f64, b: f64) -> f64 {
+    a % b
+}
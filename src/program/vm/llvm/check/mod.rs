@@ -0,0 +1,62 @@
+use crate::program::vm::analysis::{infer, Param, Scope, Spec, Ty, Typed};
+use crate::program::vm::bytecode::Bytecode;
+use anyhow::Result;
+use std::collections::HashMap;
+
+impl Ty {
+    /// Type of the value in LLVM IR, strings are pointers to NUL-terminated bytes
+    pub(super) fn ir(self) -> &'static str {
+        match self {
+            Ty::Int => "i64",
+            Ty::Float => "double",
+            Ty::Bool => "i1",
+            Ty::Str => "ptr",
+            Ty::Void => "void",
+        }
+    }
+}
+
+impl Param {
+    pub(super) fn ir(self) -> &'static str {
+        match self {
+            Param::Value(ty) => ty.ir(),
+            Param::Ref(_) => "ptr",
+        }
+    }
+}
+
+/// Program with the types of all of its values known before it runs
+pub(super) struct Checked {
+    /// Specializations in the order they were found, the init functions and
+    /// the entry function first
+    pub specs: Vec<(Spec, Typed)>,
+    pub index: HashMap<Spec, usize>,
+    /// Types of the globals the program uses
+    pub globals: Vec<Option<Ty>>,
+}
+
+/// Infers the types of the whole program, starting from its init and entry
+/// functions and following every call. Anonymous functions are checked for
+/// the types they are called with.
+///
+/// Fails on what can't be compiled ahead of time, see `analysis::infer`:
+/// besides ints, floats and bools, programs may only use strings, globals and
+/// the `print` built-in
+pub(super) fn check(bytecode: &Bytecode) -> Result<Checked> {
+    let mut checked = Checked { specs: Vec::new(), index: HashMap::new(), globals: vec![None; bytecode.globals.len()] };
+    for &func in bytecode.init.iter().chain([&bytecode.entry]) {
+        let mut pending = vec![Spec { func, params: Vec::new() }];
+        while let Some(spec) = pending.pop() {
+            if checked.index.contains_key(&spec) {
+                continue;
+            }
+            let typed = infer(bytecode, &spec, Scope::Program, &mut checked.globals)?;
+            let mut callees = typed.callees.iter().collect::<Vec<_>>();
+            callees.sort_by_key(|(pc, _)| std::cmp::Reverse(**pc));
+            pending.extend(callees.into_iter().map(|(_, callee)| callee.clone()));
+            checked.index.insert(spec.clone(), checked.specs.len());
+            checked.specs.push((spec, typed));
+        }
+    }
+    Ok(checked)
+}
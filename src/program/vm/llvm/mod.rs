@@ -0,0 +1,583 @@
+mod check;
+
+use crate::program::max_call_depth;
+use crate::program::value::Value;
+use crate::program::vm::analysis::{binary, leaders, Param, Spec, Ty, Typed};
+use crate::program::vm::bytecode::{Bytecode, Op};
+use crate::program::vm::llvm::check::Checked;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// C runtime the emitted IR calls to print values, build strings and report
+/// errors. Programs are linked with it: `clang main.ll morpho_runtime.c`
+pub const RUNTIME: &str = include_str!("runtime.c");
+
+/// Name of the runtime source `build` writes next to the IR
+pub const RUNTIME_FILE: &str = "morpho_runtime.c";
+
+const DECLARATIONS: &str = "\
+declare void @morpho_print_int(i64)
+declare void @morpho_print_float(double)
+declare void @morpho_print_bool(i32)
+declare void @morpho_print_str(ptr)
+declare void @morpho_print_newline()
+declare ptr @morpho_int_str(i64)
+declare ptr @morpho_float_str(double)
+declare ptr @morpho_bool_str(i32)
+declare ptr @morpho_str_concat(ptr, ptr)
+declare i32 @morpho_str_cmp(ptr, ptr)
+declare void @morpho_overflow(ptr, i64, i64) noreturn
+declare void @morpho_overflow_neg(i64) noreturn
+declare void @morpho_division_by_zero() noreturn
+declare void @morpho_stack_overflow(i64) noreturn
+declare { i64, i1 } @llvm.sadd.with.overflow.i64(i64, i64)
+declare { i64, i1 } @llvm.ssub.with.overflow.i64(i64, i64)
+declare { i64, i1 } @llvm.smul.with.overflow.i64(i64, i64)
+";
+
+/// Lowers a program to a module of textual LLVM IR named `name`.
+///
+/// Every value of the program needs a type known ahead of time, see `check`.
+/// Functions become LLVM functions, compiled once for every list of argument
+/// types anonymous functions are called with. Locals live in `alloca`s, which
+/// references point to, and values left on the stack across jumps become `phi`
+/// nodes. Int arithmetic is checked and errors end the program the way the
+/// interpreter reports them, calls nest up to `max_call_depth`. `main` runs
+/// the global initializers, then the entry function
+pub fn emit(bytecode: &Bytecode, name: &str) -> Result<String> {
+    let checked = check::check(bytecode)?;
+    let mut module = Module { bytecode, names: Vec::new(), strings: HashMap::new(), constants: String::new() };
+    let mut uses = HashMap::new();
+    for (spec, _) in &checked.specs {
+        let name = &bytecode.functions[spec.func as usize].name;
+        let count = uses.entry(name.clone()).or_insert(0);
+        *count += 1;
+        module.names.push(match count {
+            1 => format!("@\"morpho.{name}\""),
+            n => format!("@\"morpho.{name}.{n}\""),
+        });
+    }
+
+    let mut functions = String::new();
+    for (i, (spec, typed)) in checked.specs.iter().enumerate() {
+        let body = FunctionWriter::new(&mut module, &checked).write(i, spec, typed);
+        functions.push_str(&body);
+        functions.push('\n');
+    }
+
+    let mut out = String::new();
+    writeln!(out, "; ModuleID = '{name}'").unwrap();
+    writeln!(out, "source_filename = \"{name}\"").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "@morpho_depth = internal global i64 0").unwrap();
+    for (global, ty) in bytecode.globals.iter().zip(&checked.globals) {
+        if let Some(ty) = ty {
+            let zero = match ty {
+                Ty::Float => "0.0",
+                Ty::Bool => "false",
+                Ty::Str => "null",
+                _ => "0",
+            };
+            writeln!(out, "{} = internal global {} {zero}", global_name(&global.name), ty.ir()).unwrap();
+        }
+    }
+    out.push_str(&module.constants);
+    writeln!(out).unwrap();
+    out.push_str(DECLARATIONS);
+    writeln!(out).unwrap();
+    out.push_str(&functions);
+
+    writeln!(out, "define i32 @main() {{").unwrap();
+    writeln!(out, "entry:").unwrap();
+    for &func in bytecode.init.iter().chain([&bytecode.entry]) {
+        let i = checked.index[&Spec { func, params: Vec::new() }];
+        writeln!(out, "  call {} {}()", checked.specs[i].1.rty.ir(), module.names[i]).unwrap();
+    }
+    writeln!(out, "  ret i32 0").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+fn global_name(name: &str) -> String {
+    format!("@\"morpho_global.{name}\"")
+}
+
+/// Names of the functions and the string constants of the module
+struct Module<'b> {
+    bytecode: &'b Bytecode,
+    /// IR name of every specialization
+    names: Vec<String>,
+    strings: HashMap<String, String>,
+    /// Definitions of the string constants
+    constants: String,
+}
+
+impl Module<'_> {
+    /// Constant holding `string` with a NUL byte after it
+    fn string(&mut self, string: &str) -> String {
+        if let Some(name) = self.strings.get(string) {
+            return name.clone();
+        }
+        let name = format!("@morpho_str.{}", self.strings.len());
+        let mut bytes = String::new();
+        for &byte in string.as_bytes() {
+            match byte {
+                b' '..=b'~' if byte != b'"' && byte != b'\\' => bytes.push(byte as char),
+                _ => write!(bytes, "\\{byte:02X}").unwrap(),
+            }
+        }
+        let len = string.len() + 1;
+        writeln!(self.constants, "{name} = private unnamed_addr constant [{len} x i8] c\"{bytes}\\00\"").unwrap();
+        self.strings.insert(string.to_string(), name.clone());
+        name
+    }
+}
+
+/// Writes the IR of a specialization. Stack values are operands: registers,
+/// constants or the names of globals, `void` for values of no type
+struct FunctionWriter<'m, 'b> {
+    module: &'m mut Module<'b>,
+    checked: &'m Checked,
+    /// Labels and instructions of the blocks written so far
+    blocks: Vec<(String, Vec<String>)>,
+    temps: usize,
+    /// Values the branches to each jump target pass, with the block they come from
+    incoming: HashMap<usize, Vec<(String, Vec<String>)>>,
+}
+
+impl<'m, 'b> FunctionWriter<'m, 'b> {
+    fn new(module: &'m mut Module<'b>, checked: &'m Checked) -> Self {
+        Self { module, checked, blocks: Vec::new(), temps: 0, incoming: HashMap::new() }
+    }
+
+    fn temp(&mut self) -> String {
+        self.temps += 1;
+        format!("%t{}", self.temps)
+    }
+
+    fn line(&mut self, line: String) {
+        self.blocks.last_mut().expect("instructions are written in a block").1.push(line);
+    }
+
+    /// Writes an instruction producing a value and returns its register
+    fn value(&mut self, instruction: String) -> String {
+        let temp = self.temp();
+        self.line(format!("{temp} = {instruction}"));
+        temp
+    }
+
+    fn block(&mut self, label: String) {
+        self.blocks.push((label, Vec::new()));
+    }
+
+    fn label(&self) -> String {
+        self.blocks.last().unwrap().0.clone()
+    }
+
+    /// Records that the current block jumps to the instruction at `target`
+    fn branch(&mut self, target: usize, values: &[String]) {
+        let label = self.label();
+        self.incoming.entry(target).or_default().push((label, values.to_vec()));
+    }
+
+    /// Continues in a new block when `condition` is false, after `fail`
+    /// reported the error in a block of its own
+    fn check(&mut self, condition: &str, fail: impl FnOnce(&mut Self)) {
+        let n = self.temp();
+        let n = &n[2..];
+        self.line(format!("br i1 {condition}, label %fail{n}, label %ok{n}"));
+        self.block(format!("fail{n}"));
+        fail(self);
+        self.line("unreachable".to_string());
+        self.block(format!("ok{n}"));
+    }
+
+    /// Decrements `@morpho_depth`, written before every `ret`
+    fn leave(&mut self) {
+        let depth = self.value("load i64, ptr @morpho_depth".to_string());
+        let depth = self.value(format!("sub i64 {depth}, 1"));
+        self.line(format!("store i64 {depth}, ptr @morpho_depth"));
+    }
+
+    fn write(mut self, index: usize, spec: &Spec, typed: &Typed) -> String {
+        let bytecode = self.module.bytecode;
+        let code = &bytecode.functions[spec.func as usize].code;
+        let params = spec.params.iter().enumerate().map(|(i, param)| format!("{} %a{i}", param.ir()));
+        let header = format!(
+            "define internal {} {}({}) {{",
+            typed.rty.ir(),
+            self.module.names[index],
+            params.collect::<Vec<_>>().join(", ")
+        );
+
+        // An `alloca` per local that isn't a reference, then the call counts against the limit
+        self.block("entry".to_string());
+        for (slot, local) in typed.locals.iter().enumerate() {
+            if let Some(Param::Value(ty)) = local {
+                self.line(format!("%l{slot} = alloca {}", ty.ir()));
+                if slot < spec.params.len() {
+                    self.line(format!("store {} %a{slot}, ptr %l{slot}", ty.ir()));
+                }
+            }
+        }
+        let depth = self.value("load i64, ptr @morpho_depth".to_string());
+        let depth = self.value(format!("add i64 {depth}, 1"));
+        self.line(format!("store i64 {depth}, ptr @morpho_depth"));
+        let too_deep = self.value(format!("icmp sgt i64 {depth}, {}", max_call_depth()));
+        self.check(&too_deep, |w| w.line(format!("call void @morpho_stack_overflow(i64 {depth})")));
+        self.branch(0, &[]);
+        self.line("br label %pc0".to_string());
+
+        let leaders = leaders(code);
+        let mut stack: Option<Vec<String>> = None;
+        for (pc, &op) in code.iter().enumerate() {
+            let Some(types) = &typed.stacks[pc] else {
+                stack = None;
+                continue;
+            };
+            if leaders.contains(&pc) {
+                if let Some(values) = stack.take() {
+                    self.branch(pc, &values);
+                    self.line(format!("br label %pc{pc}"));
+                }
+                self.block(format!("pc{pc}"));
+                let phis = types.iter().enumerate().map(|(i, param)| match param {
+                    Param::Value(Ty::Void) => "void".to_string(),
+                    _ => format!("%p{pc}.{i}"),
+                });
+                stack = Some(phis.collect());
+            }
+            let mut values = stack.take().expect("reachable code follows a jump target or the previous instruction");
+            let ty = |n: usize| match types[types.len() - n] {
+                Param::Value(ty) | Param::Ref(ty) => ty,
+            };
+            let mut falls_through = true;
+            match op {
+                Op::Const(id) => {
+                    let value = self.constant(&bytecode.constants[id as usize]);
+                    values.push(value);
+                }
+                Op::Void => values.push("void".to_string()),
+                Op::Pop => {
+                    values.pop();
+                }
+                Op::LoadLocal(slot) => {
+                    let local_ty = match typed.locals[slot as usize] {
+                        Some(Param::Value(ty) | Param::Ref(ty)) => ty,
+                        None => unreachable!("the checker typed every local the code reads"),
+                    };
+                    let value = self.value(format!("load {}, ptr {}", local_ty.ir(), local(typed, slot)));
+                    values.push(value);
+                }
+                Op::DefineLocal(slot) | Op::StoreLocal(slot) => {
+                    let value = values.pop().unwrap();
+                    self.line(format!("store {} {value}, ptr {}", ty(1).ir(), local(typed, slot)));
+                }
+                Op::RefLocal(slot) => values.push(local(typed, slot)),
+                Op::LoadGlobal(id) => {
+                    let global = global_name(&bytecode.globals[id as usize].name);
+                    let global_ty = self.checked.globals[id as usize].expect("the checker typed every global the code reads");
+                    let value = self.value(format!("load {}, ptr {global}", global_ty.ir()));
+                    values.push(value);
+                }
+                Op::StoreGlobal(id) => {
+                    let value = values.pop().unwrap();
+                    let global = global_name(&bytecode.globals[id as usize].name);
+                    self.line(format!("store {} {value}, ptr {global}", ty(1).ir()));
+                }
+                Op::RefGlobal(id) => values.push(global_name(&bytecode.globals[id as usize].name)),
+                Op::Concat(len) => {
+                    let parts = values.split_off(values.len() - len as usize);
+                    let mut string = self.module.string("");
+                    for (i, part) in parts.iter().enumerate() {
+                        let part = self.display(ty(len as usize - i), part);
+                        string = self.value(format!("call ptr @morpho_str_concat(ptr {string}, ptr {part})"));
+                    }
+                    values.push(string);
+                }
+                Op::Neg | Op::Not => {
+                    let value = values.pop().unwrap();
+                    let value = self.unary(op, ty(1), &value);
+                    values.push(value);
+                }
+                Op::Jump(target) => {
+                    self.branch(target as usize, &values);
+                    self.line(format!("br label %pc{target}"));
+                    falls_through = false;
+                }
+                Op::JumpIfFalse(target) => {
+                    let condition = values.pop().unwrap();
+                    self.branch(pc + 1, &values);
+                    self.branch(target as usize, &values);
+                    self.line(format!("br i1 {condition}, label %pc{}, label %pc{target}", pc + 1));
+                    falls_through = false;
+                }
+                Op::Call { argc, .. } | Op::TailCall { argc, .. } => {
+                    let callee = &typed.callees[&pc];
+                    let args = values.split_off(values.len() - argc as usize);
+                    let mut operands = Vec::new();
+                    for (i, (arg, param)) in args.iter().zip(&callee.params).enumerate() {
+                        let arg = match (ty(argc as usize - i), param) {
+                            (Ty::Int, Param::Value(Ty::Float)) => self.value(format!("sitofp i64 {arg} to double")),
+                            _ => arg.clone(),
+                        };
+                        operands.push(format!("{} {arg}", param.ir()));
+                    }
+                    let index = self.checked.index[callee];
+                    let name = &self.module.names[index];
+                    let rty = self.checked.specs[index].1.rty;
+                    let call = format!("call {} {name}({})", rty.ir(), operands.join(", "));
+                    match (op, rty) {
+                        (Op::TailCall { .. }, Ty::Void) => {
+                            self.leave();
+                            self.line(format!("tail {call}"));
+                            self.line("ret void".to_string());
+                            falls_through = false;
+                        }
+                        (Op::TailCall { .. }, rty) => {
+                            self.leave();
+                            let result = self.value(format!("tail {call}"));
+                            self.line(format!("ret {} {result}", rty.ir()));
+                            falls_through = false;
+                        }
+                        (_, Ty::Void) => {
+                            self.line(call);
+                            values.push("void".to_string());
+                        }
+                        _ => {
+                            let result = self.value(call);
+                            values.push(result);
+                        }
+                    }
+                }
+                Op::CallNative { argc, .. } => {
+                    // `print`, which writes its arguments and a newline
+                    let args = values.split_off(values.len() - argc as usize);
+                    for (i, arg) in args.iter().enumerate() {
+                        match ty(argc as usize - i) {
+                            Ty::Int => self.line(format!("call void @morpho_print_int(i64 {arg})")),
+                            Ty::Float => self.line(format!("call void @morpho_print_float(double {arg})")),
+                            Ty::Bool => {
+                                let arg = self.value(format!("zext i1 {arg} to i32"));
+                                self.line(format!("call void @morpho_print_bool(i32 {arg})"));
+                            }
+                            ty => {
+                                let arg = self.display(ty, arg);
+                                self.line(format!("call void @morpho_print_str(ptr {arg})"));
+                            }
+                        }
+                    }
+                    self.line("call void @morpho_print_newline()".to_string());
+                    values.push("void".to_string());
+                }
+                Op::Return => {
+                    let mut value = values.pop().unwrap();
+                    if ty(1) == Ty::Int && typed.rty == Ty::Float {
+                        value = self.value(format!("sitofp i64 {value} to double"));
+                    }
+                    self.leave();
+                    self.line(format!("ret {} {value}", typed.rty.ir()));
+                    falls_through = false;
+                }
+                Op::ReturnVoid => {
+                    self.leave();
+                    self.line("ret void".to_string());
+                    falls_through = false;
+                }
+                Op::Box | Op::Array(_) => unreachable!("the checker rejects {op:?}"),
+                op => {
+                    let rhs = values.pop().unwrap();
+                    let lhs = values.pop().unwrap();
+                    let value = self.binary(op, ty(2), ty(1), &lhs, &rhs);
+                    values.push(value);
+                }
+            }
+            if falls_through {
+                stack = Some(values);
+            }
+        }
+
+        let mut out = header;
+        out.push('\n');
+        for (label, lines) in &self.blocks {
+            writeln!(out, "{label}:").unwrap();
+            // Stack values of jump targets come from the blocks jumping there
+            if let Some(pc) = label.strip_prefix("pc").and_then(|pc| pc.parse::<usize>().ok()) {
+                let types = typed.stacks[pc].as_ref().unwrap();
+                let incoming = &self.incoming[&pc];
+                for (i, param) in types.iter().enumerate() {
+                    if *param == Param::Value(Ty::Void) {
+                        continue;
+                    }
+                    let sources = incoming.iter().map(|(from, values)| format!("[ {}, %{from} ]", values[i]));
+                    writeln!(out, "  %p{pc}.{i} = phi {} {}", param.ir(), sources.collect::<Vec<_>>().join(", ")).unwrap();
+                }
+            }
+            for line in lines {
+                writeln!(out, "  {line}").unwrap();
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    fn constant(&mut self, value: &Value) -> String {
+        match value {
+            Value::Int(i) => i.to_string(),
+            // Hexadecimal floats are exact
+            Value::Float(f) => format!("0x{:016X}", f.to_bits()),
+            Value::Bool(b) => b.to_string(),
+            Value::String(s) => self.module.string(s),
+            _ => unreachable!("the checker only lets int, float, bool and string constants through"),
+        }
+    }
+
+    /// The string the interpreter prints for `value`
+    fn display(&mut self, ty: Ty, value: &str) -> String {
+        match ty {
+            Ty::Int => self.value(format!("call ptr @morpho_int_str(i64 {value})")),
+            Ty::Float => self.value(format!("call ptr @morpho_float_str(double {value})")),
+            Ty::Bool => {
+                let value = self.value(format!("zext i1 {value} to i32"));
+                self.value(format!("call ptr @morpho_bool_str(i32 {value})"))
+            }
+            Ty::Str => value.to_string(),
+            Ty::Void => self.module.string("None"),
+        }
+    }
+
+    fn float(&mut self, ty: Ty, value: &str) -> String {
+        match ty {
+            Ty::Int => self.value(format!("sitofp i64 {value} to double")),
+            _ => value.to_string(),
+        }
+    }
+
+    fn unary(&mut self, op: Op, ty: Ty, value: &str) -> String {
+        match (op, ty) {
+            (Op::Neg, Ty::Int) => {
+                let min = self.value(format!("icmp eq i64 {value}, {}", i64::MIN));
+                self.check(&min, |w| w.line(format!("call void @morpho_overflow_neg(i64 {value})")));
+                self.value(format!("sub i64 0, {value}"))
+            }
+            (Op::Neg, _) => self.value(format!("fneg double {value}")),
+            (_, Ty::Bool) => self.value(format!("xor i1 {value}, true")),
+            _ => self.value(format!("xor i64 {value}, -1")),
+        }
+    }
+
+    /// Writes `op` on operands of the checked types. Overflowing int
+    /// arithmetic and division by zero call the runtime, which reports them
+    /// like the interpreter, and strings are compared with `morpho_str_cmp`
+    fn binary(&mut self, op: Op, lhs_ty: Ty, rhs_ty: Ty, lhs: &str, rhs: &str) -> String {
+        let ty = binary(op, lhs_ty, rhs_ty).expect("the checker typed every operation");
+        let symbol = match op {
+            Op::Add => "+",
+            Op::Sub => "-",
+            Op::Mul => "*",
+            Op::Div => "/",
+            Op::Mod => "%",
+            Op::Shl => "<<",
+            _ => ">>",
+        };
+        let ints = lhs_ty == Ty::Int && rhs_ty == Ty::Int;
+        let numbers = matches!(lhs_ty, Ty::Int | Ty::Float) && matches!(rhs_ty, Ty::Int | Ty::Float);
+        if numbers && !ints {
+            let lhs = self.float(lhs_ty, lhs);
+            let rhs = self.float(rhs_ty, rhs);
+            return self.float_binary(op, &lhs, &rhs);
+        }
+        match op {
+            Op::Add if ty == Ty::Str => self.value(format!("call ptr @morpho_str_concat(ptr {lhs}, ptr {rhs})")),
+            Op::Add | Op::Sub | Op::Mul => {
+                let intrinsic = match op {
+                    Op::Add => "sadd",
+                    Op::Sub => "ssub",
+                    _ => "smul",
+                };
+                let result = self.value(format!("call {{ i64, i1 }} @llvm.{intrinsic}.with.overflow.i64(i64 {lhs}, i64 {rhs})"));
+                let value = self.value(format!("extractvalue {{ i64, i1 }} {result}, 0"));
+                let overflow = self.value(format!("extractvalue {{ i64, i1 }} {result}, 1"));
+                self.overflow(&overflow, symbol, lhs, rhs);
+                value
+            }
+            Op::Div | Op::Mod => {
+                let zero = self.value(format!("icmp eq i64 {rhs}, 0"));
+                self.check(&zero, |w| w.line("call void @morpho_division_by_zero()".to_string()));
+                let min = self.value(format!("icmp eq i64 {lhs}, {}", i64::MIN));
+                let minus_one = self.value(format!("icmp eq i64 {rhs}, -1"));
+                let overflow = self.value(format!("and i1 {min}, {minus_one}"));
+                self.overflow(&overflow, symbol, lhs, rhs);
+                let instruction = if op == Op::Div { "sdiv" } else { "srem" };
+                self.value(format!("{instruction} i64 {lhs}, {rhs}"))
+            }
+            Op::Shl | Op::Shr => {
+                let out_of_range = self.value(format!("icmp uge i64 {rhs}, 64"));
+                self.overflow(&out_of_range, symbol, lhs, rhs);
+                let instruction = if op == Op::Shl { "shl" } else { "ashr" };
+                self.value(format!("{instruction} i64 {lhs}, {rhs}"))
+            }
+            Op::Xor => self.value(format!("xor {} {lhs}, {rhs}", ty.ir())),
+            Op::BitAnd | Op::And => self.value(format!("and {} {lhs}, {rhs}", lhs_ty.ir())),
+            Op::BitOr | Op::Or => self.value(format!("or {} {lhs}, {rhs}", lhs_ty.ir())),
+            _ => {
+                // Ints compare signed, bools as `false < true` and strings by their bytes
+                let predicate = match (op, lhs_ty) {
+                    (Op::Eq, _) => "eq",
+                    (Op::Ne, _) => "ne",
+                    (Op::Gt, Ty::Bool) => "ugt",
+                    (Op::Lt, Ty::Bool) => "ult",
+                    (Op::Ge, Ty::Bool) => "uge",
+                    (Op::Le, Ty::Bool) => "ule",
+                    (Op::Gt, _) => "sgt",
+                    (Op::Lt, _) => "slt",
+                    (Op::Ge, _) => "sge",
+                    _ => "sle",
+                };
+                match lhs_ty {
+                    Ty::Str => {
+                        let order = self.value(format!("call i32 @morpho_str_cmp(ptr {lhs}, ptr {rhs})"));
+                        self.value(format!("icmp {predicate} i32 {order}, 0"))
+                    }
+                    ty => self.value(format!("icmp {predicate} {} {lhs}, {rhs}", ty.ir())),
+                }
+            }
+        }
+    }
+
+    fn float_binary(&mut self, op: Op, lhs: &str, rhs: &str) -> String {
+        let instruction = match op {
+            Op::Add => "fadd",
+            Op::Sub => "fsub",
+            Op::Mul => "fmul",
+            Op::Div => "fdiv",
+            Op::Mod => "frem",
+            // Ordered comparisons are false for NaN, which is unequal to everything
+            Op::Eq => "fcmp oeq",
+            Op::Ne => "fcmp une",
+            Op::Gt => "fcmp ogt",
+            Op::Lt => "fcmp olt",
+            Op::Ge => "fcmp oge",
+            _ => "fcmp ole",
+        };
+        if matches!(op, Op::Div | Op::Mod) {
+            let zero = self.value(format!("fcmp oeq double {rhs}, 0.0"));
+            self.check(&zero, |w| w.line("call void @morpho_division_by_zero()".to_string()));
+        }
+        self.value(format!("{instruction} double {lhs}, {rhs}"))
+    }
+
+    fn overflow(&mut self, condition: &str, symbol: &str, lhs: &str, rhs: &str) {
+        let symbol = self.module.string(symbol);
+        self.check(condition, |w| w.line(format!("call void @morpho_overflow(ptr {symbol}, i64 {lhs}, i64 {rhs})")));
+    }
+}
+
+/// Pointer to the local in `slot`, an `alloca` or the reference it was passed as
+fn local(typed: &Typed, slot: u16) -> String {
+    match typed.locals[slot as usize] {
+        Some(Param::Ref(_)) => format!("%a{slot}"),
+        _ => format!("%l{slot}"),
+    }
+}
@@ -0,0 +1,135 @@
+/* Runtime of Morpho programs compiled to LLVM IR with `morpho_c build --emit=llvm-ir`.
+ *
+ * Values print the way the interpreter prints them and errors end the program
+ * with the interpreter's message, without the trace. Strings are NUL-terminated
+ * and never freed. */
+#include <inttypes.h>
+#include <math.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+static char *copy(const char *s) {
+    char *out = malloc(strlen(s) + 1);
+    strcpy(out, s);
+    return out;
+}
+
+static void fail(void) {
+    fflush(stdout);
+    exit(1);
+}
+
+char *morpho_int_str(int64_t i) {
+    char buffer[24];
+    snprintf(buffer, sizeof buffer, "%" PRId64, i);
+    return copy(buffer);
+}
+
+/* Whole numbers keep one decimal, other floats take the fewest digits that
+ * read back as the same float, written out without an exponent like Rust does */
+char *morpho_float_str(double f) {
+    if (isnan(f)) return copy("NaN");
+    if (isinf(f)) return copy(f > 0 ? "inf" : "-inf");
+    if (f == trunc(f)) {
+        int len = snprintf(NULL, 0, "%.1f", f);
+        char *out = malloc(len + 1);
+        snprintf(out, len + 1, "%.1f", f);
+        return out;
+    }
+
+    char scientific[32];
+    int precision = 1;
+    for (;;) {
+        snprintf(scientific, sizeof scientific, "%.*e", precision - 1, f);
+        if (precision == 17 || strtod(scientific, NULL) == f) break;
+        precision++;
+    }
+    int negative = scientific[0] == '-';
+    char *e = strchr(scientific, 'e');
+    int exponent = atoi(e + 1);
+    char digits[20];
+    int n = 0;
+    for (char *c = scientific + negative; c < e; c++) {
+        if (*c != '.') digits[n++] = *c;
+    }
+
+    char *out = malloc(n + abs(exponent) + 4);
+    char *end = out;
+    if (negative) *end++ = '-';
+    if (exponent < 0) {
+        *end++ = '0';
+        *end++ = '.';
+        for (int i = 0; i < -exponent - 1; i++) *end++ = '0';
+        for (int i = 0; i < n; i++) *end++ = digits[i];
+    } else {
+        for (int i = 0; i <= exponent; i++) *end++ = i < n ? digits[i] : '0';
+        *end++ = '.';
+        for (int i = exponent + 1; i < n; i++) *end++ = digits[i];
+    }
+    *end = '\0';
+    return out;
+}
+
+char *morpho_bool_str(int32_t b) {
+    return copy(b ? "true" : "false");
+}
+
+char *morpho_str_concat(const char *lhs, const char *rhs) {
+    size_t len = strlen(lhs);
+    char *out = malloc(len + strlen(rhs) + 1);
+    strcpy(out, lhs);
+    strcpy(out + len, rhs);
+    return out;
+}
+
+/* Orders strings by their bytes */
+int32_t morpho_str_cmp(const char *lhs, const char *rhs) {
+    return strcmp(lhs, rhs);
+}
+
+void morpho_print_int(int64_t i) {
+    printf("%" PRId64, i);
+}
+
+void morpho_print_float(double f) {
+    char *s = morpho_float_str(f);
+    fputs(s, stdout);
+    free(s);
+}
+
+void morpho_print_bool(int32_t b) {
+    fputs(b ? "true" : "false", stdout);
+}
+
+void morpho_print_str(const char *s) {
+    fputs(s, stdout);
+}
+
+void morpho_print_newline(void) {
+    putchar('\n');
+}
+
+void morpho_overflow(const char *op, int64_t lhs, int64_t rhs) {
+    fflush(stdout);
+    fprintf(stderr, "Error: Overflow: %" PRId64 " %s %" PRId64 " overflows\n", lhs, op, rhs);
+    fail();
+}
+
+void morpho_overflow_neg(int64_t value) {
+    fflush(stdout);
+    fprintf(stderr, "Error: Overflow: -(%" PRId64 ") overflows\n", value);
+    fail();
+}
+
+void morpho_division_by_zero(void) {
+    fflush(stdout);
+    fprintf(stderr, "Error: DivisionByZero: division by zero\n");
+    fail();
+}
+
+void morpho_stack_overflow(int64_t depth) {
+    fflush(stdout);
+    fprintf(stderr, "Error: StackOverflow: %" PRId64 " nested calls\n", depth);
+    fail();
+}
@@ -0,0 +1,503 @@
+use crate::program::builtins;
+use crate::program::value::Value;
+use crate::program::vm::bytecode::{Bytecode, FunctionProto, Global, Op};
+use crate::program::vm::verifier::verify;
+use crate::program::global_env;
+use anyhow::{anyhow, Context, Result};
+use std::fs;
+use std::path::Path;
+use crate::program::shared::Shared;
+
+pub const EXTENSION: &str = "mbc";
+pub const MAGIC: [u8; 4] = *b"MBC\0";
+pub const VERSION: u16 = 2;
+
+/// Bytes before the payload: magic, version, flags, payload length and checksum
+const HEADER_LEN: usize = 16;
+
+const SECTION_CONSTANTS: u8 = 1;
+const SECTION_GLOBALS: u8 = 2;
+const SECTION_FUNCTIONS: u8 = 3;
+const SECTION_LINES: u8 = 4;
+const SECTION_ENTRY: u8 = 5;
+
+const GLOBAL_SLOT: u8 = 0;
+const GLOBAL_BUILTIN: u8 = 1;
+
+/// Writes `bytecode` as a `.mbc` file.
+///
+/// All numbers are little-endian. The header holds the magic, the format
+/// version, reserved flags, the payload length and its FNV-1a checksum. The
+/// payload is made of the constant pool, the global table, the function table,
+/// the debug line table and the entry section, each starting with its tag.
+/// Built-in functions and core modules items are stored by path and linked
+/// again when the file is loaded, module-level globals are stored as empty
+/// slots filled by the init functions
+pub fn encode(bytecode: &Bytecode) -> Result<Vec<u8>> {
+    let mut payload = Writer::default();
+
+    payload.u8(SECTION_CONSTANTS);
+    payload.len(bytecode.constants.len());
+    for value in &bytecode.constants {
+        payload.value(value)?;
+    }
+
+    payload.u8(SECTION_GLOBALS);
+    payload.len(bytecode.globals.len());
+    let builtins = builtin_cells();
+    for global in &bytecode.globals {
+        payload.str(&global.name);
+        match builtins.iter().find(|(_, cell)| Shared::ptr_eq(cell, &global.cell)) {
+            Some((path, _)) => {
+                payload.u8(GLOBAL_BUILTIN);
+                payload.str(path);
+            }
+            None if matches!(*global.cell.borrow(), Value::Void) => payload.u8(GLOBAL_SLOT),
+            None => return Err(anyhow!("{} can't be stored in a .{EXTENSION} file", global.name)),
+        }
+    }
+
+    payload.u8(SECTION_FUNCTIONS);
+    payload.len(bytecode.functions.len());
+    for func in &bytecode.functions {
+        payload.str(&func.name);
+        payload.len(func.params.len());
+        for (ident, ty) in &func.params {
+            payload.str(ident);
+            payload.opt_str(ty.as_deref());
+        }
+        payload.str(&func.rty);
+        payload.u16(func.locals);
+        payload.len(func.code.len());
+        for op in &func.code {
+            payload.op(op);
+        }
+    }
+
+    payload.u8(SECTION_LINES);
+    for func in &bytecode.functions {
+        payload.opt_str(func.file.as_deref());
+        payload.len(func.lines.len());
+        for &(pc, line) in &func.lines {
+            payload.u32(pc);
+            payload.u32(line);
+        }
+    }
+
+    payload.u8(SECTION_ENTRY);
+    payload.len(bytecode.init.len());
+    for &init in &bytecode.init {
+        payload.u32(init);
+    }
+    payload.u32(bytecode.entry);
+
+    let payload = payload.0;
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend(MAGIC);
+    bytes.extend(VERSION.to_le_bytes());
+    bytes.extend(0u16.to_le_bytes());
+    bytes.extend((payload.len() as u32).to_le_bytes());
+    bytes.extend(checksum(&payload).to_le_bytes());
+    bytes.extend(payload);
+    Ok(bytes)
+}
+
+/// Reads a `.mbc` file, links its built-ins and verifies it
+pub fn decode(bytes: &[u8]) -> Result<Bytecode> {
+    let bytecode = parse(bytes).context("Malformed bytecode file")?;
+    verify(&bytecode).context("Invalid bytecode file")?;
+    Ok(bytecode)
+}
+
+pub fn write(path: &Path, bytecode: &Bytecode) -> Result<()> {
+    fs::write(path, encode(bytecode)?).with_context(|| format!("Failed to write {}", path.display()))
+}
+
+pub fn read(path: &Path) -> Result<Bytecode> {
+    let bytes = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    decode(&bytes).with_context(|| format!("Failed to load {}", path.display()))
+}
+
+fn parse(bytes: &[u8]) -> Result<Bytecode> {
+    if bytes.len() < HEADER_LEN || bytes[..4] != MAGIC {
+        return Err(anyhow!("not a .{EXTENSION} file"));
+    }
+    let mut header = Reader { bytes: &bytes[4..HEADER_LEN], pos: 4 };
+    let version = header.u16()?;
+    if version != VERSION {
+        return Err(anyhow!("unsupported format version {version}, expected {VERSION}"));
+    }
+    let flags = header.u16()?;
+    if flags != 0 {
+        return Err(anyhow!("unknown flags {flags:#06x}"));
+    }
+    let len = header.u32()? as usize;
+    let sum = header.u32()?;
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() != len {
+        return Err(anyhow!("payload is {} bytes long, the header says {len}", payload.len()));
+    }
+    if checksum(payload) != sum {
+        return Err(anyhow!("checksum mismatch"));
+    }
+
+    let mut reader = Reader { bytes: payload, pos: HEADER_LEN };
+    reader.section(SECTION_CONSTANTS)?;
+    let constants = (0..reader.len()?).map(|_| reader.value()).collect::<Result<_>>()?;
+
+    reader.section(SECTION_GLOBALS)?;
+    let mut globals = Vec::new();
+    for _ in 0..reader.len()? {
+        let name = reader.str()?;
+        let value = match reader.u8()? {
+            GLOBAL_SLOT => Value::Void,
+            GLOBAL_BUILTIN => {
+                let path = reader.str()?;
+                link(&path).ok_or_else(|| anyhow!("unknown built-in {path}"))?
+            }
+            kind => return Err(anyhow!("unknown kind {kind} of global {name}")),
+        };
+        globals.push(Global { name, cell: Shared::new(value) });
+    }
+
+    reader.section(SECTION_FUNCTIONS)?;
+    let mut functions = Vec::new();
+    for _ in 0..reader.len()? {
+        let name = reader.str()?;
+        let params = (0..reader.len()?)
+            .map(|_| Ok((reader.str()?, reader.opt_str()?)))
+            .collect::<Result<_>>()?;
+        let rty = reader.str()?;
+        let locals = reader.u16()?;
+        let code = (0..reader.len()?).map(|_| reader.op()).collect::<Result<_>>()?;
+        functions.push(FunctionProto { name, params, rty, locals, code, ..FunctionProto::default() });
+    }
+
+    reader.section(SECTION_LINES)?;
+    for func in &mut functions {
+        func.file = reader.opt_str()?;
+        func.lines = (0..reader.len()?).map(|_| Ok((reader.u32()?, reader.u32()?))).collect::<Result<_>>()?;
+    }
+
+    reader.section(SECTION_ENTRY)?;
+    let init = (0..reader.len()?).map(|_| reader.u32()).collect::<Result<_>>()?;
+    let entry = reader.u32()?;
+    if reader.pos - HEADER_LEN != payload.len() {
+        return Err(anyhow!("unexpected data at byte {}", reader.pos));
+    }
+    Ok(Bytecode { constants, functions, globals, init, entry })
+}
+
+/// Paths and cells of the built-ins and core module items of the program set up
+/// last. User items shadowing them are skipped: until the globals are
+/// initialized they hold functions, modules or nothing
+fn builtin_cells() -> Vec<(String, Shared<Value>)> {
+    let global_env = global_env();
+    let global = global_env.borrow();
+    let mut cells = Vec::new();
+    for (ident, _) in builtins() {
+        let Some(cell) = global.global_stmts.get(ident) else { continue };
+        match &*cell.borrow() {
+            Value::FuncPtr(_) => cells.push((ident.to_string(), cell.clone())),
+            Value::Module(module) => {
+                for (item, cell, _) in module.visible_items(&[]) {
+                    if !matches!(*cell.borrow(), Value::Void | Value::Func(_) | Value::Module(_)) {
+                        cells.push((format!("{ident}::{item}"), cell.clone()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    cells
+}
+
+/// Value of the built-in at `path`, such as `print` or `math::pi`
+fn link(path: &str) -> Option<Value> {
+    let mut segments = path.split("::");
+    let first = segments.next()?;
+    let mut value = builtins().into_iter().find(|(ident, _)| *ident == first)?.1;
+    for segment in segments {
+        let Value::Module(module) = value else { return None };
+        value = module.get(segment)?.get();
+    }
+    Some(value)
+}
+
+/// 32-bit FNV-1a
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c_9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+const VALUE_INT: u8 = 0;
+const VALUE_BIGINT: u8 = 1;
+const VALUE_FLOAT: u8 = 2;
+const VALUE_BOOL: u8 = 3;
+const VALUE_STRING: u8 = 4;
+const VALUE_RANGE: u8 = 5;
+
+#[derive(Default)]
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend(value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+
+    fn str(&mut self, s: &str) {
+        self.len(s.len());
+        self.0.extend(s.as_bytes());
+    }
+
+    fn opt_str(&mut self, s: Option<&str>) {
+        match s {
+            Some(s) => {
+                self.u8(1);
+                self.str(s);
+            }
+            None => self.u8(0),
+        }
+    }
+
+    fn value(&mut self, value: &Value) -> Result<()> {
+        match value {
+            Value::Int(i) => {
+                self.u8(VALUE_INT);
+                self.i64(*i);
+            }
+            Value::BigInt(b) => {
+                self.u8(VALUE_BIGINT);
+                self.str(&b.to_string());
+            }
+            Value::Float(f) => {
+                self.u8(VALUE_FLOAT);
+                self.0.extend(f.to_bits().to_le_bytes());
+            }
+            Value::Bool(b) => {
+                self.u8(VALUE_BOOL);
+                self.u8(*b as u8);
+            }
+            Value::String(s) => {
+                self.u8(VALUE_STRING);
+                self.str(s);
+            }
+            Value::Range(start, end) => {
+                self.u8(VALUE_RANGE);
+                self.i64(*start);
+                self.i64(*end);
+            }
+            value => return Err(anyhow!("constant {value:?} can't be stored in a .{EXTENSION} file")),
+        }
+        Ok(())
+    }
+
+    fn op(&mut self, op: &Op) {
+        let (code, operands): (u8, &[u32]) = match *op {
+            Op::Const(id) => (0, &[id]),
+            Op::Void => (1, &[]),
+            Op::Pop => (2, &[]),
+            Op::LoadLocal(slot) => (3, &[slot as u32]),
+            Op::DefineLocal(slot) => (4, &[slot as u32]),
+            Op::StoreLocal(slot) => (5, &[slot as u32]),
+            Op::RefLocal(slot) => (6, &[slot as u32]),
+            Op::LoadGlobal(id) => (7, &[id]),
+            Op::StoreGlobal(id) => (8, &[id]),
+            Op::RefGlobal(id) => (9, &[id]),
+            Op::Box => (10, &[]),
+            Op::Add => (11, &[]),
+            Op::Sub => (12, &[]),
+            Op::Mul => (13, &[]),
+            Op::Div => (14, &[]),
+            Op::Mod => (15, &[]),
+            Op::Xor => (16, &[]),
+            Op::BitAnd => (17, &[]),
+            Op::BitOr => (18, &[]),
+            Op::Shl => (19, &[]),
+            Op::Shr => (20, &[]),
+            Op::Neg => (21, &[]),
+            Op::Not => (22, &[]),
+            Op::Eq => (23, &[]),
+            Op::Ne => (24, &[]),
+            Op::Gt => (25, &[]),
+            Op::Lt => (26, &[]),
+            Op::Ge => (27, &[]),
+            Op::Le => (28, &[]),
+            Op::And => (29, &[]),
+            Op::Or => (30, &[]),
+            Op::Array(len) => (31, &[len]),
+            Op::Concat(len) => (32, &[len]),
+            Op::Jump(target) => (33, &[target]),
+            Op::JumpIfFalse(target) => (34, &[target]),
+            Op::Call { func, argc } => (35, &[func, argc]),
+            Op::CallNative { global, argc } => (36, &[global, argc]),
+            Op::Return => (37, &[]),
+            Op::ReturnVoid => (38, &[]),
+            Op::TailCall { func, argc } => (39, &[func, argc]),
+            Op::NameError(id) => (40, &[id]),
+            Op::TypeError(id) => (41, &[id]),
+            Op::Closure { func, captured } => (42, &[func, captured]),
+            Op::CallValue { name, argc } => (43, &[name, argc]),
+        };
+        self.u8(code);
+        for &operand in operands {
+            self.u32(operand);
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    /// Offset in the file, for error messages
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let bytes = self.bytes.split_first_chunk::<N>();
+        let (chunk, rest) = bytes.ok_or_else(|| anyhow!("unexpected end of file at byte {}", self.pos))?;
+        self.bytes = rest;
+        self.pos += N;
+        Ok(*chunk)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        let len = self.u32()? as usize;
+        // Every item takes at least a byte, so a longer table is surely truncated
+        if len > self.bytes.len() {
+            return Err(anyhow!("length {len} at byte {} runs past the end of file", self.pos - 4));
+        }
+        Ok(len)
+    }
+
+    fn str(&mut self) -> Result<String> {
+        let len = self.len()?;
+        let (s, rest) = self.bytes.split_at(len);
+        let s = std::str::from_utf8(s).map_err(|_| anyhow!("invalid UTF-8 string at byte {}", self.pos))?;
+        self.bytes = rest;
+        self.pos += len;
+        Ok(s.to_string())
+    }
+
+    fn opt_str(&mut self) -> Result<Option<String>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.str()?)),
+            tag => Err(anyhow!("invalid optional tag {tag} at byte {}", self.pos - 1)),
+        }
+    }
+
+    fn section(&mut self, tag: u8) -> Result<()> {
+        match self.u8()? {
+            found if found == tag => Ok(()),
+            found => Err(anyhow!("expected section {tag} at byte {}, found {found}", self.pos - 1)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        let value = match self.u8()? {
+            VALUE_INT => Value::Int(self.i64()?),
+            VALUE_BIGINT => {
+                let digits = self.str()?;
+                Value::BigInt(digits.parse().map_err(|_| anyhow!("invalid big integer {digits}"))?)
+            }
+            VALUE_FLOAT => Value::Float(f64::from_bits(u64::from_le_bytes(self.take()?))),
+            VALUE_BOOL => match self.u8()? {
+                0 => Value::Bool(false),
+                1 => Value::Bool(true),
+                b => return Err(anyhow!("invalid bool {b} at byte {}", self.pos - 1)),
+            },
+            VALUE_STRING => Value::String(self.str()?),
+            VALUE_RANGE => Value::Range(self.i64()?, self.i64()?),
+            tag => return Err(anyhow!("unknown constant tag {tag} at byte {}", self.pos - 1)),
+        };
+        Ok(value)
+    }
+
+    fn slot(&mut self) -> Result<u16> {
+        let slot = self.u32()?;
+        u16::try_from(slot).map_err(|_| anyhow!("slot {slot} out of range at byte {}", self.pos - 4))
+    }
+
+    fn op(&mut self) -> Result<Op> {
+        let op = match self.u8()? {
+            0 => Op::Const(self.u32()?),
+            1 => Op::Void,
+            2 => Op::Pop,
+            3 => Op::LoadLocal(self.slot()?),
+            4 => Op::DefineLocal(self.slot()?),
+            5 => Op::StoreLocal(self.slot()?),
+            6 => Op::RefLocal(self.slot()?),
+            7 => Op::LoadGlobal(self.u32()?),
+            8 => Op::StoreGlobal(self.u32()?),
+            9 => Op::RefGlobal(self.u32()?),
+            10 => Op::Box,
+            11 => Op::Add,
+            12 => Op::Sub,
+            13 => Op::Mul,
+            14 => Op::Div,
+            15 => Op::Mod,
+            16 => Op::Xor,
+            17 => Op::BitAnd,
+            18 => Op::BitOr,
+            19 => Op::Shl,
+            20 => Op::Shr,
+            21 => Op::Neg,
+            22 => Op::Not,
+            23 => Op::Eq,
+            24 => Op::Ne,
+            25 => Op::Gt,
+            26 => Op::Lt,
+            27 => Op::Ge,
+            28 => Op::Le,
+            29 => Op::And,
+            30 => Op::Or,
+            31 => Op::Array(self.u32()?),
+            32 => Op::Concat(self.u32()?),
+            33 => Op::Jump(self.u32()?),
+            34 => Op::JumpIfFalse(self.u32()?),
+            35 => Op::Call { func: self.u32()?, argc: self.u32()? },
+            36 => Op::CallNative { global: self.u32()?, argc: self.u32()? },
+            37 => Op::Return,
+            38 => Op::ReturnVoid,
+            39 => Op::TailCall { func: self.u32()?, argc: self.u32()? },
+            40 => Op::NameError(self.u32()?),
+            41 => Op::TypeError(self.u32()?),
+            42 => Op::Closure { func: self.u32()?, captured: self.u32()? },
+            43 => Op::CallValue { name: self.u32()?, argc: self.u32()? },
+            code => return Err(anyhow!("unknown opcode {code} at byte {}", self.pos - 1)),
+        };
+        Ok(op)
+    }
+}
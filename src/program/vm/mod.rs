@@ -0,0 +1,353 @@
+mod analysis;
+pub mod bytecode;
+pub mod compiler;
+pub mod disasm;
+#[cfg(feature = "jit")]
+mod jit;
+pub mod llvm;
+pub mod mbc;
+pub mod verifier;
+
+#[cfg(feature = "jit")]
+pub use jit::{stats as jit_stats, JitStats};
+
+use crate::ast::Prog;
+use crate::program::environment::LocalEnvironment;
+use crate::program::error::{catch, raise, MorphoError};
+use crate::program::loader::SourceMap;
+use crate::program::value::Value;
+use crate::program::vm::bytecode::{Bytecode, Callable, Op};
+use crate::program::vm::compiler::Compiler;
+use crate::program::{max_call_depth, sandbox, Program};
+use crate::program::shared::{Ptr, Shared};
+
+/// Sets the program up and compiles it without running `main` or the global
+/// initializers. `sources` are the files `prog` was loaded from, if any
+pub fn compile(prog: Prog, sources: &SourceMap) -> anyhow::Result<Bytecode> {
+    let prog = catch(|| Program::new(prog))??;
+    catch(|| Compiler::compile(&prog.main_function, &prog.globals, sources.clone()))?
+}
+
+struct Frame {
+    func: u32,
+    ip: usize,
+    /// Index of the frame's first slot on the value stack
+    base: usize,
+}
+
+/// Stack machine running `Bytecode`.
+///
+/// Locals live on the value stack above the frame's base. A local that has been
+/// referenced with `&` holds a `RefValue` to the cell it was moved to, reads and
+/// assignments go through that cell, just like the evaluator binds referenced
+/// arguments to the caller's cell. Morpho calls don't recurse on the Rust stack
+/// and tail calls reuse the caller's frame
+pub struct Vm<'b> {
+    bytecode: &'b Bytecode,
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    /// Frames allowed before a call raises `StackOverflow`
+    max_depth: usize,
+    /// Passed to built-in functions, which only need it for lazy conditions
+    env: Shared<LocalEnvironment>,
+    /// Compiles hot functions, unless disabled or the program is metered by
+    /// the sandbox, which only counts the steps of the interpreter
+    #[cfg(feature = "jit")]
+    jit: Option<jit::Jit>,
+}
+
+impl<'b> Vm<'b> {
+    pub fn new(bytecode: &'b Bytecode) -> Self {
+        Self {
+            bytecode,
+            stack: Vec::new(),
+            frames: Vec::new(),
+            max_depth: max_call_depth(),
+            env: Shared::new(LocalEnvironment::new()),
+            #[cfg(feature = "jit")]
+            jit: (crate::program::jit() && !sandbox::is_metered()).then(|| jit::Jit::new(bytecode)),
+        }
+    }
+
+    /// Initializes the globals, then runs the entry function to completion and
+    /// returns its result
+    pub fn run(&mut self) -> Value {
+        let bytecode = self.bytecode;
+        for &init in &bytecode.init {
+            self.execute(init);
+        }
+        self.execute(bytecode.entry)
+    }
+
+    fn execute(&mut self, func: u32) -> Value {
+        let bytecode = self.bytecode;
+        self.call(func, 0);
+        loop {
+            let frame = self.frames.last_mut().unwrap();
+            let op = bytecode.functions[frame.func as usize].code[frame.ip];
+            frame.ip += 1;
+            sandbox::step();
+            let base = frame.base;
+            match op {
+                Op::Const(id) => self.stack.push(bytecode.constants[id as usize].clone()),
+                Op::Void => self.stack.push(Value::Void),
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::LoadLocal(slot) => {
+                    let value = match &self.stack[base + slot as usize] {
+                        Value::RefValue(cell) => cell.get(),
+                        value => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+                Op::DefineLocal(slot) => {
+                    let value = self.pop();
+                    self.stack[base + slot as usize] = value;
+                }
+                Op::StoreLocal(slot) => {
+                    let value = self.pop();
+                    match &mut self.stack[base + slot as usize] {
+                        Value::RefValue(cell) => cell.set(value),
+                        local => *local = value,
+                    }
+                }
+                Op::RefLocal(slot) => {
+                    let local = &mut self.stack[base + slot as usize];
+                    if !matches!(local, Value::RefValue(_)) {
+                        let value = std::mem::replace(local, Value::Void);
+                        *local = Value::RefValue(Shared::new(value));
+                    }
+                    let reference = local.clone();
+                    self.stack.push(reference);
+                }
+                Op::LoadGlobal(id) => {
+                    let value = bytecode.globals[id as usize].cell.get();
+                    self.stack.push(value);
+                }
+                Op::StoreGlobal(id) => {
+                    let value = self.pop();
+                    let cell = &bytecode.globals[id as usize].cell;
+                    let current = cell.get();
+                    match current {
+                        Value::RefValue(r) => r.set(value),
+                        _ => cell.set(value),
+                    }
+                }
+                Op::RefGlobal(id) => {
+                    let cell = bytecode.globals[id as usize].cell.clone();
+                    self.stack.push(Value::RefValue(cell));
+                }
+                Op::Box => {
+                    let value = self.pop();
+                    self.stack.push(Value::RefValue(Shared::new(value)));
+                }
+                Op::Add => self.binary(|l, r| l + r),
+                Op::Sub => self.binary(|l, r| l - r),
+                Op::Mul => self.binary(|l, r| l * r),
+                Op::Div => self.binary(|l, r| l / r),
+                Op::Mod => self.binary(|l, r| l % r),
+                Op::Xor => self.binary(|l, r| l ^ r),
+                Op::BitAnd => self.binary(|l, r| l & r),
+                Op::BitOr => self.binary(|l, r| l | r),
+                Op::Shl => self.binary(|l, r| l << r),
+                Op::Shr => self.binary(|l, r| l >> r),
+                Op::Neg => {
+                    let value = self.pop();
+                    self.stack.push(-value);
+                }
+                Op::Not => {
+                    let value = self.pop();
+                    self.stack.push(!value);
+                }
+                Op::Eq => self.binary(|l, r| Value::Bool(l == r)),
+                Op::Ne => self.binary(|l, r| Value::Bool(l != r)),
+                Op::Gt => self.binary(|l, r| Value::Bool(l > r)),
+                Op::Lt => self.binary(|l, r| Value::Bool(l < r)),
+                Op::Ge => self.binary(|l, r| Value::Bool(l >= r)),
+                Op::Le => self.binary(|l, r| Value::Bool(l <= r)),
+                Op::And => self.binary(|l, r| Value::Bool(truthy(l) & truthy(r))),
+                Op::Or => self.binary(|l, r| Value::Bool(truthy(l) | truthy(r))),
+                Op::Array(len) => {
+                    let items = self.stack.split_off(self.stack.len() - len as usize);
+                    self.stack.push(sandbox::limit_size(Value::Array(items)));
+                }
+                Op::Concat(len) => {
+                    let parts = self.stack.split_off(self.stack.len() - len as usize);
+                    let string = parts.into_iter().map(|part| part.deref_value().to_string()).collect();
+                    self.stack.push(sandbox::limit_size(Value::String(string)));
+                }
+                Op::Jump(target) => self.jump(target),
+                Op::JumpIfFalse(target) => {
+                    let cond = self.pop();
+                    if !truthy(cond) {
+                        self.jump(target);
+                    }
+                }
+                Op::Call { func, argc } => {
+                    #[cfg(feature = "jit")]
+                    if let Some(value) = self.jit_call(func, argc as usize, self.frames.len()) {
+                        self.stack.truncate(self.stack.len() - argc as usize);
+                        self.stack.push(value);
+                        continue;
+                    }
+                    self.call(func, argc as usize)
+                }
+                Op::TailCall { func, argc } => {
+                    #[cfg(feature = "jit")]
+                    if let Some(value) = self.jit_call(func, argc as usize, self.frames.len() - 1) {
+                        self.stack.truncate(self.stack.len() - argc as usize);
+                        if let Some(value) = self.leave(value) {
+                            return value;
+                        }
+                        continue;
+                    }
+                    self.tail_call(func, argc as usize)
+                }
+                Op::CallNative { global, argc } => {
+                    let global = &bytecode.globals[global as usize];
+                    let native = match &*global.cell.borrow() {
+                        Value::FuncPtr(native) => *native,
+                        _ => raise(MorphoError::TypeError(format!("{} is not a function", global.name))),
+                    };
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let value = native(args, self.env.clone());
+                    self.stack.push(value);
+                }
+                Op::Closure { func, captured } => {
+                    let bound = self.stack.split_off(self.stack.len() - captured as usize);
+                    let proto = &bytecode.functions[func as usize];
+                    let name = proto.name.rsplit("::").next().unwrap_or_default().to_string();
+                    let ty = if name == "<anonymous>" { "func".to_string() } else { proto.rty.clone() };
+                    self.stack.push(Value::Callable(Ptr::new(Callable { func, name, ty, bound })));
+                }
+                Op::CallValue { name, argc } => {
+                    let args = self.stack.split_off(self.stack.len() - argc as usize);
+                    let name = &bytecode.constants[name as usize];
+                    match self.pop() {
+                        Value::Callable(callable) => {
+                            let params = bytecode.functions[callable.func as usize].params.len() - callable.bound.len();
+                            if args.len() != params {
+                                raise(MorphoError::TypeError(format!("{name} expects {params} arguments, found {argc}")));
+                            }
+                            self.stack.extend(callable.bound.iter().cloned().chain(args));
+                            self.call(callable.func, params + callable.bound.len());
+                        }
+                        Value::FuncPtr(native) => {
+                            let value = native(args, self.env.clone());
+                            self.stack.push(value);
+                        }
+                        _ => raise(MorphoError::TypeError(format!("{name} is not a function"))),
+                    }
+                }
+                Op::Return => {
+                    let value = self.pop();
+                    let proto = &bytecode.functions[self.frames.last().unwrap().func as usize];
+                    match value.clone().coerce_to(&proto.rty) {
+                        Some(value) => {
+                            if let Some(value) = self.leave(value) {
+                                return value;
+                            }
+                        }
+                        None => raise(MorphoError::TypeError(format!(
+                            "{} should return {}, found {}",
+                            proto.name,
+                            proto.rty,
+                            value.into_type()
+                        ))),
+                    }
+                }
+                Op::ReturnVoid => {
+                    if let Some(value) = self.leave(Value::Void) {
+                        return value;
+                    }
+                }
+                Op::NameError(id) => raise(MorphoError::NameError(bytecode.constants[id as usize].to_string())),
+                Op::TypeError(id) => raise(MorphoError::TypeError(bytecode.constants[id as usize].to_string())),
+            }
+        }
+    }
+
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("value stack underflow")
+    }
+
+    fn binary(&mut self, op: impl FnOnce(Value, Value) -> Value) {
+        let rhs = self.pop();
+        let lhs = self.pop();
+        self.stack.push(op(lhs, rhs));
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.frames.last_mut().unwrap().ip = target as usize;
+    }
+
+    /// Enters `func` with its `argc` arguments on top of the stack, checking them
+    /// against the declared parameter types.
+    fn call(&mut self, func: u32, argc: usize) {
+        let bytecode = self.bytecode;
+        let proto = &bytecode.functions[func as usize];
+        let base = self.stack.len() - argc;
+        if self.frames.len() >= self.max_depth {
+            let mut trace = vec![proto.name.clone()];
+            trace.extend(self.frames.iter().rev().map(|frame| bytecode.functions[frame.func as usize].name.clone()));
+            raise(MorphoError::StackOverflow { depth: self.frames.len() + 1, trace });
+        }
+        for (i, (ident, ty)) in proto.params.iter().enumerate() {
+            let Some(ty) = ty else { continue };
+            let arg = &mut self.stack[base + i];
+            // A reference of the declared type aliases the caller's cell
+            if matches!(arg, Value::RefValue(_)) && arg.clone().into_type() == Value::Type(ty.clone()) {
+                continue;
+            }
+            let value = std::mem::replace(arg, Value::Void);
+            match value.clone().coerce_to(ty) {
+                Some(value) => *arg = value,
+                None => raise(MorphoError::TypeError(format!(
+                    "argument {ident} of {} expects {ty}, found {}",
+                    proto.name,
+                    value.into_type()
+                ))),
+            }
+        }
+        self.stack.truncate(base + proto.params.len());
+        self.stack.resize(base + proto.locals as usize, Value::Void);
+        self.frames.push(Frame { func, ip: 0, base });
+    }
+
+    /// Runs the call of `func` as native code when the JIT has compiled it or
+    /// just did, with `frames` frames below the callee
+    #[cfg(feature = "jit")]
+    fn jit_call(&mut self, func: u32, argc: usize, frames: usize) -> Option<Value> {
+        let jit = self.jit.as_mut()?;
+        let args = &self.stack[self.stack.len() - argc..];
+        jit.call(self.bytecode, func, args, self.max_depth.saturating_sub(frames))
+    }
+
+    /// Replaces the current frame with a frame of `func`, moving its arguments
+    /// down to the current frame's base
+    fn tail_call(&mut self, func: u32, argc: usize) {
+        let frame = self.frames.pop().unwrap();
+        let args = self.stack.len() - argc;
+        self.stack.drain(frame.base..args);
+        self.call(func, argc);
+    }
+
+    /// Pops the current frame, returns the result once the entry function is left
+    fn leave(&mut self, value: Value) -> Option<Value> {
+        let frame = self.frames.pop().unwrap();
+        self.stack.truncate(frame.base);
+        if self.frames.is_empty() {
+            return Some(value);
+        }
+        self.stack.push(value);
+        None
+    }
+}
+
+fn truthy(value: Value) -> bool {
+    match value.deref_value() {
+        Value::Bool(b) => b,
+        value => raise(MorphoError::TypeError(format!("expected bool, found {}", value.into_type()))),
+    }
+}
@@ -0,0 +1,168 @@
+use crate::program::value::Value;
+use crate::program::vm::bytecode::{Bytecode, FunctionProto, Op};
+use anyhow::{anyhow, Result};
+
+/// Checks that `bytecode` can't make the VM index out of bounds or underflow its
+/// stack, as bytecode read from a file didn't necessarily come from the compiler.
+///
+/// Every operand has to point into its table, every jump into its function and
+/// every call has to pass at least the callee's parameters. The stack depth of
+/// each instruction has to be the same on all paths reaching it, and no path
+/// may run past the end of a function
+pub fn verify(bytecode: &Bytecode) -> Result<()> {
+    let function = |id: u32, what: &str| {
+        bytecode.functions.get(id as usize).ok_or_else(|| anyhow!("{what} {id} is not a function"))
+    };
+    let entry = function(bytecode.entry, "entry")?;
+    for &init in &bytecode.init {
+        if !function(init, "init function")?.params.is_empty() {
+            return Err(anyhow!("init function {init} takes parameters"));
+        }
+    }
+    if !entry.params.is_empty() {
+        return Err(anyhow!("entry function {} takes parameters", entry.name));
+    }
+    for func in &bytecode.functions {
+        verify_function(bytecode, func).map_err(|e| anyhow!("{}: {e}", func.name))?;
+    }
+    Ok(())
+}
+
+fn verify_function(bytecode: &Bytecode, func: &FunctionProto) -> Result<()> {
+    if func.params.len() > func.locals as usize {
+        return Err(anyhow!("{} parameters don't fit in {} slots", func.params.len(), func.locals));
+    }
+    let code = &func.code;
+    let mut depths: Vec<Option<usize>> = vec![None; code.len()];
+    let mut pending = vec![(0, 0)];
+    while let Some((pc, depth)) = pending.pop() {
+        let op = code.get(pc).ok_or_else(|| anyhow!("control reaches past the end of the code at {pc}"))?;
+        match depths[pc] {
+            Some(known) if known == depth => continue,
+            Some(known) => return Err(anyhow!("stack depth at {pc} is {known} or {depth} depending on the path")),
+            None => depths[pc] = Some(depth),
+        }
+        let (pops, pushes) = operands(bytecode, func, op).map_err(|e| anyhow!("{op:?} at {pc}: {e}"))?;
+        if depth < pops {
+            return Err(anyhow!("{op:?} at {pc} pops {pops} values from a stack of {depth}"));
+        }
+        let next = depth - pops + pushes;
+        match *op {
+            Op::Jump(target) => pending.push((target as usize, next)),
+            Op::JumpIfFalse(target) => pending.extend([(target as usize, next), (pc + 1, next)]),
+            Op::Return | Op::ReturnVoid | Op::TailCall { .. } | Op::NameError(_) | Op::TypeError(_) => {}
+            _ => pending.push((pc + 1, next)),
+        }
+    }
+
+    let mut last = None;
+    for &(pc, line) in &func.lines {
+        if pc as usize >= code.len() || last.is_some_and(|last| last >= pc) || line == 0 {
+            return Err(anyhow!("invalid line table entry ({pc}, {line})"));
+        }
+        last = Some(pc);
+    }
+    Ok(())
+}
+
+/// Checks the operands of `op` and returns how many values it pops and pushes
+fn operands(bytecode: &Bytecode, func: &FunctionProto, op: &Op) -> Result<(usize, usize)> {
+    let local = |slot: u16| match slot < func.locals {
+        true => Ok(()),
+        false => Err(anyhow!("slot {slot} out of {} locals", func.locals)),
+    };
+    let global = |id: u32| match bytecode.globals.get(id as usize) {
+        Some(global) => Ok(global),
+        None => Err(anyhow!("global {id} out of {}", bytecode.globals.len())),
+    };
+    let function = |id: u32| {
+        bytecode.functions.get(id as usize).ok_or_else(|| anyhow!("function {id} out of {}", bytecode.functions.len()))
+    };
+    let string = |id: u32| match bytecode.constants.get(id as usize) {
+        Some(Value::String(_)) => Ok(()),
+        _ => Err(anyhow!("constant {id} is not a string")),
+    };
+    let effect = match *op {
+        Op::Const(id) if id as usize >= bytecode.constants.len() => {
+            return Err(anyhow!("constant {id} out of {}", bytecode.constants.len()))
+        }
+        Op::Const(_) | Op::Void => (0, 1),
+        Op::LoadLocal(slot) | Op::RefLocal(slot) => {
+            local(slot)?;
+            (0, 1)
+        }
+        Op::DefineLocal(slot) | Op::StoreLocal(slot) => {
+            local(slot)?;
+            (1, 0)
+        }
+        Op::LoadGlobal(id) | Op::RefGlobal(id) => {
+            global(id)?;
+            (0, 1)
+        }
+        Op::StoreGlobal(id) => {
+            global(id)?;
+            (1, 0)
+        }
+        Op::Pop => (1, 0),
+        Op::Box | Op::Neg | Op::Not => (1, 1),
+        Op::Add
+        | Op::Sub
+        | Op::Mul
+        | Op::Div
+        | Op::Mod
+        | Op::Xor
+        | Op::BitAnd
+        | Op::BitOr
+        | Op::Shl
+        | Op::Shr
+        | Op::Eq
+        | Op::Ne
+        | Op::Gt
+        | Op::Lt
+        | Op::Ge
+        | Op::Le
+        | Op::And
+        | Op::Or => (2, 1),
+        Op::Array(len) | Op::Concat(len) => (len as usize, 1),
+        Op::Jump(target) | Op::JumpIfFalse(target) if target as usize >= func.code.len() => {
+            return Err(anyhow!("jump target out of {} instructions", func.code.len()))
+        }
+        Op::Jump(_) => (0, 0),
+        Op::JumpIfFalse(_) => (1, 0),
+        Op::Call { func: callee, argc } | Op::TailCall { func: callee, argc } => {
+            let callee = function(callee)?;
+            if argc as usize != callee.params.len() {
+                return Err(anyhow!("{} expects {} arguments, found {argc}", callee.name, callee.params.len()));
+            }
+            match op {
+                Op::TailCall { .. } => (argc as usize, 0),
+                _ => (argc as usize, 1),
+            }
+        }
+        Op::CallNative { global: id, argc } => {
+            let global = global(id)?;
+            if !matches!(*global.cell.borrow(), Value::FuncPtr(_)) {
+                return Err(anyhow!("{} is not a built-in function", global.name));
+            }
+            (argc as usize, 1)
+        }
+        Op::Closure { func: callee, captured } => {
+            let callee = function(callee)?;
+            if captured as usize > callee.params.len() {
+                return Err(anyhow!("{} binds {captured} of its {} parameters", callee.name, callee.params.len()));
+            }
+            (captured as usize, 1)
+        }
+        Op::CallValue { name, argc } => {
+            string(name)?;
+            (argc as usize + 1, 1)
+        }
+        Op::Return => (1, 0),
+        Op::ReturnVoid => (0, 0),
+        Op::NameError(id) | Op::TypeError(id) => {
+            string(id)?;
+            (0, 0)
+        }
+    };
+    Ok(effect)
+}
@@ -1,25 +1,56 @@
 use anyhow::Result;
 use morpho_c::parser::ProgParser;
 use tracing_log::log::{log, Level};
-use morpho_c::program::evaluating_functions::eval_program;
+use morpho_c::program::error::{catch, MorphoError};
+use morpho_c::program::evaluating_functions::{eval_bytecode_file, eval_file, eval_program};
+use morpho_c::program::loader::{find_entry, ModuleLoader, SourceMap};
+use morpho_c::package::lockfile::Lockfile;
+use morpho_c::package::registry::Registry;
+use morpho_c::package::Project;
+use morpho_c::program::evaluating_functions::eval_project;
+use morpho_c::program::vm::bytecode::Op;
+use morpho_c::program::vm::{compile, disasm, mbc, Vm};
+use morpho_c::program::sandbox::{set_sandbox, Capability, Sandbox};
+use morpho_c::program::gc;
+use morpho_c::program::{set_backend, set_max_call_depth, Backend, DEFAULT_MAX_CALL_DEPTH};
+use std::path::Path;
+use std::time::Duration;
+use std::process::Command;
 
 fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
-    print_hello_world()?;
-    print_hello_world_with_var()?;
-    print_int_with_var()?;
-    print_int_with_two_var()?;
-    create_new_func()?;
-    call_func_with_args()?;
-    eval_expr_in_func()?;
-    eval_expr_in_func_with_vars()?;
-    condition_block()?;
-    condition_block_recursion()?;
-    for_block()?;
-    for_block_with_anon_func()?;
-    for_block_with_anon_func_and_ref()?;
-    print_not_and_neg_value()?;
-    evaluating_fibonacci_nums()?;
+    for backend in [Backend::Tree, Backend::Vm] {
+        log!(Level::Info, "Running on the {backend} backend...");
+        set_backend(backend);
+        print_hello_world()?;
+        print_hello_world_with_var()?;
+        print_int_with_var()?;
+        print_int_with_two_var()?;
+        create_new_func()?;
+        call_func_with_args()?;
+        eval_expr_in_func()?;
+        eval_expr_in_func_with_vars()?;
+        condition_block()?;
+        condition_block_recursion()?;
+        for_block()?;
+        for_block_with_anon_func()?;
+        for_block_with_anon_func_and_ref()?;
+        print_not_and_neg_value()?;
+        evaluating_fibonacci_nums()?;
+        checked_arithmetic_errors()?;
+        file_modules()?;
+        module_visibility()?;
+        use_declarations()?;
+        module_globals()?;
+        stack_overflow()?;
+        sandbox_limits()?;
+        anonymous_closures()?;
+        garbage_collection()?;
+        packages()?;
+    }
+    vm_bytecode()?;
+    bytecode_files()?;
+    disassembler()?;
     Ok(())
 }
 
@@ -136,4 +167,517 @@ fn evaluating_fibonacci_nums() -> Result<()> {
         .parse(r#"func main = () {print(fibonacci(11));} func fibonacci = (n: int) -> int {return if(n == 0,$|| -> int { return 0; },$if |n == 1 || n == 2,$|| -> int { return 1; },$|n: n| -> int { return fibonacci(n-1) + fibonacci(n-2); }|);}"#)?;
     eval_program(ast).unwrap();
     Ok(())
-}
\ No newline at end of file
+}
+
+fn checked_arithmetic_errors() -> Result<()> {
+    log!(Level::Info, "Starting checked_arithmetic_errors...");
+    for (src, expected) in [
+        (r#"func main = () { print(1 / 0); }"#, MorphoError::DivisionByZero),
+        (r#"func main = () { print(1.5 % 0.0); }"#, MorphoError::DivisionByZero),
+        (
+            r#"func main = () { let x = 9223372036854775807; print(x + 1); }"#,
+            MorphoError::Overflow("9223372036854775807 + 1".into()),
+        ),
+        (r#"func main = () { print(10n / 0n); }"#, MorphoError::DivisionByZero),
+        (
+            r#"func main = () { print(int(99999999999999999999n)); }"#,
+            MorphoError::Overflow("int(99999999999999999999)".into()),
+        ),
+    ] {
+        let err = eval_program(ProgParser::new().parse(src)?).unwrap_err();
+        log!(Level::Info, "{err}");
+        assert_eq!(err.downcast_ref::<MorphoError>(), Some(&expected));
+    }
+    Ok(())
+}
+
+fn file_modules() -> Result<()> {
+    log!(Level::Info, "Starting file_modules...");
+    let project = Path::new(env!("CARGO_MANIFEST_DIR")).join("fixtures/project");
+    eval_file(&find_entry(&project)?)?;
+
+    let err = eval_program(ProgParser::new().parse("mod missing; func main = () {}")?).unwrap_err();
+    log!(Level::Info, "{err}");
+
+    let dir = std::env::temp_dir().join(format!("morpho_cycle_{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("a"))?;
+    std::fs::write(dir.join("main.mo"), "mod a; func main = () {}")?;
+    std::fs::write(dir.join("a.mo"), "mod b;")?;
+    #[cfg(unix)]
+    {
+        // a/b.mo points back to a.mo and closes the cycle
+        let _ = std::fs::remove_file(dir.join("a/b.mo"));
+        std::os::unix::fs::symlink(dir.join("a.mo"), dir.join("a/b.mo"))?;
+        let err = eval_file(&dir.join("main.mo")).unwrap_err();
+        log!(Level::Info, "{err}");
+        assert!(err.to_string().starts_with("Cyclic module dependency"));
+    }
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn module_visibility() -> Result<()> {
+    log!(Level::Info, "Starting module_visibility...");
+    let modules = r#"
+        mod bank {
+            func secret = () -> int { return 42; }
+            pub func balance = () { print("balance ", secret()); }
+            pub mod audit {
+                pub func check = () { bank::secret(); print("audit ok"); }
+            }
+            mod vault {
+                pub func open = () { print("vault"); }
+            }
+        }
+    "#;
+    let code = format!(r#"{modules}
+        use crate::bank::balance;
+        func main = () {{
+            balance();
+            bank::audit::check();
+        }}"#);
+    eval_program(ProgParser::new().parse(&code).unwrap())?;
+
+    for main in [
+        "func main = () { bank::secret(); }",
+        "func main = () { print(bank::secret()); }",
+        "func main = () { bank::vault::open(); }",
+        "use crate::bank::secret; func main = () {}",
+    ] {
+        let code = format!("{modules} {main}");
+        let err = eval_program(ProgParser::new().parse(&code).unwrap()).unwrap_err();
+        log!(Level::Info, "{err}");
+        assert!(matches!(err.downcast_ref::<MorphoError>(), Some(MorphoError::PrivacyError { .. })));
+    }
+    Ok(())
+}
+
+fn use_declarations() -> Result<()> {
+    log!(Level::Info, "Starting use_declarations...");
+    let modules = r#"
+        mod geo {
+            pub func area = (w: int, h: int) { print("area ", w * h); }
+            pub mod shapes {
+                pub func square = (side: int) { print("square ", side * side); }
+                pub func area = () { print("shapes::area"); }
+            }
+        }
+        mod app {
+            use super::geo::area;
+            use self::helpers::{twice as double};
+            pub func run = () { area(2, 3); double(21); }
+            mod helpers {
+                pub func twice = (x: int) { print("twice ", x * 2); }
+            }
+        }
+    "#;
+    let code = format!(r#"{modules}
+        use geo::area as rect;
+        use geo::shapes::*;
+        use crate::geo::{{self as g, shapes::square as sq}};
+        func main = () {{
+            rect(3, 4);
+            square(5);
+            sq(6);
+            g::area(1, 1);
+            app::run();
+        }}"#);
+    eval_program(ProgParser::new().parse(&code).unwrap())?;
+
+    for main in [
+        "use geo::area; use geo::shapes::area; func main = () {}",
+        "use geo::area; func area = () {} func main = () {}",
+        "use geo::*; use geo::shapes::*; func main = () {}",
+        "use super::geo; func main = () {}",
+        "use geo::missing; func main = () {}",
+    ] {
+        let code = format!("{modules} {main}");
+        let err = eval_program(ProgParser::new().parse(&code).unwrap()).unwrap_err();
+        log!(Level::Info, "{err}");
+        assert!(matches!(err.downcast_ref::<MorphoError>(), Some(MorphoError::ImportError(_))));
+    }
+    Ok(())
+}
+
+fn module_globals() -> Result<()> {
+    log!(Level::Info, "Starting module_globals...");
+    let ast = ProgParser::new()
+        .parse(r#"
+        const AREA = config::WIDTH * config::HEIGHT;
+        mod config {
+            pub const HEIGHT = WIDTH / 2;
+            pub const WIDTH = 640;
+            pub const TITLE = "window {WIDTH}x{HEIGHT}";
+            pub const TAU = math::pi * 2;
+        }
+        mod counter {
+            pub let count = START + 1;
+            const START = 10;
+            pub func bump = () { count = count + 1; }
+        }
+        let greeting = string::to_upper("hi");
+        func main = () {
+            print(AREA, " ", config::TITLE, " ", config::TAU > 6.28, " ", greeting);
+            counter::bump();
+            counter::bump();
+            print(counter::count);
+        }"#)?;
+    eval_program(ast)?;
+
+    for (code, expected) in [
+        ("const A = B; const B = A; func main = () {}", "depend on each other"),
+        ("let x = 1; const A = x; func main = () {}", "not a constant"),
+        ("const A = math::sqrt(4); func main = () {}", "can't call functions"),
+    ] {
+        let err = eval_program(ProgParser::new().parse(code).unwrap()).unwrap_err();
+        log!(Level::Info, "{err}");
+        assert!(err.to_string().contains(expected));
+    }
+    let err = eval_program(ProgParser::new().parse("const A = 1; func main = () { A = 2; }")?).unwrap_err();
+    log!(Level::Info, "{err}");
+    assert!(matches!(err.downcast_ref::<MorphoError>(), Some(MorphoError::TypeError(_))));
+    Ok(())
+}
+
+fn stack_overflow() -> Result<()> {
+    log!(Level::Info, "Starting stack_overflow...");
+    // Running out of calls fails the program with the trace of the calls, the
+    // next program starts with an empty call stack
+    let src = r#"
+        func main = () { print(depth(0)); }
+        func depth = (n: int) -> int {
+            return 1 + if(n == 60, $|| -> int { return 0; }, $depth|n + 1|);
+        }"#;
+    for (max_depth, expected) in [(DEFAULT_MAX_CALL_DEPTH, None), (50, Some(51))] {
+        set_max_call_depth(max_depth);
+        let result = eval_program(ProgParser::new().parse(src)?);
+        match expected {
+            None => result?,
+            Some(expected) => {
+                let err = result.unwrap_err();
+                log!(Level::Info, "{err}");
+                let Some(MorphoError::StackOverflow { depth, trace }) = err.downcast_ref::<MorphoError>() else {
+                    panic!("expected StackOverflow, found {err}");
+                };
+                assert_eq!(*depth, expected);
+                assert_eq!(trace.len(), expected);
+                assert!(trace[..expected - 1].iter().all(|name| name == "depth"));
+                assert_eq!(trace.last().map(String::as_str), Some("main"));
+            }
+        }
+    }
+    set_max_call_depth(DEFAULT_MAX_CALL_DEPTH);
+
+    // Unbounded recursion stops at the limit instead of overflowing the native stack
+    let err = eval_program(ProgParser::new().parse(r#"
+        func main = () { print(forever(0)); }
+        func forever = (n: int) -> int { return forever(n + 1) + 1; }"#)?)
+    .unwrap_err();
+    let Some(MorphoError::StackOverflow { depth, .. }) = err.downcast_ref::<MorphoError>() else {
+        panic!("expected StackOverflow, found {err}");
+    };
+    assert_eq!(*depth, DEFAULT_MAX_CALL_DEPTH + 1);
+    Ok(())
+}
+
+fn sandbox_limits() -> Result<()> {
+    log!(Level::Info, "Starting sandbox_limits...");
+    let forever = r#"func main = () { while(1 < 2, $|| { let x = 1; }); }"#;
+    let doubling = r#"func main = () {
+        let s = "ab";
+        while(1 < 2, $|s: &s| { s = s + s; });
+    }"#;
+    let reads_stdin = r#"func main = () { print(input()); }"#;
+    for (sandbox, src, expected) in [
+        (Sandbox::strict().with_max_steps(10_000), forever, MorphoError::StepLimit(10_000)),
+        (
+            Sandbox::strict().with_timeout(Duration::from_millis(100)),
+            forever,
+            MorphoError::Timeout(Duration::from_millis(100)),
+        ),
+        (
+            Sandbox::strict().with_max_memory(1024),
+            doubling,
+            MorphoError::MemoryLimit { size: 2048, limit: 1024 },
+        ),
+        (Sandbox::strict(), reads_stdin, MorphoError::CapabilityError(Capability::Stdin)),
+    ] {
+        set_sandbox(sandbox);
+        let err = eval_program(ProgParser::new().parse(src)?).unwrap_err();
+        log!(Level::Info, "{err}");
+        assert_eq!(err.downcast_ref::<MorphoError>(), Some(&expected));
+    }
+    // Strings, arrays and bigints built by the library and by operators count too
+    set_sandbox(Sandbox::strict().with_max_memory(1024));
+    let long = "x".repeat(600);
+    for src in [
+        format!(r#"func main = () {{ print(string::split("{}", "")); }}"#, "x ".repeat(300)),
+        format!(r#"func main = () {{ print(string::split("{}", ",")); }}"#, ",".repeat(600)),
+        format!(r#"func main = () {{ print(string::chars("{long}")); }}"#),
+        r#"func main = () { print(string::format(1.5, 100000000)); }"#.to_string(),
+        r#"func main = () { print(math::pow(3n, 100000)); }"#.to_string(),
+        r#"func main = () { print(1n << 1000000000000); }"#.to_string(),
+        r#"func main = () { let b = 3n; while(1 < 2, $|b: &b| { b = b * b; }); }"#.to_string(),
+    ] {
+        let err = eval_program(ProgParser::new().parse(&src).unwrap()).unwrap_err();
+        log!(Level::Info, "{err}");
+        assert!(
+            matches!(err.downcast_ref::<MorphoError>(), Some(MorphoError::MemoryLimit { limit: 1024, .. })),
+            "{src}: {err}"
+        );
+    }
+    eval_program(ProgParser::new().parse(r#"func main = () { print(1n << 100 >> 1000000000000, math::pow(1n, 100000)); }"#)?)?;
+    // Without the sandbox the same loop just runs long enough
+    set_sandbox(Sandbox::default());
+    eval_program(ProgParser::new().parse(r#"func main = () { for(i in 0..20000, $|i: i| { let x = i; }); print("done"); }"#)?)?;
+    Ok(())
+}
+
+fn anonymous_closures() -> Result<()> {
+    log!(Level::Info, "Starting anonymous_closures...");
+    // Functions built for a place in the source belong to the program they were
+    // written in, the second one raises instead of running the first
+    eval_program(ProgParser::new().parse(r#"func main = () { if(1 < 2, $|| { first(); }); } func first = () {}"#)?)?;
+    let err = eval_program(ProgParser::new().parse(r#"func main = () { if(1 < 2, $|| { other(); }); }"#)?).unwrap_err();
+    log!(Level::Info, "{err}");
+    assert!(matches!(err.downcast_ref::<MorphoError>(), Some(MorphoError::NameError(msg)) if msg.contains("other")));
+    Ok(())
+}
+
+fn garbage_collection() -> Result<()> {
+    log!(Level::Info, "Starting garbage_collection...");
+    // Every call leaves its variable in an array that refers back to it
+    let before = gc::stats();
+    let ast = ProgParser::new()
+        .parse(r#"
+        func main = () {
+            for(i in 0..20000, $|| { cycle(); });
+            gc();
+            print(gc_stats());
+        }
+        func cycle = () {
+            let a = 0;
+            a = [&a, 1];
+        }"#)?;
+    eval_program(ast)?;
+    let after = gc::stats();
+    assert!(after.collected - before.collected >= 20000, "{before} then {after}");
+    // The cycles were freed while the loop ran, not only by `gc()`
+    assert!(after.collections > before.collections + 1, "{before} then {after}");
+    Ok(())
+}
+
+fn write_package(dir: &Path, name: &str, version: &str, dependencies: &str, lib: &str) -> Result<()> {
+    std::fs::create_dir_all(dir.join("src"))?;
+    std::fs::write(
+        dir.join("morpho.toml"),
+        format!("[package]\nname = \"{name}\"\nversion = \"{version}\"\n\n[dependencies]\n{dependencies}\n"),
+    )?;
+    std::fs::write(dir.join("src/lib.mo"), lib)?;
+    Ok(())
+}
+
+fn git(dir: &Path, args: &[&str]) -> Result<()> {
+    let status = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["-c", "user.name=morpho", "-c", "user.email=morpho@localhost"])
+        .args(args)
+        .output()?
+        .status;
+    assert!(status.success(), "git {args:?} failed");
+    Ok(())
+}
+
+fn packages() -> Result<()> {
+    log!(Level::Info, "Starting packages...");
+    let root = std::env::temp_dir().join(format!("morpho_packages_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let registry = Registry::new(root.join("registry"));
+    for version in ["1.0.0", "1.2.0", "2.0.0"] {
+        write_package(
+            &registry.package_dir("geometry", &version.parse()?),
+            "geometry",
+            version,
+            "",
+            &format!(r#"pub func area = (w: int, h: int) {{ print("area ", w * h); }}
+                pub func version = () -> string {{ return "{version}"; }}"#),
+        )?;
+    }
+    write_package(
+        &root.join("utils"),
+        "utils",
+        "0.1.0",
+        r#"geometry = "^1.1""#,
+        r#"pub func describe = () { print("utils uses geometry ", geometry::version()); }"#,
+    )?;
+    let repo = root.join("strings");
+    write_package(&repo, "strings", "0.1.0", "", r#"pub func hello = () { print("hello v1"); }"#)?;
+    git(&repo, &["init", "--quiet"])?;
+    git(&repo, &["add", "."])?;
+    git(&repo, &["commit", "--quiet", "-m", "v1"])?;
+    git(&repo, &["tag", "v1"])?;
+    std::fs::write(repo.join("src/lib.mo"), r#"pub func hello = () { print("hello v2"); }"#)?;
+    git(&repo, &["commit", "--quiet", "-am", "v2"])?;
+
+    let app = root.join("app");
+    write_package(
+        &app,
+        "app",
+        "0.1.0",
+        r#"geometry = "1"
+utils = { path = "../utils" }
+strings = { git = "../strings", rev = "v1" }"#,
+        "",
+    )?;
+    std::fs::write(
+        app.join("src/main.mo"),
+        r#"use geometry::area;
+        func main = () {
+            area(2, 3);
+            print("geometry ", geometry::version());
+            utils::describe();
+            strings::hello();
+        }"#,
+    )?;
+
+    eval_project(&Project::open(&app, &registry)?)?;
+    let locked = |name: &str| -> Result<String> {
+        let lock = Lockfile::load(&app)?;
+        let package = lock.get(name).expect("package is locked");
+        Ok(format!("{} {}", package.version, package.source))
+    };
+    assert_eq!(locked("geometry")?, "1.2.0 registry");
+    assert_eq!(locked("utils")?, format!("0.1.0 path+{}", root.join("utils").canonicalize()?.display()));
+    assert!(locked("strings")?.contains("?rev=v1#"));
+
+    // A newer release is ignored while the lockfile pins the old one
+    write_package(
+        &registry.package_dir("geometry", &"1.3.0".parse()?),
+        "geometry",
+        "1.3.0",
+        "",
+        r#"pub func area = (w: int, h: int) {} pub func version = () -> string { return "1.3.0"; }"#,
+    )?;
+    eval_project(&Project::open(&app, &registry)?)?;
+    assert_eq!(locked("geometry")?, "1.2.0 registry");
+    std::fs::remove_file(app.join("morpho.lock"))?;
+    eval_project(&Project::open(&app, &registry)?)?;
+    assert_eq!(locked("geometry")?, "1.3.0 registry");
+
+    write_package(&root.join("utils"), "utils", "0.1.0", r#"geometry = "^2""#, "")?;
+    let err = Project::open(&app, &registry).and_then(|project| eval_project(&project)).unwrap_err();
+    log!(Level::Info, "{err:#}");
+    assert!(format!("{err:#}").contains("No version of geometry matches ^1, ^2"));
+
+    std::fs::remove_dir_all(&root)?;
+    Ok(())
+}
+
+fn vm_bytecode() -> Result<()> {
+    log!(Level::Info, "Starting vm_bytecode...");
+    let ast = ProgParser::new()
+        .parse(r#"func main = () { let a = 1; for(i in 0..3, $|a: &a, i: i| { a = a + i; }); print(a); }"#)?;
+    let bytecode = compile(ast, &SourceMap::default())?;
+    let main = &bytecode.functions[bytecode.entry as usize];
+    // The loop is compiled in place, the anonymous function is called directly
+    assert!(!main.code.iter().any(|op| matches!(op, Op::CallNative { global, .. } if bytecode.globals[*global as usize].name == "for")));
+    assert!(main.code.iter().any(|op| matches!(op, Op::Jump(_))));
+    assert!(main.code.contains(&Op::RefLocal(0)));
+    assert_eq!(bytecode.functions.len(), 2);
+
+    // The call can't be made, it raises once it runs
+    let bytecode = compile(ProgParser::new().parse("func main = () { f(1); } func f = (a: int, b: int) {}")?, &SourceMap::default())?;
+    let main = &bytecode.functions[bytecode.entry as usize];
+    assert!(main.code.iter().any(|op| matches!(op, Op::TypeError(_))));
+    let err = catch(|| Vm::new(&bytecode).run()).unwrap_err();
+    log!(Level::Info, "{err}");
+    assert!(matches!(err.downcast_ref::<MorphoError>(), Some(MorphoError::TypeError(_))));
+    Ok(())
+}
+
+fn bytecode_files() -> Result<()> {
+    log!(Level::Info, "Starting bytecode_files...");
+    let dir = std::env::temp_dir().join(format!("morpho_mbc_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(
+        dir.join("main.mo"),
+        "mod shapes;\nconst SIDE = 3;\n\nfunc main = () {\n    let area = shapes::square(SIDE);\n    print(\"area: {area}, pi: {math::pi}\");\n    print(string::len(\"bytes\"));\n}\n",
+    )?;
+    std::fs::write(dir.join("shapes.mo"), "pub func square = (side: int) -> int {\n    return side * side;\n}\n")?;
+
+    let mut loader = ModuleLoader::new();
+    let prog = loader.load(&dir.join("main.mo"))?;
+    let bytecode = compile(prog, loader.sources())?;
+    let main = &bytecode.functions[bytecode.entry as usize];
+    assert_eq!(main.lines.iter().map(|&(_, line)| line).collect::<Vec<_>>(), [5, 6, 7]);
+    assert!(main.file.as_ref().is_some_and(|file| file.ends_with("main.mo")));
+    let square = bytecode.functions.iter().find(|func| func.name == "shapes::square").unwrap();
+    assert!(square.file.as_ref().is_some_and(|file| file.ends_with("shapes.mo")));
+    assert_eq!(square.line(0), Some(2));
+
+    let bytes = mbc::encode(&bytecode)?;
+    assert_eq!(bytes[..4], mbc::MAGIC);
+    let decoded = mbc::decode(&bytes)?;
+    assert_eq!(decoded.functions.len(), bytecode.functions.len());
+    assert_eq!(decoded.functions[decoded.entry as usize].lines, main.lines);
+    let path = dir.join("main.mbc");
+    std::fs::write(&path, &bytes)?;
+    eval_bytecode_file(&path)?;
+
+    let rejected = |bytes: &[u8], expected: &str| {
+        let err = format!("{:#}", mbc::decode(bytes).unwrap_err());
+        log!(Level::Info, "{err}");
+        assert!(err.contains(expected), "{err}");
+    };
+    rejected(b"#!/bin/morpho", "not a .mbc file");
+    rejected(&bytes[..bytes.len() - 3], "the header says");
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 0xff;
+    rejected(&corrupted, "checksum mismatch");
+    let mut future = bytes.clone();
+    future[4] = 9;
+    rejected(&future, "unsupported format version 9");
+
+    let mut broken = bytecode.clone();
+    broken.functions[broken.entry as usize].code.insert(0, Op::Jump(1000));
+    rejected(&mbc::encode(&broken)?, "jump target out of");
+    let mut broken = bytecode.clone();
+    broken.functions[broken.entry as usize].code.insert(0, Op::Add);
+    rejected(&mbc::encode(&broken)?, "pops 2 values from a stack of 0");
+    let mut broken = bytecode.clone();
+    broken.functions[broken.entry as usize].code.pop();
+    rejected(&mbc::encode(&broken)?, "past the end of the code");
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+fn disassembler() -> Result<()> {
+    log!(Level::Info, "Starting disassembler...");
+    let dir = std::env::temp_dir().join(format!("morpho_disasm_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    std::fs::write(dir.join("main.mo"), "const N = 2;\n\nfunc main = () {\n    let x = N * 21;\n    print(\"x = {x}\");\n}\n")?;
+    let mut loader = ModuleLoader::new();
+    let prog = loader.load(&dir.join("main.mo"))?;
+    let bytecode = compile(prog, loader.sources())?;
+
+    let listing = disasm::disassemble(&bytecode);
+    log!(Level::Info, "{listing}");
+    assert!(listing.contains("func #0 N::<init>() -> void, 0 locals (init)"));
+    assert!(listing.contains("func #1 main() -> void, 1 locals (entry)"));
+    assert!(listing.lines().any(|line| line.trim_start().starts_with("4     0  LoadGlobal(0)") && line.ends_with("; N")));
+    assert!(listing.lines().any(|line| line.trim_start().starts_with("5 ") && line.contains("; \"x = \"")));
+    assert!(listing.contains("; print"));
+
+    let json = disasm::to_json(&bytecode);
+    let main = &json["functions"][json["entry"].as_u64().unwrap() as usize];
+    assert_eq!(main["name"], "main");
+    assert_eq!(main["code"][0]["op"]["LoadGlobal"], 0);
+    assert_eq!(main["code"][0]["line"], 4);
+    assert!(main["code"].as_array().unwrap().iter().any(|op| op["line"] == 5 && op["comment"] == "print"));
+    assert_eq!(json["constants"][0], serde_json::json!({ "type": "int", "value": "2" }));
+
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
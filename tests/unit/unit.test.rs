@@ -1,8 +1,43 @@
 #[cfg(test)]
 mod tests {
-    use func_lang::ast::{CallExpr, Expr, FuncBody, FuncIdent, Stmt, VarIdent};
-    use func_lang::*;
+    use morpho_c::ast::{
+        Body, CallExpr, Expr, FuncIdent, FuncPtr, GlobalIdent, Import, InlineAccess, PrivacyType, Stmt, UseKind, UseTree,
+        VarIdent,
+    };
+    use morpho_c::*;
+    use std::path::PathBuf;
+    use std::process::{Command, Output};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Path of a new file named after `name` in the temp directory of the tests
+    fn temp_file(name: &str) -> PathBuf {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("morpho_tests_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(format!("{}_{name}", NEXT.fetch_add(1, Ordering::Relaxed)))
+    }
+
+    /// Runs the binary with `args` on a source file holding `src`
+    fn run_morpho(src: &str, args: &[&str]) -> Output {
+        let source = temp_file("main.mo");
+        std::fs::write(&source, src).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_main"))
+            .args(args)
+            .arg(&source)
+            .env("RUST_BACKTRACE", "0")
+            .output()
+            .unwrap();
+        std::fs::remove_file(&source).unwrap();
+        output
+    }
+
+    /// Whether the program succeeded, with what it wrote to stdout and stderr
+    fn outcome(output: Output) -> (bool, String, String) {
+        (output.status.success(), String::from_utf8(output.stdout).unwrap(), String::from_utf8(output.stderr).unwrap())
+    }
+
     #[test]
+    #[allow(clippy::approx_constant)]
     fn expr_parsing_test() {
         assert_eq!(
             parser::ExprParser::new().parse("12").unwrap(),
@@ -22,6 +57,16 @@ mod tests {
             parser::ExprParser::new().parse("main").unwrap(),
             Expr::Ident("main".into())
         );
+        assert_eq!(
+            parser::ExprParser::new().parse("-100000000000000000000n").unwrap(),
+            Expr::BigInteger("-100000000000000000000".into())
+        );
+        assert_eq!(
+            parser::ExprParser::new().parse("9223372036854775807").unwrap(),
+            Expr::Integer(i64::MAX)
+        );
+        assert!(parser::ExprParser::new().parse("99999999999999999999").is_err());
+        assert!(parser::ExprParser::new().parse("0..99999999999999999999").is_err());
         assert_eq!(
             parser::ExprParser::new().parse("true").unwrap(),
             Expr::Bool(true)
@@ -71,9 +116,10 @@ mod tests {
                 .parse(r#"func main = () {}"#)
                 .unwrap(),
             Stmt::FuncIdent(FuncIdent::new_wo_rty(
+                PrivacyType::Private,
                 "main",
                 vec![],
-                Some(FuncBody::new(vec![]))
+                Some(Body::new(vec![]))
             ))
         );
 
@@ -82,10 +128,11 @@ mod tests {
                 .parse(r#"func main = () -> void {}"#)
                 .unwrap(),
             Stmt::FuncIdent(FuncIdent::new_w_rty(
+                PrivacyType::Private,
                 "main",
                 vec![],
                 "void".into(),
-                Some(FuncBody::new(vec![]))
+                Some(Body::new(vec![]))
             ))
         );
 
@@ -94,9 +141,10 @@ mod tests {
                 .parse(r#"func main = () { let var = 10; }"#)
                 .unwrap(),
             Stmt::FuncIdent(FuncIdent::new_wo_rty(
+                PrivacyType::Private,
                 "main",
                 vec![],
-                Some(FuncBody::new(vec![Stmt::VarIdent(VarIdent::new(
+                Some(Body::new(vec![Stmt::VarIdent(VarIdent::new(
                     "var",
                     Expr::Integer(10)
                 ))]))
@@ -107,9 +155,10 @@ mod tests {
                 .parse(r#"func main = () { let var = 10; let str = "Hello, world!"; }"#)
                 .unwrap(),
             Stmt::FuncIdent(FuncIdent::new_wo_rty(
+                PrivacyType::Private,
                 "main",
                 vec![],
-                Some(FuncBody::new(vec![
+                Some(Body::new(vec![
                     Stmt::VarIdent(VarIdent::new("var", Expr::Integer(10))),
                     Stmt::VarIdent(VarIdent::new(
                         "str",
@@ -123,9 +172,10 @@ mod tests {
                 .parse(r#"func main = () { print("Hello, world!"); }"#)
                 .unwrap(),
             Stmt::FuncIdent(FuncIdent::new_wo_rty(
+                PrivacyType::Private,
                 "main",
                 vec![],
-                Some(FuncBody::new(vec![Stmt::Expr(Box::new(Expr::Call(
+                Some(Body::new(vec![Stmt::Expr(Box::new(Expr::Call(
                     CallExpr::new(
                         "print".into(),
                         vec![Expr::StringLit("Hello, world!".into())]
@@ -134,4 +184,932 @@ mod tests {
             ))
         );
     }
+
+    #[test]
+    fn string_lit_parsing_test() {
+        assert_eq!(
+            parser::ExprParser::new().parse(r#""a\nb\t\"c\" \\ \u{1F600}""#).unwrap(),
+            Expr::StringLit("a\nb\t\"c\" \\ \u{1F600}".into())
+        );
+        assert_eq!(
+            parser::ExprParser::new().parse(r#""x = {x + 1}!""#).unwrap(),
+            Expr::Concat(vec![
+                Expr::StringLit("x = ".into()),
+                Expr::Add(Box::new(Expr::Ident("x".into())), Box::new(Expr::Integer(1))),
+                Expr::StringLit("!".into())
+            ])
+        );
+        assert_eq!(
+            parser::ExprParser::new().parse(r#""\{literal\}""#).unwrap(),
+            Expr::StringLit("{literal}".into())
+        );
+        assert!(parser::ExprParser::new().parse(r#""\q""#).is_err());
+        assert!(parser::ExprParser::new().parse(r#""{x + }""#).is_err());
+        assert!(parser::ExprParser::new().parse(r#""{x""#).is_err());
+    }
+
+    #[test]
+    fn core_module_call_parsing_test() {
+        assert_eq!(
+            parser::ExprParser::new().parse(r#"string::len("abc")"#).unwrap(),
+            Expr::InlineAccess(InlineAccess::new(
+                "string".into(),
+                Some(Box::new(Expr::Call(CallExpr::new(
+                    "len".into(),
+                    vec![Expr::StringLit("abc".into())]
+                ))))
+            ))
+        );
+        assert!(parser::StmtParser::new()
+            .parse(r#"func f = (s: string) -> int { return string::len(s); }"#)
+            .is_ok());
+    }
+
+    #[test]
+    fn arithmetic_associativity_test() {
+        assert_eq!(
+            parser::ExprParser::new().parse("10 - 4 - 1").unwrap(),
+            Expr::Sub(
+                Box::new(Expr::Sub(
+                    Box::new(Expr::Integer(10)),
+                    Box::new(Expr::Integer(4))
+                )),
+                Box::new(Expr::Integer(1))
+            )
+        );
+        assert_eq!(
+            parser::ExprParser::new().parse("-x * 2.5").unwrap(),
+            Expr::Mul(
+                Box::new(Expr::Neg(Box::new(Expr::Ident("x".into())))),
+                Box::new(Expr::Float(2.5))
+            )
+        );
+    }
+
+    #[test]
+    fn bitwise_parsing_test() {
+        assert_eq!(
+            parser::ExprParser::new().parse("a | b & 1 << 2").unwrap(),
+            Expr::BitOr(
+                Box::new(Expr::Ident("a".into())),
+                Box::new(Expr::BitAnd(
+                    Box::new(Expr::Ident("b".into())),
+                    Box::new(Expr::Shl(
+                        Box::new(Expr::Integer(1)),
+                        Box::new(Expr::Integer(2))
+                    ))
+                ))
+            )
+        );
+        assert_eq!(
+            parser::ExprParser::new().parse("$f|&a, (a | b)|").unwrap(),
+            Expr::Func(FuncPtr::new(
+                "f",
+                Some(vec![
+                    Expr::Ref(Box::new(Expr::Ident("a".into()))),
+                    Expr::BitOr(
+                        Box::new(Expr::Ident("a".into())),
+                        Box::new(Expr::Ident("b".into()))
+                    )
+                ])
+            ))
+        );
+    }
+
+    #[test]
+    fn module_file_parsing_test() {
+        assert_eq!(
+            parser::StmtParser::new().parse("mod geometry;").unwrap(),
+            Stmt::ModuleFile(PrivacyType::Private, "geometry".into())
+        );
+        assert_eq!(
+            parser::StmtParser::new().parse("pub mod geometry;").unwrap(),
+            Stmt::ModuleFile(PrivacyType::Public, "geometry".into())
+        );
+        assert!(parser::ProgParser::new()
+            .parse("mod a; pub mod b { mod c; pub func f = () {} } func main = () {}")
+            .is_ok());
+    }
+
+    #[test]
+    fn use_tree_parsing_test() {
+        let path = |p: &[&str]| p.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(
+            parser::StmtParser::new().parse("use crate::a::b as c;").unwrap(),
+            Stmt::Import(Import::new(UseTree::new(
+                path(&["crate", "a", "b"]),
+                UseKind::Simple(Some("c".into()))
+            )))
+        );
+        assert_eq!(
+            parser::StmtParser::new().parse("use super::a::*;").unwrap(),
+            Stmt::Import(Import::new(UseTree::new(path(&["super", "a"]), UseKind::Glob)))
+        );
+        assert_eq!(
+            parser::StmtParser::new().parse("use a::{self, b, c::d as e};").unwrap(),
+            Stmt::Import(Import::new(UseTree::new(
+                path(&["a"]),
+                UseKind::Group(vec![
+                    UseTree::new(path(&["self"]), UseKind::Simple(None)),
+                    UseTree::new(path(&["b"]), UseKind::Simple(None)),
+                    UseTree::new(path(&["c", "d"]), UseKind::Simple(Some("e".into()))),
+                ])
+            )))
+        );
+    }
+
+    #[test]
+    fn global_parsing_test() {
+        assert_eq!(
+            parser::StmtParser::new().parse("pub const WIDTH = 640;").unwrap(),
+            Stmt::Global(GlobalIdent::new(PrivacyType::Public, true, "WIDTH".into(), Expr::Integer(640)))
+        );
+        assert_eq!(
+            parser::StmtParser::new().parse("pub let count = 0;").unwrap(),
+            Stmt::Global(GlobalIdent::new(PrivacyType::Public, false, "count".into(), Expr::Integer(0)))
+        );
+        assert!(parser::ProgParser::new()
+            .parse("const A = 1; let b = A + 1; mod m { const C = super::A; } func main = () {}")
+            .is_ok());
+    }
+
+    #[test]
+    fn statement_offsets_test() {
+        let source = "func main = () {\n    let a = 1;\n    print(a);\n}";
+        let prog = parser::ProgParser::new().parse(source).unwrap();
+        let Stmt::FuncIdent(main) = &prog.0[0] else { panic!("expected a function") };
+        let body = main.stmt.as_ref().unwrap();
+        assert_eq!(body.offsets, [source.find("let").unwrap(), source.find("print").unwrap()]);
+        // Offsets don't take part in comparisons
+        assert_eq!(*body, Body::new(body.stmt.clone()));
+    }
+
+    #[test]
+    fn ast_dump_test() {
+        use morpho_c::ast::dump;
+        let prog = parser::ProgParser::new().parse("func main = () { print(1 + x); }").unwrap();
+        let json = dump::to_json(&prog);
+        assert_eq!(json[0]["FuncIdent"]["ident"], "main");
+        let call = &json[0]["FuncIdent"]["stmt"]["stmt"][0]["Expr"]["Call"];
+        assert_eq!(call["func_name"], "print");
+        assert_eq!(call["args"][0]["Add"][1]["Ident"], "x");
+        assert_eq!(
+            dump::render(&prog),
+            "Prog\n  FuncIdent\n    args: []\n    ident: \"main\"\n    privacy_type: \"Private\"\n    rty: \"void\"\n    stmt:\n      offsets: [17]\n      stmt:\n        Expr\n          Call\n            args:\n              Add\n                Integer 1\n                Ident \"x\"\n            func_name: \"print\"\n"
+        );
+    }
+
+    #[test]
+    fn manifest_parsing_test() {
+        use morpho_c::package::manifest::{DependencySource, Manifest};
+        let manifest = Manifest::parse(
+            r#"
+            [package]
+            name = "app"
+            version = "0.1.0"
+
+            [dependencies]
+            geometry = "1.2"
+            utils = { path = "../utils" }
+            strings = { git = "/repos/strings", rev = "v1" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(manifest.package.name, "app");
+        assert_eq!(manifest.entry(), std::path::PathBuf::from("src/main.mo"));
+        assert_eq!(manifest.dependencies["geometry"].source().unwrap(), DependencySource::Registry);
+        assert!(manifest.dependencies["geometry"].version_req().matches(&"1.4.0".parse().unwrap()));
+        assert_eq!(
+            manifest.dependencies["utils"].source().unwrap(),
+            DependencySource::Path("../utils".into())
+        );
+        assert_eq!(
+            manifest.dependencies["strings"].source().unwrap(),
+            DependencySource::Git {
+                url: "/repos/strings".into(),
+                rev: Some("v1".into())
+            }
+        );
+        assert!(Manifest::parse("[package]\nname = \"a\"\nversion = \"1.0.0\"\n[dependencies]\nb = { path = \"b\", git = \"c\" }").is_err());
+    }
+
+    #[test]
+    fn optimizer_test() {
+        use morpho_c::program::optimizer::{optimize, OptLevel};
+        let optimized = |source: &str, level| optimize(parser::ProgParser::new().parse(source).unwrap(), level);
+        let parsed = |source: &str| parser::ProgParser::new().parse(source).unwrap();
+
+        assert_eq!(
+            optimized(r#"func main = () { print(2 + 2 * 9, "a" + "b", -(1 - 3), !false, "x{1 + 1}"); }"#, OptLevel::O1),
+            parsed(r#"func main = () { print(20, "ab", 2, true, "x2"); }"#)
+        );
+        // Expressions raising an error are left to the program
+        assert_eq!(
+            optimized("func main = () { print(9223372036854775807 + 1, 1 / 0, 1 < 2); }", OptLevel::O1),
+            parsed("func main = () { print(9223372036854775807 + 1, 1 / 0, 1 < 2); }")
+        );
+        assert_eq!(
+            optimized(
+                r#"func main = () {
+                    if(1 + 1 == 2, $print|"yes"|, $print|"no"|);
+                    if(false, $print|"never"|);
+                    if(true, $|| { let a = 1; print(a); });
+                    let b = if(false, $|| { print(1); }, $|| { print(2); });
+                }"#,
+                OptLevel::O1
+            ),
+            parsed(
+                r#"func main = () {
+                    print("yes");
+                    let a = 1;
+                    print(a);
+                    let b = if(true, $|| { print(2); });
+                }"#
+            )
+        );
+        // The branch declares a name the enclosing function uses
+        let shadowing = r#"func main = () { let a = 2; if(true, $|| { let a = 1; print(a); }); print(a); }"#;
+        assert_eq!(optimized(shadowing, OptLevel::O1), parsed(shadowing));
+
+        assert_eq!(
+            optimized(
+                r#"func square = (x: int) -> int { return x * x; }
+                func half = (x: float) -> float { return x / 2.0; }
+                func unused = () { print(0); }
+                func main = (n: int) { print(square(3), square(n), square(1.5), half(1)); }"#,
+                OptLevel::O2
+            ),
+            parsed(
+                r#"func square = (x: int) -> int { return x * x; }
+                func half = (x: float) -> float { return x / 2.0; }
+                func main = (n: int) { print(9, n * n, square(1.5), half(1)); }"#
+            )
+        );
+        // Recursive and reassigning callers are left alone
+        let recursive = r#"func twice = (x: int) -> int { return x + x; }
+            func count = (n: int) -> int { n = n + 1; return twice(n); }
+            func fact = (n: int) -> int { return if(n < 2, $|| -> int { return 1; }, $|| -> int { return n * fact(n - 1); }); }
+            func main = () { print(count(1), fact(5)); }"#;
+        assert_eq!(optimized(recursive, OptLevel::O2), parsed(recursive));
+        assert_eq!(optimized(recursive, OptLevel::O0), parsed(recursive));
+    }
+
+    #[test]
+    fn optimizer_preserves_output_test() {
+        let program = r#"const LIMIT = 2 * 5 + 1;
+
+            func square = (x: int) -> int { return x * x; }
+            func half = (x: float) -> float { return x / 2.0; }
+            func greet = (name: string) -> string { return "hi " + name; }
+            func show = (n: int) { print("n = ", n); }
+            func unused = () { print("never"); }
+            func sum = (n: int) -> int { return square(n) + square(3); }
+            func fib = (n: int) -> int {
+                return if(n < 2, $|n: n| -> int { return n; }, $|n: n| -> int { return fib(n - 1) + fib(n - 2); });
+            }
+
+            func main = () {
+                print(2 + 2 * 9, " ", 7 % 3, " ", 1 << 4, " ", !true, " ", -(3 - 5), " ", 3 ^ 5);
+                print("con" + "cat", " ", 1.5 * 2, " ", 2n * 3, " ", 10 / 4, " ", "{1 + 2}!");
+                if(1 < 2, $show|1|, $show|2|);
+                if(2 + 2 == 5, $show|3|);
+                if(true, $|| {
+                    let inner = square(4);
+                    print("inner ", inner);
+                });
+                if(false, $show|4|, $|| { print("else"); });
+                let v = if(true, $square|6|);
+                print(v, " ", sum(2), " ", half(3.0), " ", greet("bob"), " ", LIMIT, " ", fib(10));
+                for(i in 0..3, $|i: i| { print("loop ", i * 2); });
+                while(1 > 3, $|| { print("never"); });
+                print(square(2.5 > 1));
+            }"#;
+        let run = |backend: &str, level: &str| outcome(run_morpho(program, &["run", "--backend", backend, "--opt-level", level]));
+        let (success, stdout, stderr) = run("tree", "0");
+        assert!(!success);
+        assert!(stdout.ends_with("loop 0\nloop 2\nloop 4\n"), "{stdout}");
+        assert!(stderr.contains("TypeError: argument x of square expects int, found bool"), "{stderr}");
+        for backend in ["tree", "vm"] {
+            for level in ["0", "1", "2"] {
+                assert_eq!(run(backend, level), (success, stdout.clone(), stderr.clone()), "--backend {backend} --opt-level {level}");
+            }
+        }
+    }
+
+    #[test]
+    fn max_call_depth_flag_test() {
+        let program = r#"func main = () { print(depth(100)); }
+            func depth = (n: int) -> int {
+                return if(n == 0, $|| -> int { return 0; }, $|n: n| -> int { return 1 + depth(n - 1); });
+            }"#;
+        for backend in ["tree", "vm"] {
+            let run = |max_depth: &str| run_morpho(program, &["run", "--backend", backend, "--max-call-depth", max_depth]);
+            let output = run("200");
+            assert!(output.status.success(), "--backend {backend}");
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), "100\n");
+            let output = run("50");
+            assert!(!output.status.success(), "--backend {backend}");
+            let stderr = String::from_utf8(output.stderr).unwrap();
+            assert!(stderr.contains("StackOverflow: 51 nested calls"), "{stderr}");
+            assert!(stderr.contains("    at main"), "{stderr}");
+        }
+    }
+
+    #[test]
+    fn backends_raise_the_same_errors_test() {
+        let cases = [
+            (
+                "func f = (a: int, b: int) -> int { return a + b; } func main = () { print(f(1)); }",
+                "",
+                "TypeError: f expects 2 arguments, found 1",
+            ),
+            (
+                "func f = (a: int, b: int) -> int { return a + b; } func main = () { print(f(1, 2, 3)); }",
+                "",
+                "TypeError: f expects 2 arguments, found 3",
+            ),
+            (r#"func main = () { 1 + 2; "a" < "b"; print("ok"); 1 / 0; }"#, "ok\n", "DivisionByZero: division by zero"),
+            ("func main = () { print(x); }", "", "NameError: x not found"),
+            ("func main = () { y = 1; }", "", "NameError: y not found"),
+            ("func main = () { g(); }", "", "NameError: Function g not found"),
+            ("func main = () { print(m::f(1)); }", "", "NameError: Module m not found"),
+            // Errors come when the code runs, after what the program printed before
+            (
+                r#"func f = (a: int, b: int) -> int { return a + b; } func main = () { print("hi"); f(1); }"#,
+                "hi\n",
+                "TypeError: f expects 2 arguments, found 1",
+            ),
+            (
+                r#"func main = () { let x = 1; x(print("arg")); }"#,
+                "arg\n",
+                "TypeError: x is not a function",
+            ),
+            (r#"func main = () { print("hi"); print(m::x); }"#, "hi\n", "NameError: Module m not found"),
+            (r#"func main = () { print("hi"); print(math::5); }"#, "hi\n", "TypeError: expected a name or a call after math::"),
+        ];
+        for (program, stdout, error) in cases {
+            let tree = outcome(run_morpho(program, &["run", "--backend", "tree"]));
+            assert!(!tree.0, "{program}");
+            assert_eq!(tree.1, stdout, "{program}");
+            assert!(tree.2.contains(error), "{program}: {}", tree.2);
+            assert_eq!(outcome(run_morpho(program, &["run", "--backend", "vm"])), tree, "{program}");
+        }
+
+        // Code that never runs can't fail the program
+        let programs = [
+            (r#"func main = () { 1 + 2; "a" < "b"; print("ok"); }"#, "ok\n"),
+            (r#"func main = () { print("hi"); if(1 > 2, $|| { nope(); }); print("bye"); }"#, "hi\nbye\n"),
+            (
+                r#"func f = (a: int) -> int { return a; }
+                func main = () { print("hi"); if(1 > 2, $|| { y = f(1, 2); print(m::g(x)); }); print("bye"); }"#,
+                "hi\nbye\n",
+            ),
+        ];
+        for (program, stdout) in programs {
+            for backend in ["tree", "vm"] {
+                let output = run_morpho(program, &["run", "--backend", backend]);
+                assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}: {program}");
+            }
+        }
+    }
+
+    #[test]
+    fn conditions_passed_to_built_in_functions_test() {
+        let program = r#"func main = () {
+            print(string::from(1 < 2));
+            print(string::len(string::from(2 == 3)));
+            print(string::from(1 + 1 != 2) + "!");
+            print(int(1 < 2) + int(2 < 1) * 10);
+        }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            assert_eq!(outcome(output), (true, "true\n5\nfalse!\n1\n".to_string(), String::new()), "--backend {backend}");
+        }
+        let program = "func main = () { print(float(1 < 2)); }";
+        let tree = outcome(run_morpho(program, &["run", "--backend", "tree"]));
+        assert!(!tree.0 && tree.2.contains("TypeError: cannot convert bool to float"), "{}", tree.2);
+        assert_eq!(outcome(run_morpho(program, &["run", "--backend", "vm"])), tree);
+    }
+
+    #[test]
+    fn closure_in_variable_test() {
+        let program = r#"
+            func apply = (g: func) -> int { return g(); }
+            func add = (a: int, b: int) -> int { return a + b; }
+            func main = () {
+                let f = $|v: 2| -> int { return v * 2; };
+                print(f());
+                print(apply($|v: 5| -> int { return v + 1; }));
+                let x = 1;
+                let h = $|v: x, w: x + 10| -> int { return v + w; };
+                x = 5;
+                print(h());
+                let inc = $|v: &x| { v = v + 1; };
+                inc();
+                print(x);
+                let g = add;
+                print(g(1, 2));
+                print(f);
+                print(g);
+            }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            let stdout = "4\n6\n20\n6\n3\n<anonymous>\nadd\n";
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+
+        let cases = [
+            ("func main = () { let f = $|v: 2| -> int { return v; }; f(1); }", "TypeError: f expects 0 arguments, found 1"),
+            ("func main = () { let x = 1; x(); }", "TypeError: x is not a function"),
+            (r#"func main = () { let s = $print|"x"|; s(); }"#, "TypeError: s is not a function"),
+        ];
+        for (program, error) in cases {
+            let tree = outcome(run_morpho(program, &["run", "--backend", "tree"]));
+            assert!(!tree.0 && tree.2.contains(error), "{program}: {}", tree.2);
+            assert_eq!(outcome(run_morpho(program, &["run", "--backend", "vm"])), tree, "{program}");
+        }
+    }
+
+    #[test]
+    fn string_escapes_and_interpolation_test() {
+        let program = r#"func main = () {
+            let x = 41;
+            print("tab:\t\"quoted\"\nnext line \u{2728}");
+            print("x = {x + 1}, x > 40 is {x > 40}");
+        }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            let stdout = "tab:\t\"quoted\"\nnext line \u{2728}\nx = 42, x > 40 is true\n";
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn string_core_module_test() {
+        let program = r#"func main = () {
+            let t = string::trim("  Hello, Morpho  ");
+            print(t, " ", string::len(t), " ", string::to_upper(t), " ", string::to_lower(t));
+            print(string::split("a,b,c", ","), " ", string::join(["x", "y", "z"], "-"), " ", string::chars("abc"));
+            print(string::substr(t, 7, 6), " ", string::find(t, "Morpho"), " ", string::starts_with(t, "Hell"));
+            print(string::replace(t, "Morpho", "world"));
+            print(string::parse_int("41") + 1, " ", string::format(string::parse_float("3.14159"), 2));
+            let greeting = "Hello, " + "world";
+            print(greeting, " ", "abc" < "abd", " ", "b" >= "a");
+        }"#;
+        let stdout = "Hello, Morpho 13 HELLO, MORPHO hello, morpho\n\
+            [a, b, c] x-y-z [a, b, c]\n\
+            Morpho 7 true\n\
+            Hello, world\n\
+            42 3.14\n\
+            Hello, world true true\n";
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+
+        // An empty separator splits at whitespace, a separator splits at each occurrence
+        let program = r#"func main = () {
+            print(string::split("  a b\t\tc \n", ""), " ", string::split("   ", ""));
+            print(string::split("a,,b", ","));
+        }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            assert_eq!(outcome(output), (true, "[a, b, c] []\n[a, , b]\n".to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn numeric_tower_test() {
+        let program = r#"func main = () {
+            print(1 + 2.5, " ", 7 / 2, " ", 7 / 2.0, " ", float(7) / 2, " ", int(3.99), " ", int(-3.99), " ", int("12"));
+            print(1.5 < 2.0, " ", 2 > 1.5, " ", 1 == 1.0, " ", -3.14, " ", -(1.5 * 2));
+            let x = 2.5;
+            print(-x, " ", 2 * 3 + 4 * 5, " ", (10 - 4) / 2 - 1, " ", 10 - 4 - 1, " ", 16 / 4 / 2, " ", half(3));
+        }
+        func half = (x: float) -> float { return x / 2; }"#;
+        let stdout = "3.5 3 3.5 3.5 3 -3 12\ntrue true true -3.14 -3.0\n-2.5 26 2 5 2 1.5\n";
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn bigint_arithmetic_test() {
+        let program = r#"func main = () {
+            print(fact(30));
+            print(2n * 9223372036854775807 + 1, " ", -7n / 2n, " ", 100000000000000000000n % 7);
+            print(int(12n) + 1, " ", float(10n) / 4, " ", 10n > 9, " ", 5 == 5n, " ", 1n < 1.5);
+            print(bigint("123456789012345678901234567890") - 1, " ", string::from(42n), " ", bigint(3.9));
+        }
+        func fact = (n: bigint) -> bigint {
+            return if(n <= 1, $|| -> bigint { return 1n; }, $|n: n| -> bigint { return n * fact(n - 1); });
+        }"#;
+        let stdout = "265252859812191058636308480000000\n\
+            18446744073709551615 -3 2\n\
+            13 2.5 true true true\n\
+            123456789012345678901234567889 42 3\n";
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn math_core_module_test() {
+        let program = r#"func main = () {
+            print(math::sqrt(16), " ", math::pow(2, 10), " ", math::pow(2.0, 0.5), " ", math::pow(2n, 100));
+            print(math::abs(-5), " ", math::abs(-2.5), " ", math::min(3, 1.5, 2), " ", math::max(3, 7, 5));
+            print(math::floor(2.7), " ", math::ceil(2.1), " ", math::round(2.5), " ", string::format(math::pi, 5), " ", string::format(math::e, 3));
+            print(math::sin(0), " ", math::cos(0), " ", string::format(math::atan2(1, 1) * 4, 4), " ", math::log(math::exp(2)), " ", math::log(8, 2), " ", math::log10(1000));
+            print(math::gcd(48, 18), " ", math::lcm(4, 6), " ", math::gcd(100000000000000000000n, 30));
+            print(6 & 3, " ", 6 | 3, " ", 6 ^ 3, " ", 1 << 10, " ", 1024 >> 3, " ", 1 << 2 + 1, " ", true & false);
+            for(i in 0..3, $|i: i| { print(i, " -> ", (i | 4) << 1); });
+        }"#;
+        let stdout = "4.0 1024 1.4142135623730951 1267650600228229401496703205376\n\
+            5 2.5 1.5 7\n\
+            2.0 3.0 3.0 3.14159 2.718\n\
+            0.0 1.0 3.1416 2.0 3.0 3.0\n\
+            6 12 10\n\
+            2 7 5 1024 128 8 false\n\
+            0 -> 8\n1 -> 10\n2 -> 12\n";
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+
+        let program = r#"func main = () { print("before"); print(math::sqrt(-1)); }"#;
+        let tree = outcome(run_morpho(program, &["run", "--backend", "tree"]));
+        assert!(!tree.0 && tree.1 == "before\n", "{}", tree.1);
+        assert!(tree.2.contains("ValueError: math::sqrt: -1 is out of the function domain"), "{}", tree.2);
+        assert_eq!(outcome(run_morpho(program, &["run", "--backend", "vm"])), tree);
+    }
+
+    #[test]
+    fn qualified_paths_in_expressions_test() {
+        let program = r#"
+            mod calc {
+                pub func square = (x: int) -> int { return x * x; }
+                pub func name = () -> string { return "calc"; }
+                pub mod nested {
+                    pub func cube = (x: int) -> int { return x * super::square(x); }
+                    pub func twice = (x: int) -> int { return self::helper(x) * 2; }
+                    func helper = (x: int) -> int { return x; }
+                }
+                pub func sum_cubes = (a: int, b: int) -> int { return nested::cube(a) + nested::cube(b); }
+            }
+            func name = () -> string { return "root"; }
+            func main = () {
+                let x = calc::square(7);
+                print(x, " ", calc::square(calc::square(2)) + 1, " ", calc::nested::cube(3));
+                print(calc::sum_cubes(1, 2), " ", crate::calc::nested::twice(21), " ", calc::name(), " ", name());
+                print(string::len(calc::name()), " ", math::pi > 3);
+                let y = if(calc::square(3) == 9, $|| -> int { return calc::nested::cube(2); }, $|| -> int { return 0; });
+                print(y);
+            }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            let stdout = "49 17 27\n9 42 calc root\n4 true\n8\n";
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn resolved_names_test() {
+        // A name refers to the global until its `let`, references and conditions
+        // passed to functions use the caller's slots
+        let program = r#"
+            let total = 100;
+            mod shapes {
+                pub const SIDES = 4;
+                pub func sides = () -> int { return SIDES; }
+            }
+            func main = () {
+                print(total);
+                let total = 1;
+                total = total + shapes::sides();
+                bump(&total);
+                print(total, " ", same(total > 5));
+                for(i in 0..3, $|total: &total, i: i| { let step = i * 2; total = total + step; });
+                print(total);
+            }
+            func bump = (n: int) { n = n + 1; }
+            func same = (flag: bool) -> bool { let total = flag; return total; }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            let stdout = "100\n6 true\n12\n";
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn tail_calls_test() {
+        // Calls in tail position, also through the branches of `if`, reuse the
+        // frame of the caller and don't grow the stack
+        let program = r#"
+            func main = () {
+                count(1000000);
+                print(is_even(100001));
+            }
+            func count = (n: int) {
+                if(n == 0, $print|"done"|, $count|n - 1|);
+            }
+            func is_even = (n: int) -> bool {
+                return if(n == 0, $|| -> bool { return true; }, $is_odd|n - 1|);
+            }
+            func is_odd = (n: int) -> bool {
+                return if(n == 0, $|| -> bool { return false; }, $|n: n| -> bool { return is_even(n - 1); });
+            }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            let stdout = "done\nfalse\n";
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn aliased_references_test() {
+        // References to one variable read and write it in turn, never at the same time
+        let program = r#"
+            func main = () {
+                let a = 3;
+                twice(&a, &a);
+                print(a);
+                let b = 2;
+                for(i in 0..2, $|x: &b, y: &b| { x = x + y; y = -y; });
+                print(b, " ", b == b);
+            }
+            func twice = (x: int, y: int) { x = x + y; y = y * 2; }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            let stdout = "12\n8 true\n";
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn anonymous_closures_test() {
+        // The same anonymous function takes arguments of any type each time it runs
+        let program = r#"
+            func main = () {
+                let value = 21;
+                for(i in 0..3, $|value: &value, i: i| {
+                    if(1 < 2, $|v: value| { print(v + v); });
+                    value = if(i == 0, $|| -> string { return "ab"; }, $|| -> float { return 1.5; });
+                });
+            }"#;
+        for backend in ["tree", "vm"] {
+            let output = run_morpho(program, &["run", "--backend", backend]);
+            let stdout = "42\nabab\n3.0\n";
+            assert_eq!(outcome(output), (true, stdout.to_string(), String::new()), "--backend {backend}");
+        }
+    }
+
+    #[test]
+    fn load_program_test() {
+        use program::environment::LocalEnvironment;
+        use program::evaluating_functions::{call_func, load_program};
+        use program::shared::Shared;
+        use program::value::Value;
+
+        let prog = parser::ProgParser::new()
+            .parse("let base = 40; func main = () {} func add = (n: int) -> int { return base + n; }")
+            .unwrap();
+        load_program(prog).unwrap();
+        let call = CallExpr::new("add".into(), vec![Expr::Integer(2)]);
+        let env = Shared::new(LocalEnvironment::new());
+        assert_eq!(call_func(&call, env.clone()), Value::Int(42));
+        assert_eq!(call_func(&call, env), Value::Int(42));
+    }
+
+    #[cfg(feature = "jit")]
+    #[test]
+    fn jit_matches_interpreter_test() {
+        let programs = [
+            // Recursion through the anonymous functions of `if`
+            r#"func main = () { print(fib(22)); }
+            func fib = (n: int) -> int {
+                return if(n < 2, $|n: n| -> int { return n; }, $|n: n| -> int { return fib(n - 1) + fib(n - 2); });
+            }"#,
+            // Loops assigning to the variables of the caller, bit operations and comparisons
+            r#"func main = () {
+                let s = 0;
+                let i = 0;
+                let odd = false;
+                while(i < 3000, $|s: &s, i: &i, odd: &odd| {
+                    s = s + (i ^ 5) * 3 - (i & 7) + (i | 1) % 11 + (i << 2) - (i >> 1) / 3;
+                    odd = !odd && i % 2 != 0 || i >= 2999;
+                    i = i + 1;
+                });
+                print(s, " ", odd, " ", !s, " ", -s);
+            }"#,
+            // Ints passed to floats, float remainder and ints returned as floats
+            r#"func main = () {
+                let x = 0.5;
+                let i = 0;
+                while(i < 500, $|x: &x, i: &i| { x = x + mean(x, i) % 7.5 - half(i); i = i + 1; });
+                print(x, " ", x > 100, " ", 3 == 3.0, " ", x <= x);
+            }
+            func mean = (a: float, b: float) -> float { return (a + b) / 2; }
+            func half = (n: int) -> float { return n / 2; }"#,
+            // References to one variable passed twice
+            r#"func main = () {
+                let total = 0;
+                let i = 0;
+                while(i < 300, $|total: &total, i: &i| { let a = i; twice(&a, &a); total = total + a; i = i + 1; });
+                print(total);
+            }
+            func twice = (x: int, y: int) { x = x + y; y = y * 2; }"#,
+            // Errors raised once the functions are compiled
+            r#"func main = () { let x = 1; while(true, $|x: &x| { x = triple(x); }); }
+            func triple = (x: int) -> int { return x * 3 + 1; }"#,
+            r#"func main = () { let i = 200; while(true, $|i: &i| { print(inverse(i - 50)); i = i - 1; }); }
+            func inverse = (i: int) -> int { return 1000 / i; }"#,
+            r#"func main = () { let i = 0; while(true, $|i: &i| { i = shift(i); }); }
+            func shift = (i: int) -> int { return (1 << i) * 0 + i + 1; }"#,
+            r#"func main = () { let i = 300; while(true, $|i: &i| { i = i - 1; print(ratio(i)); }); }
+            func ratio = (i: int) -> float { return 1.5 % (i - 100); }"#,
+            r#"func main = () { let x = 0; while(true, $|x: &x| { x = x + 1; x = check(x); }); }
+            func check = (x: int) -> int { return if(x < 150, $|x: x| -> int { return x; }, $|x: x| -> int { return x * 1.0; }); }"#,
+            // Recursion deeper than the JIT nests calls, then deeper than allowed
+            r#"func main = () { print(depth(0, 3000)); print(depth(0, 20000)); }
+            func depth = (n: int, max: int) -> int {
+                return if(n == max, $|| -> int { return 0; }, $|n: n, max: max| -> int { return 1 + depth(n + 1, max); });
+            }"#,
+        ];
+        for program in programs {
+            let run = |flags: &[&str]| outcome(run_morpho(program, &[&["run", "--backend", "vm"], flags].concat()));
+            assert_eq!(run(&[]), run(&["--no-jit"]), "{program}");
+        }
+
+        // The hot functions of the programs that run to the end are compiled and run natively
+        let compiled = |flags: &[&str], program: &str| -> (u64, u64) {
+            let (ok, _, stderr) = outcome(run_morpho(program, &[&["run", "--backend", "vm", "--jit-stats"], flags].concat()));
+            assert!(ok, "{program}: {stderr}");
+            let stats = stderr.lines().find_map(|line| line.strip_prefix("JIT compiled: ")).expect("stats are printed");
+            let (compiled, native_calls) = stats.split_once(", native calls: ").unwrap();
+            (compiled.parse().unwrap(), native_calls.parse().unwrap())
+        };
+        for program in &programs[..4] {
+            let (functions, calls) = compiled(&[], program);
+            assert!(functions > 0 && calls > 0, "{program}: {functions} compiled, {calls} native calls");
+            assert_eq!(compiled(&["--no-jit"], program), (0, 0));
+        }
+    }
+
+    /// Programs compiled to LLVM IR, with a line of IR each of them needs
+    const LLVM_PROGRAMS: [(&str, &str); 5] = [
+        (
+            r#"func main = () {
+                let sign = if(fib(5) > 3, $|| -> int { return 1; }, $|| -> int { return -1; });
+                print(fib(20) * sign, " ", mean(1.5, 2), " ", 0.1 + 0.2, " ", -7 / 2, " ", 7.5 % 2);
+            }
+            func fib = (n: int) -> int {
+                return if(n < 2, $|n: n| -> int { return n; }, $|n: n| -> int { return fib(n - 1) + fib(n - 2); });
+            }
+            func mean = (a: float, b: int) -> float { return (a + b) / 2; }"#,
+            "define internal double @\"morpho.mean\"(double %a0, i64 %a1) {",
+        ),
+        (
+            r#"let GREETING = "hello";
+            func main = () {
+                let s = "";
+                let i = 0;
+                while(i < 4, $|s: &s, i: &i| { s = s + "{i * 2},"; i = i + 1; });
+                print(GREETING + " " + s, " ", s < GREETING, " ", 1.0 / 3 > 0.3, " ", !true, " ", 6 ^ 3);
+                for(i in 0..2, $|i: i| { print("i = {i} {i == 1} {i * 0.5}"); });
+            }"#,
+            "@\"morpho_global.GREETING\" = internal global ptr null",
+        ),
+        (
+            r#"func main = () { let x = 1; while(true, $|x: &x| { print(x); x = x * 1000; }); }"#,
+            "call { i64, i1 } @llvm.smul.with.overflow.i64(",
+        ),
+        (
+            r#"func main = () { print(inverse(0)); }
+            func inverse = (i: int) -> int { return 1000 / i; }"#,
+            "call void @morpho_division_by_zero()",
+        ),
+        (
+            r#"func main = () { print(depth(0)); }
+            func depth = (n: int) -> int { return if(n < 0, $|| -> int { return 0; }, $|n: n| -> int { return 1 + depth(n + 1); }); }"#,
+            "call void @morpho_stack_overflow(i64 ",
+        ),
+    ];
+
+    /// Builds `program` to LLVM IR, returning the IR file
+    fn build_llvm_ir(program: &str) -> PathBuf {
+        let ll = temp_file("main.ll");
+        let output = run_morpho(program, &["build", "--emit=llvm-ir", "-o", ll.to_str().unwrap()]);
+        assert!(output.status.success(), "{program}: {}", String::from_utf8_lossy(&output.stderr));
+        assert!(ll.with_file_name("morpho_runtime.c").exists());
+        ll
+    }
+
+    #[test]
+    fn llvm_ir_build_test() {
+        for (program, line) in LLVM_PROGRAMS {
+            let ir = std::fs::read_to_string(build_llvm_ir(program)).unwrap();
+            assert!(ir.contains("define i32 @main() {"), "{ir}");
+            assert!(ir.contains("define internal void @\"morpho.main\"() {"), "{ir}");
+            assert!(ir.contains("declare { i64, i1 } @llvm.smul.with.overflow.i64(i64, i64)"), "{ir}");
+            assert_eq!(ir.matches('{').count(), ir.matches('}').count(), "{ir}");
+            assert!(ir.contains(line), "{line}\n{ir}");
+            // Every block ends with a terminator
+            let mut last = "";
+            for ir_line in ir.lines().map(str::trim) {
+                if ir_line.ends_with(':') || ir_line == "}" {
+                    assert!(
+                        last.is_empty() || last.ends_with('{') || ["ret", "br", "unreachable"].iter().any(|t| last.starts_with(t)),
+                        "{last} ends a block\n{ir}"
+                    );
+                }
+                if !ir_line.is_empty() && !ir_line.starts_with(';') {
+                    last = ir_line;
+                }
+            }
+        }
+        let ir = std::fs::read_to_string(build_llvm_ir(LLVM_PROGRAMS[0].0)).unwrap();
+        assert!(ir.contains("define internal i64 @\"morpho.fib\"(i64 %a0) {"), "{ir}");
+        assert!(ir.contains(" = phi i64 "), "{ir}");
+        assert!(ir.contains("call void @morpho_print_float(double "), "{ir}");
+
+        // Values need a type known ahead of time
+        let output = run_morpho(
+            r#"func main = () { print(first([1, 2])); }
+            func first = (xs: array) -> int { return 1; }"#,
+            &["build", "--emit=llvm-ir", "-o", temp_file("main.ll").to_str().unwrap()],
+        );
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("main (line 1)"), "{stderr}");
+    }
+
+    #[test]
+    #[ignore = "needs clang on the PATH to compile the IR"]
+    fn llvm_ir_runs_like_vm_test() {
+        for (program, _) in LLVM_PROGRAMS {
+            let ll = build_llvm_ir(program);
+            let binary = temp_file("main");
+            let status = Command::new("clang")
+                .arg("-Wno-override-module")
+                .args([&ll, &ll.with_file_name("morpho_runtime.c")])
+                .args(["-lm", "-o"])
+                .arg(&binary)
+                .status()
+                .expect("clang runs");
+            assert!(status.success(), "{program}");
+            let native = outcome(Command::new(&binary).output().unwrap());
+            let interpreted = outcome(run_morpho(program, &["run", "--backend", "vm"]));
+            assert_eq!((native.0, &native.1), (interpreted.0, &interpreted.1), "{program}");
+            // The interpreter follows the message with the trace of calls
+            assert!(interpreted.2.starts_with(&native.2), "{}", native.2);
+        }
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn sync_values_are_send_test() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<program::value::Value>();
+        assert_send_sync::<program::shared::Shared<program::environment::LocalEnvironment>>();
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn shared_values_across_threads_test() {
+        use program::shared::Shared;
+        use program::value::Value;
+
+        let counter = Shared::new(Value::Int(0));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let counter = counter.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let mut value = counter.borrow_mut();
+                        let Value::Int(i) = *value else { panic!("counter holds an int") };
+                        *value = Value::Int(i + 1);
+                        drop(value);
+                        assert!(matches!(counter.get(), Value::Int(_)));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(counter.get(), Value::Int(8000));
+
+        // A thread that panics while writing leaves the value usable
+        let writer = counter.clone();
+        let result = std::thread::spawn(move || {
+            let _guard = writer.borrow_mut();
+            panic!("error while writing");
+        })
+        .join();
+        assert!(result.is_err());
+        counter.set(Value::Int(1));
+        assert_eq!(counter.get(), Value::Int(1));
+    }
 }